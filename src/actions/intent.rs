@@ -1,56 +1,93 @@
 use crate::actions::action::Action;
-use crate::api::Api;
+use crate::api::{Api, ApiHealth};
 use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::dialogue::follow_up::{FollowUps, Turn};
 use crate::dialogue::intent::{Intent, IntentInfo, Slot};
 use crate::dialogue::languages::lang;
-use crate::dialogue::reply::Replayed;
+use crate::dialogue::matcher::{IntentEngine, LocalMatcher};
+use crate::dialogue::reply::{Replayed, ReplyHandler, RequestReply};
+use crate::dialogue::request::{Origin, TextRequest};
+use crate::dialogue::response::BoolValidator;
 use crate::skills::manager::SkillManager;
-use crate::{subscribe, watch_dir};
+use crate::{speak, subscribe, watch_dir};
 use avi_device::device::AviDevice;
 use avi_nlu_client::models::{self, Alive, Data1Inner};
 use log::{error, info, warn};
+use parking_lot::RwLock;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
+/// Number of intents considered for each utterance, the runners-up are offered when the
+/// user turns down the first guess.
+const CANDIDATES: usize = 3;
+
 pub struct IntentAction {
     device: Arc<AviDevice>,
-    api: Arc<Mutex<Api>>,
-    skill_manager: Arc<Mutex<SkillManager>>,
+    /// The avi-nlu client, `None` when only the local matcher is used.
+    api: Option<Arc<Mutex<Api>>>,
+    /// Whether the avi-nlu server answers, retried with a backoff in hybrid mode.
+    health: Arc<ApiHealth>,
+    matcher: Arc<RwLock<LocalMatcher>>,
+    /// Intents that may continue the last turn of any skill.
+    follow_ups: Arc<RwLock<FollowUps>>,
+    skill_manager: Arc<SkillManager>,
     config: IntentConfig,
 }
 
 pub struct IntentConfig {
     pub watch_skill_dir: bool,
     pub watch_dir_debounce_time: u64,
+    pub engine: IntentEngine,
 }
 
 impl IntentAction {
-    pub async fn process_reply_text(text: &str) -> Result<Replayed, String> {
+    pub async fn process_reply_text(origin: &Origin, text: &str) -> Result<Replayed, String> {
         match runtime() {
-            Ok(c) => c.reply_manager.process_text(text).await,
+            Ok(c) => c.reply_manager.process_text(origin, text).await,
             Err(e) => Err(e),
         }
     }
 
-    pub async fn parse_as_reply(&self, text: &str) -> bool {
-        let skill_manager: Arc<Mutex<SkillManager>> = Arc::clone(&self.skill_manager);
-
-        match IntentAction::process_reply_text(text).await {
+    pub async fn parse_as_reply(&self, origin: &Origin, text: &str) -> bool {
+        match IntentAction::process_reply_text(origin, text).await {
             Ok(replay) => {
-                let mut mg = skill_manager.lock().await;
-                if let Err(e) = mg.run_skill_function_ptr(
-                    &replay.pending_reply.skill_request,
-                    replay.pending_reply.handler,
-                    vec![replay.parsed_output],
-                ) {
-                    warn!("Error executing replay: {}", e);
+                let result = match replay.pending_reply.handler {
+                    ReplyHandler::Function(handler) => {
+                        self.skill_manager
+                            .run_skill_function_ptr(
+                                &replay.pending_reply.skill_request,
+                                handler,
+                                vec![replay.parsed_output],
+                                origin,
+                            )
+                            .await
+                    }
+                    ReplyHandler::Slot(filling) => {
+                        match filling.fill(&replay.text, replay.parsed_output) {
+                            Ok(intent) => self.skill_manager.run_intent(intent, origin).await,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    ReplyHandler::Confirm(intent, alternatives) => {
+                        if replay.parsed_output.as_bool().unwrap_or(false) {
+                            Ok(self.process_intent(*intent, origin).await)
+                        } else if !alternatives.is_empty() {
+                            Ok(self.confirm_intent(alternatives, origin).await)
+                        } else {
+                            Ok(self.fallback(origin, &intent.input).await)
+                        }
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("[{}] Error executing replay: {}", origin.trace_id, e);
                 }
                 true
             }
             Err(e) => {
                 if !e.is_empty() {
-                    warn!("Error processing replay: {}", e);
+                    warn!("[{}] Error processing replay: {}", origin.trace_id, e);
                     return true;
                 }
                 false
@@ -58,33 +95,184 @@ impl IntentAction {
         }
     }
 
-    pub async fn parse_as_intent(&self, text: &str) -> bool {
-        let api = Arc::clone(&self.api);
+    /// Recognizes the intent of `text` and acts on its confidence.
+    ///
+    /// Confident intents run, less confident ones are confirmed with the user first, offering
+    /// the next candidates when the user says no, and anything below the confirmation
+    /// threshold goes to the fallback skills.
+    pub async fn parse_as_intent(&self, origin: &Origin, text: &str) -> bool {
+        let threshold = setting_or::<f64>("intent_threshold", 70.0) / 100.0;
+        let confirm_threshold = setting_or::<f64>("intent_confirm_threshold", 40.0) / 100.0;
 
-        let maybe_intent = match api.lock().await.intent(text).await {
-            Ok(intent) => Some(intent),
-            Err(e) => {
-                warn!("Failed to parse intent: {}", e);
-                None
+        let mut candidates: Vec<models::NluResultInput> = Vec::new();
+
+        if let Some(api) = &self.api
+            && (self.config.engine == IntentEngine::Remote || self.api_ready(api).await)
+        {
+            let recognized = match api.lock().await.intent(text).await {
+                Ok(recognized) => Some(*recognized.result),
+                Err(e) => {
+                    warn!("[{}] Failed to parse intent: {}", origin.trace_id, e);
+                    self.health.failed(Instant::now());
+                    None
+                }
+            };
+
+            if let Some(models::Result::Nlu(intent)) = recognized {
+                candidates.push(*intent);
             }
-        };
+        }
 
-        if let Some(recognized) = maybe_intent {
-            match *recognized.result {
-                models::Result::Nlu(intent) => {
-                    return self.process_intent(*intent).await;
+        // The local matcher gets a say whenever the api isn't sure
+        if self.config.engine != IntentEngine::Remote
+            && candidates
+                .first()
+                .is_none_or(|b| b.intent.probability < threshold)
+        {
+            let local = self.matcher.read().candidates(text, CANDIDATES);
+            candidates.extend(local);
+        }
+        let candidates = Self::rank(candidates);
+
+        match candidates.first() {
+            Some(best) if best.intent.probability >= threshold => {
+                let best = candidates.into_iter().next().map(Self::to_intent);
+                match best {
+                    Some(best) => self.process_intent(best, origin).await,
+                    None => false,
                 }
-                _ => return false,
             }
+            Some(best) if best.intent.probability >= confirm_threshold => {
+                info!(
+                    "[{}] Not sure about {} ({:.2}), asking the user",
+                    origin.trace_id, best.intent.intent_name, best.intent.probability
+                );
+                let candidates = candidates
+                    .into_iter()
+                    .filter(|c| c.intent.probability >= confirm_threshold)
+                    .map(Self::to_intent)
+                    .collect();
+                self.confirm_intent(candidates, origin).await
+            }
+            _ => {
+                info!("[{}] No intent matched '{}'", origin.trace_id, text);
+                self.fallback(origin, text).await
+            }
+        }
+    }
+
+    /// Orders candidates from several engines by probability, keeping the best of each intent.
+    fn rank(mut candidates: Vec<models::NluResultInput>) -> Vec<models::NluResultInput> {
+        candidates.sort_by(|a, b| b.intent.probability.total_cmp(&a.intent.probability));
+        let mut seen = Vec::new();
+        candidates.retain(|c| {
+            if seen.contains(&c.intent.intent_name) {
+                return false;
+            }
+            seen.push(c.intent.intent_name.clone());
+            true
+        });
+        candidates.truncate(CANDIDATES);
+        candidates
+    }
+
+    /// Whether the server can be asked, checking again if it is back once its retry is due.
+    ///
+    /// A server that is back gets its engine prepared in the background and is asked from
+    /// the next utterance on.
+    async fn api_ready(&self, api: &Arc<Mutex<Api>>) -> bool {
+        let now = Instant::now();
+        if self.health.is_up() {
+            return true;
+        }
+        if !self.health.should_try(now) {
+            return false;
+        }
+
+        let alive = api.lock().await.alive().await;
+        // Held back until the engine is ready, or the next retry if preparing it stalls
+        self.health.failed(now);
+        match alive {
+            Ok(alive) => {
+                info!("Avi NLU API is back (Server v{}).", alive.version);
+                let api = Arc::clone(api);
+                let health = Arc::clone(&self.health);
+                let skill_manager = Arc::clone(&self.skill_manager);
+                tokio::spawn(async move {
+                    Self::prepare_engine(&api, alive, &skill_manager).await;
+                    health.succeeded();
+                });
+            }
+            Err(e) => warn!("Avi NLU API is still down: {}", e),
         }
         false
     }
 
-    async fn update_engines(&self) {
-        let skill_manager = Arc::clone(&self.skill_manager);
-        let api = Arc::clone(&self.api);
+    /// Gets the server's engine ready for the intents of the loaded skills.
+    async fn prepare_engine(api: &Mutex<Api>, alive: Alive, skill_manager: &SkillManager) {
+        Self::api_check(&*api.lock().await, alive).await;
+        if Self::should_update_engine(api, skill_manager).await {
+            info!("Updating the engine...");
+            Self::update_engines(api, skill_manager).await;
+        } else {
+            info!("Engine has the latest intent... Ignoring...");
+        }
+    }
+
+    /// Asks the user whether they meant the first of `candidates`, running it if they did.
+    ///
+    /// When they didn't, the remaining candidates are asked about in order.
+    async fn confirm_intent(&self, mut candidates: Vec<Intent>, origin: &Origin) -> bool {
+        if candidates.is_empty() {
+            return false;
+        }
+        let intent = candidates.remove(0);
+        let Some(name) = intent.intent.as_ref().map(|i| i.0.intent_name.clone()) else {
+            return false;
+        };
+        let Ok(c) = runtime() else {
+            return false;
+        };
+
+        match self.skill_manager.confirm_prompt(&name) {
+            Some(question) => speak!(&question, to: origin),
+            None => warn!("Failed to build the confirmation prompt of {}", name),
+        }
+
+        c.reply_manager
+            .set_reply(
+                &origin.device,
+                RequestReply {
+                    skill_request: name.split('@').next().unwrap_or_default().to_string(),
+                    handler: ReplyHandler::Confirm(Box::new(intent), candidates),
+                    validator: Box::new(BoolValidator::new(false)),
+                },
+            )
+            .await
+    }
+
+    /// Offers `text` to the fallback skills, telling the user when none of them handled it.
+    async fn fallback(&self, origin: &Origin, text: &str) -> bool {
+        if self.skill_manager.run_fallback(text, origin).await {
+            return true;
+        }
+        speak!(locale: "not_understood", to: origin);
+        false
+    }
 
-        let dataset = skill_manager.lock().await.get_dataset();
+    /// Rebuilds the local matcher and the follow-up intents from the intent files of the loaded skills.
+    fn reload_intents(
+        matcher: &RwLock<LocalMatcher>,
+        follow_ups: &RwLock<FollowUps>,
+        skill_manager: &SkillManager,
+    ) {
+        let paths = skill_manager.skill_paths();
+        *matcher.write() = LocalMatcher::load(&paths);
+        *follow_ups.write() = FollowUps::load(&paths);
+    }
+
+    async fn update_engines(api: &Mutex<Api>, skill_manager: &SkillManager) {
+        let dataset = skill_manager.get_dataset();
 
         match api.lock().await.set_engine_dataset(dataset).await {
             Ok(_) => info!("Updated the engine sucessfully"),
@@ -102,9 +290,7 @@ impl IntentAction {
         }
     }
 
-    async fn should_update_engine(&self) -> bool {
-        let api = Arc::clone(&self.api);
-
+    async fn should_update_engine(api: &Mutex<Api>, skill_manager: &SkillManager) -> bool {
         let active_intents_on_api: Vec<String> = api
             .lock()
             .await
@@ -115,10 +301,7 @@ impl IntentAction {
             .cloned()
             .unwrap_or_default();
 
-        let intents_i_have = self
-            .skill_manager
-            .lock()
-            .await
+        let intents_i_have = skill_manager
             .get_dataset()
             .data
             .iter()
@@ -141,18 +324,30 @@ impl IntentAction {
         false
     }
 
-    async fn process_intent(&self, intent: models::NluResultInput) -> bool {
-        let skill_manager = Arc::clone(&self.skill_manager);
-
-        let intent = Intent {
+    fn to_intent(intent: models::NluResultInput) -> Intent {
+        Intent {
             input: intent.input,
             intent: Some(IntentInfo(*intent.intent)),
-            slots: intent.slots.unwrap().into_iter().map(Slot).collect(),
-        };
-        let mut mg = skill_manager.lock().await;
+            slots: intent
+                .slots
+                .unwrap_or_default()
+                .into_iter()
+                .map(Slot)
+                .collect(),
+        }
+    }
 
-        if let Err(e) = mg.run_intent(intent) {
-            warn!("Error executing intent: {}", e);
+    async fn process_intent(&self, mut intent: Intent, origin: &Origin) -> bool {
+        if let Some(turn) = Turn::last(&origin.device)
+            && turn.merge_into(&mut intent, &self.follow_ups.read())
+        {
+            info!(
+                "[{}] Completed intent with slots from the previous turn ({})",
+                origin.trace_id, turn.intent
+            );
+        }
+        if let Err(e) = self.skill_manager.run_intent(intent, origin).await {
+            warn!("[{}] Error executing intent: {}", origin.trace_id, e);
         } else {
             return true;
         }
@@ -192,66 +387,112 @@ impl Action for IntentAction {
     type Config = IntentConfig;
 
     async fn new(config: Self::Config) -> Result<IntentAction, String> {
-        let api = Api::new();
-
-        match api.alive().await {
-            Ok(alive) => {
-                info!("Avi NLU API is up and running (Server v{}).", alive.version);
-                Self::api_check(&api, alive).await;
+        let (api, up) = match config.engine {
+            IntentEngine::Local => {
+                info!("Using the local intent matcher.");
+                (None, false)
             }
-            Err(_) => {
-                return Err("Avi NLU API is not running. Skipping intent actions.".to_string());
+            engine => {
+                let api = Api::new();
+
+                match api.alive().await {
+                    Ok(alive) => {
+                        info!("Avi NLU API is up and running (Server v{}).", alive.version);
+                        Self::api_check(&api, alive).await;
+                        (Some(Arc::new(Mutex::new(api))), true)
+                    }
+                    Err(_) if engine == IntentEngine::Remote => {
+                        return Err(
+                            "Avi NLU API is not running. Skipping intent actions.".to_string()
+                        );
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Avi NLU API is not running. Using the local intent matcher until it is back."
+                        );
+                        (Some(Arc::new(Mutex::new(api))), false)
+                    }
+                }
             }
         };
 
+        let skill_manager = Arc::new(SkillManager::new());
+        let matcher = Arc::new(RwLock::new(LocalMatcher::load(
+            &skill_manager.skill_paths(),
+        )));
+        let follow_ups = Arc::new(RwLock::new(FollowUps::load(&skill_manager.skill_paths())));
+
         Ok(Self {
             device: Arc::clone(&runtime()?.device),
-            api: Arc::new(Mutex::new(api)),
-            skill_manager: Arc::new(Mutex::new(SkillManager::new())),
+            api,
+            health: Arc::new(ApiHealth::new(up)),
+            matcher,
+            follow_ups,
+            skill_manager,
             config,
         })
     }
 
     async fn register(&mut self) {
         let device = Arc::clone(&self.device);
-        let api = Arc::clone(&self.api);
+        let api = self.api.clone();
+        let health = Arc::clone(&self.health);
+        let matcher = Arc::clone(&self.matcher);
+        let follow_ups = Arc::clone(&self.follow_ups);
         let skill_manager = Arc::clone(&self.skill_manager);
+        let engine = self.config.engine;
 
-        if self.should_update_engine().await {
-            info!("Updating the engine...");
-            self.update_engines().await;
-        } else {
-            info!("Engine has the latest intent... Ignoring...");
+        if let Some(api) = &self.api
+            && self.health.is_up()
+        {
+            if Self::should_update_engine(api, &self.skill_manager).await {
+                info!("Updating the engine...");
+                Self::update_engines(api, &self.skill_manager).await;
+            } else {
+                info!("Engine has the latest intent... Ignoring...");
+            }
+        }
+
+        if !self.health.is_up() && self.matcher.read().is_empty() {
+            warn!("The local intent matcher has no intents.");
         }
 
-        subscribe!("intent/execute/text", captures: [skill_manager, api, device], async: |_from, _topic, data| {
-                let msg = String::from_utf8_lossy(&data);
-                let text = msg.trim();
+        subscribe!("intent/execute/text", captures: [skill_manager, api, health, matcher, follow_ups, device], async: |from, _topic, data| {
+                let request = TextRequest::parse(&data);
+                let origin = request.origin(&from);
+                let text = request.text.as_str();
+                info!("[{}] Text request from {}: '{}'", origin.trace_id, origin.device, text);
 
                 let intent_action = IntentAction {
                     device: Arc::clone(&device),
                     api,
+                    health,
+                    matcher,
+                    follow_ups,
                     skill_manager,
-                    config: IntentConfig { watch_skill_dir: false, watch_dir_debounce_time: 10 }
+                    config: IntentConfig { watch_skill_dir: false, watch_dir_debounce_time: 10, engine }
                 };
 
-                if !intent_action.parse_as_reply(text).await {
-                    let _ = intent_action.parse_as_intent(text).await;
+                if !intent_action.parse_as_reply(&origin, text).await {
+                    let _ = intent_action.parse_as_intent(&origin, text).await;
                 }
         });
 
-        subscribe!("intent/reply/cancel", async: move |_from, _topic, _data| async move {
-            if let Ok(c) = runtime() { c.reply_manager.cancel().await };
+        // A device may only cancel its own reply
+        subscribe!("intent/reply/cancel", async: move |from, _topic, _data| async move {
+            if let Ok(c) = runtime() { c.reply_manager.cancel(&from).await; };
         });
 
-        subscribe!("skills/reload", captures: [skill_manager], async: |_from, _topic, _data| {
-            let mut lock = skill_manager.lock().await;
-            let _ = lock.reload();
+        subscribe!("skills/reload", captures: [skill_manager, matcher, follow_ups], async: |_from, _topic, _data| {
+            if let Err(e) = skill_manager.reload().await {
+                error!("Error reloading skills: {}", e);
+            }
+            Self::reload_intents(&matcher, &follow_ups, &skill_manager);
         });
 
         if self.config.watch_skill_dir {
             let time = self.config.watch_dir_debounce_time;
-            watch_dir!("./config/skills", Duration::from_secs(time), captures: [skill_manager], async: |event| {
+            watch_dir!("./config/skills", Duration::from_secs(time), captures: [skill_manager, matcher, follow_ups], async: |event| {
 
                 for path in &event.paths {
                     if path.is_dir() {
@@ -266,9 +507,11 @@ impl Action for IntentAction {
                         return;
                     }
 
-                    let mut lock = skill_manager.lock().await;
-                    let _ = lock.reload();
-                    info!("Reloaded skills due to change in: {:?}", path);
+                    match skill_manager.reload().await {
+                        Ok(_) => info!("Reloaded skills due to change in: {:?}", path),
+                        Err(e) => error!("Error reloading skills: {}", e),
+                    }
+                    Self::reload_intents(&matcher, &follow_ups, &skill_manager);
                 }
             });
         }
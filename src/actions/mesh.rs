@@ -1,8 +1,11 @@
 use crate::actions::action::Action;
+use crate::commands::{CommandRequest, CommandResponse, call_topic, result_topic};
 use crate::ctx::runtime;
-use crate::subscribe;
+use crate::events::PEER_CONNECTED;
+use crate::{emit, subscribe};
 use avi_device::device::AviDevice;
 use log::{error, info, trace, warn};
+use serde_json::json;
 use std::sync::Arc;
 
 pub struct MeshConfig {}
@@ -20,6 +23,10 @@ pub async fn on_peer_disconnected(avi_device: AviDevice, peer_id: String) {
         Err(e) => warn!("Error removing peer {} from caps: {}", peer_id, e),
     }
 
+    if let Ok(c) = runtime() {
+        c.commands.fail_peer(&peer_id);
+    }
+
     let mut data = match avi_device.get_ctx("").await {
         Ok(v) => v,
         Err(e) => {
@@ -74,6 +81,10 @@ pub async fn on_peer_connected(_device: AviDevice, peer_id: String, address: Str
         "Connected Avi Device {} from {} into the mesh.",
         peer_id, address
     );
+    emit!(
+        PEER_CONNECTED,
+        json!({ "peer": peer_id, "address": address })
+    );
     tokio::task::block_in_place(|| {
         tokio::runtime::Handle::current().block_on(match runtime() {
             Ok(c) => c.user.save_to_device(),
@@ -95,6 +106,36 @@ impl Action for MeshAction {
         self.device.on_peer_connected(on_peer_connected).await;
         self.device.on_peer_disconnected(on_peer_disconnected).await;
 
+        let id = self.device.get_id().await;
+
+        subscribe!(&call_topic(&id), async: move |from, _topic, data| async move {
+            match serde_json::from_slice::<CommandRequest>(&data) {
+                Ok(request) => {
+                    if let Ok(c) = runtime() {
+                        c.commands.handle_request(&from, request).await
+                    }
+                }
+                Err(e) => warn!("Invalid command call: {}", e),
+            }
+        });
+
+        subscribe!(&result_topic(&id), move |_from, _topic, data| {
+            match serde_json::from_slice::<CommandResponse>(&data) {
+                Ok(response) => {
+                    if let Ok(c) = runtime() {
+                        c.commands.resolve(response)
+                    }
+                }
+                Err(e) => warn!("Invalid command result: {}", e),
+            }
+        });
+
+        if let Ok(c) = runtime()
+            && let Err(e) = c.commands.announce().await
+        {
+            warn!("{}", e);
+        }
+
         subscribe!("user/update", async: move |_from, _topic, _data| async move {
             tokio::task::block_in_place(|| {
                 tokio::runtime::Handle::current().block_on(match runtime() {
@@ -8,6 +8,8 @@ use avi_nlu_client::models::{
     Alive, Created, Data, EngineTrain, EngineTrainType, Installed, RecognizedInput,
 };
 use log::trace;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
 
 fn box_err<E: std::fmt::Display>(e: E) -> Box<dyn std::error::Error> {
     Box::new(std::io::Error::other(e.to_string()))
@@ -334,3 +336,93 @@ impl Api {
         Ok(serde_json::from_value(r.ok_or("No response from server")?)?)
     }*/
 }
+
+/// Delay before the first retry of a server that stopped answering.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Longest delay between two retries of a server that is down.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct HealthState {
+    retry_at: Option<Instant>,
+    delay: Duration,
+}
+
+/// Whether the avi-nlu server is answering, so the hybrid engine stops asking a server that
+/// is down on every utterance and tries it again after a growing delay instead.
+#[derive(Debug)]
+pub struct ApiHealth {
+    state: Mutex<HealthState>,
+}
+
+impl ApiHealth {
+    /// A server that answered, or one that is down and is retried after the first delay.
+    pub fn new(up: bool) -> Self {
+        let health = Self {
+            state: Mutex::new(HealthState {
+                retry_at: None,
+                delay: Duration::ZERO,
+            }),
+        };
+        if !up {
+            health.failed(Instant::now());
+        }
+        health
+    }
+
+    /// Whether the server is considered up.
+    pub fn is_up(&self) -> bool {
+        self.state.lock().retry_at.is_none()
+    }
+
+    /// Whether the server should be asked at `now`, because it is up or its retry is due.
+    pub fn should_try(&self, now: Instant) -> bool {
+        self.state
+            .lock()
+            .retry_at
+            .is_none_or(|retry_at| now >= retry_at)
+    }
+
+    /// Marks the server as down, doubling the delay before it is tried again.
+    pub fn failed(&self, now: Instant) {
+        let mut state = self.state.lock();
+        state.delay = (state.delay * 2).clamp(MIN_RETRY_DELAY, MAX_RETRY_DELAY);
+        state.retry_at = Some(now + state.delay);
+    }
+
+    /// Marks the server as up, returning whether it was down until now.
+    pub fn succeeded(&self) -> bool {
+        let mut state = self.state.lock();
+        state.delay = Duration::ZERO;
+        state.retry_at.take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_down_server_is_retried_with_backoff() {
+        let health = ApiHealth::new(false);
+        let start = Instant::now();
+
+        assert!(!health.is_up());
+        assert!(!health.should_try(start));
+        assert!(health.should_try(start + MIN_RETRY_DELAY));
+
+        health.failed(start);
+        assert!(!health.should_try(start + MIN_RETRY_DELAY));
+        assert!(health.should_try(start + MIN_RETRY_DELAY * 2));
+
+        assert!(health.succeeded());
+        assert!(health.is_up());
+        assert!(health.should_try(start));
+        assert!(!health.succeeded());
+
+        for _ in 0..20 {
+            health.failed(start);
+        }
+        assert!(health.should_try(start + MAX_RETRY_DELAY));
+    }
+}
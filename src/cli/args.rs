@@ -70,6 +70,13 @@ pub enum Commands {
         output: String,
     },
 
+    /// Inspect or restore the persistent context
+    #[command(about = "Dump or restore the persistent context store")]
+    Ctx {
+        #[command(subcommand)]
+        action: CtxCommand,
+    },
+
     /// Display version and build information
     #[command(about = "Show detailed version and build information")]
     Version {
@@ -82,3 +89,58 @@ pub enum Commands {
         verbose: bool,
     },
 }
+
+#[derive(Subcommand, Debug)]
+pub enum CtxCommand {
+    /// Write the stored context as JSON
+    #[command(about = "Export the persistent context as JSON")]
+    Dump {
+        /// Configuration file path
+        #[arg(long = "config", short = 'c', help = "Path to configuration path")]
+        config: Option<String>,
+
+        /// Scope to export
+        #[arg(
+            long = "scope",
+            short = 's',
+            help = "Only export one scope, e.g. global, skill:<name> or skill:<name>/user:<id>"
+        )]
+        scope: Option<String>,
+
+        /// Output file
+        #[arg(
+            long = "output",
+            short = 'o',
+            help = "File to write the dump to (defaults to stdout)"
+        )]
+        output: Option<String>,
+    },
+
+    /// Load context from a dump
+    #[command(about = "Import a JSON dump into the persistent context")]
+    Restore {
+        /// The dump to load
+        #[arg(help = "File written by ctx dump")]
+        file: String,
+
+        /// Configuration file path
+        #[arg(long = "config", short = 'c', help = "Path to configuration path")]
+        config: Option<String>,
+
+        /// Scope to import
+        #[arg(
+            long = "scope",
+            short = 's',
+            help = "Only import one scope of the dump, e.g. global or skill:<name>/user:<id>"
+        )]
+        scope: Option<String>,
+
+        /// Replace the current values
+        #[arg(
+            long = "replace",
+            short = 'r',
+            help = "Remove the values of each restored scope that are not in the dump"
+        )]
+        replace: bool,
+    },
+}
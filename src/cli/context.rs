@@ -0,0 +1,54 @@
+//! The `ctx dump` and `ctx restore` commands, which work on the context store of a
+//! configuration directory while the core using it is stopped.
+
+use crate::data::context::{ContextManager, ContextScope};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+fn open(config: &Path) -> Result<ContextManager, String> {
+    ContextManager::new(config.join("context"))
+}
+
+fn parse_scope(scope: Option<&str>) -> Result<Option<ContextScope>, String> {
+    scope.map(str::parse).transpose()
+}
+
+/// Exports the stored context as an object of scope to the values of that scope.
+pub fn dump(config: &Path, scope: Option<&str>) -> Result<Value, String> {
+    let manager = open(config)?;
+    let scopes = match parse_scope(scope)? {
+        Some(scope) => vec![scope],
+        None => manager.scopes(),
+    };
+
+    Ok(Value::Object(
+        scopes
+            .into_iter()
+            .map(|scope| (scope.to_string(), manager.export(&scope)))
+            .collect::<Map<_, _>>(),
+    ))
+}
+
+/// Imports a [`dump`] into the stored context, returns how many values were stored.
+pub fn restore(
+    config: &Path,
+    data: Value,
+    scope: Option<&str>,
+    replace: bool,
+) -> Result<usize, String> {
+    let Value::Object(scopes) = data else {
+        return Err("Expected an object of scopes".to_string());
+    };
+    let only = parse_scope(scope)?;
+    let manager = open(config)?;
+
+    let mut restored = 0;
+    for (name, values) in scopes {
+        let scope: ContextScope = name.parse()?;
+        if only.as_ref().is_some_and(|only| only != &scope) {
+            continue;
+        }
+        restored += manager.import(&scope, values, true, replace)?;
+    }
+    Ok(restored)
+}
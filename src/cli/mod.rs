@@ -1,3 +1,4 @@
 pub mod args;
+pub mod context;
 pub mod setup;
 pub mod ui;
@@ -0,0 +1,496 @@
+use crate::ctx::runtime;
+use crate::events::COMMAND_RECEIVED;
+use crate::{emit, publish};
+use avi_device::DeviceCapabilities;
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Prefix of the mesh context key holding the capabilities of each device.
+pub const CAPS_CTX_PREFIX: &str = "avi.device.caps";
+
+/// Key of the `extended` capability listing the commands of a device.
+pub const COMMANDS_CAPABILITY: &str = "commands";
+
+/// Topic a device listens on for command calls.
+pub fn call_topic(peer: &str) -> String {
+    format!("avi/commands/{}/call", peer)
+}
+
+/// Topic a device listens on for the results of its calls.
+pub fn result_topic(peer: &str) -> String {
+    format!("avi/commands/{}/result", peer)
+}
+
+/// Where a device announces its commands, inside its capabilities.
+fn ctx_key(peer: &str) -> String {
+    format!(
+        "{}.{}.extended.{}",
+        CAPS_CTX_PREFIX, peer, COMMANDS_CAPABILITY
+    )
+}
+
+/// Adds the `commands` extended capability, so peers know the device takes command calls.
+///
+/// The commands themselves are announced once the skills register them, see
+/// [`CommandRegistry::announce`].
+pub fn with_commands(capabilities: DeviceCapabilities) -> DeviceCapabilities {
+    let declared = serde_json::to_value(&capabilities).and_then(|mut value| {
+        if !value["extended"].is_object() {
+            value["extended"] = json!({});
+        }
+        value["extended"][COMMANDS_CAPABILITY] = json!([]);
+        serde_json::from_value(value)
+    });
+
+    declared.unwrap_or_else(|e| {
+        warn!("Failed to declare the commands capability: {}", e);
+        capabilities
+    })
+}
+
+/// The type of a command argument.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgType {
+    String,
+    Number,
+    Bool,
+    List,
+    Map,
+    Any,
+}
+
+impl ArgType {
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            ArgType::String => value.is_string(),
+            ArgType::Number => value.is_number(),
+            ArgType::Bool => value.is_boolean(),
+            ArgType::List => value.is_array(),
+            ArgType::Map => value.is_object(),
+            ArgType::Any => true,
+        }
+    }
+}
+
+impl FromStr for ArgType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "string" => Ok(ArgType::String),
+            "number" | "int" | "float" => Ok(ArgType::Number),
+            "bool" | "boolean" => Ok(ArgType::Bool),
+            "list" | "array" => Ok(ArgType::List),
+            "map" | "object" => Ok(ArgType::Map),
+            "any" => Ok(ArgType::Any),
+            _ => Err(format!("Unknown argument type '{}'", s)),
+        }
+    }
+}
+
+/// A single argument of a command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArgSchema {
+    #[serde(rename = "type")]
+    pub kind: ArgType,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl FromStr for ArgSchema {
+    type Err = String;
+
+    /// Parses `"number"`, or `"number?"` for an optional argument.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, optional) = match s.trim().strip_suffix('?') {
+            Some(kind) => (kind, true),
+            None => (s.trim(), false),
+        };
+
+        Ok(Self {
+            kind: kind.parse()?,
+            optional,
+        })
+    }
+}
+
+/// A named command a device can execute, with the arguments it takes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandSchema {
+    pub name: String,
+    #[serde(default)]
+    pub args: BTreeMap<String, ArgSchema>,
+}
+
+impl CommandSchema {
+    /// Checks that `args` is a map matching the schema.
+    pub fn validate(&self, args: &Value) -> Result<(), String> {
+        let empty = serde_json::Map::new();
+        let given = match args {
+            Value::Object(map) => map,
+            Value::Null => &empty,
+            _ => return Err(format!("Arguments of '{}' must be a map", self.name)),
+        };
+
+        if let Some(unknown) = given.keys().find(|k| !self.args.contains_key(*k)) {
+            return Err(format!("'{}' has no argument '{}'", self.name, unknown));
+        }
+
+        for (name, schema) in &self.args {
+            match given.get(name) {
+                None | Some(Value::Null) if schema.optional => {}
+                None | Some(Value::Null) => {
+                    return Err(format!("'{}' requires argument '{}'", self.name, name));
+                }
+                Some(value) if !schema.kind.accepts(value) => {
+                    return Err(format!(
+                        "Argument '{}' of '{}' must be a {:?}",
+                        name, self.name, schema.kind
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A call sent to the device that owns a command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandRequest {
+    /// Correlation id echoed back in the response.
+    pub id: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Value,
+}
+
+/// The outcome of a [`CommandRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub id: String,
+    pub result: Result<Value, String>,
+}
+
+/// A command implemented by a function of a local skill.
+struct LocalCommand {
+    schema: CommandSchema,
+    skill: String,
+    function: String,
+}
+
+struct PendingCall {
+    peer: String,
+    sender: oneshot::Sender<Result<Value, String>>,
+}
+
+/// Commands this device offers to the mesh and the calls it is waiting on.
+///
+/// Every device announces its command schemas in the mesh context under
+/// [`CAPS_CTX_PREFIX`], so callers can validate arguments before sending them.
+/// Calls and results travel over pub/sub and are matched by a correlation id.
+pub struct CommandRegistry {
+    local: RwLock<HashMap<String, LocalCommand>>,
+    pending: Mutex<HashMap<String, PendingCall>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            local: RwLock::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a command implemented by a skill function.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if another skill already registered a command with that name.
+    pub fn register(
+        &self,
+        skill: &str,
+        schema: CommandSchema,
+        function: &str,
+    ) -> Result<(), String> {
+        let mut local = self.local.write();
+        if let Some(existing) = local.get(&schema.name)
+            && existing.skill != skill
+        {
+            return Err(format!(
+                "Command '{}' is already registered by skill {}",
+                schema.name, existing.skill
+            ));
+        }
+
+        info!("Skill {} registered command {}", skill, schema.name);
+        local.insert(
+            schema.name.clone(),
+            LocalCommand {
+                schema,
+                skill: skill.to_string(),
+                function: function.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes every command registered by a skill.
+    pub fn remove_skill(&self, skill: &str) {
+        self.local
+            .write()
+            .retain(|_, command| command.skill != skill);
+    }
+
+    /// Returns the schemas of the local commands.
+    pub fn schemas(&self) -> Vec<CommandSchema> {
+        let mut schemas: Vec<CommandSchema> = self
+            .local
+            .read()
+            .values()
+            .map(|command| command.schema.clone())
+            .collect();
+        schemas.sort_by(|a, b| a.name.cmp(&b.name));
+        schemas
+    }
+
+    /// Publishes the local command schemas to the mesh context.
+    pub async fn announce(&self) -> Result<(), String> {
+        let c = runtime()?;
+        let id = c.device.get_id().await;
+        c.device
+            .update_ctx(&ctx_key(&id), json!(self.schemas()))
+            .await
+            .map_err(|e| format!("Failed to announce commands: {}", e))
+    }
+
+    /// Returns the commands a peer announced.
+    pub async fn peer_commands(&self, peer: &str) -> Result<Vec<CommandSchema>, String> {
+        let value = runtime()?
+            .device
+            .get_ctx(&ctx_key(peer))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if value.is_null() {
+            return Ok(Vec::new());
+        }
+        serde_json::from_value(value).map_err(|e| format!("Invalid commands of {}: {}", peer, e))
+    }
+
+    /// Calls a command on a peer and waits for its result.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer does not offer the command, the arguments do not
+    /// match its schema, the peer fails or disconnects, or no result arrives in time.
+    pub async fn call(
+        &self,
+        peer: &str,
+        command: &str,
+        args: Value,
+        timeout: Duration,
+    ) -> Result<Value, String> {
+        let schema = self
+            .peer_commands(peer)
+            .await?
+            .into_iter()
+            .find(|schema| schema.name == command)
+            .ok_or(format!("Peer {} has no command '{}'", peer, command))?;
+        schema.validate(&args)?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().insert(
+            id.clone(),
+            PendingCall {
+                peer: peer.to_string(),
+                sender,
+            },
+        );
+
+        let request = CommandRequest {
+            id: id.clone(),
+            command: command.to_string(),
+            args,
+        };
+        debug!("Calling {} on {} ({})", command, peer, id);
+
+        let payload = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
+        if let Err(e) = publish!(&call_topic(peer), payload) {
+            self.pending.lock().remove(&id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(format!("Call {} to {} was dropped", command, peer)),
+            Err(_) => {
+                self.pending.lock().remove(&id);
+                Err(format!(
+                    "Peer {} did not answer '{}' within {:?}",
+                    peer, command, timeout
+                ))
+            }
+        }
+    }
+
+    /// Completes the pending call a response belongs to.
+    pub fn resolve(&self, response: CommandResponse) {
+        match self.pending.lock().remove(&response.id) {
+            Some(call) => {
+                let _ = call.sender.send(response.result);
+            }
+            None => debug!("Ignoring result of unknown or expired call {}", response.id),
+        }
+    }
+
+    /// Fails every call waiting on a peer that left the mesh.
+    pub fn fail_peer(&self, peer: &str) {
+        let calls: Vec<PendingCall> = {
+            let mut pending = self.pending.lock();
+            let ids: Vec<String> = pending
+                .iter()
+                .filter(|(_, call)| call.peer == peer)
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+
+        for call in calls {
+            let _ = call.sender.send(Err(format!("Peer {} disconnected", peer)));
+        }
+    }
+
+    /// Handles a call from another device, dispatching it to the skill that owns the command.
+    ///
+    /// `from` is the peer the transport got the call from, the result is sent back to it.
+    pub async fn handle_request(&self, from: &str, request: CommandRequest) {
+        let target = self.local.read().get(&request.command).map(|command| {
+            (
+                command.schema.validate(&request.args),
+                command.skill.clone(),
+                command.function.clone(),
+            )
+        });
+
+        match target {
+            Some((Ok(()), skill, function)) => emit!(
+                COMMAND_RECEIVED,
+                json!({
+                    "id": request.id,
+                    "command": request.command,
+                    "skill": skill,
+                    "function": function,
+                    "args": request.args,
+                    "from": from,
+                })
+            ),
+            Some((Err(e), _, _)) => self.respond(from, &request.id, Err(e)).await,
+            None => {
+                warn!("{} called unknown command {}", from, request.command);
+                let error = format!("Unknown command '{}'", request.command);
+                self.respond(from, &request.id, Err(error)).await
+            }
+        }
+    }
+
+    /// Sends the result of a call back to the device that made it.
+    pub async fn respond(&self, to: &str, id: &str, result: Result<Value, String>) {
+        let response = CommandResponse {
+            id: id.to_string(),
+            result,
+        };
+
+        match serde_json::to_vec(&response) {
+            Ok(payload) => {
+                let _ = publish!(&result_topic(to), payload);
+            }
+            Err(e) => warn!("Failed to serialize result of call {}: {}", id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lights_set() -> CommandSchema {
+        CommandSchema {
+            name: "lights.set".to_string(),
+            args: BTreeMap::from([
+                ("level".to_string(), "number".parse().unwrap()),
+                ("room".to_string(), "string?".parse().unwrap()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_schema_validates_arguments() {
+        let schema = lights_set();
+
+        assert!(schema.validate(&json!({ "level": 50 })).is_ok());
+        assert!(
+            schema
+                .validate(&json!({ "level": 50, "room": "kitchen" }))
+                .is_ok()
+        );
+        assert!(schema.validate(&json!({})).is_err());
+        assert!(schema.validate(&json!({ "level": "high" })).is_err());
+        assert!(
+            schema
+                .validate(&json!({ "level": 50, "color": "red" }))
+                .is_err()
+        );
+        assert!(schema.validate(&json!([50])).is_err());
+    }
+
+    #[test]
+    fn test_commands_belong_to_one_skill() {
+        let registry = CommandRegistry::new();
+
+        assert!(registry.register("lights", lights_set(), "set").is_ok());
+        assert!(
+            registry
+                .register("lights", lights_set(), "set_level")
+                .is_ok()
+        );
+        assert!(registry.register("other", lights_set(), "set").is_err());
+
+        registry.remove_skill("lights");
+        assert!(registry.schemas().is_empty());
+    }
+
+    #[test]
+    fn test_capabilities_declare_commands() {
+        let capabilities = json!(with_commands(DeviceCapabilities::default()));
+        assert_eq!(capabilities["extended"][COMMANDS_CAPABILITY], json!([]));
+        assert_eq!(ctx_key("peer"), "avi.device.caps.peer.extended.commands");
+    }
+
+    #[test]
+    fn test_disconnect_fails_pending_calls() {
+        let registry = CommandRegistry::new();
+        let (sender, mut receiver) = oneshot::channel();
+        registry.pending.lock().insert(
+            "call".to_string(),
+            PendingCall {
+                peer: "peer".to_string(),
+                sender,
+            },
+        );
+
+        registry.fail_peer("peer");
+
+        assert!(registry.pending.lock().is_empty());
+        assert!(receiver.try_recv().unwrap().is_err());
+    }
+}
@@ -1,8 +1,11 @@
+use crate::commands::CommandRegistry;
 use crate::data::config::ConfigSystem;
 use crate::data::context::ContextManager;
+use crate::data::scheduler::Scheduler;
 use crate::data::user::UserManager;
 use crate::dialogue::languages::LanguageSystem;
 use crate::dialogue::reply::{ReplyConfig, ReplyManager};
+use crate::events::EventBus;
 use avi_device::device::AviDevice;
 use log::{debug, error, info, trace};
 use std::path::PathBuf;
@@ -27,6 +30,12 @@ pub struct RuntimeContext {
     pub context: ContextManager,
 
     pub user: UserManager,
+
+    pub events: EventBus,
+
+    pub scheduler: Scheduler,
+
+    pub commands: CommandRegistry,
 }
 
 /// Global static storage for the `RuntimeContext`.
@@ -49,9 +58,17 @@ pub fn runtime() -> Result<&'static Arc<RuntimeContext>, String> {
     }
 }
 
-pub fn create_runtime(config_path: &str, device: Arc<AviDevice>) {
+/// Creates the global [`RuntimeContext`].
+///
+/// # Errors
+///
+/// Returns an error if the persistent context can't be opened, rather than running
+/// with values that would be lost on exit.
+pub fn create_runtime(config_path: &str, device: Arc<AviDevice>) -> Result<(), String> {
     trace!("Creating runtime with config_path={}", config_path);
     info!("Initializing runtime.");
+    let context = ContextManager::new(format!("{}/context", config_path))
+        .map_err(|e| format!("Failed to open the persistent context: {}", e))?;
     RUNTIMECTX
         .set(Arc::from(RuntimeContext {
             device,
@@ -62,8 +79,11 @@ pub fn create_runtime(config_path: &str, device: Arc<AviDevice>) {
             })),
             language_system: LanguageSystem::new(&format!("{}/lang", config_path)),
             configuration: ConfigSystem::new(&format!("{}/config", config_path)),
-            context: ContextManager::new(format!("{}/context", config_path)),
+            context,
             user: UserManager::new(),
+            events: EventBus::new(),
+            scheduler: Scheduler::new(format!("{}/scheduler", config_path)),
+            commands: CommandRegistry::new(),
             config_path: config_path.into(),
         }))
         .unwrap_or_else(|_| {
@@ -71,4 +91,5 @@ pub fn create_runtime(config_path: &str, device: Arc<AviDevice>) {
             panic!("Runtime context already initialized")
         });
     info!("Runtime initialized successfully.");
+    Ok(())
 }
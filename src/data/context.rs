@@ -1,10 +1,15 @@
 use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::data::context_replication::{ReplicaWrite, Replication, Version};
+use crate::data::context_store::{ContextStore, LogStore, StoreWrite, migrate_directories};
+use crate::dialogue::request::{DEFAULT_ORIGIN, current_device, current_origin};
+use avi_device::device::AviDevice;
 use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,6 +17,10 @@ pub struct ContextValue {
     pub value: serde_json::Value,
     pub expires_at: Option<u64>, // timestamp in seconds
     pub created_at: u64,
+    /// The version of a replicated value, kept so a restarted node doesn't take older
+    /// replicas over it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
 }
 
 impl ContextValue {
@@ -27,6 +36,7 @@ impl ContextValue {
             value,
             expires_at,
             created_at: now,
+            version: None,
         }
     }
 
@@ -40,40 +50,415 @@ impl ContextValue {
         }
         false
     }
+
+    /// The time left before the value expires, `None` if it never does.
+    pub fn ttl(&self) -> Option<Duration> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.expires_at
+            .map(|expires_at| Duration::from_secs(expires_at.saturating_sub(now)))
+    }
+}
+
+/// The user id of scopes built while there is no runtime, e.g. in tools.
+pub const DEFAULT_USER: &str = "default";
+
+/// Global key prefix recording the skills whose values were moved to per-user scopes.
+pub const MIGRATED_CTX_PREFIX: &str = "avi.context.migrated";
+
+/// The longest a session value is kept, in seconds, since nothing ends a session.
+fn session_ttl() -> Duration {
+    Duration::from_secs(setting_or::<u64>("session_context_ttl", 600))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ContextScope {
     Global,
     Skill(String),
+    /// Values about a person, whichever device they talk to.
+    User(String),
+    /// Values about a device of the mesh, by peer id.
+    Device(String),
+    /// Values of a single request and what is done on its behalf, by trace id.
+    Session(String),
+    /// The values of the first scope that only apply within the second,
+    /// e.g. a skill's values for one user.
+    Within(Box<ContextScope>, Box<ContextScope>),
+}
+
+impl std::fmt::Display for ContextScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextScope::Global => write!(f, "global"),
+            ContextScope::Skill(name) => write!(f, "skill:{}", name),
+            ContextScope::User(id) => write!(f, "user:{}", id),
+            ContextScope::Device(id) => write!(f, "device:{}", id),
+            ContextScope::Session(id) => write!(f, "session:{}", id),
+            ContextScope::Within(outer, inner) => write!(f, "{}/{}", outer, inner),
+        }
+    }
+}
+
+impl std::str::FromStr for ContextScope {
+    type Err = String;
+
+    /// Parses `global`, `skill:<name>`, `user:<id>`, `device:<id>` or `session:<id>`,
+    /// and two of them joined by `/`, e.g. `skill:timer/user:ana`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((outer, inner)) = s.split_once('/') {
+            return Ok(ContextScope::Within(
+                Box::new(outer.parse()?),
+                Box::new(inner.parse()?),
+            ));
+        }
+        match s.split_once(':') {
+            None if s == "global" => Ok(ContextScope::Global),
+            Some((kind, id)) if !id.is_empty() => match kind {
+                "skill" => Ok(ContextScope::Skill(id.to_string())),
+                "user" => Ok(ContextScope::User(id.to_string())),
+                "device" => Ok(ContextScope::Device(id.to_string())),
+                "session" => Ok(ContextScope::Session(id.to_string())),
+                _ => Err(format!("Unknown context scope '{}'", kind)),
+            },
+            _ => Err(format!(
+                "Invalid context scope '{}', expected global, skill:<name>, user:<id>, \
+                 device:<id>, session:<id> or two of them joined by /",
+                s
+            )),
+        }
+    }
 }
 
 impl ContextScope {
-    pub fn to_string_key(&self) -> String {
+    /// The scope of a folder of the old one-file-per-key layout, `global` or `skill_<name>`.
+    pub fn from_string_key(key: &str) -> Option<Self> {
+        match key {
+            "global" => Some(ContextScope::Global),
+            _ => key
+                .strip_prefix("skill_")
+                .map(|name| ContextScope::Skill(name.to_string())),
+        }
+    }
+
+    /// This scope narrowed to the values that apply within `inner`.
+    pub fn within(self, inner: ContextScope) -> Self {
+        ContextScope::Within(Box::new(self), Box::new(inner))
+    }
+
+    /// The scope of the user being served.
+    pub fn current_user() -> Self {
+        let id = runtime()
+            .map(|c| c.user.get_id())
+            .unwrap_or(DEFAULT_USER.to_string());
+        ContextScope::User(id)
+    }
+
+    /// The scope of the device whose request is being handled on this thread.
+    pub fn current_device() -> Self {
+        ContextScope::Device(current_device())
+    }
+
+    /// Whether the values of this scope belong to a single request.
+    pub fn is_session(&self) -> bool {
         match self {
-            ContextScope::Global => "global".to_string(),
-            ContextScope::Skill(name) => format!("skill_{}", name),
+            ContextScope::Session(_) => true,
+            ContextScope::Within(outer, inner) => outer.is_session() || inner.is_session(),
+            _ => false,
         }
     }
+
+    /// The scope of the request being handled on this thread.
+    pub fn current_session() -> Self {
+        let id = current_origin()
+            .map(|o| o.trace_id)
+            .unwrap_or(DEFAULT_ORIGIN.to_string());
+        ContextScope::Session(id)
+    }
+}
+
+/// Where a watched value lives.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WatchSource {
+    /// A scope of the [`ContextManager`].
+    Scope(ContextScope),
+    /// The context shared by the devices of the mesh.
+    Device,
+}
+
+impl WatchSource {
+    /// Splits a watch spec into where it looks and the key pattern.
+    ///
+    /// `global:user` watches the global context, `device:avi.user` the device context,
+    /// `skill:timer` the context `skill` shares between users, and anything else the
+    /// context `skill` keeps for the current user.
+    pub fn parse<'a>(spec: &'a str, skill: &str) -> (WatchSource, &'a str) {
+        let own = ContextScope::Skill(skill.to_string());
+        if let Some(pattern) = spec.strip_prefix("global:") {
+            (WatchSource::Scope(ContextScope::Global), pattern)
+        } else if let Some(pattern) = spec.strip_prefix("device:") {
+            (WatchSource::Device, pattern)
+        } else if let Some(pattern) = spec.strip_prefix("skill:") {
+            (WatchSource::Scope(own), pattern)
+        } else {
+            let scope = own.within(ContextScope::current_user());
+            (WatchSource::Scope(scope), spec)
+        }
+    }
+}
+
+/// The keys a watcher is notified about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchPattern {
+    Key(String),
+    /// Every key starting with the prefix, written as `prefix*`.
+    Prefix(String),
+}
+
+impl WatchPattern {
+    pub fn parse(pattern: &str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => WatchPattern::Prefix(prefix.to_string()),
+            None => WatchPattern::Key(pattern.to_string()),
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            WatchPattern::Key(k) => k == key,
+            WatchPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+
+    /// The device context path polled for this pattern, and whether the values below it
+    /// are reported one key each.
+    ///
+    /// A prefix polls the object it is in, so `avi.user.*` reports `avi.user.name` and
+    /// `avi.us*` reports `avi.user.name` and `avi.usage`.
+    fn device_path(&self) -> (&str, bool) {
+        match self {
+            WatchPattern::Key(k) => (k, false),
+            WatchPattern::Prefix(prefix) => match prefix.rsplit_once('.') {
+                Some((parent, _)) => (parent, true),
+                None => (prefix, true),
+            },
+        }
+    }
+}
+
+/// What happened to a watched value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContextChangeKind {
+    Set,
+    Removed,
+    Expired,
+}
+
+/// A change delivered to the watchers of a key.
+#[derive(Debug, Clone)]
+pub struct ContextChange {
+    pub source: WatchSource,
+    pub key: String,
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>,
+    pub kind: ContextChangeKind,
+}
+
+type WatchCallback = Arc<dyn Fn(&ContextChange) + Send + Sync>;
+
+struct Watcher {
+    id: u64,
+    /// Who registered the watcher, e.g. the skill, so it can be dropped on reload.
+    owner: String,
+    source: WatchSource,
+    pattern: WatchPattern,
+    callback: WatchCallback,
+}
+
+enum TransactionWrite {
+    Set {
+        scope: ContextScope,
+        key: String,
+        value: ContextValue,
+        persistent: bool,
+    },
+    Remove {
+        scope: ContextScope,
+        key: String,
+    },
+}
+
+impl TransactionWrite {
+    fn target(&self) -> (&ContextScope, &String) {
+        match self {
+            TransactionWrite::Set { scope, key, .. } | TransactionWrite::Remove { scope, key } => {
+                (scope, key)
+            }
+        }
+    }
+}
+
+/// Context writes applied together by [`ContextManager::commit`]: either all of them are
+/// stored, or none is.
+#[derive(Default)]
+pub struct ContextTransaction {
+    writes: Vec<TransactionWrite>,
+}
+
+impl ContextTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a value, session values living at most `session_context_ttl` seconds.
+    pub fn set(
+        &mut self,
+        scope: ContextScope,
+        key: impl Into<String>,
+        value: serde_json::Value,
+        ttl: Option<Duration>,
+        persistent: bool,
+    ) -> &mut Self {
+        let ttl = match ttl {
+            _ if !scope.is_session() => ttl,
+            Some(ttl) => Some(ttl.min(session_ttl())),
+            None => Some(session_ttl()),
+        };
+        self.writes.push(TransactionWrite::Set {
+            scope,
+            key: key.into(),
+            value: ContextValue::new(value, ttl),
+            persistent,
+        });
+        self
+    }
+
+    pub fn remove(&mut self, scope: ContextScope, key: impl Into<String>) -> &mut Self {
+        self.writes.push(TransactionWrite::Remove {
+            scope,
+            key: key.into(),
+        });
+        self
+    }
 }
 
 pub struct ContextManager {
     memory_store: Arc<RwLock<HashMap<ContextScope, HashMap<String, ContextValue>>>>,
-    persistence_path: PathBuf,
+    /// Where persistent values are kept.
+    store: Box<dyn ContextStore>,
+    /// Held while a transaction is applied, so the store, the memory and the replicated
+    /// versions see commits in the same order.
+    commits: Mutex<()>,
+    /// The values mirrored to the mesh.
+    replication: Replication,
+    watchers: RwLock<Vec<Watcher>>,
+    next_watcher: AtomicU64,
+    /// The values last seen under each polled device context path, by key.
+    device_values: Mutex<HashMap<(String, bool), HashMap<String, serde_json::Value>>>,
 }
 
 impl ContextManager {
-    pub fn new<P: AsRef<Path>>(persistence_path: P) -> Self {
-        let path = persistence_path.as_ref().to_path_buf();
-        if !path.exists() {
-            fs::create_dir_all(&path).expect("Failed to create persistence directory");
-            info!("Created context persistence directory: {}", path.display());
+    /// Creates a manager whose persistent values live in `context.log` under `persistence_path`,
+    /// migrating the values of the old one-file-per-key layout found there.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log can't be opened, e.g. because a running core holds it.
+    pub fn new<P: AsRef<Path>>(persistence_path: P) -> Result<Self, String> {
+        let path = persistence_path.as_ref();
+        let store = LogStore::open(path.join("context.log"))?;
+        if let Err(e) = migrate_directories(path, &store) {
+            error!("Failed to migrate the context in {}: {}", path.display(), e);
         }
+        Ok(Self::with_store(Box::new(store)))
+    }
+
+    /// Creates a manager that keeps persistent values in `store`.
+    pub fn with_store(store: Box<dyn ContextStore>) -> Self {
         info!("Created Context Manager.");
 
         Self {
             memory_store: Arc::new(RwLock::new(HashMap::new())),
-            persistence_path: path,
+            store,
+            commits: Mutex::new(()),
+            replication: Replication::new(),
+            watchers: RwLock::new(Vec::new()),
+            next_watcher: AtomicU64::new(1),
+            device_values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Calls `callback` whenever a key of `source` matching `pattern` is set, removed or expires.
+    ///
+    /// A pattern ending in `*` matches every key with that prefix. Returns the id that
+    /// [`ContextManager::unwatch`] takes.
+    pub fn watch(
+        &self,
+        owner: &str,
+        source: WatchSource,
+        pattern: &str,
+        callback: impl Fn(&ContextChange) + Send + Sync + 'static,
+    ) -> u64 {
+        let id = self.next_watcher.fetch_add(1, Ordering::Relaxed);
+        debug!("{} watches {:?} {}", owner, source, pattern);
+        if let Ok(mut watchers) = self.watchers.write() {
+            watchers.push(Watcher {
+                id,
+                owner: owner.to_string(),
+                source,
+                pattern: WatchPattern::parse(pattern),
+                callback: Arc::new(callback),
+            });
+        }
+        id
+    }
+
+    /// Drops a watcher of `owner`, returns whether it existed.
+    pub fn unwatch(&self, owner: &str, id: u64) -> bool {
+        let Ok(mut watchers) = self.watchers.write() else {
+            return false;
+        };
+        let before = watchers.len();
+        watchers.retain(|w| w.id != id || w.owner != owner);
+        watchers.len() != before
+    }
+
+    /// Drops every watcher of `owner`.
+    pub fn unwatch_owner(&self, owner: &str) {
+        if let Ok(mut watchers) = self.watchers.write() {
+            watchers.retain(|w| w.owner != owner);
+        }
+    }
+
+    fn is_watched(&self, source: &WatchSource, key: &str) -> bool {
+        self.watchers.read().is_ok_and(|watchers| {
+            watchers
+                .iter()
+                .any(|w| &w.source == source && w.pattern.matches(key))
+        })
+    }
+
+    /// Calls the watchers of a change, outside of any lock so they may use the context.
+    fn notify(&self, change: ContextChange) {
+        let callbacks: Vec<WatchCallback> = match self.watchers.read() {
+            Ok(watchers) => watchers
+                .iter()
+                .filter(|w| w.source == change.source && w.pattern.matches(&change.key))
+                .map(|w| Arc::clone(&w.callback))
+                .collect(),
+            Err(_) => return,
+        };
+        if callbacks.is_empty() {
+            return;
+        }
+
+        trace!(
+            "Context change {:?} of {} in {:?}",
+            change.kind, change.key, change.source
+        );
+        for callback in callbacks {
+            callback(&change);
         }
     }
 
@@ -89,14 +474,218 @@ impl ContextManager {
             "Setting context: scope={:?}, key={}, persistent={}, ttl={:?}",
             scope, key, persistent, ttl
         );
-        let ctx_value = ContextValue::new(value, ttl);
+        let mut transaction = ContextTransaction::new();
+        transaction.set(scope, key, value, ttl, persistent);
+        if let Err(e) = self.commit(transaction) {
+            error!("Failed to set context: {}", e);
+        }
+    }
+
+    /// Applies the writes of a transaction, keeping the memory and the store unchanged
+    /// if the store fails to take them.
+    pub fn commit(&self, transaction: ContextTransaction) -> Result<(), String> {
+        self.commit_writes(transaction, true)
+    }
+
+    /// The values of this manager that are mirrored to the mesh.
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    /// Applies the writes other nodes replicated, skipping the ones older than what this
+    /// node already has.
+    pub fn apply_replicas(&self, replicas: Vec<ReplicaWrite>) {
+        let changes = {
+            let _commit = self.lock_commits();
+            let mut transaction = ContextTransaction::new();
+            for (scope, key, replica) in replicas {
+                // After a restart only the store knows the version of a value
+                let stored = || self.store.get(&scope, &key).and_then(|value| value.version);
+                if !self
+                    .replication
+                    .accept(&scope, &key, &replica.version, stored)
+                {
+                    continue;
+                }
+                debug!(
+                    "Taking replicated {} of {} from {}",
+                    key, scope, replica.version.node
+                );
+                match replica.value {
+                    Some(mut value) if !value.is_expired() => {
+                        value.version = Some(replica.version);
+                        transaction.writes.push(TransactionWrite::Set {
+                            scope,
+                            key,
+                            value,
+                            persistent: replica.persistent,
+                        });
+                    }
+                    _ => {
+                        transaction.remove(scope, key);
+                    }
+                }
+            }
+
+            match self.apply(transaction, false) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    error!("Failed to apply replicated context: {}", e);
+                    return;
+                }
+            }
+        };
+
+        for change in changes {
+            self.notify(change);
+        }
+    }
 
-        self.save(&scope, &key, &ctx_value);
+    /// Commits a transaction, versioning its replicated writes for the mesh unless they
+    /// came from it.
+    fn commit_writes(
+        &self,
+        transaction: ContextTransaction,
+        replicate: bool,
+    ) -> Result<(), String> {
+        let changes = {
+            let _commit = self.lock_commits();
+            self.apply(transaction, replicate)?
+        };
 
-        // Save to persistent storage if requested
-        if persistent {
-            self.save_persistent(&scope, &key, &ctx_value);
+        for change in changes {
+            self.notify(change);
         }
+        Ok(())
+    }
+
+    fn lock_commits(&self) -> MutexGuard<'_, ()> {
+        self.commits.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies a transaction under the commit lock, returning the changes to notify.
+    fn apply(
+        &self,
+        mut transaction: ContextTransaction,
+        replicate: bool,
+    ) -> Result<Vec<ContextChange>, String> {
+        // Versioned before the store takes the writes, so persisted values keep their version
+        let mut assigned: HashMap<(ContextScope, String), Version> = HashMap::new();
+        let versions: Vec<Option<Version>> = transaction
+            .writes
+            .iter_mut()
+            .map(|write| {
+                if !replicate {
+                    return None;
+                }
+                let (scope, key) = write.target();
+                let id = (scope.clone(), key.clone());
+                let version = self
+                    .replication
+                    .next_version(&id.0, &id.1, assigned.get(&id))?;
+                if let TransactionWrite::Set { value, .. } = write {
+                    value.version = Some(version.clone());
+                }
+                assigned.insert(id, version.clone());
+                Some(version)
+            })
+            .collect();
+
+        let mut pending: HashMap<(ContextScope, String), Option<serde_json::Value>> =
+            HashMap::new();
+        let mut changes = Vec::new();
+        let mut writes = Vec::new();
+
+        for write in &transaction.writes {
+            let (scope, key, new) = match write {
+                TransactionWrite::Set {
+                    scope,
+                    key,
+                    value,
+                    persistent,
+                } => {
+                    if *persistent {
+                        writes.push(StoreWrite::Put {
+                            scope: scope.clone(),
+                            key: key.clone(),
+                            value: value.clone(),
+                        });
+                    }
+                    (scope, key, Some(value.value.clone()))
+                }
+                TransactionWrite::Remove { scope, key } => {
+                    if self.store.get(scope, key).is_some() {
+                        writes.push(StoreWrite::Delete {
+                            scope: scope.clone(),
+                            key: key.clone(),
+                        });
+                    }
+                    (scope, key, None)
+                }
+            };
+
+            let source = WatchSource::Scope(scope.clone());
+            if !self.is_watched(&source, key) {
+                continue;
+            }
+            let old = match pending.get(&(scope.clone(), key.clone())) {
+                Some(old) => old.clone(),
+                None => self.load(scope, key),
+            };
+            pending.insert((scope.clone(), key.clone()), new.clone());
+            if old != new {
+                let kind = match new {
+                    Some(_) => ContextChangeKind::Set,
+                    None => ContextChangeKind::Removed,
+                };
+                changes.push(ContextChange {
+                    source,
+                    key: key.clone(),
+                    old,
+                    new,
+                    kind,
+                });
+            }
+        }
+
+        self.store.commit(&writes)?;
+
+        for (write, version) in transaction.writes.iter().zip(versions) {
+            let Some(version) = version else {
+                continue;
+            };
+            match write {
+                TransactionWrite::Set {
+                    scope,
+                    key,
+                    value,
+                    persistent,
+                } => self
+                    .replication
+                    .record(scope, key, version, Some(value.clone()), *persistent),
+                TransactionWrite::Remove { scope, key } => {
+                    self.replication.record(scope, key, version, None, false)
+                }
+            }
+        }
+
+        if let Ok(mut store) = self.memory_store.write() {
+            for write in transaction.writes {
+                match write {
+                    TransactionWrite::Set {
+                        scope, key, value, ..
+                    } => {
+                        store.entry(scope).or_default().insert(key, value);
+                    }
+                    TransactionWrite::Remove { scope, key } => {
+                        if let Some(scope_map) = store.get_mut(&scope) {
+                            scope_map.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(changes)
     }
 
     fn save(&self, scope: &ContextScope, key: &str, ctx_value: &ContextValue) {
@@ -116,8 +705,21 @@ impl ContextManager {
             return Some(value);
         }
 
+        // Cached under the commit lock, so a value committed meanwhile isn't replaced by
+        // the older one read from the store
+        let _commit = self.lock_commits();
+        self.load(scope, key)
+    }
+
+    /// Looks a key up in memory, then in the store, caching what the store has.
+    /// The caller holds the commit lock.
+    fn load(&self, scope: &ContextScope, key: &str) -> Option<serde_json::Value> {
+        if let Some(value) = self.get_memory(scope, key) {
+            return Some(value);
+        }
+
         // Try persistent storage
-        if let Some(ctx_value) = self.load_persistent(scope, key) {
+        if let Some(ctx_value) = self.store.get(scope, key) {
             if !ctx_value.is_expired() {
                 debug!("Found {} in persistent storage for scope {:?}", key, scope);
                 self.save(scope, key, &ctx_value);
@@ -125,7 +727,13 @@ impl ContextManager {
             } else {
                 debug!("Found expired {} in persistent storage, deleting", key);
                 // Clean up expired persistent value
-                self.delete_persistent(scope, key);
+                let delete = StoreWrite::Delete {
+                    scope: scope.clone(),
+                    key: key.to_string(),
+                };
+                if let Err(e) = self.store.commit(&[delete]) {
+                    warn!("Failed to delete expired context {}: {}", key, e);
+                }
             }
         }
 
@@ -134,109 +742,217 @@ impl ContextManager {
     }
 
     pub fn remove(&self, scope: &ContextScope, key: &str) {
-        self.delete_persistent(scope, key);
+        let mut transaction = ContextTransaction::new();
+        transaction.remove(scope.clone(), key);
+        if let Err(e) = self.commit(transaction) {
+            error!("Failed to remove context: {}", e);
+        }
     }
 
-    pub fn has(&self, scope: &ContextScope, key: &str) -> bool {
-        self.get(scope, key).is_some()
-    }
+    /// Every unexpired value of a scope whose key starts with `prefix`, sorted by key.
+    pub fn entries(&self, scope: &ContextScope, prefix: &str) -> Vec<(String, ContextValue)> {
+        let mut entries: HashMap<String, ContextValue> = self
+            .store
+            .entries()
+            .into_iter()
+            .filter(|(s, key, _)| s == scope && key.starts_with(prefix))
+            .map(|(_, key, value)| (key, value))
+            .collect();
 
-    pub fn get_memory(&self, scope: &ContextScope, key: &str) -> Option<serde_json::Value> {
-        let store = self.memory_store.read().ok()?;
-        if let Some(scope_map) = store.get(scope)
-            && let Some(ctx_value) = scope_map.get(key)
-            && !ctx_value.is_expired()
+        if let Ok(store) = self.memory_store.read()
+            && let Some(scope_map) = store.get(scope)
         {
-            return Some(ctx_value.value.clone());
+            for (key, value) in scope_map {
+                if key.starts_with(prefix) {
+                    entries.insert(key.clone(), value.clone());
+                }
+            }
         }
-        None
+
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .filter(|(_, value)| !value.is_expired())
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
     }
 
-    fn get_scope_path(&self, scope: &ContextScope) -> PathBuf {
-        self.persistence_path.join(scope.to_string_key())
+    /// The keys of a scope starting with `prefix`, sorted.
+    pub fn keys(&self, scope: &ContextScope, prefix: &str) -> Vec<String> {
+        self.entries(scope, prefix)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
     }
 
-    fn save_persistent(&self, scope: &ContextScope, key: &str, value: &ContextValue) {
-        let scope_path = self.get_scope_path(scope);
-        if !scope_path.exists()
-            && let Err(e) = fs::create_dir_all(&scope_path)
-        {
-            error!(
-                "Failed to create scope directory {}: {}",
-                scope_path.display(),
-                e
+    /// The time left before a key expires, `None` if it doesn't exist or never expires.
+    pub fn ttl(&self, scope: &ContextScope, key: &str) -> Option<Duration> {
+        self.get(scope, key)?;
+        let memory = self
+            .memory_store
+            .read()
+            .ok()
+            .and_then(|store| store.get(scope)?.get(key).cloned());
+        memory.or_else(|| self.store.get(scope, key))?.ttl()
+    }
+
+    /// Every scope holding at least one value.
+    pub fn scopes(&self) -> Vec<ContextScope> {
+        let mut scopes: Vec<ContextScope> = self
+            .store
+            .entries()
+            .into_iter()
+            .map(|(scope, _, _)| scope)
+            .collect();
+        if let Ok(store) = self.memory_store.read() {
+            scopes.extend(
+                store
+                    .iter()
+                    .filter(|(_, scope_map)| !scope_map.is_empty())
+                    .map(|(scope, _)| scope.clone()),
             );
-            return;
         }
+        scopes.sort_by_key(|scope| scope.to_string());
+        scopes.dedup();
+        scopes
+    }
+
+    /// Removes every value of a scope in a single transaction, returns how many there were.
+    pub fn clear(&self, scope: &ContextScope) -> Result<usize, String> {
+        let keys = self.keys(scope, "");
+        let mut transaction = ContextTransaction::new();
+        for key in &keys {
+            transaction.remove(scope.clone(), key.as_str());
+        }
+        self.commit(transaction)?;
+        Ok(keys.len())
+    }
+
+    /// The unexpired values of a scope as a JSON object of key to value, TTL and creation time.
+    pub fn export(&self, scope: &ContextScope) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.entries(scope, "")
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, serde_json::to_value(value).ok()?)))
+                .collect(),
+        )
+    }
 
-        let file_path = scope_path.join(format!("{}.json", key));
-        match serde_json::to_string(value) {
-            Ok(content) => {
-                if let Err(e) = fs::write(&file_path, content) {
-                    error!(
-                        "Failed to write persistent context to {}: {}",
-                        file_path.display(),
-                        e
-                    );
-                } else {
-                    trace!("Saved persistent context to {}", file_path.display());
+    /// Stores the values of an [`ContextManager::export`] in a scope, all or none of them.
+    ///
+    /// Expired values are skipped, and with `replace` the values already in the scope are
+    /// removed first. Returns how many values were stored.
+    pub fn import(
+        &self,
+        scope: &ContextScope,
+        data: serde_json::Value,
+        persistent: bool,
+        replace: bool,
+    ) -> Result<usize, String> {
+        let values: HashMap<String, ContextValue> =
+            serde_json::from_value(data).map_err(|e| format!("Invalid context export: {}", e))?;
+
+        let mut transaction = ContextTransaction::new();
+        if replace {
+            for key in self.keys(scope, "") {
+                if !values.contains_key(&key) {
+                    transaction.remove(scope.clone(), key);
                 }
             }
-            Err(e) => error!("Failed to serialize context value for {}: {}", key, e),
-        }
-    }
-
-    fn load_persistent(&self, scope: &ContextScope, key: &str) -> Option<ContextValue> {
-        let file_path = self.get_scope_path(scope).join(format!("{}.json", key));
-        if file_path.exists() {
-            match fs::read_to_string(&file_path) {
-                Ok(content) => match serde_json::from_str(&content) {
-                    Ok(v) => Some(v),
-                    Err(e) => {
-                        error!(
-                            "Failed to deserialize context from {}: {}",
-                            file_path.display(),
-                            e
-                        );
-                        None
-                    }
-                },
-                Err(e) => {
-                    error!(
-                        "Failed to read persistent context from {}: {}",
-                        file_path.display(),
-                        e
-                    );
-                    None
-                }
+        }
+
+        let mut imported = 0;
+        for (key, value) in values {
+            if value.is_expired() {
+                continue;
             }
-        } else {
-            None
+            transaction.writes.push(TransactionWrite::Set {
+                scope: scope.clone(),
+                key,
+                value,
+                persistent,
+            });
+            imported += 1;
         }
+        self.commit(transaction)?;
+        Ok(imported)
     }
 
-    fn delete_persistent(&self, scope: &ContextScope, key: &str) {
-        let file_path = self.get_scope_path(scope).join(format!("{}.json", key));
-        if file_path.exists() {
-            if let Err(e) = fs::remove_file(&file_path) {
-                warn!(
-                    "Failed to delete persistent context {}: {}",
-                    file_path.display(),
-                    e
-                );
-            } else {
-                trace!("Deleted persistent context {}", file_path.display());
+    /// Moves the persistent values of `from` that `to` doesn't have into `to`, all or none
+    /// of them, unless the global `marker` key shows it was done before.
+    ///
+    /// Returns how many values were moved.
+    pub fn migrate_scope(
+        &self,
+        from: &ContextScope,
+        to: &ContextScope,
+        marker: &str,
+    ) -> Result<usize, String> {
+        if self.has(&ContextScope::Global, marker) {
+            return Ok(0);
+        }
+
+        let mut transaction = ContextTransaction::new();
+        let mut moved = 0;
+        for (scope, key, value) in self.store.entries() {
+            if scope != *from || value.is_expired() {
+                continue;
             }
+            if !self.has(to, &key) {
+                transaction.writes.push(TransactionWrite::Set {
+                    scope: to.clone(),
+                    key: key.clone(),
+                    value,
+                    persistent: true,
+                });
+                moved += 1;
+            }
+            transaction.remove(from.clone(), key);
         }
+        transaction.set(
+            ContextScope::Global,
+            marker,
+            serde_json::Value::Bool(true),
+            None,
+            true,
+        );
+        self.commit(transaction)?;
+        Ok(moved)
     }
 
-    pub fn cleanup_expired(&self) {
-        trace!("Cleaning up expired context values");
+    pub fn has(&self, scope: &ContextScope, key: &str) -> bool {
+        self.get(scope, key).is_some()
+    }
+
+    pub fn get_memory(&self, scope: &ContextScope, key: &str) -> Option<serde_json::Value> {
+        let store = self.memory_store.read().ok()?;
+        if let Some(scope_map) = store.get(scope)
+            && let Some(ctx_value) = scope_map.get(key)
+            && !ctx_value.is_expired()
+        {
+            return Some(ctx_value.value.clone());
+        }
+        None
+    }
+
+    /// Drops the expired values kept in memory, notifying their watchers.
+    pub fn expire_memory(&self) {
+        let has_expired = self.memory_store.read().is_ok_and(|store| {
+            store
+                .values()
+                .any(|scope_map| scope_map.values().any(ContextValue::is_expired))
+        });
+        if !has_expired {
+            return;
+        }
+
+        let mut expired = Vec::new();
         if let Ok(mut store) = self.memory_store.write() {
-            for scope_map in store.values_mut() {
+            for (scope, scope_map) in store.iter_mut() {
                 scope_map.retain(|k, v| {
                     if v.is_expired() {
                         debug!("Memory context expired for key: {}", k);
+                        expired.push((scope.clone(), k.clone(), v.value.clone()));
                         false
                     } else {
                         true
@@ -245,32 +961,125 @@ impl ContextManager {
             }
         }
 
-        // Also cleanup persistent files
-        if let Ok(entries) = fs::read_dir(&self.persistence_path) {
-            for entry in entries.flatten() {
-                if entry.path().is_dir()
-                    && let Ok(sub_entries) = fs::read_dir(entry.path())
-                {
-                    for sub_entry in sub_entries.flatten() {
-                        if let Ok(content) = fs::read_to_string(sub_entry.path())
-                            && let Ok(ctx_value) = serde_json::from_str::<ContextValue>(&content)
-                            && ctx_value.is_expired()
-                        {
-                            debug!(
-                                "Persistent context expired for: {}",
-                                sub_entry.path().display()
-                            );
-                            if let Err(e) = fs::remove_file(sub_entry.path()) {
-                                warn!(
-                                    "Failed to remove expired persistent file {}: {}",
-                                    sub_entry.path().display(),
-                                    e
-                                );
-                            }
-                        }
-                    }
-                }
+        for (scope, key, old) in expired {
+            self.notify(ContextChange {
+                source: WatchSource::Scope(scope),
+                key,
+                old: Some(old),
+                new: None,
+                kind: ContextChangeKind::Expired,
+            });
+        }
+    }
+
+    /// Compares the device context paths being watched with their last seen values,
+    /// notifying the watchers of the ones that changed.
+    ///
+    /// A path is only compared from the second poll on, the first one just records it.
+    pub async fn poll_device(&self, device: &AviDevice) {
+        let mut paths: Vec<(String, bool)> = match self.watchers.read() {
+            Ok(watchers) => watchers
+                .iter()
+                .filter(|w| w.source == WatchSource::Device)
+                .map(|w| {
+                    let (path, flatten) = w.pattern.device_path();
+                    (path.to_string(), flatten)
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        paths.sort();
+        paths.dedup();
+
+        for (path, flatten) in paths {
+            let value = device.get_ctx(&path).await.ok();
+            self.device_changed(&path, flatten, value);
+        }
+    }
+
+    /// Records the value polled at a device context `path`, notifying the watchers of the
+    /// keys that changed since the last poll.
+    fn device_changed(&self, path: &str, flatten: bool, value: Option<serde_json::Value>) {
+        let mut current = HashMap::new();
+        match value {
+            Some(value) if flatten => flatten_values(path, value, &mut current),
+            Some(serde_json::Value::Null) | None => {}
+            Some(value) => {
+                current.insert(path.to_string(), value);
+            }
+        }
+
+        let previous = {
+            let Ok(mut seen) = self.device_values.lock() else {
+                return;
+            };
+            match seen.insert((path.to_string(), flatten), current.clone()) {
+                Some(previous) => previous,
+                None => return,
+            }
+        };
+
+        let mut keys: Vec<&String> = previous.keys().chain(current.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let (old, new) = (previous.get(key).cloned(), current.get(key).cloned());
+            if old == new {
+                continue;
             }
+            let kind = match new {
+                Some(_) => ContextChangeKind::Set,
+                None => ContextChangeKind::Removed,
+            };
+            self.notify(ContextChange {
+                source: WatchSource::Device,
+                key: key.clone(),
+                old,
+                new,
+                kind,
+            });
+        }
+    }
+
+    pub fn cleanup_expired(&self) {
+        trace!("Cleaning up expired context values");
+        self.expire_memory();
+
+        // Also cleanup persistent values
+        let expired: Vec<StoreWrite> = self
+            .store
+            .entries()
+            .into_iter()
+            .filter(|(_, _, value)| value.is_expired())
+            .map(|(scope, key, _)| {
+                debug!("Persistent context expired for key: {}", key);
+                StoreWrite::Delete { scope, key }
+            })
+            .collect();
+        if let Err(e) = self.store.commit(&expired) {
+            warn!("Failed to remove expired persistent context: {}", e);
+        }
+        if let Err(e) = self.store.compact() {
+            warn!("Failed to compact the context store: {}", e);
+        }
+    }
+}
+
+/// Adds every value below `path` to `out`, keyed by its dotted path.
+fn flatten_values(
+    path: &str,
+    value: serde_json::Value,
+    out: &mut HashMap<String, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                flatten_values(&format!("{}.{}", path, key), value, out);
+            }
+        }
+        serde_json::Value::Null => {}
+        value => {
+            out.insert(path.to_string(), value);
         }
     }
 }
@@ -289,6 +1098,24 @@ pub fn context_cleanup_task() {
     });
 }
 
+/// Expires memory values and polls the watched device context paths, so watchers
+/// hear about changes within `context_watch_interval_ms`.
+pub fn context_watch_task() {
+    info!("Started context watchers.");
+    let ctx = runtime().clone();
+    let period = Duration::from_millis(setting_or::<u64>("context_watch_interval_ms", 1000));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Ok(c) = ctx {
+                c.context.expire_memory();
+                c.context.poll_device(&c.device).await;
+            }
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -299,7 +1126,7 @@ mod tests {
     #[test]
     fn test_memory_storage() {
         let dir = tempdir().unwrap();
-        let manager = ContextManager::new(dir.path());
+        let manager = ContextManager::new(dir.path()).unwrap();
         let scope = ContextScope::Global;
         let key = "test_key".to_string();
         let value = json!({"foo": "bar"});
@@ -313,7 +1140,7 @@ mod tests {
     #[test]
     fn test_persistent_storage() {
         let dir = tempdir().unwrap();
-        let manager = ContextManager::new(dir.path());
+        let manager = ContextManager::new(dir.path()).unwrap();
         let scope = ContextScope::Skill("test_skill".to_string());
         let key = "persistent_key".to_string();
         let value = json!(42);
@@ -335,7 +1162,7 @@ mod tests {
     #[test]
     fn test_ttl_expiration() {
         let dir = tempdir().unwrap();
-        let manager = ContextManager::new(dir.path());
+        let manager = ContextManager::new(dir.path()).unwrap();
         let scope = ContextScope::Global;
         let key = "expiring_key".to_string();
         let value = json!("will_expire");
@@ -359,7 +1186,7 @@ mod tests {
     #[test]
     fn test_scoping() {
         let dir = tempdir().unwrap();
-        let manager = ContextManager::new(dir.path());
+        let manager = ContextManager::new(dir.path()).unwrap();
         let global_scope = ContextScope::Global;
         let skill_scope = ContextScope::Skill("my_skill".to_string());
         let key = "key".to_string();
@@ -382,4 +1209,212 @@ mod tests {
         assert_eq!(manager.get(&global_scope, &key), Some(json!("global")));
         assert_eq!(manager.get(&skill_scope, &key), Some(json!("skill")));
     }
+
+    #[test]
+    fn test_watchers_see_sets_removals_and_expiry() {
+        let dir = tempdir().unwrap();
+        let manager = ContextManager::new(dir.path()).unwrap();
+        let skill = ContextScope::Skill("timer".to_string());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = Arc::clone(&seen);
+        let (source, pattern) = WatchSource::parse("skill:alarm.*", "timer");
+        let id = manager.watch("timer", source, pattern, move |change| {
+            log.lock()
+                .unwrap()
+                .push((change.key.clone(), change.kind, change.old.clone()));
+        });
+
+        manager.set(skill.clone(), "alarm.kitchen".into(), json!(1), None, false);
+        manager.set(skill.clone(), "alarm.kitchen".into(), json!(1), None, false);
+        manager.set(skill.clone(), "other".into(), json!(2), None, false);
+        manager.set(
+            ContextScope::Global,
+            "alarm.kitchen".into(),
+            json!(3),
+            None,
+            false,
+        );
+        manager.remove(&skill, "alarm.kitchen");
+        assert_eq!(manager.get(&skill, "alarm.kitchen"), None);
+
+        manager.set(
+            skill.clone(),
+            "alarm.bedroom".into(),
+            json!(4),
+            Some(Duration::from_secs(1)),
+            false,
+        );
+        thread::sleep(Duration::from_secs(2));
+        manager.expire_memory();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("alarm.kitchen".to_string(), ContextChangeKind::Set, None),
+                (
+                    "alarm.kitchen".to_string(),
+                    ContextChangeKind::Removed,
+                    Some(json!(1))
+                ),
+                ("alarm.bedroom".to_string(), ContextChangeKind::Set, None),
+                (
+                    "alarm.bedroom".to_string(),
+                    ContextChangeKind::Expired,
+                    Some(json!(4))
+                ),
+            ]
+        );
+
+        assert!(!manager.unwatch("other", id));
+        assert!(manager.unwatch("timer", id));
+        manager.set(skill, "alarm.kitchen".into(), json!(5), None, false);
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_device_prefix_watchers_see_the_keys_below_it() {
+        let dir = tempdir().unwrap();
+        let manager = ContextManager::new(dir.path()).unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let log = Arc::clone(&seen);
+        let (source, pattern) = WatchSource::parse("device:avi.user.*", "greeter");
+        assert_eq!(
+            WatchPattern::parse(pattern).device_path(),
+            ("avi.user", true)
+        );
+        manager.watch("greeter", source, pattern, move |change| {
+            log.lock().unwrap().push((change.key.clone(), change.kind));
+        });
+
+        // The first poll only records the values
+        manager.device_changed("avi.user", true, Some(json!({ "name": "Ana", "age": 30 })));
+        manager.device_changed("avi.user", true, Some(json!({ "name": "Rui", "age": 30 })));
+        manager.device_changed("avi.user", true, Some(json!({ "age": 30 })));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("avi.user.name".to_string(), ContextChangeKind::Set),
+                ("avi.user.name".to_string(), ContextChangeKind::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_values_expire() {
+        let dir = tempdir().unwrap();
+        let manager = ContextManager::new(dir.path()).unwrap();
+        let session =
+            ContextScope::Skill("timer".into()).within(ContextScope::Session("t-1".into()));
+
+        manager.set(session.clone(), "step".into(), json!(1), None, false);
+        let ttl = manager.ttl(&session, "step").unwrap();
+        assert!(ttl <= Duration::from_secs(600) && ttl > Duration::from_secs(590));
+
+        let skill = ContextScope::Skill("timer".into());
+        manager.set(skill.clone(), "step".into(), json!(1), None, false);
+        assert_eq!(manager.ttl(&skill, "step"), None);
+    }
+
+    #[test]
+    fn test_concurrent_commits_agree_in_memory_and_on_disk() {
+        let dir = tempdir().unwrap();
+        let scope = ContextScope::Global;
+        let manager = Arc::new(ContextManager::new(dir.path()).unwrap());
+        let writers: Vec<_> = (0..8)
+            .map(|n| {
+                let manager = Arc::clone(&manager);
+                let scope = scope.clone();
+                std::thread::spawn(move || {
+                    for round in 0..50 {
+                        manager.set(scope.clone(), "race".into(), json!([n, round]), None, true);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let in_memory = manager.get(&scope, "race");
+        drop(manager);
+        let reopened = ContextManager::new(dir.path()).unwrap();
+        assert_eq!(reopened.get(&scope, "race"), in_memory);
+    }
+
+    #[test]
+    fn test_migrate_scope_once() {
+        let dir = tempdir().unwrap();
+        let manager = ContextManager::new(dir.path()).unwrap();
+        let legacy = ContextScope::Skill("Timer".into());
+        let user = ContextScope::Skill("timer".into()).within(ContextScope::User("ana".into()));
+
+        manager.set(legacy.clone(), "last".into(), json!(5), None, true);
+        manager.set(legacy.clone(), "kept".into(), json!(1), None, true);
+        manager.set(user.clone(), "kept".into(), json!(2), None, true);
+
+        assert_eq!(
+            manager.migrate_scope(&legacy, &user, "migrated.timer"),
+            Ok(1)
+        );
+        assert_eq!(manager.get(&user, "last"), Some(json!(5)));
+        assert_eq!(manager.get(&user, "kept"), Some(json!(2)));
+        assert!(manager.keys(&legacy, "").is_empty());
+
+        // Values written to the old scope afterwards stay there
+        manager.set(legacy.clone(), "shared".into(), json!(3), None, true);
+        assert_eq!(
+            manager.migrate_scope(&legacy, &user, "migrated.timer"),
+            Ok(0)
+        );
+        assert_eq!(manager.get(&legacy, "shared"), Some(json!(3)));
+    }
+
+    #[test]
+    fn test_query_and_round_trip_a_scope() {
+        let dir = tempdir().unwrap();
+        let manager = ContextManager::new(dir.path()).unwrap();
+        let scope: ContextScope = "skill:timer".parse().unwrap();
+        assert_eq!(scope.to_string(), "skill:timer");
+        assert!("skill:".parse::<ContextScope>().is_err());
+        let per_user = ContextScope::Skill("timer".into()).within(ContextScope::User("ana".into()));
+        assert_eq!(per_user.to_string(), "skill:timer/user:ana");
+        assert_eq!("skill:timer/user:ana".parse(), Ok(per_user.clone()));
+
+        let mut transaction = ContextTransaction::new();
+        transaction
+            .set(scope.clone(), "alarm.kitchen", json!(1), None, true)
+            .set(scope.clone(), "alarm.bedroom", json!(2), None, false)
+            .set(
+                scope.clone(),
+                "last",
+                json!("5m"),
+                Some(Duration::from_secs(60)),
+                true,
+            );
+        manager.commit(transaction).unwrap();
+
+        assert_eq!(
+            manager.keys(&scope, "alarm."),
+            ["alarm.bedroom", "alarm.kitchen"]
+        );
+        assert!(manager.ttl(&scope, "last").unwrap() <= Duration::from_secs(60));
+        assert_eq!(manager.ttl(&scope, "alarm.kitchen"), None);
+        assert_eq!(manager.scopes(), vec![scope.clone()]);
+        manager.set(per_user.clone(), "last".into(), json!("10m"), None, false);
+        assert_eq!(manager.get(&scope, "last"), Some(json!("5m")));
+        assert_eq!(manager.get(&per_user, "last"), Some(json!("10m")));
+        manager.remove(&per_user, "last");
+
+        let export = manager.export(&scope);
+        assert_eq!(manager.clear(&scope).unwrap(), 3);
+        assert!(manager.keys(&scope, "").is_empty());
+
+        manager.set(scope.clone(), "stale".into(), json!(0), None, true);
+        assert_eq!(manager.import(&scope, export, true, true).unwrap(), 3);
+        assert_eq!(manager.get(&scope, "alarm.bedroom"), Some(json!(2)));
+        assert!(!manager.has(&scope, "stale"));
+    }
 }
@@ -0,0 +1,629 @@
+//! Mirrors selected context values to the device context shared by the mesh, so another
+//! node taking over as core, or a restarted core, gets them back.
+//!
+//! Every replicated write carries a version, the time it was made and the node that made it,
+//! and the newest version of a key wins wherever the writes meet. Each write is also
+//! announced on [`REPLICA_TOPIC`], the device context is read in full only to catch up.
+
+use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::data::context::{ContextScope, ContextValue, WatchPattern};
+use crate::{publish, subscribe};
+use avi_device::device::AviDevice;
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+/// Device context path the replicas are kept under.
+pub const REPLICA_CTX_PREFIX: &str = "avi.context";
+
+/// Topic every replicated write is announced on.
+pub const REPLICA_TOPIC: &str = "context/replica";
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// When and where a replicated write was made, later versions win.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Version {
+    /// Milliseconds since the epoch.
+    pub at: u64,
+    /// The device that made the write, which breaks ties.
+    pub node: String,
+}
+
+/// A replicated value as stored in the device context, `None` once it was removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replica {
+    pub version: Version,
+    pub value: Option<ContextValue>,
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// A replicated write of a key.
+pub type ReplicaWrite = (ContextScope, String, Replica);
+
+/// A `context_replication` setting entry.
+#[derive(Debug, Clone, Deserialize)]
+struct RuleConfig {
+    scope: String,
+    #[serde(default)]
+    keys: Option<String>,
+}
+
+/// The keys of a scope that are replicated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationRule {
+    pub scope: ContextScope,
+    pub keys: WatchPattern,
+}
+
+impl ReplicationRule {
+    /// Whether the rule covers a key of `scope`, which is the rule's scope or that scope
+    /// kept for a user or device. Session values are never replicated.
+    fn matches(&self, scope: &ContextScope, key: &str) -> bool {
+        let covered = match scope {
+            ContextScope::Within(outer, _) => **outer == self.scope,
+            _ => false,
+        };
+        (*scope == self.scope || covered) && !scope.is_session() && self.keys.matches(key)
+    }
+}
+
+/// Where the local writes go, kept until the node is known and the task connected.
+#[derive(Default)]
+struct Outbox {
+    sender: Option<UnboundedSender<ReplicaWrite>>,
+    node: Option<String>,
+    pending: Vec<ReplicaWrite>,
+}
+
+/// Which context values are replicated and the version of each of them.
+#[derive(Default)]
+pub struct Replication {
+    rules: RwLock<Vec<ReplicationRule>>,
+    versions: Mutex<HashMap<(ContextScope, String), Version>>,
+    outbox: Mutex<Outbox>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replicates the keys of `scope` matching `keys`, `*` for all of them.
+    pub fn mark(&self, scope: ContextScope, keys: &str) {
+        let rule = ReplicationRule {
+            scope,
+            keys: WatchPattern::parse(keys),
+        };
+        let mut rules = self.rules.write();
+        if !rules.contains(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    /// Whether writes to a key are replicated, because a rule marks it or because it
+    /// came from the mesh.
+    pub fn is_replicated(&self, scope: &ContextScope, key: &str) -> bool {
+        self.rules
+            .read()
+            .iter()
+            .any(|rule| rule.matches(scope, key))
+            || self
+                .versions
+                .lock()
+                .contains_key(&(scope.clone(), key.to_string()))
+    }
+
+    /// Names this node in the versions and returns the writes to send to the mesh,
+    /// starting with the ones made before, which get this node's name too.
+    pub fn connect(&self, node: String) -> UnboundedReceiver<ReplicaWrite> {
+        let (sender, receiver) = unbounded_channel();
+        let mut outbox = self.outbox.lock();
+        let mut versions = self.versions.lock();
+        for (scope, key, mut replica) in outbox.pending.drain(..) {
+            let id = (scope.clone(), key.clone());
+            let named = Version {
+                at: replica.version.at,
+                node: node.clone(),
+            };
+            if versions.get(&id) == Some(&replica.version) {
+                versions.insert(id, named.clone());
+            }
+            replica.version = named;
+            let _ = sender.send((scope, key, replica));
+        }
+        outbox.sender = Some(sender);
+        outbox.node = Some(node);
+        receiver
+    }
+
+    /// Forgets the versions of the keys `is_gone` says no longer have a value, once they
+    /// are older than `max_age`.
+    ///
+    /// A write older than a forgotten removal is taken again if it arrives afterwards, so
+    /// `max_age` should be well above how long a node may stay away from the mesh.
+    pub(crate) fn prune(
+        &self,
+        max_age: Duration,
+        is_gone: impl Fn(&ContextScope, &str) -> bool,
+    ) -> Vec<(ContextScope, String)> {
+        let horizon = now_ms().saturating_sub(max_age.as_millis() as u64);
+        let mut pruned = Vec::new();
+        self.versions.lock().retain(|(scope, key), version| {
+            if version.at <= horizon && is_gone(scope, key) {
+                pruned.push((scope.clone(), key.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        pruned
+    }
+
+    /// The version a local write of a key gets, `None` if the key isn't replicated.
+    ///
+    /// `previous` is the version an earlier write of the same transaction got.
+    pub(crate) fn next_version(
+        &self,
+        scope: &ContextScope,
+        key: &str,
+        previous: Option<&Version>,
+    ) -> Option<Version> {
+        if !self.is_replicated(scope, key) {
+            return None;
+        }
+
+        let now = now_ms();
+        let node = self.outbox.lock().node.clone().unwrap_or_default();
+        let versions = self.versions.lock();
+        let last = previous.or(versions.get(&(scope.clone(), key.to_string())));
+        // Never go back in time, even if the clock does
+        let at = match last {
+            Some(last) if last.at >= now => last.at + 1,
+            _ => now,
+        };
+        Some(Version { at, node })
+    }
+
+    /// Takes the version of a local write of a replicated key and queues it for the mesh.
+    pub(crate) fn record(
+        &self,
+        scope: &ContextScope,
+        key: &str,
+        mut version: Version,
+        value: Option<ContextValue>,
+        persistent: bool,
+    ) {
+        let mut outbox = self.outbox.lock();
+        if version.node.is_empty()
+            && let Some(node) = &outbox.node
+        {
+            version.node = node.clone();
+        }
+        self.versions
+            .lock()
+            .insert((scope.clone(), key.to_string()), version.clone());
+
+        let write = (
+            scope.clone(),
+            key.to_string(),
+            Replica {
+                version,
+                value,
+                persistent,
+            },
+        );
+        match outbox.sender.as_ref() {
+            Some(sender) => {
+                let _ = sender.send(write);
+            }
+            None => outbox.pending.push(write),
+        }
+    }
+
+    /// Takes the version of a write from the mesh if it is newer than the one of the key,
+    /// asking `stored` for the version the key was persisted with when there is none yet.
+    pub(crate) fn accept(
+        &self,
+        scope: &ContextScope,
+        key: &str,
+        version: &Version,
+        stored: impl FnOnce() -> Option<Version>,
+    ) -> bool {
+        let mut versions = self.versions.lock();
+        let id = (scope.clone(), key.to_string());
+        if !versions.contains_key(&id)
+            && let Some(stored) = stored()
+        {
+            versions.insert(id.clone(), stored);
+        }
+        if versions.get(&id).is_some_and(|known| known >= version) {
+            return false;
+        }
+        versions.insert(id, version.clone());
+        true
+    }
+}
+
+/// Escapes a scope or a key into a single device context path segment.
+fn encode_segment(raw: &str) -> String {
+    raw.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c.to_string(),
+            _ => {
+                let mut buf = [0; 4];
+                c.encode_utf8(&mut buf)
+                    .bytes()
+                    .map(|b| format!("~{:02x}", b))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+fn decode_segment(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut chars = segment.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'~' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The device context path of a replicated key.
+pub fn replica_path(scope: &ContextScope, key: &str) -> String {
+    format!(
+        "{}.{}.{}",
+        REPLICA_CTX_PREFIX,
+        encode_segment(&scope.to_string()),
+        encode_segment(key)
+    )
+}
+
+/// Reads the replicas out of the device context tree under [`REPLICA_CTX_PREFIX`].
+pub fn decode_replicas(tree: Value) -> Vec<ReplicaWrite> {
+    let Value::Object(scopes) = tree else {
+        return Vec::new();
+    };
+
+    let mut replicas = Vec::new();
+    for (scope, keys) in scopes {
+        let Some(scope) = decode_segment(&scope).and_then(|s| s.parse::<ContextScope>().ok())
+        else {
+            warn!("Ignoring replicas of unknown scope {}", scope);
+            continue;
+        };
+        let Value::Object(keys) = keys else {
+            continue;
+        };
+        for (key, replica) in keys {
+            let Some(key) = decode_segment(&key) else {
+                continue;
+            };
+            match serde_json::from_value::<Replica>(replica) {
+                Ok(replica) => replicas.push((scope.clone(), key, replica)),
+                Err(e) => warn!("Ignoring replica of {} in {}: {}", key, scope, e),
+            }
+        }
+    }
+    replicas
+}
+
+/// Sends replicated writes to the device context and takes in the ones of other nodes,
+/// starting by recovering what the mesh holds.
+///
+/// Scopes and keys are picked with the `context_replication` setting, a list of
+/// `{ scope, keys }` where `keys` is a key or a `prefix*`, all of them if left out, and
+/// by the `replicated` keys of the skill manifests.
+pub fn context_replication_task() {
+    let Ok(c) = runtime() else {
+        return;
+    };
+    let rules = setting_or::<Vec<RuleConfig>>("context_replication", Vec::new());
+    for rule in rules {
+        match rule.scope.parse() {
+            Ok(scope) => c
+                .context
+                .replication()
+                .mark(scope, rule.keys.as_deref().unwrap_or("*")),
+            Err(e) => warn!("Invalid context_replication entry: {}", e),
+        }
+    }
+    let period =
+        Duration::from_millis(setting_or::<u64>("context_replication_interval_ms", 60_000));
+    let max_age = Duration::from_secs(setting_or::<u64>(
+        "context_replication_tombstone_ttl",
+        86_400,
+    ));
+
+    info!("Started context replication.");
+    tokio::spawn(async move {
+        subscribe!(REPLICA_TOPIC, async: |_from, _topic, data| async move {
+            match serde_json::from_slice::<ReplicaWrite>(&data) {
+                Ok(replica) => {
+                    if let Ok(c) = runtime() {
+                        c.context.apply_replicas(vec![replica]);
+                    }
+                }
+                Err(e) => warn!("Ignoring invalid replica announcement: {}", e),
+            }
+        });
+
+        let mut outbox = c.context.replication().connect(c.device.get_id().await);
+        let mut interval = tokio::time::interval(period);
+        // The writes the device context didn't take, by key, sent again on every tick
+        let mut unsent: HashMap<(ContextScope, String), ReplicaWrite> = HashMap::new();
+        loop {
+            tokio::select! {
+                Some(write) = outbox.recv() => {
+                    let id = (write.0.clone(), write.1.clone());
+                    unsent.remove(&id);
+                    if !push(&c.device, &write).await {
+                        unsent.insert(id, write);
+                    }
+                }
+                _ = interval.tick() => {
+                    for (id, write) in std::mem::take(&mut unsent) {
+                        if !push(&c.device, &write).await {
+                            unsent.insert(id, write);
+                        }
+                    }
+                    pull(&c.device, max_age).await;
+                    prune(&c.device, max_age).await;
+                }
+            }
+        }
+    });
+}
+
+/// Writes a replica to the device context and announces it, returning whether the device
+/// context took it.
+async fn push(device: &AviDevice, write: &ReplicaWrite) -> bool {
+    let (scope, key, replica) = write;
+    let value = match serde_json::to_value(replica) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to serialize the replica of {}: {}", key, e);
+            // Can't get better by trying again
+            return true;
+        }
+    };
+    if let Err(e) = device.update_ctx(&replica_path(scope, key), value).await {
+        warn!(
+            "Failed to replicate {} of {}, retrying later: {}",
+            key, scope, e
+        );
+        return false;
+    }
+    if let Ok(data) = serde_json::to_vec(write) {
+        let _ = publish!(REPLICA_TOPIC, data);
+    }
+    true
+}
+
+/// Takes in the replicas the announcements missed, dropping the removals older than
+/// `max_age` from the device context.
+async fn pull(device: &AviDevice, max_age: Duration) {
+    let tree = match device.get_ctx(REPLICA_CTX_PREFIX).await {
+        Ok(tree) => tree,
+        Err(e) => {
+            debug!("No context replicas to read: {}", e);
+            return;
+        }
+    };
+    let replicas = decode_replicas(tree);
+    let horizon = now_ms().saturating_sub(max_age.as_millis() as u64);
+    for (scope, key, replica) in &replicas {
+        if replica.value.is_none() && replica.version.at <= horizon {
+            let _ = device.delete_ctx(&replica_path(scope, key)).await;
+        }
+    }
+    if let Ok(c) = runtime() {
+        c.context.apply_replicas(replicas);
+    }
+}
+
+/// Forgets the versions of the keys removed or expired more than `max_age` ago.
+async fn prune(device: &AviDevice, max_age: Duration) {
+    let Ok(c) = runtime() else {
+        return;
+    };
+    let pruned = c
+        .context
+        .replication()
+        .prune(max_age, |scope, key| !c.context.has(scope, key));
+    for (scope, key) in pruned {
+        let _ = device.delete_ctx(&replica_path(&scope, &key)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::context::ContextManager;
+    use crate::data::context_store::MemoryStore;
+    use serde_json::json;
+
+    fn manager() -> ContextManager {
+        let manager = ContextManager::with_store(Box::new(MemoryStore::new()));
+        manager.replication().mark(ContextScope::Global, "shared.*");
+        manager
+    }
+
+    #[test]
+    fn test_paths_round_trip_through_the_device_tree() {
+        let scope = ContextScope::Skill("timer".into()).within(ContextScope::User("ana".into()));
+        let path = replica_path(&scope, "alarm.kitchen");
+        assert_eq!(
+            path,
+            "avi.context.skill~3atimer~2fuser~3aana.alarm~2ekitchen"
+        );
+
+        let replica = Replica {
+            version: Version {
+                at: 1,
+                node: "core".into(),
+            },
+            value: None,
+            persistent: false,
+        };
+        let segments: Vec<_> = path.split('.').collect();
+        let tree = json!({ segments[2]: { segments[3]: replica } });
+        let replicas = decode_replicas(tree);
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].0, scope);
+        assert_eq!(replicas[0].1, "alarm.kitchen");
+    }
+
+    #[test]
+    fn test_last_writer_wins_between_nodes() {
+        let core = manager();
+        let node = manager();
+        let mut from_core = core.replication().connect("core".into());
+        let mut from_node = node.replication().connect("node".into());
+
+        core.set(ContextScope::Global, "private".into(), json!(0), None, true);
+        core.set(
+            ContextScope::Global,
+            "shared.mode".into(),
+            json!("a"),
+            None,
+            true,
+        );
+        let first: Vec<_> = std::iter::from_fn(|| from_core.try_recv().ok()).collect();
+        assert_eq!(first.len(), 1);
+
+        node.apply_replicas(first.clone());
+        assert_eq!(
+            node.get(&ContextScope::Global, "shared.mode"),
+            Some(json!("a"))
+        );
+        assert!(from_node.try_recv().is_err());
+
+        node.remove(&ContextScope::Global, "shared.mode");
+        let removal: Vec<_> = std::iter::from_fn(|| from_node.try_recv().ok()).collect();
+
+        // The core's older write arriving late doesn't bring the value back
+        node.apply_replicas(first);
+        assert_eq!(node.get(&ContextScope::Global, "shared.mode"), None);
+
+        core.apply_replicas(removal);
+        assert_eq!(core.get(&ContextScope::Global, "shared.mode"), None);
+    }
+
+    #[test]
+    fn test_writes_before_connecting_are_sent_with_the_node() {
+        let manager = manager();
+        manager.set(
+            ContextScope::Global,
+            "shared.mode".into(),
+            json!("a"),
+            None,
+            true,
+        );
+
+        let mut outbox = manager.replication().connect("core".into());
+        let (_, key, replica) = outbox.try_recv().unwrap();
+        assert_eq!(key, "shared.mode");
+        assert_eq!(replica.version.node, "core");
+
+        // The renamed version still wins over the write it was sent as
+        assert!(!manager.replication().accept(
+            &ContextScope::Global,
+            "shared.mode",
+            &replica.version,
+            || None
+        ));
+    }
+
+    #[test]
+    fn test_a_restarted_node_keeps_values_newer_than_the_replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        let scope = ContextScope::Global;
+        let open = || {
+            let manager = ContextManager::new(dir.path()).unwrap();
+            manager.replication().mark(scope.clone(), "shared.*");
+            manager
+        };
+
+        let written = {
+            let manager = open();
+            let _outbox = manager.replication().connect("core".into());
+            manager.set(
+                scope.clone(),
+                "shared.mode".into(),
+                json!("new"),
+                None,
+                true,
+            );
+            manager.replication().versions.lock()[&(scope.clone(), "shared.mode".to_string())]
+                .clone()
+        };
+
+        let restarted = open();
+        let older = Replica {
+            version: Version {
+                at: written.at - 1000,
+                node: "node".into(),
+            },
+            value: Some(ContextValue::new(json!("old"), None)),
+            persistent: true,
+        };
+        restarted.apply_replicas(vec![(scope.clone(), "shared.mode".into(), older)]);
+        assert_eq!(restarted.get(&scope, "shared.mode"), Some(json!("new")));
+    }
+
+    #[test]
+    fn test_rules_cover_per_user_values_but_not_sessions() {
+        let replication = Replication::new();
+        let skill = ContextScope::Skill("timer".into());
+        replication.mark(skill.clone(), "alarm*");
+
+        let user = skill.clone().within(ContextScope::User("ana".into()));
+        let session = skill.clone().within(ContextScope::Session("t-1".into()));
+        assert!(replication.is_replicated(&skill, "alarm.kitchen"));
+        assert!(replication.is_replicated(&user, "alarm.kitchen"));
+        assert!(!replication.is_replicated(&user, "volume"));
+        assert!(!replication.is_replicated(&session, "alarm.kitchen"));
+    }
+
+    #[test]
+    fn test_prune_forgets_only_old_removals() {
+        let manager = manager();
+        let _outbox = manager.replication().connect("core".into());
+        let scope = ContextScope::Global;
+        manager.set(scope.clone(), "shared.kept".into(), json!(1), None, true);
+        manager.set(scope.clone(), "shared.gone".into(), json!(1), None, true);
+        manager.remove(&scope, "shared.gone");
+
+        let is_gone = |scope: &ContextScope, key: &str| !manager.has(scope, key);
+        assert!(
+            manager
+                .replication()
+                .prune(Duration::from_secs(60), is_gone)
+                .is_empty()
+        );
+
+        // Versions of one key may run a few milliseconds ahead of the clock
+        std::thread::sleep(Duration::from_millis(5));
+        let pruned = manager.replication().prune(Duration::ZERO, is_gone);
+        assert_eq!(pruned, vec![(scope.clone(), "shared.gone".to_string())]);
+        assert!(manager.replication().is_replicated(&scope, "shared.kept"));
+    }
+}
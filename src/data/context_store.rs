@@ -0,0 +1,472 @@
+//! Storage backends of the persistent context values.
+
+use crate::data::context::{ContextScope, ContextValue};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the folder the per-key files of the old layout are moved to once migrated.
+const MIGRATED_DIR: &str = "migrated";
+
+/// Records a log may hold before it is worth compacting, whatever the share of stale ones.
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// A write of a [`ContextStore::commit`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreWrite {
+    Put {
+        scope: ContextScope,
+        key: String,
+        value: ContextValue,
+    },
+    Delete {
+        scope: ContextScope,
+        key: String,
+    },
+}
+
+/// Where the persistent context values are kept.
+pub trait ContextStore: Send + Sync {
+    /// The stored value of a key, expired or not.
+    fn get(&self, scope: &ContextScope, key: &str) -> Option<ContextValue>;
+
+    /// Every stored value.
+    fn entries(&self) -> Vec<(ContextScope, String, ContextValue)>;
+
+    /// Applies every write or, when it fails, none of them.
+    fn commit(&self, writes: &[StoreWrite]) -> Result<(), String>;
+
+    /// Drops what the store keeps of overwritten, removed or expired values.
+    fn compact(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+type Entries = HashMap<ContextScope, HashMap<String, ContextValue>>;
+
+fn apply(entries: &mut Entries, writes: &[StoreWrite]) {
+    for write in writes {
+        match write {
+            StoreWrite::Put { scope, key, value } => {
+                entries
+                    .entry(scope.clone())
+                    .or_default()
+                    .insert(key.clone(), value.clone());
+            }
+            StoreWrite::Delete { scope, key } => {
+                if let Some(scope_map) = entries.get_mut(scope) {
+                    scope_map.remove(key);
+                }
+            }
+        }
+    }
+}
+
+fn flatten(entries: &Entries) -> Vec<(ContextScope, String, ContextValue)> {
+    entries
+        .iter()
+        .flat_map(|(scope, scope_map)| {
+            scope_map
+                .iter()
+                .map(|(key, value)| (scope.clone(), key.clone(), value.clone()))
+        })
+        .collect()
+}
+
+/// A store that keeps nothing on disk, for tests and tools.
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<Entries>,
+}
+
+#[allow(dead_code)]
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ContextStore for MemoryStore {
+    fn get(&self, scope: &ContextScope, key: &str) -> Option<ContextValue> {
+        self.entries.lock().get(scope)?.get(key).cloned()
+    }
+
+    fn entries(&self) -> Vec<(ContextScope, String, ContextValue)> {
+        flatten(&self.entries.lock())
+    }
+
+    fn commit(&self, writes: &[StoreWrite]) -> Result<(), String> {
+        apply(&mut self.entries.lock(), writes);
+        Ok(())
+    }
+}
+
+/// FNV-1a hash of a record, enough to tell a torn write from a complete one.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn encode(writes: &[StoreWrite]) -> Result<String, String> {
+    let json = serde_json::to_string(writes).map_err(|e| e.to_string())?;
+    Ok(format!("{:016x} {}\n", checksum(json.as_bytes()), json))
+}
+
+fn decode(line: &str) -> Option<Vec<StoreWrite>> {
+    let (sum, json) = line.strip_suffix('\n')?.split_once(' ')?;
+    if u64::from_str_radix(sum, 16).ok()? != checksum(json.as_bytes()) {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
+
+/// Opens a log for appending, failing if another process holds it.
+fn open_locked(path: &Path) -> Result<File, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    file.try_lock()
+        .map_err(|e| format!("{} is in use by another process: {}", path.display(), e))?;
+    Ok(file)
+}
+
+struct Log {
+    file: File,
+    /// Length of the file up to the end of the last complete record.
+    len: u64,
+    /// Writes in the file, live or overwritten.
+    records: usize,
+    entries: Entries,
+}
+
+/// A crash-safe store kept in a single append-only file.
+///
+/// Each commit is appended as one line holding a checksum and its writes, and synced before
+/// returning. A crash in the middle of a commit leaves a torn last line, which is dropped the
+/// next time the log is opened, so a commit is either fully there or not at all. The log is
+/// rewritten with only the live values once the overwritten ones outnumber them.
+pub struct LogStore {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+impl LogStore {
+    /// Opens the log at `path`, creating it if needed and replaying its commits.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+
+        let file = open_locked(&path)?;
+        let mut reader = BufReader::new(file.try_clone().map_err(|e| e.to_string())?);
+        let mut entries = Entries::new();
+        let mut len = 0;
+        let mut records = 0;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            let Some(writes) = decode(&line) else {
+                warn!(
+                    "Dropping the torn end of {} after {} bytes",
+                    path.display(),
+                    len
+                );
+                break;
+            };
+            apply(&mut entries, &writes);
+            records += writes.len();
+            len += read as u64;
+        }
+
+        file.set_len(len).map_err(|e| e.to_string())?;
+        debug!(
+            "Opened context log {} with {} records",
+            path.display(),
+            records
+        );
+
+        Ok(Self {
+            path,
+            log: Mutex::new(Log {
+                file,
+                len,
+                records,
+                entries,
+            }),
+        })
+    }
+
+    fn compact_log(&self, log: &mut Log) -> Result<(), String> {
+        let tmp = self.path.with_extension("tmp");
+        let mut live = 0;
+        let mut content = String::new();
+        for (scope, key, value) in flatten(&log.entries) {
+            if value.is_expired() {
+                continue;
+            }
+            content.push_str(&encode(&[StoreWrite::Put { scope, key, value }])?);
+            live += 1;
+        }
+
+        let mut file = File::create(&tmp).map_err(|e| e.to_string())?;
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &self.path).map_err(|e| e.to_string())?;
+        if let Some(parent) = self.path.parent()
+            && let Ok(dir) = File::open(parent)
+        {
+            let _ = dir.sync_all();
+        }
+
+        debug!(
+            "Compacted context log {} from {} to {} records",
+            self.path.display(),
+            log.records,
+            live
+        );
+        log.file = open_locked(&self.path)?;
+        log.len = content.len() as u64;
+        log.records = live;
+        log.entries.values_mut().for_each(|scope_map| {
+            scope_map.retain(|_, v| !v.is_expired());
+        });
+        Ok(())
+    }
+}
+
+impl ContextStore for LogStore {
+    fn get(&self, scope: &ContextScope, key: &str) -> Option<ContextValue> {
+        self.log.lock().entries.get(scope)?.get(key).cloned()
+    }
+
+    fn entries(&self) -> Vec<(ContextScope, String, ContextValue)> {
+        flatten(&self.log.lock().entries)
+    }
+
+    fn commit(&self, writes: &[StoreWrite]) -> Result<(), String> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let line = encode(writes)?;
+        let mut log = self.log.lock();
+
+        if let Err(e) = log
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| log.file.sync_data())
+        {
+            // Cut off what made it to disk, or the next commits would follow a torn line
+            let _ = log.file.set_len(log.len);
+            return Err(format!("Failed to write {}: {}", self.path.display(), e));
+        }
+        log.len += line.len() as u64;
+        log.records += writes.len();
+        apply(&mut log.entries, writes);
+
+        let live: usize = log.entries.values().map(HashMap::len).sum();
+        if log.records > COMPACT_MIN_RECORDS
+            && log.records > live * 2
+            && let Err(e) = self.compact_log(&mut log)
+        {
+            warn!("Failed to compact {}: {}", self.path.display(), e);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with only its live, unexpired values.
+    fn compact(&self) -> Result<(), String> {
+        self.compact_log(&mut self.log.lock())
+    }
+}
+
+/// Moves the values of the old layout, one JSON file per key under a folder per scope,
+/// into `store` in a single commit.
+///
+/// The migrated folders are moved into `migrated/` instead of being deleted. Returns how
+/// many values were moved.
+pub fn migrate_directories(dir: &Path, store: &dyn ContextStore) -> Result<usize, String> {
+    let Ok(scopes) = fs::read_dir(dir) else {
+        return Ok(0);
+    };
+
+    let mut writes = Vec::new();
+    let mut migrated = Vec::new();
+    for entry in scopes.flatten() {
+        let path = entry.path();
+        let Some(scope) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(ContextScope::from_string_key)
+        else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(&path).map_err(|e| e.to_string())?.flatten() {
+            let file = file.path();
+            if file.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(key) = file.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let value = fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|c| serde_json::from_str::<ContextValue>(&c).map_err(|e| e.to_string()));
+            match value {
+                Ok(value) if !value.is_expired() => writes.push(StoreWrite::Put {
+                    scope: scope.clone(),
+                    key: key.to_string(),
+                    value,
+                }),
+                Ok(_) => {}
+                Err(e) => warn!("Not migrating {}: {}", file.display(), e),
+            }
+        }
+        migrated.push(path);
+    }
+
+    if migrated.is_empty() {
+        return Ok(0);
+    }
+    store.commit(&writes)?;
+
+    let target = dir.join(MIGRATED_DIR);
+    fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+    for path in migrated {
+        if let Some(name) = path.file_name()
+            && let Err(e) = fs::rename(&path, target.join(name))
+        {
+            warn!("Failed to move migrated {}: {}", path.display(), e);
+        }
+    }
+    info!(
+        "Migrated {} context values from {} into the context log",
+        writes.len(),
+        dir.display()
+    );
+    Ok(writes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn put(key: &str, value: serde_json::Value) -> StoreWrite {
+        StoreWrite::Put {
+            scope: ContextScope::Global,
+            key: key.to_string(),
+            value: ContextValue::new(value, None),
+        }
+    }
+
+    fn value(store: &dyn ContextStore, key: &str) -> Option<serde_json::Value> {
+        store.get(&ContextScope::Global, key).map(|v| v.value)
+    }
+
+    #[test]
+    fn test_log_replays_commits_and_drops_a_torn_end() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("context.log");
+
+        {
+            let store = LogStore::open(&path).unwrap();
+            store
+                .commit(&[put("a/b", json!(1)), put("..", json!(2))])
+                .unwrap();
+            store
+                .commit(&[StoreWrite::Delete {
+                    scope: ContextScope::Global,
+                    key: "..".into(),
+                }])
+                .unwrap();
+            assert!(LogStore::open(&path).is_err());
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"0000000000000000 [{\"put\":").unwrap();
+        drop(file);
+
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(value(&store, "a/b"), Some(json!(1)));
+        assert_eq!(value(&store, ".."), None);
+
+        store.commit(&[put("c", json!(3))]).unwrap();
+        drop(store);
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(value(&store, "c"), Some(json!(3)));
+    }
+
+    #[test]
+    fn test_compaction_keeps_live_values() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("context.log");
+        let store = LogStore::open(&path).unwrap();
+
+        for i in 0..10 {
+            store.commit(&[put("counter", json!(i))]).unwrap();
+        }
+        store
+            .commit(&[StoreWrite::Put {
+                scope: ContextScope::Global,
+                key: "gone".into(),
+                value: ContextValue::new(json!(true), Some(Duration::ZERO)),
+            }])
+            .unwrap();
+        store.compact().unwrap();
+        drop(store);
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        let store = LogStore::open(&path).unwrap();
+        assert_eq!(value(&store, "counter"), Some(json!(9)));
+        assert_eq!(value(&store, "gone"), None);
+    }
+
+    #[test]
+    fn test_migrates_the_directory_layout() {
+        let dir = tempdir().unwrap();
+        let skill = dir.path().join("skill_timer");
+        fs::create_dir_all(&skill).unwrap();
+        let value = ContextValue::new(json!({"minutes": 5}), None);
+        fs::write(
+            skill.join("last.json"),
+            serde_json::to_string(&value).unwrap(),
+        )
+        .unwrap();
+
+        let store = MemoryStore::new();
+        assert_eq!(migrate_directories(dir.path(), &store).unwrap(), 1);
+        assert_eq!(
+            store
+                .get(&ContextScope::Skill("timer".into()), "last")
+                .map(|v| v.value),
+            Some(json!({"minutes": 5}))
+        );
+        assert!(!skill.exists());
+        assert!(dir.path().join("migrated/skill_timer/last.json").exists());
+        assert_eq!(migrate_directories(dir.path(), &store).unwrap(), 0);
+    }
+}
@@ -1,3 +1,6 @@
 pub mod config;
 pub mod context;
+pub mod context_replication;
+pub mod context_store;
+pub mod scheduler;
 pub mod user;
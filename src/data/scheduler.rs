@@ -0,0 +1,661 @@
+use crate::ctx::runtime;
+use crate::data::user::user_timezone;
+use crate::events::SCHEDULE_FIRED;
+use crate::{emit, publish};
+use chrono::{
+    DateTime, Duration as ChronoDuration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use cron::Schedule;
+use log::{debug, error, info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Longest time the scheduler sleeps before looking at its jobs again.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// When a job fires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// A cron expression evaluated in the user's timezone.
+    Cron { expression: String },
+    /// A fixed number of seconds between runs.
+    Interval { seconds: u64 },
+    /// A single run at an absolute time.
+    At { time: DateTime<Utc> },
+}
+
+impl Trigger {
+    /// Parses the spec of an `every` job: an interval such as `30s`, `5 minutes`
+    /// or `2h`, or a cron expression with 5 to 7 fields.
+    pub fn every(spec: &str) -> Result<Trigger, String> {
+        let spec = spec.trim();
+
+        if let Some(seconds) = parse_interval(spec) {
+            if seconds == 0 {
+                return Err("Interval must be greater than zero".to_string());
+            }
+            return Ok(Trigger::Interval { seconds });
+        }
+
+        let expression = match spec.split_whitespace().count() {
+            5 => format!("0 {}", spec),
+            6 | 7 => spec.to_string(),
+            _ => {
+                return Err(format!(
+                    "'{}' is not an interval or a cron expression",
+                    spec
+                ));
+            }
+        };
+
+        Schedule::from_str(&expression)
+            .map_err(|e| format!("Invalid cron expression '{}': {}", spec, e))?;
+
+        Ok(Trigger::Cron { expression })
+    }
+
+    /// Parses the spec of an `at` job: `HH:MM[:SS]` for the next occurrence of that
+    /// time, `YYYY-MM-DD HH:MM[:SS]` in the given timezone, or an RFC 3339 timestamp.
+    pub fn at(spec: &str, now: DateTime<Utc>, tz: Tz) -> Result<Trigger, String> {
+        let spec = spec.trim();
+
+        if let Ok(time) = DateTime::parse_from_rfc3339(spec) {
+            return Ok(Trigger::At {
+                time: time.with_timezone(&Utc),
+            });
+        }
+
+        for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"] {
+            if let Ok(naive) = NaiveDateTime::parse_from_str(spec, format) {
+                return Ok(Trigger::At {
+                    time: local_to_utc(naive, tz)?,
+                });
+            }
+        }
+
+        for format in ["%H:%M:%S", "%H:%M"] {
+            if let Ok(time) = NaiveTime::parse_from_str(spec, format) {
+                let local_now = now.with_timezone(&tz);
+                let mut date: NaiveDate = local_now.date_naive();
+                if time <= local_now.time() {
+                    date = date.succ_opt().ok_or("Date out of range")?;
+                }
+                return Ok(Trigger::At {
+                    time: local_to_utc(date.and_time(time), tz)?,
+                });
+            }
+        }
+
+        Err(format!("'{}' is not a valid time", spec))
+    }
+
+    /// Computes the first run strictly after `after`, or `None` if the trigger will not fire again.
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron { expression } => Schedule::from_str(expression)
+                .ok()?
+                .after(&after.with_timezone(&tz))
+                .next()
+                .map(|time| time.with_timezone(&Utc)),
+            Trigger::Interval { seconds } => {
+                Some(after + ChronoDuration::seconds(i64::try_from(*seconds).ok()?))
+            }
+            Trigger::At { time } => (*time > after).then_some(*time),
+        }
+    }
+
+    fn is_recurring(&self) -> bool {
+        !matches!(self, Trigger::At { .. })
+    }
+}
+
+fn parse_interval(spec: &str) -> Option<u64> {
+    let split = spec.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = spec.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 60 * 60 * 24,
+        _ => return None,
+    };
+
+    amount.checked_mul(multiplier)
+}
+
+fn local_to_utc(naive: NaiveDateTime, tz: Tz) -> Result<DateTime<Utc>, String> {
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or(format!("{} does not exist in {}", naive, tz))
+}
+
+/// What a job does when it fires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobTarget {
+    /// Calls a named function of the owning skill.
+    Function { function: String },
+    /// Runs an `every` or `at` block of the owning skill, by registration order.
+    Handler { index: usize },
+    /// Publishes a message to a mesh topic.
+    Topic {
+        topic: String,
+        data: serde_json::Value,
+    },
+}
+
+/// A scheduled job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    /// The skill that created the job.
+    pub owner: String,
+    pub trigger: Trigger,
+    pub target: JobTarget,
+    pub next_run: Option<DateTime<Utc>>,
+    /// Whether the job is written to disk and survives restarts.
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// Runs jobs on cron expressions, intervals or absolute times.
+///
+/// Persistent jobs are stored in `jobs.json` under the scheduler directory so they
+/// survive restarts. Jobs that call skill code are dispatched through the
+/// [`SCHEDULE_FIRED`] event, topic jobs are published directly.
+pub struct Scheduler {
+    jobs: RwLock<HashMap<String, Job>>,
+    /// Skills that aren't loaded, whose jobs wait until they load again.
+    suspended: RwLock<HashSet<String>>,
+    persistence_path: PathBuf,
+    wake: Notify,
+}
+
+impl Scheduler {
+    pub fn new<P: AsRef<Path>>(persistence_path: P) -> Self {
+        let path = persistence_path.as_ref().to_path_buf();
+        if !path.exists()
+            && let Err(e) = fs::create_dir_all(&path)
+        {
+            error!(
+                "Failed to create scheduler directory {}: {}",
+                path.display(),
+                e
+            );
+        }
+
+        let jobs = Self::load_jobs(&path.join("jobs.json"));
+        info!("Created Scheduler with {} persisted jobs.", jobs.len());
+
+        Self {
+            jobs: RwLock::new(jobs),
+            suspended: RwLock::new(HashSet::new()),
+            persistence_path: path,
+            wake: Notify::new(),
+        }
+    }
+
+    fn load_jobs(file: &Path) -> HashMap<String, Job> {
+        let Ok(content) = fs::read_to_string(file) else {
+            return HashMap::new();
+        };
+
+        match serde_json::from_str::<Vec<Job>>(&content) {
+            Ok(jobs) => jobs.into_iter().map(|job| (job.id.clone(), job)).collect(),
+            Err(e) => {
+                error!(
+                    "Failed to parse scheduled jobs from {}: {}",
+                    file.display(),
+                    e
+                );
+                HashMap::new()
+            }
+        }
+    }
+
+    fn save(&self) {
+        let jobs: Vec<Job> = self
+            .jobs
+            .read()
+            .values()
+            .filter(|job| job.persistent)
+            .cloned()
+            .collect();
+
+        let file = self.persistence_path.join("jobs.json");
+        let tmp = self.persistence_path.join("jobs.json.tmp");
+
+        let result = serde_json::to_string_pretty(&jobs)
+            .map_err(|e| e.to_string())
+            .and_then(|content| fs::write(&tmp, content).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, &file).map_err(|e| e.to_string()));
+
+        if let Err(e) = result {
+            error!("Failed to save scheduled jobs: {}", e);
+        }
+    }
+
+    /// Schedules a new job and returns its id.
+    ///
+    /// Skills schedule their persistent jobs every time they load, so adding a job
+    /// identical to an existing one returns the id of the existing job instead.
+    pub fn add(
+        &self,
+        owner: &str,
+        trigger: Trigger,
+        target: JobTarget,
+        persistent: bool,
+    ) -> Result<String, String> {
+        // Checked and inserted under one lock, so two loads can't both add the job
+        let id = {
+            let mut jobs = self.jobs.write();
+            if let Some(job) = jobs
+                .values()
+                .find(|job| job.owner == owner && job.trigger == trigger && job.target == target)
+            {
+                return Ok(job.id.clone());
+            }
+
+            let next_run = trigger
+                .next_after(Utc::now(), user_timezone())
+                .ok_or("The job would never run")?;
+
+            let id = uuid::Uuid::new_v4().to_string();
+            debug!("Scheduling job {} for {} at {}", id, owner, next_run);
+
+            jobs.insert(
+                id.clone(),
+                Job {
+                    id: id.clone(),
+                    owner: owner.to_string(),
+                    trigger,
+                    target,
+                    next_run: Some(next_run),
+                    persistent,
+                },
+            );
+            id
+        };
+
+        if persistent {
+            self.save();
+        }
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    /// Cancels a job owned by `owner`, returning whether it existed.
+    pub fn cancel(&self, owner: &str, id: &str) -> bool {
+        let removed = {
+            let mut jobs = self.jobs.write();
+            match jobs.get(id) {
+                Some(job) if job.owner == owner => jobs.remove(id),
+                _ => None,
+            }
+        };
+
+        match removed {
+            Some(job) => {
+                if job.persistent {
+                    self.save();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lists the jobs owned by a skill.
+    pub fn jobs_of(&self, owner: &str) -> Vec<Job> {
+        self.jobs
+            .read()
+            .values()
+            .filter(|job| job.owner == owner)
+            .cloned()
+            .collect()
+    }
+
+    /// The skills that own at least one job.
+    pub fn owners(&self) -> HashSet<String> {
+        self.jobs
+            .read()
+            .values()
+            .map(|job| job.owner.clone())
+            .collect()
+    }
+
+    /// Holds the jobs of a skill that failed to load, keeping them for when it loads again.
+    pub fn suspend(&self, owner: &str) {
+        self.suspended.write().insert(owner.to_string());
+    }
+
+    /// Lets the jobs of a skill run again. The ones that came due while it was suspended
+    /// run once right away.
+    pub fn resume(&self, owner: &str) {
+        if self.suspended.write().remove(owner) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Drops every job of a skill, persistent ones included.
+    pub fn remove_owner(&self, owner: &str) {
+        let removed: Vec<Job> = {
+            let mut jobs = self.jobs.write();
+            let ids: Vec<String> = jobs
+                .values()
+                .filter(|job| job.owner == owner)
+                .map(|job| job.id.clone())
+                .collect();
+            ids.iter().filter_map(|id| jobs.remove(id)).collect()
+        };
+
+        if removed.iter().any(|job| job.persistent) {
+            self.save();
+        }
+    }
+
+    /// Replaces the `every`/`at` block jobs of a skill with the ones it declared on its last load.
+    pub fn replace_handlers(&self, owner: &str, triggers: Vec<Trigger>) {
+        self.jobs.write().retain(|_, job| {
+            !(job.owner == owner && matches!(job.target, JobTarget::Handler { .. }))
+        });
+
+        for (index, trigger) in triggers.into_iter().enumerate() {
+            if let Err(e) = self.add(owner, trigger, JobTarget::Handler { index }, false) {
+                warn!(
+                    "Skipping schedule block {} of skill {}: {}",
+                    index, owner, e
+                );
+            }
+        }
+    }
+
+    /// Takes every job due at `now`, rescheduling recurring jobs and dropping finished ones.
+    fn take_due(&self, now: DateTime<Utc>, tz: Tz) -> Vec<Job> {
+        let mut due = Vec::new();
+        let mut changed = false;
+
+        let suspended = self.suspended.read().clone();
+        self.jobs.write().retain(|_, job| {
+            if job.next_run.is_none_or(|next| next > now) || suspended.contains(&job.owner) {
+                return true;
+            }
+
+            due.push(job.clone());
+            changed |= job.persistent;
+
+            if !job.trigger.is_recurring() {
+                return false;
+            }
+            job.next_run = job.trigger.next_after(now, tz);
+            job.next_run.is_some()
+        });
+
+        if changed {
+            self.save();
+        }
+        due
+    }
+
+    /// Time until the next job is due, capped at [`MAX_IDLE`].
+    fn idle_time(&self, now: DateTime<Utc>) -> Duration {
+        let suspended = self.suspended.read();
+        self.jobs
+            .read()
+            .values()
+            .filter(|job| !suspended.contains(&job.owner))
+            .filter_map(|job| job.next_run)
+            .min()
+            .map(|next| (next - now).to_std().unwrap_or(Duration::ZERO))
+            .unwrap_or(MAX_IDLE)
+            .min(MAX_IDLE)
+    }
+
+    async fn fire(job: Job) {
+        info!("Running scheduled job {} of {}", job.id, job.owner);
+
+        match job.target {
+            JobTarget::Function { function } => emit!(
+                SCHEDULE_FIRED,
+                json!({ "job": job.id, "skill": job.owner, "function": function })
+            ),
+            JobTarget::Handler { index } => emit!(
+                SCHEDULE_FIRED,
+                json!({ "job": job.id, "skill": job.owner, "handler": index })
+            ),
+            JobTarget::Topic { topic, data } => {
+                let payload = match data {
+                    serde_json::Value::String(text) => text.into_bytes(),
+                    serde_json::Value::Null => Vec::new(),
+                    other => other.to_string().into_bytes(),
+                };
+                let _ = publish!(&topic, payload);
+            }
+        }
+    }
+}
+
+pub fn scheduler_task() {
+    info!("Started scheduler.");
+    tokio::spawn(async move {
+        loop {
+            let c = match runtime() {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Stopping scheduler: {}", e);
+                    return;
+                }
+            };
+
+            for job in c.scheduler.take_due(Utc::now(), user_timezone()) {
+                Scheduler::fire(job).await;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(c.scheduler.idle_time(Utc::now())) => {}
+                _ = c.scheduler.wake.notified() => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_every_parses_intervals_and_cron() {
+        assert_eq!(
+            Trigger::every("30s").unwrap(),
+            Trigger::Interval { seconds: 30 }
+        );
+        assert_eq!(
+            Trigger::every("5 minutes").unwrap(),
+            Trigger::Interval { seconds: 300 }
+        );
+        assert_eq!(
+            Trigger::every("0 7 * * Mon-Fri").unwrap(),
+            Trigger::Cron {
+                expression: "0 0 7 * * Mon-Fri".to_string()
+            }
+        );
+        assert!(Trigger::every("0s").is_err());
+        assert!(Trigger::every("whenever").is_err());
+    }
+
+    #[test]
+    fn test_cron_is_evaluated_in_timezone() {
+        let trigger = Trigger::every("0 7 * * *").unwrap();
+        let tz: Tz = "America/Sao_Paulo".parse().unwrap();
+
+        let next = trigger.next_after(utc("2026-03-10T12:00:00Z"), tz).unwrap();
+        assert_eq!(next, utc("2026-03-11T10:00:00Z"));
+    }
+
+    #[test]
+    fn test_at_picks_next_occurrence() {
+        let tz: Tz = "Europe/Lisbon".parse().unwrap();
+        let now = utc("2026-01-15T20:00:00Z");
+
+        assert_eq!(
+            Trigger::at("21:30", now, tz).unwrap(),
+            Trigger::At {
+                time: utc("2026-01-15T21:30:00Z")
+            }
+        );
+        assert_eq!(
+            Trigger::at("07:00", now, tz).unwrap(),
+            Trigger::At {
+                time: utc("2026-01-16T07:00:00Z")
+            }
+        );
+        assert_eq!(
+            Trigger::at("2026-07-01 09:00", now, tz).unwrap(),
+            Trigger::At {
+                time: utc("2026-07-01T08:00:00Z")
+            }
+        );
+        assert!(Trigger::at("tomorrow", now, tz).is_err());
+    }
+
+    #[test]
+    fn test_due_jobs_are_rescheduled_or_dropped() {
+        let dir = tempdir().unwrap();
+        let scheduler = Scheduler::new(dir.path());
+        let now = Utc::now();
+
+        scheduler
+            .add(
+                "skill",
+                Trigger::Interval { seconds: 60 },
+                JobTarget::Function {
+                    function: "poll".to_string(),
+                },
+                true,
+            )
+            .unwrap();
+        scheduler
+            .add(
+                "skill",
+                Trigger::At {
+                    time: now + ChronoDuration::seconds(30),
+                },
+                JobTarget::Function {
+                    function: "remind".to_string(),
+                },
+                true,
+            )
+            .unwrap();
+
+        let duplicate = scheduler.add(
+            "skill",
+            Trigger::Interval { seconds: 60 },
+            JobTarget::Function {
+                function: "poll".to_string(),
+            },
+            true,
+        );
+        assert!(duplicate.is_ok());
+        assert_eq!(scheduler.jobs_of("skill").len(), 2);
+
+        assert!(scheduler.take_due(now, Tz::UTC).is_empty());
+
+        let later = now + ChronoDuration::seconds(90);
+        assert_eq!(scheduler.take_due(later, Tz::UTC).len(), 2);
+
+        let remaining = scheduler.jobs_of("skill");
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(
+            remaining[0].next_run,
+            Some(later + ChronoDuration::seconds(60))
+        );
+    }
+
+    #[test]
+    fn test_persistent_jobs_survive_restart() {
+        let dir = tempdir().unwrap();
+        let id = {
+            let scheduler = Scheduler::new(dir.path());
+            scheduler.replace_handlers("skill", vec![Trigger::Interval { seconds: 5 }]);
+            scheduler
+                .add(
+                    "skill",
+                    Trigger::Interval { seconds: 60 },
+                    JobTarget::Topic {
+                        topic: "lights/off".to_string(),
+                        data: serde_json::Value::Null,
+                    },
+                    true,
+                )
+                .unwrap()
+        };
+
+        let restored = Scheduler::new(dir.path());
+        let jobs = restored.jobs_of("skill");
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, id);
+        assert!(!restored.cancel("other", &id));
+        assert!(restored.cancel("skill", &id));
+        assert!(Scheduler::new(dir.path()).jobs_of("skill").is_empty());
+    }
+
+    #[test]
+    fn test_remove_owner_drops_persistent_jobs() {
+        let dir = tempdir().unwrap();
+        let scheduler = Scheduler::new(dir.path());
+        scheduler.replace_handlers("skill", vec![Trigger::Interval { seconds: 5 }]);
+        let target = JobTarget::Function {
+            function: "tick".to_string(),
+        };
+        let trigger = Trigger::Interval { seconds: 60 };
+        scheduler
+            .add("skill", trigger.clone(), target.clone(), true)
+            .unwrap();
+        scheduler.add("other", trigger, target, true).unwrap();
+
+        scheduler.remove_owner("skill");
+        assert!(scheduler.jobs_of("skill").is_empty());
+
+        let restored = Scheduler::new(dir.path());
+        assert!(restored.jobs_of("skill").is_empty());
+        assert_eq!(restored.jobs_of("other").len(), 1);
+    }
+
+    #[test]
+    fn test_suspended_jobs_wait_for_their_skill() {
+        let dir = tempdir().unwrap();
+        let scheduler = Scheduler::new(dir.path());
+        let target = JobTarget::Function {
+            function: "tick".to_string(),
+        };
+        scheduler
+            .add("broken", Trigger::Interval { seconds: 60 }, target, true)
+            .unwrap();
+        scheduler.suspend("broken");
+
+        let later = Utc::now() + ChronoDuration::seconds(120);
+        assert!(scheduler.take_due(later, Tz::UTC).is_empty());
+        assert_eq!(scheduler.idle_time(later), MAX_IDLE);
+        assert_eq!(scheduler.owners(), HashSet::from(["broken".to_string()]));
+
+        scheduler.resume("broken");
+        assert_eq!(scheduler.take_due(later, Tz::UTC).len(), 1);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::ctx::runtime;
-use crate::{get_ctx, remove_ctx, set_ctx};
-use log::{debug, info, trace};
+use crate::events::USER_UPDATED;
+use crate::{emit, get_ctx, remove_ctx, set_ctx};
+use chrono_tz::Tz;
+use log::{debug, info, trace, warn};
 use rhai::Dynamic;
 use rhai::EvalAltResult;
 use rhai::Position;
@@ -56,6 +58,29 @@ pub enum ResponseLength {
     Detailed,
 }
 
+impl CommunicationStyle {
+    /// The name of the style in locale keys, e.g. `hello.formal`.
+    pub fn key(&self) -> &'static str {
+        match self {
+            CommunicationStyle::Formal => "formal",
+            CommunicationStyle::Casual => "casual",
+            CommunicationStyle::Friendly => "friendly",
+            CommunicationStyle::Professional => "professional",
+        }
+    }
+}
+
+impl ResponseLength {
+    /// The name of the length in locale keys, e.g. `hello.concise`.
+    pub fn key(&self) -> &'static str {
+        match self {
+            ResponseLength::Concise => "concise",
+            ResponseLength::Balanced => "balanced",
+            ResponseLength::Detailed => "detailed",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, CustomType)]
 pub struct NotificationPreferences {
     pub quiet_hours: Option<QuietHours>,
@@ -166,6 +191,8 @@ impl UserManager {
     }
 
     pub fn save(&self) {
+        emit!(USER_UPDATED, json!({ "id": self.get_id() }));
+
         let self_clone = self.clone();
         std::thread::spawn(move || {
             tokio::runtime::Runtime::new().unwrap().block_on(async {
@@ -192,6 +219,7 @@ impl UserManager {
             *self.user.write() = user;
             self.save_to_memory();
             self.save_to_persistent();
+            emit!(USER_UPDATED, json!({ "id": self.get_id() }));
             return;
         }
         debug!("No user data found on device context");
@@ -500,6 +528,19 @@ pub async fn get_user_from_mesh() -> Option<User> {
     }
 }
 
+/// The user's timezone, falling back to UTC when it is unknown.
+pub fn user_timezone() -> Tz {
+    let Ok(c) = runtime() else {
+        return Tz::UTC;
+    };
+
+    let name = c.user.get_timezone();
+    name.parse::<Tz>().unwrap_or_else(|_| {
+        warn!("Unknown user timezone '{}', using UTC", name);
+        Tz::UTC
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
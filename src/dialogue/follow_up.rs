@@ -0,0 +1,223 @@
+use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::data::context::ContextScope;
+use crate::dialogue::intent::{Intent, Slot};
+use crate::dialogue::intent_file::IntentDefinition;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Context key prefix of the last turn of each device.
+pub const TURN_CTX_PREFIX: &str = "dialogue.turn";
+
+/// The last intent a device ran, kept for a short while so the next utterance can
+/// leave out what it already said, e.g. "and tomorrow?" after asking for the weather.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Turn {
+    pub skill: String,
+    pub intent: String,
+    pub slots: Vec<Slot>,
+}
+
+impl Turn {
+    fn key(device: &str) -> String {
+        format!("{}.{}", TURN_CTX_PREFIX, device)
+    }
+
+    /// Stores `intent` as the last turn of `device`.
+    pub fn remember(device: &str, skill: &str, intent: &Intent) {
+        let Some(info) = &intent.intent else {
+            return;
+        };
+        let turn = Turn {
+            skill: skill.to_string(),
+            intent: info.0.intent_name.clone(),
+            slots: intent.slots.clone(),
+        };
+        let value = match serde_json::to_value(turn) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to store the last turn of {}: {}", device, e);
+                return;
+            }
+        };
+        let ttl = Duration::from_secs(setting_or::<u64>("follow_up_ttl", 30));
+
+        if let Ok(c) = runtime() {
+            c.context.set(
+                ContextScope::Global,
+                Self::key(device),
+                value,
+                Some(ttl),
+                false,
+            );
+        }
+    }
+
+    /// The last turn of `device`, if it hasn't expired.
+    pub fn last(device: &str) -> Option<Turn> {
+        let value = runtime()
+            .ok()?
+            .context
+            .get(&ContextScope::Global, &Self::key(device))?;
+        serde_json::from_value(value).ok()
+    }
+
+    /// Adds the slots of this turn that `intent` declares but is missing.
+    ///
+    /// Only done when `intent` belongs to the same skill or is a follow-up intent,
+    /// returns whether any slot was added.
+    pub fn merge_into(&self, intent: &mut Intent, follow_ups: &FollowUps) -> bool {
+        let Some(name) = intent.intent.as_ref().map(|i| i.0.intent_name.clone()) else {
+            return false;
+        };
+        let skill = name.split('@').next().unwrap_or_default();
+        if skill != self.skill && !follow_ups.contains(&name) {
+            return false;
+        }
+
+        let Some(declared) = follow_ups.slots.get(&name) else {
+            return false;
+        };
+
+        let mut merged = false;
+        for slot in &self.slots {
+            if !declared.contains(&slot.0.slot_name)
+                || intent
+                    .slots
+                    .iter()
+                    .any(|s| s.0.slot_name == slot.0.slot_name)
+            {
+                continue;
+            }
+            debug!(
+                "Carrying slot {} over from {} to {}",
+                slot.0.slot_name, self.intent, name
+            );
+            intent.slots.push(slot.clone());
+            merged = true;
+        }
+        merged
+    }
+}
+
+/// What turns may carry over: the intents marked with `follow_up: true`, which may
+/// continue a turn of any skill, and the slots each intent declares.
+#[derive(Debug, Default)]
+pub struct FollowUps {
+    follow_ups: HashSet<String>,
+    slots: HashMap<String, HashSet<String>>,
+}
+
+impl FollowUps {
+    /// Reads the intent files in the `intent/intents` folder of every skill.
+    pub fn load(skills: &[PathBuf]) -> Self {
+        Self::new(
+            skills
+                .iter()
+                .flat_map(|skill| IntentDefinition::load(&skill.join("intent/intents")))
+                .collect(),
+        )
+    }
+
+    fn new(definitions: Vec<IntentDefinition>) -> Self {
+        let mut follow_ups = Self::default();
+        for definition in definitions {
+            if definition.follow_up {
+                follow_ups.follow_ups.insert(definition.name.clone());
+            }
+            follow_ups
+                .slots
+                .insert(definition.name.clone(), definition.slot_names());
+        }
+        follow_ups
+    }
+
+    pub fn contains(&self, intent: &str) -> bool {
+        self.follow_ups.contains(intent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn intent(name: &str, slots: &[(&str, &str)]) -> Intent {
+        let slots: Vec<_> = slots
+            .iter()
+            .map(|(slot, value)| {
+                json!({
+                    "range": { "start": 0, "end": value.len() },
+                    "rawValue": value,
+                    "value": { "kind": "Custom", "value": value },
+                    "entity": slot,
+                    "slotName": slot,
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "input": "",
+            "intent": { "intentName": name, "probability": 1.0 },
+            "slots": slots,
+        }))
+        .unwrap()
+    }
+
+    fn slot_names(intent: &Intent) -> Vec<String> {
+        intent.slots.iter().map(|s| s.0.slot_name.clone()).collect()
+    }
+
+    fn definitions() -> Vec<IntentDefinition> {
+        serde_yaml::from_str(
+            r#"
+- name: weather@forecast
+  slots:
+    - { name: city, entity: city }
+    - { name: day, entity: snips/datetime }
+- name: music@play
+  follow_up: true
+  slots:
+    - { name: day, entity: snips/datetime }
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_merges_missing_slots_of_the_same_skill() {
+        let follow_ups = FollowUps::new(definitions());
+        let previous = intent("weather@forecast", &[("city", "Lisbon"), ("day", "today")]);
+        let turn = Turn {
+            skill: "weather".into(),
+            intent: "weather@forecast".into(),
+            slots: previous.slots,
+        };
+
+        let mut next = intent("weather@forecast", &[("day", "tomorrow")]);
+        assert!(turn.merge_into(&mut next, &follow_ups));
+        assert_eq!(slot_names(&next), ["day", "city"]);
+        assert_eq!(next.slots[0].0.raw_value, "tomorrow");
+
+        let mut other = intent("music@play", &[]);
+        assert!(!turn.merge_into(&mut other, &FollowUps::default()));
+        assert!(turn.merge_into(&mut other, &follow_ups));
+    }
+
+    #[test]
+    fn test_merges_only_the_declared_slots() {
+        let follow_ups = FollowUps::new(definitions());
+        let previous = intent("weather@forecast", &[("city", "Lisbon"), ("day", "today")]);
+        let turn = Turn {
+            skill: "weather".into(),
+            intent: "weather@forecast".into(),
+            slots: previous.slots,
+        };
+
+        let mut other = intent("music@play", &[]);
+        assert!(turn.merge_into(&mut other, &follow_ups));
+        assert_eq!(slot_names(&other), ["day"]);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::utils::get_all_docs_on_folder;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The `validator` of a slot in an intent file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidatorKind {
+    #[default]
+    Any,
+    Bool,
+    Optional,
+    /// One of the slot's `values`, or none.
+    List,
+}
+
+/// A slot declared in the `slots` list of an intent file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlotDefinition {
+    pub name: String,
+    pub entity: String,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub validator: ValidatorKind,
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+/// A `.intent` file, shared by the local matcher, slot filling and follow-ups.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntentDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub slots: Vec<SlotDefinition>,
+    #[serde(default)]
+    pub utterances: Vec<String>,
+    /// Whether the intent may continue a turn of any skill.
+    #[serde(default)]
+    pub follow_up: bool,
+}
+
+impl IntentDefinition {
+    /// Reads the intent files in `path`, usually the `intent/intents` folder of a skill.
+    pub fn load(path: &Path) -> Vec<Self> {
+        get_all_docs_on_folder::<Self>(path.to_path_buf(), None, "intent".into())
+    }
+
+    /// The names of the slots the intent declares.
+    pub fn slot_names(&self) -> HashSet<String> {
+        self.slots.iter().map(|s| s.name.clone()).collect()
+    }
+}
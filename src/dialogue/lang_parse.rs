@@ -1,3 +1,15 @@
+//! Extracts numbers, durations and datetimes from English and Portuguese text.
+//!
+//! ```text
+//! "twenty five", "vinte e cinco", "3,5", "one and a half"   numbers
+//! "in an hour and a half", "uma hora e meia"                durations, in seconds
+//! "next friday at 3pm", "amanhã às 15h30"                   datetimes, relative to now
+//! ```
+//!
+//! Languages other than Portuguese are parsed as English.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeDelta, TimeZone};
+use chrono_tz::Tz;
 use rhai::CustomType;
 use rhai::Dynamic;
 use rhai::EvalAltResult;
@@ -6,27 +18,1110 @@ use rhai::TypeBuilder;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize, CustomType, Clone)]
-#[allow(dead_code)]
-pub struct ExtractNumbers(pub Vec<f64>);
+pub struct ExtractNumbers(
+    #[rhai_type(name = "values", get = ExtractNumbers::array, readonly)] pub Vec<f64>,
+);
+
+impl ExtractNumbers {
+    fn array(&self) -> rhai::Array {
+        self.0.iter().map(|n| Dynamic::from_float(*n)).collect()
+    }
+}
 
 #[derive(Debug, Deserialize, CustomType, Clone)]
-#[allow(dead_code)]
-pub struct ExtractNumber(pub Option<f64>);
+pub struct ExtractNumber(#[rhai_type(name = "value")] pub Option<f64>);
 
+/// A duration in seconds and the text around it.
 #[derive(Debug, Deserialize, CustomType, Clone)]
-#[allow(dead_code)]
 pub struct ExtractDuration {
     pub duration: Option<f64>,
     pub leftover: String,
 }
 
+/// An RFC 3339 datetime and the text around it.
 #[derive(Debug, Deserialize, CustomType, Clone)]
-#[allow(dead_code)]
 pub struct ExtractDatetime {
     pub datetime: Option<String>,
     pub leftover: String,
 }
 
 #[derive(Debug, Deserialize, CustomType, Clone)]
-#[allow(dead_code)]
-pub struct IsFractional(pub Option<f64>);
+pub struct IsFractional(#[rhai_type(name = "value")] pub Option<f64>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lang {
+    En,
+    Pt,
+}
+
+impl Lang {
+    fn of(code: &str) -> Self {
+        if code.to_lowercase().starts_with("pt") {
+            Lang::Pt
+        } else {
+            Lang::En
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Token {
+    raw: String,
+    /// Lowercased and without accents.
+    word: String,
+}
+
+fn unaccent(c: char) -> char {
+    match c {
+        'á' | 'à' | 'â' | 'ã' => 'a',
+        'é' | 'ê' => 'e',
+        'í' => 'i',
+        'ó' | 'ô' | 'õ' => 'o',
+        'ú' | 'ü' => 'u',
+        'ç' => 'c',
+        c => c,
+    }
+}
+
+/// Splits on whitespace and on the hyphens of words like "twenty-five" or "sexta-feira".
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for piece in text.split_whitespace() {
+        let piece = piece
+            .trim_matches(|c| matches!(c, ',' | ';' | '!' | '?' | '"' | '(' | ')'))
+            .trim_end_matches('.');
+        let parts: Vec<&str> = if piece.contains('-') && !piece.chars().any(|c| c.is_ascii_digit())
+        {
+            piece.split('-').filter(|p| !p.is_empty()).collect()
+        } else {
+            vec![piece]
+        };
+        for part in parts.into_iter().filter(|p| !p.is_empty()) {
+            tokens.push(Token {
+                raw: part.to_string(),
+                word: part.to_lowercase().chars().map(unaccent).collect(),
+            });
+        }
+    }
+    tokens
+}
+
+fn leftover(tokens: &[Token], consumed: &[bool]) -> String {
+    tokens
+        .iter()
+        .zip(consumed)
+        .filter(|(_, consumed)| !**consumed)
+        .map(|(t, _)| t.raw.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Units, teens, tens and, in Portuguese, the hundreds.
+fn small_number(word: &str, lang: Lang) -> Option<f64> {
+    let n = match lang {
+        Lang::En => match word {
+            "zero" => 0,
+            "one" => 1,
+            "two" => 2,
+            "three" => 3,
+            "four" => 4,
+            "five" => 5,
+            "six" => 6,
+            "seven" => 7,
+            "eight" => 8,
+            "nine" => 9,
+            "ten" => 10,
+            "eleven" => 11,
+            "twelve" => 12,
+            "thirteen" => 13,
+            "fourteen" => 14,
+            "fifteen" => 15,
+            "sixteen" => 16,
+            "seventeen" => 17,
+            "eighteen" => 18,
+            "nineteen" => 19,
+            "twenty" => 20,
+            "thirty" => 30,
+            "forty" => 40,
+            "fifty" => 50,
+            "sixty" => 60,
+            "seventy" => 70,
+            "eighty" => 80,
+            "ninety" => 90,
+            _ => return None,
+        },
+        Lang::Pt => match word {
+            "zero" => 0,
+            "um" | "uma" => 1,
+            "dois" | "duas" => 2,
+            "tres" => 3,
+            "quatro" => 4,
+            "cinco" => 5,
+            "seis" => 6,
+            "sete" => 7,
+            "oito" => 8,
+            "nove" => 9,
+            "dez" => 10,
+            "onze" => 11,
+            "doze" => 12,
+            "treze" => 13,
+            "catorze" | "quatorze" => 14,
+            "quinze" => 15,
+            "dezasseis" | "dezesseis" => 16,
+            "dezassete" | "dezessete" => 17,
+            "dezoito" => 18,
+            "dezanove" | "dezenove" => 19,
+            "vinte" => 20,
+            "trinta" => 30,
+            "quarenta" => 40,
+            "cinquenta" => 50,
+            "sessenta" => 60,
+            "setenta" => 70,
+            "oitenta" => 80,
+            "noventa" => 90,
+            "cem" | "cento" => 100,
+            "duzentos" | "duzentas" => 200,
+            "trezentos" | "trezentas" => 300,
+            "quatrocentos" | "quatrocentas" => 400,
+            "quinhentos" | "quinhentas" => 500,
+            "seiscentos" | "seiscentas" => 600,
+            "setecentos" | "setecentas" => 700,
+            "oitocentos" | "oitocentas" => 800,
+            "novecentos" | "novecentas" => 900,
+            _ => return None,
+        },
+    };
+    Some(n as f64)
+}
+
+fn is_hundred(word: &str, lang: Lang) -> bool {
+    lang == Lang::En && matches!(word, "hundred" | "hundreds")
+}
+
+fn scale(word: &str, lang: Lang) -> Option<f64> {
+    match (lang, word) {
+        (Lang::En, "thousand" | "thousands") | (Lang::Pt, "mil") => Some(1e3),
+        (Lang::En, "million" | "millions") | (Lang::Pt, "milhao" | "milhoes") => Some(1e6),
+        (Lang::En, "billion" | "billions") | (Lang::Pt, "bilhao" | "bilhoes") => Some(1e9),
+        _ => None,
+    }
+}
+
+fn is_joiner(word: &str, lang: Lang) -> bool {
+    match lang {
+        Lang::En => word == "and",
+        Lang::Pt => word == "e",
+    }
+}
+
+fn is_point(word: &str, lang: Lang) -> bool {
+    match lang {
+        Lang::En => word == "point",
+        Lang::Pt => word == "virgula",
+    }
+}
+
+fn is_negative(word: &str, lang: Lang) -> bool {
+    match lang {
+        Lang::En => matches!(word, "minus" | "negative"),
+        Lang::Pt => word == "menos",
+    }
+}
+
+/// The denominator named by a fraction word, e.g. 3 for "third" or "terços".
+fn denominator(word: &str, lang: Lang) -> Option<f64> {
+    let n = match lang {
+        Lang::En => match word {
+            "half" | "halves" => 2,
+            "third" | "thirds" => 3,
+            "quarter" | "quarters" | "fourth" | "fourths" => 4,
+            "fifth" | "fifths" => 5,
+            "sixth" | "sixths" => 6,
+            "seventh" | "sevenths" => 7,
+            "eighth" | "eighths" => 8,
+            "ninth" | "ninths" => 9,
+            "tenth" | "tenths" => 10,
+            _ => return None,
+        },
+        Lang::Pt => match word {
+            "meio" | "meia" | "meios" | "meias" => 2,
+            "terco" | "tercos" => 3,
+            "quarto" | "quartos" => 4,
+            "quinto" | "quintos" => 5,
+            "sexto" | "sextos" => 6,
+            "setimo" | "setimos" => 7,
+            "oitavo" | "oitavos" => 8,
+            "nono" | "nonos" => 9,
+            "decimo" | "decimos" => 10,
+            _ => return None,
+        },
+    };
+    Some(n as f64)
+}
+
+/// A number written with digits, "1,000.5" in English and "1.000,5" in Portuguese.
+fn digits(word: &str, lang: Lang) -> Option<f64> {
+    if !word.chars().any(|c| c.is_ascii_digit())
+        || !word
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | ',' | '-'))
+    {
+        return None;
+    }
+    let (group, decimal) = match lang {
+        Lang::En => (',', '.'),
+        Lang::Pt => ('.', ','),
+    };
+    // A lone Portuguese "1.5" is still a decimal, "1.500" is a thousand separator.
+    let grouped = word
+        .split(group)
+        .skip(1)
+        .all(|g| g.len() == 3 && g.chars().all(|c| c.is_ascii_digit()));
+    let normalized: String = if word.contains(group) && (grouped || word.contains(decimal)) {
+        word.chars().filter(|c| *c != group).collect()
+    } else {
+        word.to_string()
+    };
+    normalized.replace([decimal, ','], ".").parse().ok()
+}
+
+/// Whether `value` can be added to the number read so far, "twenty" + "five" but not
+/// "five" + "twenty".
+fn can_add(current: f64, value: f64) -> bool {
+    let group = current % 1000.0;
+    let rest = current % 100.0;
+    if value >= 100.0 {
+        group == 0.0
+    } else if value >= 20.0 {
+        rest == 0.0
+    } else {
+        rest == 0.0 || (rest >= 20.0 && rest % 10.0 == 0.0 && value > 0.0 && value < 10.0)
+    }
+}
+
+/// The end of "and a half" or "e meia" at `i`.
+fn and_a_half(tokens: &[Token], i: usize, lang: Lang) -> Option<usize> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    if !is_joiner(word(i)?, lang) {
+        return None;
+    }
+    match lang {
+        Lang::En => (matches!(word(i + 1), Some("a" | "one")) && word(i + 2) == Some("half"))
+            .then_some(i + 3),
+        Lang::Pt => matches!(word(i + 1), Some("meio" | "meia")).then_some(i + 2),
+    }
+}
+
+/// The number starting at `start` and the index after it.
+fn number_at(tokens: &[Token], start: usize, lang: Lang) -> Option<(f64, usize)> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+
+    if is_negative(word(start)?, lang) {
+        return number_at(tokens, start + 1, lang).map(|(n, end)| (-n, end));
+    }
+
+    let mut total = 0.0;
+    let mut current = 0.0;
+    let mut any = false;
+    let mut digit = false;
+    let mut i = start;
+
+    while let Some(w) = word(i) {
+        if let Some(n) = digits(w, lang) {
+            if any {
+                break;
+            }
+            current = n;
+            any = true;
+            digit = true;
+        } else if let Some(n) = small_number(w, lang) {
+            if digit || !can_add(current, n) {
+                break;
+            }
+            current += n;
+            any = true;
+        } else if is_hundred(w, lang) {
+            if current % 1000.0 >= 100.0 {
+                break;
+            }
+            let rest = current % 100.0;
+            current += rest.max(1.0) * 100.0 - rest;
+            any = true;
+            digit = false;
+        } else if let Some(m) = scale(w, lang) {
+            if any && current == 0.0 {
+                break;
+            }
+            total += current.max(1.0) * m;
+            current = 0.0;
+            any = true;
+            digit = false;
+        } else if !any && lang == Lang::En && matches!(w, "a" | "an") {
+            // "a hundred", "a thousand"
+            match word(i + 1) {
+                Some(next) if is_hundred(next, lang) || scale(next, lang).is_some() => {}
+                _ => break,
+            }
+        } else if any && is_joiner(w, lang) {
+            if let Some(end) = and_a_half(tokens, i, lang) {
+                return Some((total + current + 0.5, end));
+            }
+            match word(i + 1).and_then(|next| small_number(next, lang)) {
+                Some(n) if !digit && can_add(current, n) => {}
+                _ => break,
+            }
+        } else if any && !digit && is_point(w, lang) {
+            let decimals: String = tokens[i + 1..]
+                .iter()
+                .map_while(|t| match small_number(&t.word, lang) {
+                    Some(n) if n < 10.0 => Some(n.to_string()),
+                    _ if t.word.chars().all(|c| c.is_ascii_digit()) => Some(t.word.clone()),
+                    _ => None,
+                })
+                .collect();
+            if decimals.is_empty() {
+                break;
+            }
+            let end = i
+                + 1
+                + tokens[i + 1..]
+                    .iter()
+                    .take_while(|t| {
+                        small_number(&t.word, lang).is_some_and(|n| n < 10.0)
+                            || t.word.chars().all(|c| c.is_ascii_digit())
+                    })
+                    .count();
+            let fraction: f64 = format!("0.{}", decimals).parse().unwrap_or_default();
+            return Some((total + current + fraction, end));
+        } else {
+            break;
+        }
+        i += 1;
+    }
+
+    if !any {
+        return None;
+    }
+
+    let mut value = total + current;
+    // "two thirds", "um quarto"
+    if let Some(d) = word(i).and_then(|w| denominator(w, lang)) {
+        value /= d;
+        i += 1;
+    }
+    Some((value, i))
+}
+
+/// Extracts every number in `text`.
+///
+/// # Arguments
+///
+/// * `text` - The text, e.g. "twenty five or 3.5".
+/// * `lang` - The language code of the text.
+pub fn extract_numbers(text: &str, lang: &str) -> ExtractNumbers {
+    let lang = Lang::of(lang);
+    let tokens = tokenize(text);
+    let mut numbers = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match number_at(&tokens, i, lang) {
+            Some((n, end)) => {
+                numbers.push(n);
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    ExtractNumbers(numbers)
+}
+
+/// Extracts the first number in `text`.
+pub fn extract_number(text: &str, lang: &str) -> ExtractNumber {
+    ExtractNumber(extract_numbers(text, lang).0.first().copied())
+}
+
+/// The value of a fraction word, e.g. 0.5 for "half" or "meio".
+pub fn is_fractional(text: &str, lang: &str) -> IsFractional {
+    let lang = Lang::of(lang);
+    let tokens = tokenize(text);
+    let value = match tokens.as_slice() {
+        [token] => denominator(&token.word, lang).map(|d| 1.0 / d),
+        _ => None,
+    };
+    IsFractional(value)
+}
+
+fn unit_seconds(word: &str, lang: Lang) -> Option<f64> {
+    let seconds = match lang {
+        Lang::En => match word {
+            "second" | "seconds" | "sec" | "secs" => 1,
+            "minute" | "minutes" | "min" | "mins" => 60,
+            "hour" | "hours" => 3600,
+            "day" | "days" => 86400,
+            "week" | "weeks" => 604800,
+            "month" | "months" => 2592000,
+            "year" | "years" => 31536000,
+            _ => return None,
+        },
+        Lang::Pt => match word {
+            "segundo" | "segundos" => 1,
+            "minuto" | "minutos" => 60,
+            "hora" | "horas" => 3600,
+            "dia" | "dias" => 86400,
+            "semana" | "semanas" => 604800,
+            "mes" | "meses" => 2592000,
+            "ano" | "anos" => 31536000,
+            _ => return None,
+        },
+    };
+    Some(seconds as f64)
+}
+
+/// One amount of a unit at `i`, "5 minutes", "an hour and a half", "meia hora".
+fn duration_part_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(f64, usize)> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    let unit = |i: usize| word(i).and_then(|w| unit_seconds(w, lang));
+
+    let (seconds, end) = match (lang, word(i)?) {
+        (Lang::En, "half") if matches!(word(i + 1), Some("a" | "an")) => {
+            return unit(i + 2).map(|u| (u / 2.0, i + 3));
+        }
+        (Lang::Pt, "meio" | "meia") => return unit(i + 1).map(|u| (u / 2.0, i + 2)),
+        (Lang::En, "a" | "an") => (unit(i + 1)?, i + 2),
+        _ => {
+            let (n, end) = number_at(tokens, i, lang)?;
+            (n * unit(end)?, end + 1)
+        }
+    };
+
+    match and_a_half(tokens, end, lang) {
+        Some(half) => Some((seconds * 1.5, half)),
+        None => Some((seconds, end)),
+    }
+}
+
+/// Consecutive amounts at `i`, "two hours and 30 minutes".
+fn duration_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(f64, usize)> {
+    let (mut seconds, mut end) = duration_part_at(tokens, i, lang)?;
+    loop {
+        let next = match tokens.get(end) {
+            Some(t) if is_joiner(&t.word, lang) => end + 1,
+            _ => end,
+        };
+        match duration_part_at(tokens, next, lang) {
+            Some((s, e)) => {
+                seconds += s;
+                end = e;
+            }
+            None => return Some((seconds, end)),
+        }
+    }
+}
+
+/// Extracts the duration in `text`, in seconds.
+///
+/// # Arguments
+///
+/// * `text` - The text, e.g. "set a timer for an hour and a half".
+/// * `lang` - The language code of the text.
+pub fn extract_duration(text: &str, lang: &str) -> ExtractDuration {
+    let lang = Lang::of(lang);
+    let tokens = tokenize(text);
+    let mut consumed = vec![false; tokens.len()];
+    let mut duration = None;
+    let mut i = 0;
+    while i < tokens.len() {
+        match duration_at(&tokens, i, lang) {
+            Some((seconds, end)) => {
+                *duration.get_or_insert(0.0) += seconds;
+                consumed[i..end].fill(true);
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    ExtractDuration {
+        duration,
+        leftover: leftover(&tokens, &consumed),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Meridiem {
+    Am,
+    Pm,
+}
+
+/// A part of the day, with the hour it stands for when no time is given.
+#[derive(Debug, Clone, Copy)]
+struct Period {
+    hour: u32,
+    meridiem: Meridiem,
+}
+
+fn period(word: &str, lang: Lang) -> Option<Period> {
+    let (hour, meridiem) = match (lang, word) {
+        (Lang::En, "morning") | (Lang::Pt, "manha") => (8, Meridiem::Am),
+        (Lang::En, "afternoon") | (Lang::Pt, "tarde") => (15, Meridiem::Pm),
+        (Lang::En, "evening") => (19, Meridiem::Pm),
+        (Lang::En, "night" | "tonight") | (Lang::Pt, "noite") => (22, Meridiem::Pm),
+        (Lang::Pt, "madrugada") => (3, Meridiem::Am),
+        _ => return None,
+    };
+    Some(Period { hour, meridiem })
+}
+
+/// Words joining the parts of a datetime to the rest of the text, "at", "on", "às"...
+fn is_glue(word: &str, lang: Lang) -> bool {
+    match lang {
+        Lang::En => matches!(word, "at" | "on" | "in" | "the" | "of" | "this"),
+        Lang::Pt => matches!(
+            word,
+            "as" | "a" | "no" | "na" | "em" | "de" | "da" | "do" | "ao" | "esta" | "este"
+        ),
+    }
+}
+
+fn weekday(word: &str, lang: Lang) -> Option<u32> {
+    let day = match lang {
+        Lang::En => match word {
+            "monday" => 0,
+            "tuesday" => 1,
+            "wednesday" => 2,
+            "thursday" => 3,
+            "friday" => 4,
+            "saturday" => 5,
+            "sunday" => 6,
+            _ => return None,
+        },
+        Lang::Pt => match word {
+            "segunda" => 0,
+            "terca" => 1,
+            "quarta" => 2,
+            "quinta" => 3,
+            "sexta" => 4,
+            "sabado" => 5,
+            "domingo" => 6,
+            _ => return None,
+        },
+    };
+    Some(day)
+}
+
+fn month(word: &str, lang: Lang) -> Option<u32> {
+    let month = match lang {
+        Lang::En => match word {
+            "january" | "jan" => 1,
+            "february" | "feb" => 2,
+            "march" | "mar" => 3,
+            "april" | "apr" => 4,
+            "may" => 5,
+            "june" | "jun" => 6,
+            "july" | "jul" => 7,
+            "august" | "aug" => 8,
+            "september" | "sep" | "sept" => 9,
+            "october" | "oct" => 10,
+            "november" | "nov" => 11,
+            "december" | "dec" => 12,
+            _ => return None,
+        },
+        Lang::Pt => match word {
+            "janeiro" => 1,
+            "fevereiro" => 2,
+            "marco" => 3,
+            "abril" => 4,
+            "maio" => 5,
+            "junho" => 6,
+            "julho" => 7,
+            "agosto" => 8,
+            "setembro" => 9,
+            "outubro" => 10,
+            "novembro" => 11,
+            "dezembro" => 12,
+            _ => return None,
+        },
+    };
+    Some(month)
+}
+
+/// A day of the month at `i`, "5", "5th", "5º" or "twenty five".
+fn day_of_month_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(u32, usize)> {
+    let word = tokens.get(i)?.word.as_str();
+    let stripped = ["st", "nd", "rd", "th", "º", "o"]
+        .iter()
+        .find_map(|s| word.strip_suffix(s))
+        .unwrap_or(word);
+    let (day, end) = match stripped.parse::<u32>() {
+        Ok(day) => (day, i + 1),
+        Err(_) => {
+            let (n, end) = number_at(tokens, i, lang)?;
+            (n.fract().eq(&0.0).then_some(n as u32)?, end)
+        }
+    };
+    (1..=31).contains(&day).then_some((day, end))
+}
+
+/// A date like "june 5th 2027", "the 5th of june" or "5 de junho de 2027", returning
+/// the month, day, year, and the consumed span.
+fn date_at(
+    tokens: &[Token],
+    consumed: &[bool],
+    i: usize,
+    lang: Lang,
+) -> Option<(u32, u32, Option<i32>, usize, usize)> {
+    let month = month(&tokens.get(i)?.word, lang)?;
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    let is_of = |i: usize| matches!(word(i), Some("of" | "de"));
+
+    let (day, start, mut end) = match day_of_month_at(tokens, i + 1, lang) {
+        Some((day, end)) if lang == Lang::En => (day, i, end),
+        _ => (i.saturating_sub(4)..i)
+            .filter(|s| !consumed[*s..i].iter().any(|c| *c))
+            .find_map(|s| {
+                let (day, e) = day_of_month_at(tokens, s, lang)?;
+                (e == i || (e + 1 == i && is_of(e))).then_some((day, s, i + 1))
+            })?,
+    };
+
+    let year_at = if is_of(end) { end + 1 } else { end };
+    let year = word(year_at)
+        .filter(|w| w.len() == 4)
+        .and_then(|w| w.parse::<i32>().ok());
+    if year.is_some() {
+        end = year_at + 1;
+    }
+    Some((month, day, year, start, end))
+}
+
+/// A time of day at `i`, "3pm", "15:30", "at three thirty", "às 3 e meia", "noon".
+fn time_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(u32, u32, Option<Meridiem>, usize)> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    let meridiem = |w: &str| match w.replace('.', "").as_str() {
+        "am" => Some(Meridiem::Am),
+        "pm" => Some(Meridiem::Pm),
+        _ => None,
+    };
+
+    match (lang, word(i)?, word(i + 1)) {
+        (Lang::En, "noon" | "midday", _) => return Some((12, 0, None, i + 1)),
+        (Lang::En, "midnight", _) => return Some((0, 0, None, i + 1)),
+        (Lang::Pt, "meio", Some("dia")) => return Some((12, 0, None, i + 2)),
+        (Lang::Pt, "meia", Some("noite")) => return Some((0, 0, None, i + 2)),
+        _ => {}
+    }
+
+    // "15:30", "3pm", "3:30pm", "15h", "15h30"
+    let clock = |w: &str| -> Option<(u32, Option<u32>, Option<Meridiem>)> {
+        let (w, m) = match w.strip_suffix("am").or(w.strip_suffix("pm")) {
+            Some(rest) if !rest.is_empty() => (rest, meridiem(&w[rest.len()..])),
+            _ => (w, None),
+        };
+        let (hour, minute) = match w.split_once([':', 'h']) {
+            Some((h, "")) => (h, None),
+            Some((h, m)) => (h, Some(m.parse().ok()?)),
+            None if m.is_some() => (w, None),
+            None => return None,
+        };
+        Some((hour.parse().ok()?, minute, m))
+    };
+
+    let (mut hour, mut minute, mut am_pm, mut end, mut explicit) = match clock(word(i)?) {
+        Some((h, m, am_pm)) => (h, m, am_pm, i + 1, true),
+        None => {
+            // "três e meia" reads as 3.5
+            let (n, end) = number_at(tokens, i, lang)?;
+            let minute = match n.fract() {
+                0.0 => None,
+                0.5 => Some(30),
+                _ => return None,
+            };
+            if !(0.0..=24.0).contains(&n) {
+                return None;
+            }
+            (n as u32, minute, None, end, false)
+        }
+    };
+
+    if lang == Lang::Pt && matches!(word(end), Some("hora" | "horas" | "h")) {
+        explicit = true;
+        end += 1;
+    }
+
+    if minute.is_none() {
+        let (at, joined) = match word(end) {
+            Some(w) if lang == Lang::Pt && is_joiner(w, lang) => (end + 1, true),
+            _ => (end, false),
+        };
+        match (lang, word(at)) {
+            (Lang::Pt, Some("meia")) if joined => {
+                minute = Some(30);
+                end = at + 1;
+            }
+            (_, Some(_)) if lang == Lang::En || joined => {
+                if let Some((m, e)) = number_at(tokens, at, lang)
+                    .filter(|(m, _)| m.fract() == 0.0 && (1.0..60.0).contains(m))
+                {
+                    minute = Some(m as u32);
+                    end = e;
+                    if lang == Lang::Pt && matches!(word(end), Some("minuto" | "minutos")) {
+                        end += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if matches!(word(end), Some("o'clock" | "oclock")) {
+        explicit = true;
+        end += 1;
+    }
+    if am_pm.is_none() {
+        am_pm = word(end).and_then(meridiem);
+        if am_pm.is_some() {
+            end += 1;
+        }
+    }
+
+    let after_at = i > 0
+        && matches!(
+            (lang, word(i - 1)),
+            (Lang::En, Some("at")) | (Lang::Pt, Some("as"))
+        );
+    let before_period = tokens[end..]
+        .iter()
+        .find(|t| !is_glue(&t.word, lang))
+        .is_some_and(|t| period(&t.word, lang).is_some());
+    if !(explicit || after_at || before_period || am_pm.is_some()) {
+        return None;
+    }
+
+    if hour == 24 {
+        hour = 0;
+    }
+    let minute = minute.unwrap_or(0);
+    (hour <= 23 && minute < 60).then_some((hour, minute, am_pm, end))
+}
+
+/// Relative datetimes, "in 5 minutes", "daqui a uma hora", "2 days ago", "há 2 dias".
+fn offset_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(f64, usize)> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    let forward = match (lang, word(i)?, word(i + 1)) {
+        (Lang::En, "in", _) | (Lang::Pt, "em", _) => Some(i + 1),
+        (Lang::Pt, "daqui" | "dentro", Some("a" | "de")) => Some(i + 2),
+        _ => None,
+    };
+    if let Some((seconds, end)) = forward.and_then(|at| duration_at(tokens, at, lang)) {
+        return Some((seconds, end));
+    }
+    if lang == Lang::Pt && word(i) == Some("ha") {
+        return duration_at(tokens, i + 1, lang).map(|(s, end)| (-s, end));
+    }
+
+    let (seconds, end) = duration_at(tokens, i, lang)?;
+    match (lang, word(end), word(end + 1)) {
+        (Lang::En, Some("ago"), _) | (Lang::Pt, Some("atras"), _) => Some((-seconds, end + 1)),
+        (Lang::En, Some("later"), _) => Some((seconds, end + 1)),
+        (Lang::En, Some("from"), Some("now")) => Some((seconds, end + 2)),
+        _ => None,
+    }
+}
+
+/// A day relative to today at `i`, "tomorrow", "depois de amanhã".
+fn relative_day_at(tokens: &[Token], i: usize, lang: Lang) -> Option<(i64, usize)> {
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    match (lang, word(i)?, word(i + 1), word(i + 2)) {
+        (Lang::En, "day", Some("after"), Some("tomorrow")) => Some((2, i + 3)),
+        (Lang::En, "day", Some("before"), Some("yesterday")) => Some((-2, i + 3)),
+        (Lang::Pt, "depois", Some("de"), Some("amanha")) => Some((2, i + 3)),
+        (Lang::En, "today" | "tonight", _, _) | (Lang::Pt, "hoje", _, _) => Some((0, i + 1)),
+        (Lang::En, "tomorrow", _, _) | (Lang::Pt, "amanha", _, _) => Some((1, i + 1)),
+        (Lang::En, "yesterday", _, _) | (Lang::Pt, "ontem", _, _) => Some((-1, i + 1)),
+        (Lang::Pt, "anteontem", _, _) => Some((-2, i + 1)),
+        _ => None,
+    }
+}
+
+/// Extracts the datetime in `text`, relative to `now` and in its timezone.
+///
+/// A time alone is the next time it happens, a date alone starts at midnight.
+///
+/// # Arguments
+///
+/// * `text` - The text, e.g. "remind me next friday at 3pm".
+/// * `lang` - The language code of the text.
+/// * `now` - The moment the text is relative to.
+pub fn extract_datetime(text: &str, lang: &str, now: DateTime<Tz>) -> ExtractDatetime {
+    let lang = Lang::of(lang);
+    let tokens = tokenize(text);
+    let word = |i: usize| tokens.get(i).map(|t| t.word.as_str());
+    let mut consumed = vec![false; tokens.len()];
+
+    let today = now.date_naive();
+    let mut found = false;
+    let mut offset = 0.0;
+    let mut date: Option<NaiveDate> = None;
+    let mut time: Option<(u32, u32, Option<Meridiem>)> = None;
+    let mut part_of_day: Option<Period> = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        if consumed[i] {
+            i += 1;
+            continue;
+        }
+        let w = tokens[i].word.as_str();
+        let end = if matches!((lang, w), (Lang::En, "now") | (Lang::Pt, "agora")) {
+            Some(i + 1)
+        } else if let Some((seconds, end)) = offset_at(&tokens, i, lang) {
+            offset += seconds;
+            Some(end)
+        } else if let Some((days, end)) = relative_day_at(&tokens, i, lang) {
+            date = Some(today + Duration::days(days));
+            if w == "tonight" {
+                part_of_day = period(w, lang);
+            }
+            Some(end)
+        } else if let Some(day) = weekday(w, lang) {
+            let next = i > 0
+                && !consumed[i - 1]
+                && matches!(word(i - 1), Some("next" | "proxima" | "proximo"));
+            if next {
+                consumed[i - 1] = true;
+            }
+            let mut days =
+                (day as i64 - today.weekday().num_days_from_monday() as i64).rem_euclid(7);
+            if next && days == 0 {
+                days = 7;
+            }
+            date = Some(today + Duration::days(days));
+            Some(if word(i + 1) == Some("feira") {
+                i + 2
+            } else {
+                i + 1
+            })
+        } else if let Some((month, day, year, start, end)) = date_at(&tokens, &consumed, i, lang) {
+            let dated = |year| NaiveDate::from_ymd_opt(year, month, day);
+            date = match year {
+                Some(year) => dated(year),
+                None => dated(today.year())
+                    .filter(|d| *d >= today)
+                    .or_else(|| dated(today.year() + 1)),
+            };
+            date.map(|_| {
+                consumed[start..i].fill(true);
+                end
+            })
+        } else if let Some((hour, minute, am_pm, end)) = time_at(&tokens, i, lang) {
+            time = Some((hour, minute, am_pm));
+            Some(end)
+        } else if let Some(p) = period(w, lang) {
+            part_of_day = Some(p);
+            Some(i + 1)
+        } else {
+            None
+        };
+
+        match end {
+            Some(end) => {
+                found = true;
+                consumed[i..end].fill(true);
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+
+    if !found {
+        return ExtractDatetime {
+            datetime: None,
+            leftover: text.trim().to_string(),
+        };
+    }
+
+    for i in (0..tokens.len()).rev() {
+        if consumed[i] {
+            let mut j = i;
+            while j > 0 && !consumed[j - 1] && is_glue(&tokens[j - 1].word, lang) {
+                consumed[j - 1] = true;
+                j -= 1;
+            }
+        }
+    }
+
+    // "in a million years" is past what a date can hold
+    let Some(base) = TimeDelta::try_milliseconds((offset * 1000.0) as i64)
+        .and_then(|delta| now.checked_add_signed(delta))
+    else {
+        return ExtractDatetime {
+            datetime: None,
+            leftover: leftover(&tokens, &consumed),
+        };
+    };
+    let day = date.unwrap_or(base.date_naive());
+    let clock = match (time, part_of_day) {
+        (Some((hour, minute, am_pm)), p) => {
+            let hour = match am_pm.or(p.map(|p| p.meridiem)) {
+                Some(Meridiem::Pm) if hour < 12 => hour + 12,
+                Some(Meridiem::Am) if hour == 12 => 0,
+                _ => hour,
+            };
+            Some((hour, minute))
+        }
+        (None, Some(p)) => Some((p.hour, 0)),
+        (None, None) => None,
+    };
+
+    let mut local: NaiveDateTime = match clock {
+        Some((hour, minute)) => day.and_hms_opt(hour, minute, 0).unwrap_or_default(),
+        None if date.is_some() && offset == 0.0 => day.and_hms_opt(0, 0, 0).unwrap_or_default(),
+        None => base.naive_local(),
+    };
+
+    if let Some((hour, _, None)) = time
+        && date.is_none()
+        && offset == 0.0
+        && part_of_day.is_none()
+        && local <= now.naive_local()
+    {
+        // "at 3" in the morning is three in the afternoon, not tomorrow at three
+        let afternoon = local + Duration::hours(12);
+        local = if (1..12).contains(&hour) && afternoon > now.naive_local() {
+            afternoon
+        } else {
+            local + Duration::days(1)
+        };
+    } else if time.is_some() && date.is_none() && offset == 0.0 && local <= now.naive_local() {
+        local += Duration::days(1);
+    }
+
+    let timezone = now.timezone();
+    let datetime = timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            // Skipped by a DST change
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|d| d.to_rfc3339());
+
+    ExtractDatetime {
+        datetime,
+        leftover: leftover(&tokens, &consumed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extracts_numbers() {
+        let numbers = |text, lang| extract_numbers(text, lang).0;
+        assert_eq!(numbers("twenty five", "en"), [25.0]);
+        assert_eq!(numbers("one hundred and five apples", "en"), [105.0]);
+        assert_eq!(numbers("two thousand twenty six", "en"), [2026.0]);
+        assert_eq!(numbers("two point seven five or 2.5", "en"), [2.75, 2.5]);
+        assert_eq!(
+            numbers("one and a half, two thirds", "en"),
+            [1.5, 2.0 / 3.0]
+        );
+        assert_eq!(numbers("a thousand and one nights", "en"), [1001.0]);
+        assert_eq!(numbers("vinte e cinco", "pt"), [25.0]);
+        assert_eq!(numbers("cento e vinte e três", "pt-BR"), [123.0]);
+        assert_eq!(numbers("mil e quinhentos ou 3,5", "pt"), [1500.0, 3.5]);
+        assert_eq!(numbers("dois e três", "pt"), [2.0, 3.0]);
+        assert_eq!(extract_number("no numbers here", "en").0, None);
+        assert_eq!(is_fractional("half", "en").0, Some(0.5));
+        assert_eq!(is_fractional("terço", "pt").0, Some(1.0 / 3.0));
+        assert_eq!(is_fractional("two", "en").0, None);
+    }
+
+    #[test]
+    fn test_extracts_durations() {
+        let duration = extract_duration("set a timer for an hour and a half", "en");
+        assert_eq!(duration.duration, Some(5400.0));
+        assert_eq!(duration.leftover, "set a timer for");
+
+        let duration = extract_duration("two hours and 30 minutes", "en");
+        assert_eq!(duration.duration, Some(9000.0));
+        assert_eq!(duration.leftover, "");
+
+        let duration = extract_duration("lembra-me daqui a meia hora", "pt");
+        assert_eq!(duration.duration, Some(1800.0));
+
+        let duration = extract_duration("uma hora e meia", "pt");
+        assert_eq!(duration.duration, Some(5400.0));
+
+        let duration = extract_duration("hello there", "en");
+        assert_eq!(duration.duration, None);
+        assert_eq!(duration.leftover, "hello there");
+    }
+
+    fn now() -> DateTime<Tz> {
+        // A Wednesday
+        chrono_tz::Europe::Lisbon
+            .with_ymd_and_hms(2026, 10, 14, 10, 0, 0)
+            .unwrap()
+    }
+
+    fn datetime(text: &str, lang: &str) -> (Option<String>, String) {
+        let extracted = extract_datetime(text, lang, now());
+        (extracted.datetime, extracted.leftover)
+    }
+
+    #[test]
+    fn test_extracts_datetimes() {
+        assert_eq!(
+            datetime("remind me next friday at 3pm", "en"),
+            (Some("2026-10-16T15:00:00+01:00".into()), "remind me".into())
+        );
+        assert_eq!(
+            datetime("call mom in an hour and a half", "en"),
+            (Some("2026-10-14T11:30:00+01:00".into()), "call mom".into())
+        );
+        assert_eq!(
+            datetime("tomorrow morning", "en").0.as_deref(),
+            Some("2026-10-15T08:00:00+01:00")
+        );
+        assert_eq!(
+            datetime("at 9", "en").0.as_deref(),
+            Some("2026-10-14T21:00:00+01:00")
+        );
+        assert_eq!(
+            datetime("on june 5th", "en").0.as_deref(),
+            Some("2027-06-05T00:00:00+01:00")
+        );
+        assert_eq!(
+            datetime("liga-me amanhã às 15h30", "pt"),
+            (Some("2026-10-15T15:30:00+01:00".into()), "liga me".into())
+        );
+        assert_eq!(
+            datetime("próxima sexta-feira às três e meia da tarde", "pt")
+                .0
+                .as_deref(),
+            Some("2026-10-16T15:30:00+01:00")
+        );
+        assert_eq!(
+            datetime("no dia 25 de dezembro", "pt").0.as_deref(),
+            Some("2026-12-25T00:00:00+00:00")
+        );
+        assert_eq!(
+            datetime("há duas horas", "pt").0.as_deref(),
+            Some("2026-10-14T08:00:00+01:00")
+        );
+        assert_eq!(datetime("turn on the lights", "en").0, None);
+    }
+
+    #[test]
+    fn test_huge_offsets_are_not_datetimes() {
+        assert_eq!(
+            datetime("remind me in 1000000 years", "en"),
+            (None, "remind me".into())
+        );
+        assert_eq!(datetime("in 1000000000000 years", "en").0, None);
+        assert!(datetime("in 10 years", "en").0.is_some());
+    }
+}
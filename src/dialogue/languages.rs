@@ -1,13 +1,103 @@
 use crate::ctx::runtime;
 use crate::data::config::setting_or;
+use crate::dialogue::message_format;
 use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
 use rand::prelude::IndexedRandom;
 use rhai::CustomType;
 use rhai::TypeBuilder;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use strfmt::strfmt;
+use std::sync::LazyLock;
+
+/// The language every lookup falls back to last.
+pub const FALLBACK_LANG: &str = "en";
+
+/// Missing keys that were already reported, so each one is only logged once.
+static WARNED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+fn warn_once(key: String, message: impl FnOnce() -> String) {
+    if WARNED.lock().insert(key) {
+        warn!("{}", message());
+    }
+}
+
+/// The variant of a resource matching how the user likes to be answered.
+///
+/// `hello.formal.concise`, `hello.formal`, `hello.concise` and `hello` are tried in that
+/// order, and a resource may also be a map keyed by style or length with a `default` entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocaleVariant {
+    pub style: Option<String>,
+    pub length: Option<String>,
+}
+
+impl LocaleVariant {
+    /// The variant preferred by the current user.
+    pub fn current() -> Self {
+        match runtime() {
+            Ok(c) => Self {
+                style: Some(c.user.get_communication_style().key().to_string()),
+                length: Some(c.user.get_response_length().key().to_string()),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// The ids tried for `id`, most specific first.
+    fn ids(&self, id: &str) -> Vec<String> {
+        let mut ids = Vec::new();
+        if let (Some(style), Some(length)) = (&self.style, &self.length) {
+            ids.push(format!("{}.{}.{}", id, style, length));
+        }
+        ids.extend(self.style.iter().map(|s| format!("{}.{}", id, s)));
+        ids.extend(self.length.iter().map(|l| format!("{}.{}", id, l)));
+        ids.push(id.to_string());
+        ids
+    }
+
+    /// Picks the entry of a map keyed by style or length, `default` when none matches.
+    fn select<'a>(&self, value: &'a serde_yaml::Value) -> Option<&'a serde_yaml::Value> {
+        let serde_yaml::Value::Mapping(map) = value else {
+            return Some(value);
+        };
+        let selected = [
+            self.style.as_deref(),
+            self.length.as_deref(),
+            Some("default"),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|key| map.get(key))?;
+        self.select(selected)
+    }
+}
+
+/// Normalizes a language code so `pt_BR`, `pt-br` and `pt-BR` compare equal.
+fn normalize_code(code: &str) -> String {
+    code.trim().replace('_', "-").to_lowercase()
+}
+
+/// The codes looked up for `code`, in order: the region, its base language,
+/// the system language and finally [`FALLBACK_LANG`].
+///
+/// `pt-BR` on a system set to `en` gives `pt-br`, `pt`, `en`.
+pub fn fallback_chain(code: &str) -> Vec<String> {
+    let system = lang();
+    let mut chain: Vec<String> = Vec::new();
+
+    for code in [code, system.as_str(), FALLBACK_LANG] {
+        let code = normalize_code(code);
+        let base = code.split('-').next().unwrap_or_default().to_string();
+        for candidate in [code, base] {
+            if !candidate.is_empty() && !chain.contains(&candidate) {
+                chain.push(candidate);
+            }
+        }
+    }
+    chain
+}
 
 /// Represents the structure of a language resource file.
 #[derive(Debug, Clone, Deserialize)]
@@ -103,41 +193,96 @@ impl LanguageSystem {
         Self { languages }
     }
 
-    /// Retrieves a localized resource value.
+    /// Returns the loaded language matching `code`, ignoring case and `_`/`-` differences.
+    fn language(&self, code: &str) -> Option<&Language> {
+        let code = normalize_code(code);
+        self.languages
+            .iter()
+            .find(|l| normalize_code(&l.code) == code)
+    }
+
+    /// Finds the `variant` of a resource following the [`fallback_chain`] of `code`.
+    ///
+    /// Warns once per key when it is missing from `code` or from every language.
+    fn find(&self, code: &str, id: &str, variant: &LocaleVariant) -> Option<&serde_yaml::Value> {
+        let chain = fallback_chain(code);
+        let ids = variant.ids(id);
+        let found = chain.iter().enumerate().find_map(|(n, c)| {
+            let language = self.language(c)?;
+            ids.iter()
+                .filter_map(|id| language.lang.iter().find(|i| i.id == *id))
+                .find_map(|i| variant.select(&i.value))
+                .map(|value| (n, c, value))
+        });
+
+        match found {
+            Some((0, _, locale)) => Some(locale),
+            Some((_, fallback, locale)) => {
+                warn_once(format!("{}:{}", code, id), || {
+                    format!(
+                        "Locale key {} is missing for {}, using {}",
+                        id, code, fallback
+                    )
+                });
+                Some(locale)
+            }
+            None => {
+                warn_once(id.to_string(), || {
+                    format!("Locale key {} is missing in {}", id, chain.join(", "))
+                });
+                None
+            }
+        }
+    }
+
+    /// Retrieves a localized resource value, in the variant the current user prefers.
     ///
     /// If the value is a list (sequence), it randomly selects one entry from the list.
+    /// Keys missing in `code` are looked up along its [`fallback_chain`].
     ///
     /// # Arguments
     ///
     /// * `code` - The language code.
     /// * `id` - The resource identifier.
     pub fn locale(&self, code: &str, id: &str) -> Option<serde_yaml::Value> {
-        self.languages
-            .iter()
-            .find(|l| l.code == code)
-            .and_then(|l| l.lang.iter().find(|i| i.id == id))
-            .map(|i| match &i.value {
-                serde_yaml::Value::Sequence(seq) if !seq.is_empty() => {
-                    let mut rng = rand::rng();
-                    seq.choose(&mut rng)
-                        .cloned()
-                        .unwrap_or_else(|| i.value.clone())
-                }
-                _ => i.value.clone(),
-            })
+        self.locale_variant(code, id, &LocaleVariant::current())
     }
 
+    /// Retrieves a localized resource value in a given variant, see [`LanguageSystem::locale`].
+    pub fn locale_variant(
+        &self,
+        code: &str,
+        id: &str,
+        variant: &LocaleVariant,
+    ) -> Option<serde_yaml::Value> {
+        self.find(code, id, variant).map(|value| match value {
+            serde_yaml::Value::Sequence(seq) if !seq.is_empty() => {
+                let mut rng = rand::rng();
+                seq.choose(&mut rng)
+                    .cloned()
+                    .unwrap_or_else(|| value.clone())
+            }
+            _ => value.clone(),
+        })
+    }
+
+    /// Retrieves a localized resource and formats it with `args`.
+    ///
+    /// The resource is an ICU style message, see [`message_format`]. When it can't be
+    /// formatted the error is logged and the message is returned as is.
     pub fn locale_fmt(
         &self,
         code: &str,
         id: &str,
-        fmt: &HashMap<String, String>,
+        args: &HashMap<String, serde_json::Value>,
     ) -> Option<String> {
-        match self.locale(code, id) {
-            Some(value) => self
-                .value_to_string(&value)
-                .map(|v| strfmt(&v, fmt).unwrap_or_else(|_| v.clone())),
-            None => None,
+        let message = self.value_to_string(&self.locale(code, id)?)?;
+        match message_format::format(&message, code, args) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                warn!("Failed to format locale key {}: {}", id, e);
+                Some(message)
+            }
         }
     }
 
@@ -148,19 +293,17 @@ impl LanguageSystem {
         }
     }
 
+    /// Looks `id` up in the user's language, falling back along [`fallback_chain`].
     pub fn get_translation(&self, id: &str) -> Option<String> {
-        match self.locale(&lang(), id) {
+        match self.locale(&user_language(), id) {
             Some(value) => self.value_to_string(&value),
             None => None,
         }
     }
 
     pub fn get_translation_list(&self, id: &str) -> Vec<String> {
-        self.languages
-            .iter()
-            .find(|l| l.code == *lang())
-            .and_then(|l| l.lang.iter().find(|i| i.id == id))
-            .map(|i| match &i.value {
+        self.find(&user_language(), id, &LocaleVariant::current())
+            .map(|value| match value {
                 serde_yaml::Value::Sequence(seq) if !seq.is_empty() => seq
                     .iter()
                     .filter_map(|v| {
@@ -174,18 +317,18 @@ impl LanguageSystem {
                 serde_yaml::Value::String(s) => vec![s.clone()],
                 _ => Vec::new(),
             })
-            .unwrap_or_else(Vec::new)
+            .unwrap_or_default()
     }
 
+    /// Lists every resource available in `code`, including those filled in from its fallbacks.
     pub fn list(&self, code: &str) -> HashMap<String, serde_yaml::Value> {
-        let Some(lang) = self.languages.iter().find(|l| l.code == code) else {
-            return HashMap::<String, serde_yaml::Value>::new();
-        };
-
-        lang.lang
-            .iter()
-            .map(|i| (i.id.clone(), i.value.clone()))
-            .collect()
+        let mut resources = HashMap::new();
+        for code in fallback_chain(code).iter().rev() {
+            if let Some(lang) = self.language(code) {
+                resources.extend(lang.lang.iter().map(|i| (i.id.clone(), i.value.clone())));
+            }
+        }
+        resources
     }
 
     pub fn has(&self, id: &str) -> bool {
@@ -218,3 +361,117 @@ pub fn get_translation_list(key: &str) -> Vec<String> {
 pub fn lang() -> String {
     setting_or::<String>("lang", "en".to_string())
 }
+
+/// The user's language, or the system one when there is no user.
+pub fn user_language() -> String {
+    match runtime() {
+        Ok(c) => c.user.get_language(),
+        Err(_) => lang(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(code: &str, entries: &[(&str, &str)]) -> Language {
+        Language {
+            code: code.to_string(),
+            lang: entries
+                .iter()
+                .map(|(id, value)| IndividualLocale {
+                    id: id.to_string(),
+                    value: serde_yaml::Value::String(value.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_fallback_chain() {
+        assert_eq!(fallback_chain("pt_BR"), ["pt-br", "pt", "en"]);
+        assert_eq!(fallback_chain("en-US"), ["en-us", "en"]);
+        assert_eq!(fallback_chain("en"), ["en"]);
+    }
+
+    #[test]
+    fn test_missing_keys_come_from_the_fallbacks() {
+        let system = LanguageSystem {
+            languages: vec![
+                language(
+                    "en",
+                    &[("hello", "Hello"), ("bye", "Bye"), ("thanks", "Thanks")],
+                ),
+                language("pt", &[("hello", "Olá"), ("bye", "Adeus")]),
+                language("pt-BR", &[("hello", "Oi")]),
+            ],
+        };
+
+        let text = |code, id| {
+            system
+                .locale(code, id)
+                .and_then(|v| system.value_to_string(&v))
+        };
+        assert_eq!(text("pt-BR", "hello").as_deref(), Some("Oi"));
+        assert_eq!(text("pt-BR", "bye").as_deref(), Some("Adeus"));
+        assert_eq!(text("pt-BR", "thanks").as_deref(), Some("Thanks"));
+        assert_eq!(text("pt-PT", "hello").as_deref(), Some("Olá"));
+        assert_eq!(text("pt-BR", "missing"), None);
+
+        let list = system.list("pt-BR");
+        assert_eq!(list.len(), 3);
+        assert_eq!(list["hello"], serde_yaml::Value::String("Oi".into()));
+    }
+
+    #[test]
+    fn test_variants_follow_the_user_preferences() {
+        let mut en = language(
+            "en",
+            &[
+                ("hello", "Hello"),
+                ("hello.formal", "Good day"),
+                ("hello.formal.concise", "Day"),
+            ],
+        );
+        en.lang.push(IndividualLocale {
+            id: "bye".into(),
+            value: serde_yaml::from_str("{ casual: See ya, concise: Bye, default: Goodbye }")
+                .unwrap(),
+        });
+        let system = LanguageSystem {
+            languages: vec![en],
+        };
+
+        let variant = |style: Option<&str>, length: Option<&str>| LocaleVariant {
+            style: style.map(str::to_string),
+            length: length.map(str::to_string),
+        };
+        let text = |id, variant: LocaleVariant| {
+            system
+                .locale_variant("en", id, &variant)
+                .and_then(|v| system.value_to_string(&v))
+        };
+        assert_eq!(text("hello", variant(None, None)).as_deref(), Some("Hello"));
+        assert_eq!(
+            text("hello", variant(Some("formal"), Some("detailed"))).as_deref(),
+            Some("Good day")
+        );
+        assert_eq!(
+            text("hello", variant(Some("formal"), Some("concise"))).as_deref(),
+            Some("Day")
+        );
+        assert_eq!(
+            text("hello", variant(Some("casual"), Some("concise"))).as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(
+            text("bye", variant(Some("casual"), Some("concise"))).as_deref(),
+            Some("See ya")
+        );
+        assert_eq!(
+            text("bye", variant(Some("formal"), Some("concise"))).as_deref(),
+            Some("Bye")
+        );
+        assert_eq!(text("bye", variant(None, None)).as_deref(), Some("Goodbye"));
+    }
+}
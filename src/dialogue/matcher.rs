@@ -0,0 +1,589 @@
+use crate::dialogue::intent_file::IntentDefinition;
+use crate::utils::get_all_docs_on_folder;
+use avi_nlu_client::models::NluResultInput;
+use log::{debug, warn};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Upper bound of sentences generated from a single utterance template.
+const MAX_EXPANSIONS: usize = 256;
+
+/// How much the first word a slot caught with no known entity value counts towards the
+/// score. Further words count nothing, so a wildcard slot swallowing a long tail of the
+/// utterance lowers the confidence.
+const UNKNOWN_SLOT_WEIGHT: f64 = 0.5;
+
+/// Selects which engine turns text into intents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntentEngine {
+    /// Only the built-in [`LocalMatcher`].
+    Local,
+    /// Only the avi-nlu server.
+    Remote,
+    /// The avi-nlu server, falling back to the [`LocalMatcher`] when it is down or finds nothing.
+    Hybrid,
+}
+
+impl IntentEngine {
+    /// Reads the `intent_engine` setting, using [`IntentEngine::Hybrid`] for unknown values.
+    pub fn new(engine: String) -> Self {
+        match engine.as_str() {
+            "local" => IntentEngine::Local,
+            "remote" => IntentEngine::Remote,
+            "hybrid" => IntentEngine::Hybrid,
+            _ => {
+                warn!(
+                    "Unknown intent_engine '{}', expected local, remote or hybrid. Using hybrid",
+                    engine
+                );
+                IntentEngine::Hybrid
+            }
+        }
+    }
+}
+
+/// An entity value, either `value` or `[value, synonym, ...]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum EntityValue {
+    Single(String),
+    Synonyms(Vec<String>),
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The parts of a `.entity` file the local matcher uses.
+#[derive(Debug, Clone, Deserialize)]
+struct EntityDefinition {
+    name: String,
+    #[serde(default = "default_true")]
+    automatically_extensible: bool,
+    #[serde(default = "default_true")]
+    use_synonyms: bool,
+    #[serde(default)]
+    values: Vec<EntityValue>,
+}
+
+/// The known values of an entity, each spelling mapped to its canonical value.
+#[derive(Debug, Default)]
+struct Entity {
+    spellings: Vec<(String, String)>,
+    extensible: bool,
+}
+
+impl Entity {
+    fn new(definition: EntityDefinition) -> Self {
+        let mut spellings = Vec::new();
+        for value in definition.values {
+            let names = match value {
+                EntityValue::Single(v) => v.split(',').map(|s| s.trim().to_string()).collect(),
+                EntityValue::Synonyms(v) => v,
+            };
+            let Some(canonical) = names.first().cloned() else {
+                continue;
+            };
+            let take = if definition.use_synonyms {
+                names.len()
+            } else {
+                1
+            };
+            for name in names.into_iter().take(take) {
+                spellings.push((normalize(&name), canonical.clone()));
+            }
+        }
+        // Longest first so "good morning" wins over "good"
+        spellings.sort_by_key(|(spelling, _)| std::cmp::Reverse(spelling.len()));
+
+        Self {
+            spellings,
+            extensible: definition.automatically_extensible,
+        }
+    }
+
+    fn pattern(&self) -> String {
+        let mut options: Vec<String> = self
+            .spellings
+            .iter()
+            .map(|(spelling, _)| words_pattern(spelling))
+            .collect();
+        if self.extensible || options.is_empty() {
+            options.push(r".+?".to_string());
+        }
+        options.join("|")
+    }
+
+    fn is_known(&self, raw: &str) -> bool {
+        let normalized = normalize(raw);
+        self.spellings
+            .iter()
+            .any(|(spelling, _)| *spelling == normalized)
+    }
+
+    fn canonical(&self, raw: &str) -> String {
+        let normalized = normalize(raw);
+        self.spellings
+            .iter()
+            .find(|(spelling, _)| *spelling == normalized)
+            .map(|(_, canonical)| canonical.clone())
+            .unwrap_or(raw.to_string())
+    }
+}
+
+#[derive(Debug)]
+struct SlotRef {
+    name: String,
+    entity: String,
+}
+
+#[derive(Debug)]
+struct Pattern {
+    intent: String,
+    regex: Regex,
+    slots: Vec<SlotRef>,
+    /// Number of literal words, used to prefer the most specific template.
+    literal_words: usize,
+}
+
+/// Built-in intent recognizer used when the avi-nlu server is unavailable.
+///
+/// It reads the same intent and entity files that are sent to the server, expands each
+/// utterance template into regular expressions and extracts slots from the matching one.
+/// Templates support `[slot]`, `[slot:entity]`, `[slot:entity](example)` and `(a|b|)`
+/// alternatives, where an empty alternative makes the group optional.
+#[derive(Debug, Default)]
+pub struct LocalMatcher {
+    patterns: Vec<Pattern>,
+    entities: HashMap<String, Entity>,
+}
+
+impl LocalMatcher {
+    /// Loads the intents and entities of every skill directory.
+    pub fn load(skill_paths: &[PathBuf]) -> Self {
+        let mut intents = Vec::new();
+        let mut entities = Vec::new();
+
+        for path in skill_paths {
+            intents.append(&mut IntentDefinition::load(&path.join("intent/intents")));
+            entities.append(&mut get_all_docs_on_folder::<EntityDefinition>(
+                path.join("intent/entities"),
+                None,
+                ".entity".to_string(),
+            ));
+        }
+
+        Self::new(intents, entities)
+    }
+
+    fn new(intents: Vec<IntentDefinition>, entities: Vec<EntityDefinition>) -> Self {
+        let entities: HashMap<String, Entity> = entities
+            .into_iter()
+            .map(|e| (e.name.clone(), Entity::new(e)))
+            .collect();
+
+        let mut patterns = Vec::new();
+        for intent in intents {
+            let declared: HashMap<&str, &str> = intent
+                .slots
+                .iter()
+                .map(|s| (s.name.as_str(), s.entity.as_str()))
+                .collect();
+
+            for utterance in &intent.utterances {
+                for sentence in expand(&strip_examples(utterance)) {
+                    match compile(&intent.name, &sentence, &declared, &entities) {
+                        Ok(pattern) => patterns.push(pattern),
+                        Err(e) => warn!(
+                            "Skipping utterance '{}' of {}: {}",
+                            sentence, intent.name, e
+                        ),
+                    }
+                }
+            }
+        }
+
+        debug!("Local matcher compiled {} patterns", patterns.len());
+        Self { patterns, entities }
+    }
+
+    /// Returns whether any intent was loaded.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Recognizes up to `limit` intents in `text`, most likely first, in the same shape the
+    /// avi-nlu server returns.
+    ///
+    /// The probability of a candidate is the share of the utterance its template explains:
+    /// literal words and known entity values count fully, while slots caught by a wildcard
+    /// count for little, see [`UNKNOWN_SLOT_WEIGHT`].
+    pub fn candidates(&self, text: &str, limit: usize) -> Vec<NluResultInput> {
+        let normalized = normalize_keep_offsets(text);
+        let words = normalized.split_whitespace().count().max(1);
+
+        let mut matches: Vec<(f64, &Pattern, regex::Captures)> = self
+            .patterns
+            .iter()
+            .filter_map(|p| p.regex.captures(&normalized).map(|c| (p, c)))
+            .map(|(p, c)| (self.score(p, &c, words), p, c))
+            .collect();
+        matches.sort_by(|(a, p, _), (b, q, _)| {
+            b.total_cmp(a)
+                .then_with(|| q.literal_words.cmp(&p.literal_words))
+        });
+
+        let mut seen = Vec::new();
+        let mut candidates = Vec::new();
+        for (score, pattern, captures) in matches {
+            if candidates.len() >= limit {
+                break;
+            }
+            if seen.contains(&pattern.intent) {
+                continue;
+            }
+            seen.push(pattern.intent.clone());
+            if let Some(candidate) = self.build(text, &normalized, score, pattern, &captures) {
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
+
+    /// The share of the `words` of the utterance `pattern` explains.
+    fn score(&self, pattern: &Pattern, captures: &regex::Captures, words: usize) -> f64 {
+        let mut covered = pattern.literal_words as f64;
+        for (index, slot) in pattern.slots.iter().enumerate() {
+            let Some(group) = captures.name(&format!("s{}", index)) else {
+                continue;
+            };
+            let known = self
+                .entities
+                .get(&slot.entity)
+                .is_some_and(|e| e.is_known(group.as_str()));
+            covered += if known {
+                group.as_str().split_whitespace().count() as f64
+            } else {
+                UNKNOWN_SLOT_WEIGHT
+            };
+        }
+        (covered / words as f64).min(1.0)
+    }
+
+    fn build(
+        &self,
+        text: &str,
+        normalized: &str,
+        score: f64,
+        pattern: &Pattern,
+        captures: &regex::Captures,
+    ) -> Option<NluResultInput> {
+        let original: Vec<char> = text.chars().collect();
+
+        let mut slots = Vec::new();
+        for (index, slot) in pattern.slots.iter().enumerate() {
+            let Some(group) = captures.name(&format!("s{}", index)) else {
+                continue;
+            };
+            let start = normalized[..group.start()].chars().count();
+            let end = start + group.as_str().chars().count();
+            let raw: String = original[start..end].iter().collect();
+
+            let value = self
+                .entities
+                .get(&slot.entity)
+                .map(|e| e.canonical(&raw))
+                .unwrap_or(raw.clone());
+
+            slots.push(json!({
+                "range": { "start": start, "end": end },
+                "rawValue": raw,
+                "value": { "kind": "Custom", "value": value },
+                "entity": slot.entity,
+                "slotName": slot.name,
+            }));
+        }
+
+        let result = json!({
+            "input": text,
+            "intent": { "intentName": pattern.intent, "probability": score },
+            "slots": slots,
+        });
+
+        match serde_json::from_value(result) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Failed to build local intent result: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Lowercases and replaces punctuation with spaces, keeping one char per input char.
+fn normalize_keep_offsets(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '\'' {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                ' '
+            }
+        })
+        .collect()
+}
+
+fn normalize(text: &str) -> String {
+    normalize_keep_offsets(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn words_pattern(text: &str) -> String {
+    normalize(text)
+        .split(' ')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(r"\s+")
+}
+
+/// Removes the `(example)` that may follow a slot, e.g. `[room:room](kitchen)`.
+fn strip_examples(template: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == ']' && chars.peek() == Some(&'(') {
+            for skipped in chars.by_ref() {
+                if skipped == ')' {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Expands `(a|b)` groups into every combination, innermost groups first.
+fn expand(template: &str) -> Vec<String> {
+    let Some(close) = template.find(')') else {
+        return vec![template.to_string()];
+    };
+    let Some(open) = template[..close].rfind('(') else {
+        return vec![template.to_string()];
+    };
+
+    let (before, after) = (&template[..open], &template[close + 1..]);
+    let mut sentences = Vec::new();
+    for option in template[open + 1..close].split('|') {
+        for sentence in expand(&format!("{}{}{}", before, option, after)) {
+            if sentences.len() >= MAX_EXPANSIONS {
+                return sentences;
+            }
+            sentences.push(sentence);
+        }
+    }
+    sentences
+}
+
+fn compile(
+    intent: &str,
+    sentence: &str,
+    declared: &HashMap<&str, &str>,
+    entities: &HashMap<String, Entity>,
+) -> Result<Pattern, String> {
+    let slot_re = Regex::new(r"\[([^\]:]+)(?::([^\]]+))?\]").map_err(|e| e.to_string())?;
+
+    let mut regex = String::from(r"^\s*");
+    let mut slots = Vec::new();
+    let mut literal_words = 0;
+    let mut last = 0;
+
+    let mut push_literal = |regex: &mut String, text: &str| {
+        let words = normalize(text);
+        if !words.is_empty() {
+            literal_words += words.split(' ').count();
+            regex.push_str(&words_pattern(&words));
+            regex.push_str(r"\s*");
+        }
+    };
+
+    for captures in slot_re.captures_iter(sentence) {
+        let whole = captures.get(0).ok_or("Invalid slot")?;
+        push_literal(&mut regex, &sentence[last..whole.start()]);
+        last = whole.end();
+
+        let name = captures[1].trim().to_string();
+        let entity = captures
+            .get(2)
+            .map(|e| e.as_str().trim().to_string())
+            .or(declared.get(name.as_str()).map(|e| e.to_string()))
+            .unwrap_or(name.clone());
+
+        let pattern = entities
+            .get(&entity)
+            .map(Entity::pattern)
+            .unwrap_or(r".+?".to_string());
+
+        regex.push_str(&format!(r"(?P<s{}>{})\s*", slots.len(), pattern));
+        slots.push(SlotRef { name, entity });
+    }
+    push_literal(&mut regex, &sentence[last..]);
+    regex.push('$');
+
+    if literal_words == 0 && slots.is_empty() {
+        return Err("Empty utterance".to_string());
+    }
+
+    Ok(Pattern {
+        intent: intent.to_string(),
+        regex: Regex::new(&regex).map_err(|e| e.to_string())?,
+        slots,
+        literal_words,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl LocalMatcher {
+        fn recognize(&self, text: &str) -> Option<NluResultInput> {
+            self.candidates(text, 1).into_iter().next()
+        }
+    }
+
+    fn matcher() -> LocalMatcher {
+        let intents: Vec<IntentDefinition> = serde_yaml::from_str(
+            r#"
+- name: saudation@hi
+  utterances:
+    - (Hi|Hello) Avi
+    - Good [timeOfDay] (Avi|)
+- name: lights@set
+  slots:
+    - name: room
+      entity: room
+  utterances:
+    - turn on the lights in the [room](kitchen)
+- name: power@on
+  utterances:
+    - turn on the [device]
+"#,
+        )
+        .unwrap();
+        let entities: Vec<EntityDefinition> = serde_yaml::from_str(
+            r#"
+- name: timeOfDay
+  automatically_extensible: false
+  values:
+    - [Morning, Morn]
+    - [Night]
+- name: room
+  values:
+    - Living Room
+"#,
+        )
+        .unwrap();
+
+        LocalMatcher::new(intents, entities)
+    }
+
+    #[test]
+    fn test_intent_engine_setting() {
+        assert_eq!(IntentEngine::new("local".into()), IntentEngine::Local);
+        assert_eq!(IntentEngine::new("remote".into()), IntentEngine::Remote);
+        assert_eq!(IntentEngine::new("hybrid".into()), IntentEngine::Hybrid);
+        assert_eq!(IntentEngine::new("lcoal".into()), IntentEngine::Hybrid);
+    }
+
+    #[test]
+    fn test_expand_alternatives() {
+        let mut sentences = expand("(Hi|Hello) Avi(!|)");
+        sentences.sort();
+        assert_eq!(
+            sentences,
+            vec!["Hello Avi", "Hello Avi!", "Hi Avi", "Hi Avi!"]
+        );
+        assert_eq!(strip_examples("in the [room](kitchen)"), "in the [room]");
+    }
+
+    #[test]
+    fn test_recognizes_intent_and_slots() {
+        let matcher = matcher();
+
+        let result = matcher.recognize("Hello, Avi!").unwrap();
+        assert_eq!(result.intent.intent_name, "saudation@hi");
+
+        let result = matcher.recognize("good morn").unwrap();
+        let slots = serde_json::to_value(result.slots).unwrap();
+        assert_eq!(slots[0]["slotName"], "timeOfDay");
+        assert_eq!(slots[0]["rawValue"], "morn");
+        assert_eq!(slots[0]["value"]["value"], "Morning");
+
+        assert!(matcher.recognize("good evening").is_none());
+    }
+
+    #[test]
+    fn test_extensible_entities_accept_unknown_values() {
+        let matcher = matcher();
+
+        let result = matcher
+            .recognize("Turn on the lights in the Garage")
+            .unwrap();
+        assert_eq!(result.intent.intent_name, "lights@set");
+
+        let slots = serde_json::to_value(result.slots).unwrap();
+        assert_eq!(slots[0]["rawValue"], "Garage");
+        assert_eq!(slots[0]["range"]["start"], 26);
+        assert_eq!(slots[0]["range"]["end"], 32);
+    }
+
+    #[test]
+    fn test_scores_the_share_of_the_utterance_explained() {
+        let matcher = matcher();
+
+        let exact = matcher.recognize("good morning avi").unwrap();
+        assert_eq!(exact.intent.probability, 1.0);
+
+        let known = matcher
+            .recognize("turn on the lights in the living room")
+            .unwrap();
+        assert_eq!(known.intent.probability, 1.0);
+
+        let unknown = matcher
+            .recognize("turn on the lights in the garage")
+            .unwrap();
+        assert!(unknown.intent.probability < 1.0);
+        assert!(unknown.intent.probability > 0.9);
+
+        let rambling = matcher
+            .recognize("turn on the lights in the garage and make me a coffee")
+            .unwrap();
+        assert!(rambling.intent.probability < 0.7);
+    }
+
+    #[test]
+    fn test_lists_candidates_by_score() {
+        let matcher = matcher();
+
+        let candidates = matcher.candidates("turn on the lights in the garage", 5);
+        let names: Vec<_> = candidates
+            .iter()
+            .map(|c| c.intent.intent_name.as_str())
+            .collect();
+        assert_eq!(names, ["lights@set", "power@on"]);
+        assert!(candidates[0].intent.probability > candidates[1].intent.probability);
+
+        assert_eq!(
+            matcher
+                .candidates("turn on the lights in the garage", 1)
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! A subset of ICU MessageFormat for the values of `.lang` files.
+//!
+//! ```text
+//! {name}                                   the value as text, numbers as written: 1234.5678 / 1234,5678
+//! {count, number}                          1,234.568 / 1.234,568 depending on the language
+//! {count, number, integer|percent}
+//! {when, date}  {when, date, short|long}   an RFC 3339 string or a unix timestamp, in the user's timezone
+//! {when, time}
+//! {count, plural, =0 {none} one {# minute} other {# minutes}}
+//! {gender, select, female {ela} male {ele} other {elu}}
+//! ```
+//!
+//! Inside a plural branch `#` is the formatted number. A `'` quotes the braces or `#`
+//! that follow it up to the next `'`, and `''` is a literal apostrophe.
+
+use crate::data::user::user_timezone;
+use chrono::{DateTime, TimeZone};
+use chrono_tz::Tz;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    /// The number of the enclosing plural.
+    Hash,
+    Arg(String),
+    Number(String, Option<String>),
+    Date(String, Option<String>),
+    Time(String),
+    Plural(String, Vec<(String, Vec<Part>)>),
+    Select(String, Vec<(String, Vec<Part>)>),
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    pattern: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(pattern: &'a str) -> Self {
+        Self {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            pattern,
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at {} in '{}'", message, self.pos, self.pattern)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses text and arguments until the closing `}` of a branch or the end.
+    fn message(&mut self, in_plural: bool) -> Result<Vec<Part>, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                '}' => break,
+                '{' => {
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    self.pos += 1;
+                    parts.push(self.argument(in_plural)?);
+                }
+                '#' if in_plural => {
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    self.pos += 1;
+                    parts.push(Part::Hash);
+                }
+                '\'' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\'') => {
+                            text.push('\'');
+                            self.pos += 1;
+                        }
+                        Some('{' | '}' | '#') => {
+                            while let Some(q) = self.peek() {
+                                self.pos += 1;
+                                if q == '\'' {
+                                    break;
+                                }
+                                text.push(q);
+                            }
+                        }
+                        _ => text.push('\''),
+                    }
+                }
+                c => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(parts)
+    }
+
+    /// Reads a name or keyword, stopping at whitespace, `,`, `{` or `}`.
+    fn word(&mut self) -> String {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| !c.is_whitespace() && !matches!(c, ',' | '{' | '}'))
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected '{}'", expected)))
+        }
+    }
+
+    /// Parses an argument after its opening `{`, including the closing `}`.
+    fn argument(&mut self, in_plural: bool) -> Result<Part, String> {
+        let name = self.word();
+        if name.is_empty() {
+            return Err(self.error("Expected an argument name"));
+        }
+
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Part::Arg(name));
+        }
+        self.expect(',')?;
+
+        let kind = self.word();
+        let part = match kind.as_str() {
+            "plural" | "select" => {
+                self.expect(',')?;
+                let branches = self.branches(kind == "plural" || in_plural)?;
+                if !branches.iter().any(|(selector, _)| selector == "other") {
+                    return Err(
+                        self.error(&format!("{} of {} needs an 'other' branch", kind, name))
+                    );
+                }
+                if kind == "plural" {
+                    Part::Plural(name, branches)
+                } else {
+                    Part::Select(name, branches)
+                }
+            }
+            "number" | "date" | "time" => {
+                self.skip_whitespace();
+                let style = if self.peek() == Some(',') {
+                    self.pos += 1;
+                    Some(self.word())
+                } else {
+                    None
+                };
+                match kind.as_str() {
+                    "number" => Part::Number(name, style),
+                    "date" => Part::Date(name, style),
+                    _ => Part::Time(name),
+                }
+            }
+            _ => return Err(self.error(&format!("Unknown argument type '{}'", kind))),
+        };
+
+        self.expect('}')?;
+        Ok(part)
+    }
+
+    /// Parses `selector {message}` pairs up to the closing `}` of the argument.
+    fn branches(&mut self, in_plural: bool) -> Result<Vec<(String, Vec<Part>)>, String> {
+        let mut branches = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some('}') || self.peek().is_none() {
+                break;
+            }
+            let selector = self.word();
+            if selector.is_empty() {
+                return Err(self.error("Expected a selector"));
+            }
+            self.expect('{')?;
+            let message = self.message(in_plural)?;
+            self.expect('}')?;
+            branches.push((selector, message));
+        }
+        Ok(branches)
+    }
+}
+
+/// The CLDR plural category of `n` in `lang`, for the languages Avi ships.
+pub fn plural_category(lang: &str, n: f64) -> &'static str {
+    let integer = n.fract() == 0.0;
+    let i = n.abs().trunc() as u64;
+
+    let pt_pt = lang.eq_ignore_ascii_case("pt-pt") || lang.eq_ignore_ascii_case("pt_pt");
+
+    match base_lang(lang).as_str() {
+        // "one" covers 0 and 1 in French and in Portuguese, except pt-PT
+        "fr" | "pt" if !pt_pt => {
+            if i <= 1 {
+                "one"
+            } else {
+                "other"
+            }
+        }
+        "ja" | "zh" | "ko" => "other",
+        _ => {
+            if integer && i == 1 {
+                "one"
+            } else {
+                "other"
+            }
+        }
+    }
+}
+
+fn base_lang(lang: &str) -> String {
+    lang.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Decimal and grouping separators of a language.
+fn separators(lang: &str) -> (char, char) {
+    match base_lang(lang).as_str() {
+        "pt" | "es" | "it" | "de" | "nl" => (',', '.'),
+        "fr" => (',', '\u{202f}'),
+        _ => ('.', ','),
+    }
+}
+
+/// Formats a number with the separators of `lang`, `style` being `integer` or `percent`.
+///
+/// Like ICU, numbers without a style keep at most 3 decimals.
+pub fn format_number(lang: &str, n: f64, style: Option<&str>) -> String {
+    let (n, suffix, decimals) = match style {
+        Some("integer") => (n.round(), "", 0),
+        Some("percent") => ((n * 100.0).round(), "%", 0),
+        _ => (n, "", 3),
+    };
+    let (decimal, group) = separators(lang);
+
+    let text = format!("{:.*}", decimals, n.abs());
+    let (int_part, frac_part) = match text.split_once('.') {
+        Some((i, f)) => (i.to_string(), f.trim_end_matches('0').to_string()),
+        None => (text, String::new()),
+    };
+
+    let mut digits = String::new();
+    for (n, c) in int_part.chars().enumerate() {
+        if n > 0 && (int_part.len() - n) % 3 == 0 {
+            digits.push(group);
+        }
+        digits.push(c);
+    }
+
+    let sign = if n < 0.0 { "-" } else { "" };
+    if frac_part.is_empty() {
+        format!("{}{}{}", sign, digits, suffix)
+    } else {
+        format!("{}{}{}{}{}", sign, digits, decimal, frac_part, suffix)
+    }
+}
+
+fn to_datetime(value: &Value) -> Option<DateTime<Tz>> {
+    let tz = user_timezone();
+    match value {
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|d| d.with_timezone(&tz)),
+        Value::Number(n) => tz.timestamp_opt(n.as_i64()?, 0).single(),
+        _ => None,
+    }
+}
+
+fn format_date(lang: &str, date: DateTime<Tz>, style: Option<&str>) -> String {
+    let day_first = base_lang(lang) != "en";
+    let pattern = match (style, day_first) {
+        (Some("short"), true) => "%d/%m/%y",
+        (Some("short"), false) => "%m/%d/%y",
+        (Some("long"), true) => "%-d/%m/%Y %H:%M",
+        (Some("long"), false) => "%m/%d/%Y %-I:%M %p",
+        (_, true) => "%d/%m/%Y",
+        (_, false) => "%m/%d/%Y",
+    };
+    date.format(pattern).to_string()
+}
+
+fn format_time(lang: &str, date: DateTime<Tz>) -> String {
+    if base_lang(lang) == "en" {
+        date.format("%-I:%M %p").to_string()
+    } else {
+        date.format("%H:%M").to_string()
+    }
+}
+
+/// The value of an argument as text, numbers using the decimal separator of `lang`.
+///
+/// Numbers are written in full, digits are only grouped and decimals only rounded for
+/// `{arg, number}`, so years, ids and measurements read as written.
+fn value_text(lang: &str, value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n
+            .as_f64()
+            .map(|n| n.to_string().replace('.', &separators(lang).0.to_string()))
+            .unwrap_or_else(|| n.to_string()),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn render(
+    lang: &str,
+    parts: &[Part],
+    args: &HashMap<String, Value>,
+    plural: Option<f64>,
+    out: &mut String,
+) -> Result<(), String> {
+    let arg = |name: &str| {
+        args.get(name)
+            .ok_or_else(|| format!("Missing argument '{}'", name))
+    };
+
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Hash => match plural {
+                Some(n) => out.push_str(&format_number(lang, n, None)),
+                None => out.push('#'),
+            },
+            Part::Arg(name) => out.push_str(&value_text(lang, arg(name)?)),
+            Part::Number(name, style) => {
+                let n = number(arg(name)?).ok_or_else(|| format!("'{}' is not a number", name))?;
+                out.push_str(&format_number(lang, n, style.as_deref()));
+            }
+            Part::Date(name, style) => {
+                let date =
+                    to_datetime(arg(name)?).ok_or_else(|| format!("'{}' is not a date", name))?;
+                out.push_str(&format_date(lang, date, style.as_deref()));
+            }
+            Part::Time(name) => {
+                let date =
+                    to_datetime(arg(name)?).ok_or_else(|| format!("'{}' is not a date", name))?;
+                out.push_str(&format_time(lang, date));
+            }
+            Part::Plural(name, branches) => {
+                let n = number(arg(name)?).ok_or_else(|| format!("'{}' is not a number", name))?;
+                let exact = format!("={}", n);
+                let category = plural_category(lang, n);
+                let branch = branches
+                    .iter()
+                    .find(|(selector, _)| *selector == exact)
+                    .or_else(|| branches.iter().find(|(selector, _)| selector == category))
+                    .or_else(|| branches.iter().find(|(selector, _)| selector == "other"));
+                if let Some((_, message)) = branch {
+                    render(lang, message, args, Some(n), out)?;
+                }
+            }
+            Part::Select(name, branches) => {
+                let value = value_text(lang, arg(name)?);
+                let branch = branches
+                    .iter()
+                    .find(|(selector, _)| *selector == value)
+                    .or_else(|| branches.iter().find(|(selector, _)| selector == "other"));
+                if let Some((_, message)) = branch {
+                    render(lang, message, args, plural, out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Formats `pattern` in `lang` with the named `args`.
+///
+/// # Errors
+///
+/// Returns an error if the pattern is malformed, an argument is missing or has the wrong type.
+pub fn format(pattern: &str, lang: &str, args: &HashMap<String, Value>) -> Result<String, String> {
+    let mut parser = Parser::new(pattern);
+    let parts = parser.message(false)?;
+    if parser.peek().is_some() {
+        return Err(parser.error("Unexpected '}'"));
+    }
+
+    let mut out = String::new();
+    render(lang, &parts, args, None, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_plurals_follow_the_language() {
+        let pattern = "{count, plural, =0 {no minutes} one {# minute} other {# minutes}}";
+        let fmt = |lang, n| format(pattern, lang, &args(json!({ "count": n }))).unwrap();
+
+        assert_eq!(fmt("en", 0), "no minutes");
+        assert_eq!(fmt("en", 1), "1 minute");
+        assert_eq!(fmt("en", 1500), "1,500 minutes");
+        assert_eq!(fmt("pt", 1), "1 minute");
+        assert_eq!(fmt("pt-BR", 2000), "2.000 minutes");
+        assert_eq!(plural_category("pt", 0.0), "one");
+        assert_eq!(plural_category("pt-PT", 0.0), "other");
+        assert_eq!(plural_category("en", 1.5), "other");
+    }
+
+    #[test]
+    fn test_select_and_nested_arguments() {
+        let pattern = "{gender, select, female {Ela chegou {count, plural, one {# dia} other {# dias}} antes} other {{name} chegou}}";
+
+        assert_eq!(
+            format(
+                pattern,
+                "pt",
+                &args(json!({ "gender": "female", "count": 3 }))
+            )
+            .unwrap(),
+            "Ela chegou 3 dias antes"
+        );
+        assert_eq!(
+            format(
+                pattern,
+                "pt",
+                &args(json!({ "gender": "male", "name": "Rui" }))
+            )
+            .unwrap(),
+            "Rui chegou"
+        );
+    }
+
+    #[test]
+    fn test_numbers_dates_and_quotes() {
+        let values = args(json!({ "n": 1234.5, "ratio": 0.25, "when": "2025-03-04T10:30:00Z" }));
+
+        assert_eq!(format("{n, number}", "en", &values).unwrap(), "1,234.5");
+        assert_eq!(
+            format("{n, number, integer}", "pt", &values).unwrap(),
+            "1.235"
+        );
+        assert_eq!(
+            format("{ratio, number, percent}", "en", &values).unwrap(),
+            "25%"
+        );
+        assert!(
+            format("{when, date}", "pt", &values)
+                .unwrap()
+                .ends_with("/03/2025")
+        );
+        assert_eq!(
+            format("It's '{literal}' and {n}", "en", &values).unwrap(),
+            "It's {literal} and 1234.5"
+        );
+    }
+
+    #[test]
+    fn test_plain_arguments_are_not_grouped() {
+        let values = args(json!({ "year": 2025, "n": 1234.5, "precise": 0.12345 }));
+
+        assert_eq!(format("{year}", "pt", &values).unwrap(), "2025");
+        assert_eq!(format("{n}", "pt", &values).unwrap(), "1234,5");
+        assert_eq!(format("{precise}", "pt", &values).unwrap(), "0,12345");
+        assert_eq!(format("{year, number}", "pt", &values).unwrap(), "2.025");
+        assert_eq!(format("{precise, number}", "pt", &values).unwrap(), "0,123");
+    }
+
+    #[test]
+    fn test_dates_use_the_user_timezone() {
+        let values = args(json!({ "when": "2025-03-04T23:30:00Z" }));
+
+        // Without a runtime the user's timezone is UTC, whatever the system one is
+        assert_eq!(format("{when, date}", "pt", &values).unwrap(), "04/03/2025");
+        assert_eq!(format("{when, time}", "pt", &values).unwrap(), "23:30");
+    }
+
+    #[test]
+    fn test_errors() {
+        let values = args(json!({ "n": 1 }));
+        assert!(format("{missing}", "en", &values).is_err());
+        assert!(format("{n, plural, one {x}}", "en", &values).is_err());
+        assert!(format("{n, unknown}", "en", &values).is_err());
+        assert!(format("{n", "en", &values).is_err());
+    }
+}
@@ -1,6 +1,12 @@
+pub mod follow_up;
 pub mod intent;
+pub mod intent_file;
 pub mod lang_parse;
 pub mod languages;
+pub mod matcher;
+pub mod message_format;
 pub mod reply;
+pub mod request;
 pub mod response;
+pub mod slot_filling;
 pub mod utils;
@@ -1,30 +1,34 @@
+use crate::dialogue::intent::Intent;
 use crate::dialogue::languages::locale;
-use crate::dialogue::response::{ResponseValidator, ValidationError};
-use crate::speak;
+use crate::dialogue::request::{DEFAULT_ORIGIN, Origin};
+use crate::dialogue::response::{ReplyValue, ResponseValidator, ValidationError};
+use crate::dialogue::slot_filling::SlotFilling;
+use crate::events::REPLY_TIMED_OUT;
+use crate::{emit, speak};
 use log::{debug, info, trace, warn};
-use rhai::FnPtr;
+use rhai::{Dynamic, FnPtr};
+use serde_json::json;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
+/// Keeps one pending reply per device or session, so conversations on different
+/// listeners don't replace each other's questions.
 pub struct ReplyManager {
-    pending_reply: Arc<Mutex<Option<PendingReply>>>,
+    pending_replies: Arc<Mutex<HashMap<String, PendingReply>>>,
     config: ReplyConfig,
 }
 
 pub trait ValidatorErasure: Send + Sync {
-    fn validate_erased(&self, text: &str) -> Result<String, ValidationError>;
+    fn validate_erased(&self, text: &str) -> Result<Dynamic, ValidationError>;
     fn clear_text(&self, text: &str) -> String;
     fn get_error_txt(&self, error: &ValidationError) -> String;
 }
 
-impl<V: ResponseValidator + Send + Sync> ValidatorErasure for V
-where
-    V::Output: std::fmt::Debug,
-{
-    fn validate_erased(&self, text: &str) -> Result<String, ValidationError> {
-        self.validate_and_parse(text)
-            .map(|output| format!("{:?}", output))
+impl<V: ResponseValidator + Send + Sync> ValidatorErasure for V {
+    fn validate_erased(&self, text: &str) -> Result<Dynamic, ValidationError> {
+        self.validate_and_parse(text).map(ReplyValue::into_dynamic)
     }
 
     fn clear_text(&self, text: &str) -> String {
@@ -36,28 +40,68 @@ where
     }
 }
 
+/// What happens with a valid reply.
+pub enum ReplyHandler {
+    /// Calls a function of the skill with the parsed reply.
+    Function(FnPtr),
+    /// Adds the parsed reply to an intent that is missing a required slot.
+    Slot(Box<SlotFilling>),
+    /// Runs an intent the user confirmed they meant, or asks about the next candidate.
+    Confirm(Box<Intent>, Vec<Intent>),
+}
+
+impl ReplyHandler {
+    pub fn name(&self) -> String {
+        match self {
+            ReplyHandler::Function(f) => f.fn_name().to_string(),
+            ReplyHandler::Slot(filling) => format!("slot:{}", filling.slot.name),
+            ReplyHandler::Confirm(intent, _) => format!(
+                "confirm:{}",
+                intent
+                    .intent
+                    .as_ref()
+                    .map(|i| i.0.intent_name.as_str())
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
 pub struct RequestReply {
     pub skill_request: String,
-    pub handler: FnPtr,
+    pub handler: ReplyHandler,
     pub validator: Box<dyn ValidatorErasure>,
 }
 
 pub struct PendingReply {
     pub skill_request: String,
-    pub handler: FnPtr,
+    pub handler: ReplyHandler,
     validator: Box<dyn ValidatorErasure>,
     created_at: Instant,
     retry_count: usize,
 }
 
 pub struct Replayed {
-    pub parsed_output: String,
+    /// The reply as the validator saw it.
+    pub text: String,
+    pub parsed_output: Dynamic,
     pub pending_reply: PendingReply,
 }
 
+/// A summary of a pending reply, as returned by [`ReplyManager::list`].
+#[derive(Debug, Clone)]
+pub struct PendingReplyInfo {
+    pub origin: String,
+    pub skill_request: String,
+    pub handler: String,
+    pub age: Duration,
+    pub retry_count: usize,
+}
+
 impl Replayed {
-    pub fn new(parsed_output: String, pending_reply: PendingReply) -> Self {
+    pub fn new(text: String, parsed_output: Dynamic, pending_reply: PendingReply) -> Self {
         Self {
+            text,
             parsed_output,
             pending_reply,
         }
@@ -82,110 +126,328 @@ impl Default for ReplyConfig {
 impl ReplyManager {
     pub fn new(config: Option<ReplyConfig>) -> Self {
         Self {
-            pending_reply: Arc::new(Mutex::new(None)),
+            pending_replies: Arc::new(Mutex::new(HashMap::new())),
             config: config.unwrap_or_default(),
         }
     }
 
-    pub async fn set_reply(&self, request: RequestReply) {
-        self.cancel().await;
+    fn is_expired(&self, pending: &PendingReply) -> bool {
+        pending.created_at.elapsed() > Duration::from_secs(self.config.timeout_secs)
+    }
+
+    /// Waits for a reply from `origin`, replacing the one it was already waiting for.
+    ///
+    /// The reply times out on its own after the configured timeout, telling the user.
+    ///
+    /// # Returns
+    ///
+    /// Whether the reply is awaited. Code that runs outside a conversation, e.g. a scheduled
+    /// job, has no device to get a reply from.
+    pub async fn set_reply(&self, origin: &str, request: RequestReply) -> bool {
+        if origin == DEFAULT_ORIGIN {
+            warn!(
+                "Skill {} requested a reply outside of a conversation, no device can answer it.",
+                request.skill_request
+            );
+            return false;
+        }
+        self.cancel(origin).await;
 
         info!(
-            "Skill {} requested reply for handler {}.",
-            request.skill_request, request.handler
+            "Skill {} requested reply from {} for handler {}.",
+            request.skill_request,
+            origin,
+            request.handler.name()
         );
+        let created_at = Instant::now();
         let pending = PendingReply {
             skill_request: request.skill_request.clone(),
             handler: request.handler,
             validator: request.validator,
-            created_at: Instant::now(),
+            created_at,
             retry_count: 0,
         };
 
-        *self.pending_reply.lock().await = Some(pending);
+        self.pending_replies
+            .lock()
+            .await
+            .insert(origin.to_string(), pending);
+
+        let pending_replies = Arc::clone(&self.pending_replies);
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let origin = origin.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let expired = {
+                let mut pending_replies = pending_replies.lock().await;
+                // Only this reply, not a newer one set for the same origin since
+                match pending_replies.get(&origin) {
+                    Some(pending) if pending.created_at == created_at => {
+                        pending_replies.remove(&origin)
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(pending) = expired {
+                Self::time_out(&origin, &pending);
+            }
+        });
+        true
+    }
+
+    /// Cancels the reply `origin` is waiting for, returning whether there was one.
+    pub async fn cancel(&self, origin: &str) -> bool {
+        let canceled = self.pending_replies.lock().await.remove(origin).is_some();
+        if canceled {
+            info!("Canceled pending reply from {}.", origin);
+        }
+        canceled
     }
 
-    pub async fn cancel(&self) {
-        if self.pending_reply.lock().await.is_some() {
-            info!("Canceled pending reply.");
-            *self.pending_reply.lock().await = None;
+    /// Cancels the reply `origin` is waiting for if `skill` asked for it, returning whether
+    /// there was one.
+    pub async fn cancel_of(&self, origin: &str, skill: &str) -> bool {
+        let mut pending_replies = self.pending_replies.lock().await;
+        if pending_replies
+            .get(origin)
+            .is_none_or(|pending| pending.skill_request != skill)
+        {
+            return false;
         }
+        pending_replies.remove(origin);
+        info!("Canceled pending reply from {} to {}.", origin, skill);
+        true
     }
 
+    /// Cancels every pending reply.
     #[allow(dead_code)]
-    pub async fn has_pending(&self) -> bool {
-        self.pending_reply.lock().await.is_some()
+    pub async fn cancel_all(&self) {
+        self.pending_replies.lock().await.clear();
     }
 
-    /// Processes incoming text against pending reply
-    /// Returns true if text was consumed by reply handler
-    pub async fn process_text(&self, text: &str) -> Result<Replayed, String> {
-        trace!("Processing text for pending reply: {}", text);
-        let mut pending_lock = self.pending_reply.lock().await;
-
-        if let Some(mut pending) = pending_lock.take() {
-            if pending.created_at.elapsed() > Duration::from_secs(self.config.timeout_secs) {
-                info!("Reply from skill {} timed out.", pending.skill_request);
-                return Err("".to_string());
+    #[allow(dead_code)]
+    pub async fn has_pending(&self, origin: &str) -> bool {
+        self.pending_replies.lock().await.contains_key(origin)
+    }
+
+    /// Lists the replies that have not timed out yet.
+    pub async fn list(&self) -> Vec<PendingReplyInfo> {
+        let mut pending_replies = self.pending_replies.lock().await;
+        self.remove_expired(&mut pending_replies);
+
+        pending_replies
+            .iter()
+            .map(|(origin, pending)| PendingReplyInfo {
+                origin: origin.clone(),
+                skill_request: pending.skill_request.clone(),
+                handler: pending.handler.name(),
+                age: pending.created_at.elapsed(),
+                retry_count: pending.retry_count,
+            })
+            .collect()
+    }
+
+    fn remove_expired(&self, pending_replies: &mut HashMap<String, PendingReply>) {
+        pending_replies.retain(|origin, pending| {
+            if !self.is_expired(pending) {
+                return true;
             }
+            Self::time_out(origin, pending);
+            false
+        });
+    }
 
-            if let Some(max) = self.config.max_retries
-                && pending.retry_count >= max
-            {
-                warn!(
-                    "Too many invalid attempts for skill {}. Cancelling request.",
-                    pending.skill_request
+    /// Reports a reply that timed out and tells the user it is no longer awaited.
+    fn time_out(origin: &str, pending: &PendingReply) {
+        info!(
+            "Reply from {} for skill {} timed out.",
+            origin, pending.skill_request
+        );
+        emit!(
+            REPLY_TIMED_OUT,
+            json!({ "skill": pending.skill_request, "origin": origin })
+        );
+        speak!(locale: "reply_timed_out", to: Origin::new(origin, None));
+    }
+
+    /// Processes incoming text from `origin` against the reply it is waiting for
+    /// Returns true if text was consumed by reply handler
+    pub async fn process_text(&self, origin: &Origin, text: &str) -> Result<Replayed, String> {
+        trace!(
+            "[{}] Processing text from {} for pending reply: {}",
+            origin.trace_id, origin.device, text
+        );
+        // Taken out so the lock isn't held while validating, a script validator may call
+        // back into the reply manager
+        let pending = {
+            let mut pending_replies = self.pending_replies.lock().await;
+            self.remove_expired(&mut pending_replies);
+            pending_replies.remove(&origin.device)
+        };
+        let Some(mut pending) = pending else {
+            trace!("No pending reply to process text against.");
+            return Err("".to_string());
+        };
+
+        if let Some(max) = self.config.max_retries
+            && pending.retry_count >= max
+        {
+            warn!(
+                "[{}] Too many invalid attempts for skill {}. Cancelling request.",
+                origin.trace_id, pending.skill_request
+            );
+            speak!(locale: "to_many_replay_trys", to: origin);
+            return Err("Too many invalid attempts. Cancelling request.".to_string());
+        }
+
+        let cleaned = pending.validator.clear_text(text);
+        debug!("Cleaned text for validation: '{}'", cleaned);
+
+        match pending.validator.validate_erased(&cleaned) {
+            Ok(parsed_output) => {
+                info!(
+                    "[{}] Successfully parsed reply for skill {}: {}",
+                    origin.trace_id, pending.skill_request, parsed_output
                 );
-                speak!(locale: "to_many_replay_trys");
-                return Err("Too many invalid attempts. Cancelling request.".to_string());
+                Ok(Replayed::new(cleaned, parsed_output, pending))
             }
+            Err(error) => {
+                pending.retry_count += 1;
+                let error_msg = pending.validator.get_error_txt(&error);
 
-            let cleaned = pending.validator.clear_text(text);
-            debug!("Cleaned text for validation: '{}'", cleaned);
+                warn!(
+                    "[{}] Reply for skill {} attempt {}/{} failed: {:?}",
+                    origin.trace_id,
+                    pending.skill_request,
+                    pending.retry_count,
+                    self.config.max_retries.unwrap_or(0),
+                    error
+                );
 
-            match pending.validator.validate_erased(&cleaned) {
-                Ok(parsed_output) => {
+                if let Some(max) = self.config.max_retries
+                    && pending.retry_count >= max
+                {
                     info!(
-                        "Successfully parsed reply for skill {}: {}",
-                        pending.skill_request, parsed_output
+                        "Too many invalid attempts for skill {}. Cancelling request.",
+                        pending.skill_request
                     );
-                    Ok(Replayed::new(parsed_output, pending))
+                    speak!(locale: "to_many_replay_trys", to: origin);
+                    return Err("Too many invalid attempts. Cancelling request.".to_string());
                 }
-                Err(error) => {
-                    pending.retry_count += 1;
-                    let error_msg = pending.validator.get_error_txt(&error);
-
-                    warn!(
-                        "Reply for skill {} attempt {}/{} failed: {:?}",
-                        pending.skill_request,
-                        pending.retry_count,
-                        self.config.max_retries.unwrap_or(0),
-                        error
-                    );
 
-                    if let Some(max) = self.config.max_retries
-                        && pending.retry_count >= max
-                    {
-                        info!(
-                            "Too many invalid attempts for skill {}. Cancelling request.",
-                            pending.skill_request
-                        );
-                        speak!(locale: "to_many_replay_trys");
-                        return Err("Too many invalid attempts. Cancelling request.".to_string());
-                    }
+                if self.is_expired(&pending) {
+                    Self::time_out(&origin.device, &pending);
+                    return Err("The reply timed out.".to_string());
+                }
 
-                    *pending_lock = Some(pending);
-                    speak!(locale: error_msg.as_str());
+                // A reply set while validating is newer than this one
+                self.pending_replies
+                    .lock()
+                    .await
+                    .entry(origin.device.clone())
+                    .or_insert(pending);
+                if let ValidationError::Rejected(message) = error {
+                    speak!(&message, to: origin);
+                    return Err(message);
+                }
+                speak!(locale: error_msg.as_str(), to: origin);
 
-                    match locale(&error_msg) {
-                        Some(v) => Err(v.to_string()),
-                        None => Err(error_msg),
-                    }
+                match locale(&error_msg) {
+                    Some(v) => Err(v.to_string()),
+                    None => Err(error_msg),
                 }
             }
-        } else {
-            trace!("No pending reply to process text against.");
-            Err("".to_string())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialogue::response::{AnyValidator, ScriptCall, ScriptValidator};
+
+    fn request(handler: &str) -> RequestReply {
+        RequestReply {
+            skill_request: "skill".to_string(),
+            handler: ReplyHandler::Function(FnPtr::new(handler).unwrap()),
+            validator: Box::new(AnyValidator::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replies_are_kept_per_origin() {
+        let manager = ReplyManager::new(None);
+        manager.set_reply("kitchen", request("on_kitchen")).await;
+        manager.set_reply("bedroom", request("on_bedroom")).await;
+        assert_eq!(manager.list().await.len(), 2);
+
+        let replayed = manager
+            .process_text(&Origin::new("kitchen", None), " yes ")
+            .await
+            .unwrap();
+        assert_eq!(replayed.pending_reply.handler.name(), "on_kitchen");
+        assert_eq!(replayed.parsed_output.cast::<String>(), "yes");
+
+        assert!(!manager.has_pending("kitchen").await);
+        assert!(!manager.cancel_of("bedroom", "other").await);
+        assert!(manager.cancel_of("bedroom", "skill").await);
+        assert!(manager.list().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replies_time_out_on_their_own() {
+        let manager = ReplyManager::new(Some(ReplyConfig {
+            timeout_secs: 0,
+            max_retries: None,
+        }));
+        assert!(manager.set_reply("kitchen", request("on_reply")).await);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(manager.pending_replies.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replies_need_a_device() {
+        let manager = ReplyManager::new(None);
+        assert!(!manager.set_reply(DEFAULT_ORIGIN, request("on_reply")).await);
+        assert!(!manager.has_pending(DEFAULT_ORIGIN).await);
+    }
+
+    /// A script validator that asks the reply manager whether it can see the pending replies.
+    fn touching(manager: &Arc<ReplyManager>) -> RequestReply {
+        let manager = Arc::clone(manager);
+        let call: ScriptCall = Arc::new(move |_function, text| {
+            let unlocked = manager.pending_replies.try_lock().is_ok();
+            Ok(if unlocked && text == "yes" {
+                true.into()
+            } else {
+                Dynamic::UNIT
+            })
+        });
+        RequestReply {
+            skill_request: "skill".to_string(),
+            handler: ReplyHandler::Function(FnPtr::new("on_reply").unwrap()),
+            validator: Box::new(ScriptValidator::new(
+                "skill".to_string(),
+                call,
+                FnPtr::new("validate").unwrap(),
+                None,
+            )),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_script_validators_may_use_the_reply_manager() {
+        let manager = Arc::new(ReplyManager::new(None));
+        let origin = Origin::new("kitchen", None);
+        manager.set_reply("kitchen", touching(&manager)).await;
+
+        // A rejected reply is kept for the next attempt
+        assert!(manager.process_text(&origin, "no").await.is_err());
+        assert_eq!(manager.list().await[0].retry_count, 1);
+
+        let replayed = manager.process_text(&origin, "yes").await.unwrap();
+        assert_eq!(replayed.pending_reply.handler.name(), "on_reply");
+        assert!(!manager.has_pending("kitchen").await);
+    }
+}
@@ -0,0 +1,165 @@
+use log::warn;
+use serde::Deserialize;
+use std::cell::RefCell;
+
+/// Origin used for text handled outside of a conversation, e.g. from a topic handler.
+pub const DEFAULT_ORIGIN: &str = "local";
+
+thread_local! {
+    static ORIGIN: RefCell<Option<Origin>> = const { RefCell::new(None) };
+}
+
+/// The payload of `intent/execute/text`.
+///
+/// Devices should send the JSON envelope, plain UTF-8 text is still accepted
+/// and treated as a request from the sending peer.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TextRequest {
+    pub text: String,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub trace_id: Option<String>,
+}
+
+impl TextRequest {
+    /// Parses a payload, falling back to plain text when it is not a JSON envelope.
+    pub fn parse(payload: &[u8]) -> Self {
+        match serde_json::from_slice::<TextRequest>(payload) {
+            Ok(mut request) => {
+                request.text = request.text.trim().to_string();
+                request
+            }
+            Err(_) => Self {
+                text: String::from_utf8_lossy(payload).trim().to_string(),
+                device_id: None,
+                trace_id: None,
+            },
+        }
+    }
+
+    /// The origin of the request, always `from`, the peer the transport got it from.
+    ///
+    /// A `device_id` naming another device is ignored, otherwise any peer could answer the
+    /// pending reply of another device.
+    pub fn origin(&self, from: &str) -> Origin {
+        if let Some(device) = &self.device_id
+            && device != from
+        {
+            warn!(
+                "Ignoring device_id {} of a request sent by {}",
+                device, from
+            );
+        }
+        Origin::new(from, self.trace_id.clone())
+    }
+}
+
+/// The device a piece of text came from and the trace id correlating everything
+/// done on its behalf.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub device: String,
+    pub trace_id: String,
+}
+
+impl Origin {
+    /// Creates an origin, generating a trace id when the device didn't send one.
+    pub fn new(device: &str, trace_id: Option<String>) -> Self {
+        Self {
+            device: device.to_string(),
+            trace_id: trace_id
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// Whether the text came from a known device rather than from [`DEFAULT_ORIGIN`].
+    pub fn is_device(&self) -> bool {
+        self.device != DEFAULT_ORIGIN
+    }
+}
+
+/// Runs `f` with `origin` as the device whose text is being handled.
+///
+/// Skills run synchronously on the calling thread, so replies they request and
+/// text they speak while `f` runs belong to this origin.
+pub fn with_origin<T>(origin: &Origin, f: impl FnOnce() -> T) -> T {
+    let _restore = RestoreOrigin(ORIGIN.with(|o| o.replace(Some(origin.clone()))));
+    f()
+}
+
+/// Puts the previous origin back when dropped, even if the skill panicked.
+struct RestoreOrigin(Option<Origin>);
+
+impl Drop for RestoreOrigin {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        ORIGIN.with(|o| *o.borrow_mut() = previous);
+    }
+}
+
+/// The origin of the text being handled on this thread, if any.
+pub fn current_origin() -> Option<Origin> {
+    ORIGIN.with(|o| o.borrow().clone())
+}
+
+/// The device whose text is being handled on this thread, or [`DEFAULT_ORIGIN`].
+pub fn current_device() -> String {
+    current_origin()
+        .map(|o| o.device)
+        .unwrap_or(DEFAULT_ORIGIN.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_envelope_and_plain_text() {
+        let request = TextRequest::parse(
+            br#"{"text": " lights on ", "device_id": "mic-1", "trace_id": "t-1"}"#,
+        );
+        assert_eq!(request.text, "lights on");
+        assert_eq!(
+            request.origin("mic-1"),
+            Origin::new("mic-1", Some("t-1".into()))
+        );
+
+        let request = TextRequest::parse(b"  lights on\n");
+        assert_eq!(request.text, "lights on");
+        assert_eq!(request.origin("peer").device, "peer");
+        assert!(!request.origin("peer").trace_id.is_empty());
+
+        let request = TextRequest::parse(br#"{"text": "hi"}"#);
+        assert_eq!(request.origin("peer").device, "peer");
+
+        let request = TextRequest::parse(br#"{"text": "yes", "device_id": "mic-1"}"#);
+        assert_eq!(request.origin("intruder").device, "intruder");
+    }
+
+    #[test]
+    fn test_origin_is_scoped_to_the_call() {
+        let kitchen = Origin::new("kitchen", None);
+        let bedroom = Origin::new("bedroom", None);
+
+        assert_eq!(current_device(), DEFAULT_ORIGIN);
+        with_origin(&kitchen, || {
+            assert_eq!(current_device(), "kitchen");
+            with_origin(&bedroom, || {
+                assert_eq!(current_origin(), Some(bedroom.clone()))
+            });
+            assert_eq!(current_device(), "kitchen");
+        });
+        assert_eq!(current_origin(), None);
+    }
+
+    #[test]
+    fn test_origin_is_restored_after_a_panic() {
+        let kitchen = Origin::new("kitchen", None);
+
+        let result = std::panic::catch_unwind(|| with_origin(&kitchen, || panic!("skill failed")));
+        assert!(result.is_err());
+        assert_eq!(current_origin(), None);
+    }
+}
@@ -1,21 +1,66 @@
-use crate::dialogue::languages::get_translation_list;
+use crate::data::user::user_timezone;
+use crate::dialogue::lang_parse::{extract_datetime, extract_duration, extract_number};
+use crate::dialogue::languages::{get_translation_list, user_language};
+use chrono::Utc;
+use log::warn;
+use regex::Regex;
 use rhai::CustomType;
 use rhai::Dynamic;
 use rhai::EvalAltResult;
+use rhai::FnPtr;
 use rhai::Position;
 use rhai::TypeBuilder;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ValidationError {
     #[allow(dead_code)]
     ParseError(String),
     NotAccepted,
+    /// Not accepted, with what to tell the user instead of the validator's error key.
+    Rejected(String),
+}
+
+/// Converts a validator output into the value handed to the reply handler.
+pub trait ReplyValue {
+    fn into_dynamic(self) -> Dynamic;
+}
+
+impl ReplyValue for String {
+    fn into_dynamic(self) -> Dynamic {
+        self.into()
+    }
+}
+
+impl ReplyValue for bool {
+    fn into_dynamic(self) -> Dynamic {
+        self.into()
+    }
+}
+
+impl ReplyValue for f64 {
+    fn into_dynamic(self) -> Dynamic {
+        Dynamic::from_float(self)
+    }
+}
+
+impl ReplyValue for Dynamic {
+    fn into_dynamic(self) -> Dynamic {
+        self
+    }
+}
+
+impl<T: ReplyValue> ReplyValue for Option<T> {
+    /// `None` becomes `()`.
+    fn into_dynamic(self) -> Dynamic {
+        self.map(ReplyValue::into_dynamic).unwrap_or(Dynamic::UNIT)
+    }
 }
 
 pub trait ResponseValidator {
-    type Output;
+    type Output: ReplyValue;
 
     fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError>;
 
@@ -234,6 +279,282 @@ impl ResponseValidator for MappedValidator {
     }
 }
 
+/// Accepts a number, written with digits or words, optionally within a range.
+#[derive(Debug, Deserialize, CustomType, Clone)]
+pub struct NumberValidator {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl NumberValidator {
+    pub fn new(min: Option<f64>, max: Option<f64>) -> Self {
+        Self { min, max }
+    }
+}
+
+impl ResponseValidator for NumberValidator {
+    type Output = f64;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        let number = extract_number(&self.clear_text(text), &user_language())
+            .0
+            .ok_or_else(|| ValidationError::ParseError(text.to_string()))?;
+
+        if self.min.is_some_and(|min| number < min) || self.max.is_some_and(|max| number > max) {
+            return Err(ValidationError::NotAccepted);
+        }
+        Ok(number)
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "error_validator_number".to_string()
+    }
+}
+
+/// Accepts a date and/or time, replying with an RFC 3339 timestamp in the user's timezone.
+#[derive(Debug, Deserialize, CustomType, Clone)]
+pub struct DatetimeValidator;
+
+impl DatetimeValidator {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ResponseValidator for DatetimeValidator {
+    type Output = String;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        let now = Utc::now().with_timezone(&user_timezone());
+        extract_datetime(&self.clear_text(text), &user_language(), now)
+            .datetime
+            .ok_or_else(|| ValidationError::ParseError(text.to_string()))
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "error_validator_datetime".to_string()
+    }
+}
+
+/// Accepts a duration, replying with its length in seconds.
+#[derive(Debug, Deserialize, CustomType, Clone)]
+pub struct DurationValidator;
+
+impl DurationValidator {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ResponseValidator for DurationValidator {
+    type Output = f64;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        extract_duration(&self.clear_text(text), &user_language())
+            .duration
+            .ok_or_else(|| ValidationError::ParseError(text.to_string()))
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "error_validator_duration".to_string()
+    }
+}
+
+/// Accepts replies matching a regular expression, replying with the matched text.
+#[derive(Debug, CustomType, Clone)]
+pub struct RegexValidator {
+    #[rhai_type(readonly)]
+    pub pattern: String,
+    #[rhai_type(skip)]
+    regex: Regex,
+}
+
+impl RegexValidator {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+
+impl ResponseValidator for RegexValidator {
+    type Output = String;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        self.regex
+            .find(&self.clear_text(text))
+            .map(|m| m.as_str().to_string())
+            .ok_or(ValidationError::NotAccepted)
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "error_validator_regex".to_string()
+    }
+}
+
+/// Accepts one of a list of options, tolerating typos and misheard words, and replies
+/// with the option as it was given.
+#[derive(Debug, Deserialize, CustomType, Clone)]
+pub struct ChoiceValidator {
+    pub options: Vec<String>,
+}
+
+impl ChoiceValidator {
+    pub fn new(options: Vec<String>) -> Self {
+        Self { options }
+    }
+
+    /// The edits an option of `len` characters tolerates, one per four characters, so
+    /// options of up to three characters must be said exactly.
+    fn tolerance(len: usize) -> usize {
+        len / 4
+    }
+
+    /// The edit distance between the option and the closest run of words of the reply.
+    fn distance(words: &[&str], option: &str) -> usize {
+        let size = option.split_whitespace().count().max(1);
+        if words.len() <= size {
+            return levenshtein(&words.join(" "), option);
+        }
+        words
+            .windows(size)
+            .map(|w| levenshtein(&w.join(" "), option))
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// The number of single character edits turning `a` into `b`.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(current)
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+impl ResponseValidator for ChoiceValidator {
+    type Output = String;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        let cleaned = self.clear_text(text).to_lowercase();
+        let words: Vec<&str> = cleaned.split_whitespace().collect();
+        if words.is_empty() {
+            return Err(ValidationError::NotAccepted);
+        }
+
+        // Closest first, and the longer option when one is part of the other, "red wine" over "red"
+        let mut matches: Vec<(&String, usize, usize)> = self
+            .options
+            .iter()
+            .map(|option| {
+                let lower = option.to_lowercase();
+                let size = lower.split_whitespace().count();
+                (option, Self::distance(&words, &lower), size, lower)
+            })
+            .filter(|(_, distance, _, lower)| *distance <= Self::tolerance(lower.chars().count()))
+            .map(|(option, distance, size, _)| (option, distance, size))
+            .collect();
+        matches.sort_by_key(|(_, distance, size)| (*distance, std::cmp::Reverse(*size)));
+
+        match matches.as_slice() {
+            [] => Err(ValidationError::NotAccepted),
+            // Two options as close as each other, the user has to say which
+            [(_, distance, size), (_, other_distance, other_size), ..]
+                if distance == other_distance && size == other_size =>
+            {
+                Err(ValidationError::NotAccepted)
+            }
+            [(option, _, _), ..] => Ok((*option).clone()),
+        }
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "error_validator_choice".to_string()
+    }
+}
+
+/// A validator written in a skill's script.
+///
+/// `validate` is called with the reply and returns `()` or `false` to reject it, `true`
+/// to accept it as it is, or the parsed value. The optional `error` function is called
+/// with a rejected reply and returns what to tell the user.
+#[derive(Clone)]
+pub struct ScriptValidator {
+    /// The skill the functions belong to, for the logs.
+    skill: String,
+    call: ScriptCall,
+    validate: FnPtr,
+    error: Option<FnPtr>,
+}
+
+/// Calls a function of a skill's script with the reply.
+pub type ScriptCall = Arc<dyn Fn(FnPtr, String) -> Result<Dynamic, String> + Send + Sync>;
+
+impl ScriptValidator {
+    pub fn new(skill: String, call: ScriptCall, validate: FnPtr, error: Option<FnPtr>) -> Self {
+        Self {
+            skill,
+            call,
+            validate,
+            error,
+        }
+    }
+
+    fn call(&self, function: &FnPtr, text: &str) -> Result<Dynamic, String> {
+        (self.call)(function.clone(), text.to_string())
+    }
+
+    fn rejected(&self, text: &str) -> ValidationError {
+        let Some(error) = &self.error else {
+            return ValidationError::NotAccepted;
+        };
+        match self.call(error, text) {
+            Ok(message) if !message.is_unit() => ValidationError::Rejected(message.to_string()),
+            Ok(_) => ValidationError::NotAccepted,
+            Err(e) => {
+                warn!("Error function of skill {} failed: {}", self.skill, e);
+                ValidationError::NotAccepted
+            }
+        }
+    }
+}
+
+impl ResponseValidator for ScriptValidator {
+    type Output = Dynamic;
+
+    fn validate_and_parse(&self, text: &str) -> Result<Self::Output, ValidationError> {
+        match self.call(&self.validate, text) {
+            Ok(value) if value.is_unit() || value.as_bool() == Ok(false) => {
+                Err(self.rejected(text))
+            }
+            Ok(value) if value.as_bool() == Ok(true) => Ok(text.into()),
+            Ok(value) => Ok(value),
+            Err(e) => {
+                warn!("Validator of skill {} failed: {}", self.skill, e);
+                Err(ValidationError::NotAccepted)
+            }
+        }
+    }
+
+    fn get_error_txt(&self, _error: &ValidationError) -> String {
+        "not_valid_error".to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -534,4 +855,159 @@ mod tests {
         assert!(validator.is_accepted("no"));
         assert!(!validator.is_accepted("maybe"));
     }
+
+    #[test]
+    fn test_number_validator() {
+        use crate::dialogue::reply::ValidatorErasure;
+
+        let validator = NumberValidator::new(Some(1.0), Some(10.0));
+
+        assert_eq!(validator.validate_and_parse("7").unwrap(), 7.0);
+        assert_eq!(validator.validate_and_parse("set it to 2.5").unwrap(), 2.5);
+        assert_eq!(
+            validator.validate_and_parse("11"),
+            Err(ValidationError::NotAccepted)
+        );
+        assert!(matches!(
+            validator.validate_and_parse("loud"),
+            Err(ValidationError::ParseError(_))
+        ));
+        assert!(validator.validate_erased("3").unwrap().is_float());
+    }
+
+    #[test]
+    fn test_regex_validator() {
+        assert!(RegexValidator::new("[").is_err());
+
+        let validator = RegexValidator::new(r"\d{4}").unwrap();
+        assert_eq!(
+            validator.validate_and_parse("the code is 1234").unwrap(),
+            "1234"
+        );
+        assert!(validator.validate_and_parse("no code").is_err());
+    }
+
+    #[test]
+    fn test_choice_validator_is_fuzzy() {
+        let validator = ChoiceValidator::new(vec![
+            "Banana".to_string(),
+            "Apple".to_string(),
+            "Living Room".to_string(),
+        ]);
+
+        assert_eq!(validator.validate_and_parse("banana").unwrap(), "Banana");
+        assert_eq!(validator.validate_and_parse("bananna").unwrap(), "Banana");
+        assert_eq!(
+            validator.validate_and_parse("I want an aple").unwrap(),
+            "Apple"
+        );
+        assert_eq!(
+            validator.validate_and_parse("the livin room").unwrap(),
+            "Living Room"
+        );
+        assert!(validator.validate_and_parse("orange").is_err());
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_choice_validator_rejects_empty_and_ambiguous_replies() {
+        let validator = ChoiceValidator::new(vec![
+            "Red".to_string(),
+            "Red Wine".to_string(),
+            "Bed".to_string(),
+            "Cart".to_string(),
+            "Card".to_string(),
+        ]);
+
+        assert!(validator.validate_and_parse("").is_err());
+        assert!(validator.validate_and_parse("   ").is_err());
+        // Short options must be said exactly
+        assert_eq!(validator.validate_and_parse("red").unwrap(), "Red");
+        assert!(validator.validate_and_parse("rad").is_err());
+        assert_eq!(
+            validator.validate_and_parse("red wine please").unwrap(),
+            "Red Wine"
+        );
+        // "carx" is one edit from both
+        assert!(validator.validate_and_parse("carx").is_err());
+        assert_eq!(validator.validate_and_parse("card").unwrap(), "Card");
+    }
+
+    fn script_validator(with_error: bool) -> ScriptValidator {
+        let engine = Arc::new(rhai::Engine::new());
+        let ast = Arc::new(
+            engine
+                .compile(
+                    r#"
+                    fn validate(text) {
+                        if text == "blue" { true } else if text == "two" { 2 } else if text == "green" { () } else { false }
+                    }
+                    fn error(text) { `${text} is not a color` }
+                    "#,
+                )
+                .unwrap(),
+        );
+        let call: ScriptCall = Arc::new(move |function: FnPtr, text: String| {
+            function
+                .call::<Dynamic>(&engine, &ast, (text,))
+                .map_err(|e| e.to_string())
+        });
+        ScriptValidator::new(
+            "colors".to_string(),
+            call,
+            FnPtr::new("validate").unwrap(),
+            with_error.then(|| FnPtr::new("error").unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_script_validator_results() {
+        let validator = script_validator(false);
+
+        assert_eq!(
+            validator
+                .validate_and_parse("blue")
+                .unwrap()
+                .cast::<String>(),
+            "blue"
+        );
+        assert_eq!(
+            validator.validate_and_parse("two").unwrap().cast::<i64>(),
+            2
+        );
+        assert_eq!(
+            validator.validate_and_parse("green").unwrap_err(),
+            ValidationError::NotAccepted
+        );
+        assert_eq!(
+            validator.validate_and_parse("red").unwrap_err(),
+            ValidationError::NotAccepted
+        );
+    }
+
+    #[test]
+    fn test_script_validator_error_message_is_rejected() {
+        let validator = script_validator(true);
+
+        assert_eq!(
+            validator.validate_and_parse("red").unwrap_err(),
+            ValidationError::Rejected("red is not a color".to_string())
+        );
+        assert!(validator.validate_and_parse("blue").is_ok());
+    }
+
+    #[test]
+    fn test_reply_values_keep_their_type() {
+        use crate::dialogue::reply::ValidatorErasure;
+
+        assert!(true.into_dynamic().as_bool().unwrap());
+        assert!(None::<String>.into_dynamic().is_unit());
+        assert_eq!(Some("x".to_string()).into_dynamic().cast::<String>(), "x");
+
+        let mut mappings = HashMap::new();
+        mappings.insert("two".to_string(), Dynamic::from(2_i64));
+        let validator = MappedValidator::new(mappings);
+
+        assert_eq!(validator.validate_erased("two").unwrap().as_int(), Ok(2));
+    }
 }
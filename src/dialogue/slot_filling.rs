@@ -0,0 +1,194 @@
+use crate::dialogue::intent::{Intent, Slot};
+use crate::dialogue::intent_file::{IntentDefinition, ValidatorKind};
+use crate::dialogue::reply::ValidatorErasure;
+use crate::dialogue::response::{
+    AnyValidator, BoolValidator, ListOrNoneValidator, OptionalValidator,
+};
+use crate::skills::avi_script::helpers::dynamic_to_json;
+use log::warn;
+use rhai::Dynamic;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How the answer to a slot prompt is validated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlotValidator {
+    Any,
+    Bool,
+    Optional,
+    List(Vec<String>),
+}
+
+impl SlotValidator {
+    pub fn build(&self) -> Box<dyn ValidatorErasure> {
+        match self {
+            SlotValidator::Any => Box::new(AnyValidator::new()),
+            SlotValidator::Bool => Box::new(BoolValidator::new(false)),
+            SlotValidator::Optional => Box::new(OptionalValidator::new()),
+            SlotValidator::List(values) => Box::new(ListOrNoneValidator::new(values.clone())),
+        }
+    }
+}
+
+/// A slot the core asks for when an intent arrives without it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequiredSlot {
+    pub name: String,
+    pub entity: String,
+    /// The locale id of the question asked for the slot.
+    pub prompt: String,
+    pub validator: SlotValidator,
+}
+
+/// The required slots of every intent of a skill, in the order they are asked for.
+#[derive(Debug, Clone, Default)]
+pub struct SlotRequirements(HashMap<String, Vec<RequiredSlot>>);
+
+impl SlotRequirements {
+    /// Loads the required slots from the intent files in `path`.
+    pub fn load(path: &Path) -> Self {
+        Self::new(IntentDefinition::load(path))
+    }
+
+    fn new(definitions: Vec<IntentDefinition>) -> Self {
+        let mut requirements = HashMap::new();
+
+        for definition in definitions {
+            let mut required = Vec::new();
+            for slot in definition.slots.into_iter().filter(|s| s.required) {
+                let Some(prompt) = slot.prompt else {
+                    warn!(
+                        "Required slot {} of {} has no prompt, it won't be asked for",
+                        slot.name, definition.name
+                    );
+                    continue;
+                };
+                let validator = match slot.validator {
+                    ValidatorKind::Any => SlotValidator::Any,
+                    ValidatorKind::Bool => SlotValidator::Bool,
+                    ValidatorKind::Optional => SlotValidator::Optional,
+                    ValidatorKind::List => SlotValidator::List(slot.values),
+                };
+                required.push(RequiredSlot {
+                    name: slot.name,
+                    entity: slot.entity,
+                    prompt,
+                    validator,
+                });
+            }
+
+            if !required.is_empty() {
+                requirements.insert(definition.name, required);
+            }
+        }
+
+        Self(requirements)
+    }
+
+    /// The first required slot `intent` is missing, if any.
+    pub fn missing(&self, intent: &Intent) -> Option<&RequiredSlot> {
+        let name = &intent.intent.as_ref()?.0.intent_name;
+        self.0
+            .get(name)?
+            .iter()
+            .find(|required| !intent.slots.iter().any(|s| s.0.slot_name == required.name))
+    }
+}
+
+/// An intent waiting for the answer to one of its required slots.
+#[derive(Debug, Clone)]
+pub struct SlotFilling {
+    pub intent: Intent,
+    pub slot: RequiredSlot,
+}
+
+impl SlotFilling {
+    /// Adds the answer to the intent, `raw` being the text the user replied with.
+    ///
+    /// The range of the slot points into the reply, not into the intent's input.
+    pub fn fill(mut self, raw: &str, value: Dynamic) -> Result<Intent, String> {
+        let slot = json!({
+            "range": { "start": 0, "end": raw.chars().count() },
+            "rawValue": raw,
+            "value": { "kind": "Custom", "value": dynamic_to_json(value)? },
+            "entity": self.slot.entity,
+            "slotName": self.slot.name,
+        });
+
+        let slot = serde_json::from_value(slot).map_err(|e| e.to_string())?;
+        self.intent.slots.push(Slot(slot));
+        Ok(self.intent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements() -> SlotRequirements {
+        let definition: IntentDefinition = serde_yaml::from_str(
+            r#"
+name: lights@set
+slots:
+  - name: room
+    entity: room
+    required: true
+    prompt: ask_room
+  - name: color
+    entity: color
+    required: true
+    prompt: ask_color
+    validator: list
+    values: [red, green]
+  - name: brightness
+    entity: snips/number
+"#,
+        )
+        .unwrap();
+        SlotRequirements::new(vec![definition])
+    }
+
+    fn intent() -> Intent {
+        serde_json::from_value(json!({
+            "input": "set the lights",
+            "intent": { "intentName": "lights@set", "probability": 1.0 },
+            "slots": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_asks_for_required_slots_in_order() {
+        let requirements = requirements();
+
+        let slot = requirements.missing(&intent()).unwrap().clone();
+        assert_eq!(slot.name, "room");
+        assert_eq!(slot.prompt, "ask_room");
+
+        let intent = SlotFilling {
+            intent: intent(),
+            slot,
+        }
+        .fill("kitchen", Dynamic::from("kitchen".to_string()))
+        .unwrap();
+        let slot = requirements.missing(&intent).unwrap();
+        assert_eq!(slot.name, "color");
+        assert_eq!(
+            slot.validator,
+            SlotValidator::List(vec!["red".into(), "green".into()])
+        );
+
+        let intent = SlotFilling {
+            intent,
+            slot: slot.clone(),
+        }
+        .fill("red", Dynamic::from("red".to_string()))
+        .unwrap();
+        assert!(requirements.missing(&intent).is_none());
+
+        let room = serde_json::to_value(&intent.slots[0].0).unwrap();
+        assert_eq!(room["slotName"], "room");
+        assert_eq!(room["value"]["value"], "kitchen");
+    }
+}
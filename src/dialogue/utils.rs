@@ -1,4 +1,5 @@
 use crate::ctx::runtime;
+use crate::dialogue::request::{Origin, current_origin};
 use crate::utils::core_id;
 use crate::{get_ctx, publish, rt_spawn, set_ctx};
 use log::{debug, error, trace};
@@ -61,36 +62,58 @@ pub async fn get_speaker() -> Result<String, Box<dyn std::error::Error>> {
 
 /// Publishes a text message to the speaker's topic to be spoken aloud.
 ///
+/// While a skill handles text from a device, the answer goes to that device instead of
+/// the global speaker.
+///
+/// # Arguments
+///
+/// * `text` - The string content to be spoken.
+pub fn speak(text: &str, store: bool) {
+    speak_to(text, store, current_origin())
+}
+
+/// Publishes a text message to be spoken aloud by the device `origin` came from.
+///
 /// This function spawns an asynchronous task to handle the publication.
 ///
 /// # Arguments
 ///
 /// * `text` - The string content to be spoken.
+/// * `origin` - The request being answered, `None` uses the global speaker.
 ///
 /// TODO: Handle the case where the speaker device is offline or unavailable.
-pub fn speak(text: &str, store: bool) {
+pub fn speak_to(text: &str, store: bool, origin: Option<Origin>) {
     let text = text.to_string();
-    trace!("Speak request: '{}' (store={})", text, store);
+    let origin = origin.filter(Origin::is_device);
+    let trace_id = origin
+        .as_ref()
+        .map(|o| o.trace_id.clone())
+        .unwrap_or_default();
+    trace!("[{}] Speak request: '{}' (store={})", trace_id, text, store);
 
     if store {
         set_ctx!("utterance.last", text.clone());
     }
 
     rt_spawn! {
-        let speaker = match get_speaker().await {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Failed to get speaker for speak request: {e}");
-                return;
-            }
+        let speaker = match origin {
+            Some(origin) => origin.device,
+            None => match get_speaker().await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Failed to get speaker for speak request: {e}");
+                    return;
+                }
+            },
         };
 
-        debug!("Publishing speak request to speaker {}: '{}'", speaker, text);
+        debug!("[{}] Publishing speak request to speaker {}: '{}'", trace_id, speaker, text);
         if let Err(e) = publish!(&format!("speak/{}/text", speaker), text.into_bytes()) {
-            error!("Failed to publish speak request to {}: {}", speaker, e);
+            error!("[{}] Failed to publish speak request to {}: {}", trace_id, speaker, e);
         }
     }
 }
+
 /// Commands the last active listener to start listening for voice input.
 ///
 /// This function spawns an asynchronous task to publish the start command.
@@ -0,0 +1,151 @@
+use log::trace;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+/// A skill finished loading. Data: `{ "skill" }`.
+pub const SKILL_LOADED: &str = "skill.loaded";
+/// A skill is about to be unloaded or reloaded. Data: `{ "skill" }`.
+pub const SKILL_UNLOADED: &str = "skill.unloaded";
+/// An intent was dispatched to a skill. Data: `{ "skill", "intent", "handled" }`.
+pub const INTENT_EXECUTED: &str = "intent.executed";
+/// A pending reply expired before the user answered. Data: `{ "skill", "origin" }`.
+pub const REPLY_TIMED_OUT: &str = "reply.timed_out";
+/// The user profile changed. Data: `{ "id" }`.
+pub const USER_UPDATED: &str = "user.updated";
+/// The configuration directory was reloaded. Data: `null`.
+pub const CONFIG_RELOADED: &str = "config.reloaded";
+/// A peer joined the mesh. Data: `{ "peer", "address" }`.
+pub const PEER_CONNECTED: &str = "peer.connected";
+/// A scheduled job that runs skill code is due. Data: `{ "job", "skill", "function" | "handler" }`.
+pub const SCHEDULE_FIRED: &str = "schedule.fired";
+/// Another device called a command of a local skill. Data: `{ "id", "command", "skill", "function", "args", "from" }`.
+pub const COMMAND_RECEIVED: &str = "command.received";
+
+/// Events only the core is allowed to emit.
+pub const CORE_EVENTS: &[&str] = &[
+    SKILL_LOADED,
+    SKILL_UNLOADED,
+    INTENT_EXECUTED,
+    REPLY_TIMED_OUT,
+    USER_UPDATED,
+    CONFIG_RELOADED,
+    PEER_CONNECTED,
+    SCHEDULE_FIRED,
+    COMMAND_RECEIVED,
+];
+
+/// Events only delivered to the skill named in their `skill` field, e.g. the arguments of a
+/// command call are none of the other skills' business.
+pub const TARGETED_EVENTS: &[&str] = &[SCHEDULE_FIRED, COMMAND_RECEIVED];
+
+/// Source reported for events emitted by the core.
+pub const CORE_SOURCE: &str = "core";
+
+/// How many events a slow subscriber may fall behind before it starts missing them.
+const BUS_CAPACITY: usize = 256;
+
+/// An event published on the internal bus.
+#[derive(Debug, Clone)]
+pub struct BusEvent {
+    /// The event name, e.g. `skill.loaded`.
+    pub name: String,
+    /// The event payload.
+    pub data: Value,
+    /// Who emitted the event, `core` or the id of a skill.
+    pub source: String,
+}
+
+/// In-process event bus used by `event:` subscriptions.
+///
+/// Unlike topics, events never leave the device: core components and skills emit
+/// them, and every subscriber receives its own copy.
+pub struct EventBus {
+    sender: broadcast::Sender<BusEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publishes an event to every current subscriber.
+    pub fn emit(&self, name: &str, data: Value, source: &str) {
+        trace!("Emitting event {} from {}", name, source);
+        // Sending only fails when nobody is subscribed, which is not an error for a bus.
+        let _ = self.sender.send(BusEvent {
+            name: name.to_string(),
+            data,
+            source: source.to_string(),
+        });
+    }
+
+    /// Returns a receiver for every event emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Checks if a skill may receive an event.
+    pub fn is_visible_to(event: &BusEvent, skill: &str) -> bool {
+        !TARGETED_EVENTS
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(&event.name))
+            || event.data["skill"].as_str() == Some(skill)
+    }
+
+    /// Checks if an event name is reserved for the core.
+    pub fn is_core_event(name: &str) -> bool {
+        CORE_EVENTS.iter().any(|e| e.eq_ignore_ascii_case(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_subscribers_receive_emitted_events() {
+        let bus = EventBus::new();
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+
+        bus.emit(SKILL_LOADED, json!({ "skill": "saudation" }), CORE_SOURCE);
+
+        for receiver in [&mut first, &mut second] {
+            let event = receiver.try_recv().unwrap();
+            assert_eq!(event.name, SKILL_LOADED);
+            assert_eq!(event.data["skill"], "saudation");
+            assert_eq!(event.source, CORE_SOURCE);
+        }
+    }
+
+    #[test]
+    fn test_emit_without_subscribers_is_ignored() {
+        let bus = EventBus::new();
+        bus.emit("custom.event", Value::Null, "skill");
+
+        let mut late = bus.subscribe();
+        assert!(late.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_targeted_events_only_reach_their_skill() {
+        let event = |name: &str| BusEvent {
+            name: name.to_string(),
+            data: json!({ "skill": "lights", "args": { "level": 50 } }),
+            source: CORE_SOURCE.to_string(),
+        };
+
+        assert!(EventBus::is_visible_to(&event(COMMAND_RECEIVED), "lights"));
+        assert!(!EventBus::is_visible_to(&event(COMMAND_RECEIVED), "spy"));
+        assert!(EventBus::is_visible_to(&event(SKILL_LOADED), "spy"));
+    }
+
+    #[test]
+    fn test_core_events_are_reserved() {
+        assert!(EventBus::is_core_event("skill.loaded"));
+        assert!(EventBus::is_core_event("User.Updated"));
+        assert!(!EventBus::is_core_event("timer.done"));
+    }
+}
@@ -1,5 +1,13 @@
 #[macro_export]
 macro_rules! speak {
+    (locale: $a: expr, to: $origin: expr) => {
+        match $crate::dialogue::languages::locale($a) {
+            Some(v) => speak!(&v, to: $origin),
+            None => {
+                ::log::warn!("Attempted to speak missing locale key: {}", $a);
+            }
+        }
+    };
     (locale: $a: expr) => {
         match $crate::dialogue::languages::locale($a) {
             Some(v) => speak!(&v),
@@ -8,6 +16,9 @@ macro_rules! speak {
             }
         }
     };
+    ($a: expr, to: $origin: expr) => {
+        $crate::dialogue::utils::speak_to($a, false, Some($origin.clone()))
+    };
     ($a: expr) => {
         $crate::dialogue::utils::speak($a, false)
     };
@@ -39,6 +50,21 @@ macro_rules! publish {
     };
 }
 
+#[macro_export]
+macro_rules! emit {
+    ($event: expr) => {
+        $crate::emit!($event, ::serde_json::Value::Null)
+    };
+    ($event: expr, $data: expr) => {
+        match $crate::ctx::runtime() {
+            Ok(c) => c.events.emit($event, $data, $crate::events::CORE_SOURCE),
+            Err(e) => {
+                ::log::error!("Failed to emit {}: runtime not available: {}", $event, e)
+            }
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! subscribe {
     ($topic: expr, async: $body:expr) => {
@@ -178,6 +204,18 @@ macro_rules! set_ctx {
             Err(e) => ::log::error!("Failed to set context: runtime not available: {}", e),
         }
     };
+    (scope: $scope:expr, $key:expr, $value:expr, $ttl:expr, $persistent:expr) => {
+        match $crate::ctx::runtime() {
+            Ok(c) => c.context.set(
+                $scope,
+                $key.to_string(),
+                serde_json::json!($value),
+                ($ttl > 0).then(|| ::std::time::Duration::from_secs($ttl)),
+                $persistent,
+            ),
+            Err(e) => ::log::error!("Failed to set context: runtime not available: {}", e),
+        }
+    };
 }
 
 #[macro_export]
@@ -222,6 +260,15 @@ macro_rules! get_ctx {
             }
         }
     };
+    (scope: $scope:expr, $key:expr) => {
+        match $crate::ctx::runtime() {
+            Ok(c) => c.context.get(&$scope, $key),
+            Err(e) => {
+                ::log::error!("Failed to get context: runtime not available: {}", e);
+                None
+            }
+        }
+    };
 }
 
 #[macro_export]
@@ -248,6 +295,12 @@ macro_rules! has_ctx {
             Err(_) => false,
         }
     };
+    (scope: $scope:expr, $key:expr) => {
+        match $crate::ctx::runtime() {
+            Ok(c) => c.context.has(&$scope, $key),
+            Err(_) => false,
+        }
+    };
 }
 
 #[macro_export]
@@ -281,6 +334,15 @@ macro_rules! remove_ctx {
             Err(e) => Err(e),
         }
     };
+    (scope: $scope:expr, $key:expr) => {
+        match $crate::ctx::runtime() {
+            Ok(c) => {
+                c.context.remove(&$scope, $key);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    };
 }
 
 #[macro_export]
@@ -3,18 +3,20 @@ extern crate core;
 mod actions;
 mod api;
 mod cli;
+mod commands;
 mod content;
 mod ctx;
 mod data;
 mod dialogue;
+mod events;
 mod log;
 mod macros;
 mod skills;
 mod start;
 mod utils;
 
-use crate::cli::args::{Args, Commands};
-use crate::cli::ui;
+use crate::cli::args::{Args, Commands, CtxCommand};
+use crate::cli::{context, ui};
 use crate::log::AviCoreLogger;
 use crate::skills::avi_script::avi_librarymanager::get_lib_path;
 use crate::start::start_avi;
@@ -110,6 +112,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
+        Commands::Ctx { action } => match action {
+            CtxCommand::Dump {
+                config,
+                scope,
+                output,
+            } => {
+                if output.is_none() {
+                    // The log shares stdout with the dump
+                    AviCoreLogger::set_level("error");
+                }
+                let config = config.map(Into::into).unwrap_or_else(config_dir);
+                let dump = context::dump(&config, scope.as_deref())?;
+                let content = serde_json::to_string_pretty(&dump)?;
+
+                match output {
+                    Some(output) => {
+                        std::fs::write(&output, content)?;
+                        info!("Context written to {}", output);
+                    }
+                    None => println!("{}", content),
+                }
+            }
+            CtxCommand::Restore {
+                file,
+                config,
+                scope,
+                replace,
+            } => {
+                let config = config.map(Into::into).unwrap_or_else(config_dir);
+                let data = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+                let restored = context::restore(&config, data, scope.as_deref(), replace)?;
+                info!("Restored {} context values from {}", restored, file);
+            }
+        },
+
         Commands::Version { verbose } => {
             ui::print_logo();
 
@@ -28,6 +28,11 @@ fn register_types(engine: &mut Engine) {
         .build_type::<crate::dialogue::response::OptionalValidator>()
         .build_type::<crate::dialogue::response::BoolValidator>()
         .build_type::<crate::dialogue::response::MappedValidator>()
+        .build_type::<crate::dialogue::response::NumberValidator>()
+        .build_type::<crate::dialogue::response::DatetimeValidator>()
+        .build_type::<crate::dialogue::response::DurationValidator>()
+        .build_type::<crate::dialogue::response::RegexValidator>()
+        .build_type::<crate::dialogue::response::ChoiceValidator>()
         .build_type::<crate::data::user::User>()
         .build_type::<crate::data::user::UserProfile>()
         .build_type::<crate::data::user::Location>()
@@ -1,7 +1,9 @@
+use crate::ctx::runtime;
 use crate::skills::skill_context::SkillContext;
-use rhai::{Dynamic, Map, NativeCallContext, Variant};
+use rhai::{Dynamic, EvalAltResult, Map, NativeCallContext, Position, Variant};
 use serde_json::Value;
 use std::error::Error;
+use std::future::Future;
 use std::result::Result;
 
 #[macro_export]
@@ -26,6 +28,15 @@ macro_rules! register_skill_func {
     };
 }
 
+/// Waits for a future from the synchronous script engine.
+pub fn block_on<T>(future: impl Future<Output = Result<T, String>>) -> Result<T, String> {
+    let c = runtime()?;
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+        Err(_) => c.rt.block_on(future),
+    }
+}
+
 pub fn json_to_dynamic(value: Value) -> Dynamic {
     match value {
         Value::Null => Dynamic::UNIT,
@@ -147,7 +158,7 @@ where
 }
 
 pub fn get_skill_name(ctx: &NativeCallContext) -> Result<String, String> {
-    Ok(get_skill_context(ctx)?.info.name.clone())
+    Ok(get_skill_context(ctx)?.key().to_string())
 }
 
 pub fn get_skill_context(ctx: &NativeCallContext) -> Result<SkillContext, String> {
@@ -164,9 +175,55 @@ pub fn get_skill_context(ctx: &NativeCallContext) -> Result<SkillContext, String
     Ok(skill_context)
 }
 
+/// Returns the skill context if the calling skill declared `permission` in its manifest.
+///
+/// The error names both the missing permission and the native function that asked for it,
+/// so script authors know what to add to `manifest.yaml`.
+pub fn require_permission(
+    ctx: &NativeCallContext,
+    permission: &str,
+) -> Result<SkillContext, Box<EvalAltResult>> {
+    let skill_context = get_skill_context(ctx)
+        .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+
+    if skill_context.has_permission(permission) {
+        Ok(skill_context)
+    } else {
+        Err(Box::new(EvalAltResult::ErrorRuntime(
+            format!(
+                "Skill '{}' is missing permission '{}' required by {}",
+                skill_context.info.id,
+                permission,
+                ctx.fn_name()
+            )
+            .into(),
+            ctx.call_position(),
+        )))
+    }
+}
+
 pub fn fix_module_imports(content: String) -> Result<String, Box<dyn Error>> {
     let re = regex::Regex::new(r#"import\s+"([^"]+)"\s*;"#)?;
     let script = re.replace_all(&content, r#"import "$1" as $1;"#);
     let re2 = regex::Regex::new(r#"use\s+"([^"]+)"\s*;"#)?;
     Ok(re2.replace_all(&script, r#"import "$1";"#).to_string())
 }
+
+/// Gives the bare blocks of `on_intent`, `on_fallback`, `on_context` and `subscribe` the
+/// parameters their handlers are called with.
+///
+/// `on_intent "x" { }` becomes `on_intent "x" |name, intent| { }`, `on_fallback { }` becomes
+/// `on_fallback |text| { }`, `on_context "x" { }` becomes `on_context "x" |old, new, key| { }`
+/// and `subscribe topic "x" as <data> { }` becomes `subscribe topic "x" as <data> |data, from| { }`,
+/// so the blocks can be registered as closures and keep using the variables they used before.
+pub fn expand_handler_blocks(content: String) -> Result<String, Box<dyn Error>> {
+    let re = regex::Regex::new(r#"(on_intent\s+"(?:[^"\\]|\\.)*")\s*\{"#)?;
+    let script = re.replace_all(&content, "$1 |name, intent| {");
+    let re_fallback = regex::Regex::new(r#"\bon_fallback\s*\{"#)?;
+    let script = re_fallback.replace_all(&script, "on_fallback |text| {");
+    let re_context = regex::Regex::new(r#"(on_context\s+"(?:[^"\\]|\\.)*")\s*\{"#)?;
+    let script = re_context.replace_all(&script, "$1 |old, new, key| {");
+    let re2 =
+        regex::Regex::new(r#"(subscribe\s+\w+\s+"(?:[^"\\]|\\.)*"\s+as\s*<\s*(\w+)\s*>)\s*\{"#)?;
+    Ok(re2.replace_all(&script, "$1 |$2, from| {").to_string())
+}
@@ -1,5 +1,5 @@
 pub mod avi_librarymanager;
 pub mod engine;
 pub mod helpers;
-mod module;
+pub(crate) mod module;
 mod syntax;
@@ -1,13 +1,149 @@
-use crate::skills::avi_script::helpers::json_to_dynamic;
-use crate::skills::avi_script::helpers::skill_context_def;
+use crate::ctx::runtime;
+use crate::data::context::{ContextScope, MIGRATED_CTX_PREFIX, WatchSource};
+use crate::skills::avi_script::helpers::{dynamic_to_json, json_to_dynamic, require_permission};
+use crate::skills::avi_script::helpers::{get_skill_context, skill_context_def};
+use crate::skills::manager::SkillRef;
+use crate::skills::skill_context::{SkillContext, permission};
 use crate::{get_ctx, has_ctx, remove_ctx, set_ctx};
+use log::{info, warn};
 use rhai::plugin::*;
-use rhai::{Dynamic, NativeCallContext};
+use rhai::{Dynamic, EvalAltResult, FnPtr, NativeCallContext, Position};
+use std::time::Duration;
+
+/// The context a skill uses by default, its own values for the current user.
+fn user_scope(skill: &SkillContext) -> ContextScope {
+    ContextScope::Skill(skill.key().to_string()).within(ContextScope::current_user())
+}
+
+/// Moves the values a skill stored before contexts were per user, in the scope named after
+/// its manifest `name`, to its values for the current user.
+///
+/// Done once per skill, values the skill shares between users afterwards stay shared.
+pub(crate) fn migrate_legacy_context(skill: &SkillContext) {
+    let Ok(c) = runtime() else {
+        return;
+    };
+    let legacy = ContextScope::Skill(skill.info.name.clone());
+    let marker = format!("{}.{}", MIGRATED_CTX_PREFIX, skill.key());
+    match c
+        .context
+        .migrate_scope(&legacy, &user_scope(skill), &marker)
+    {
+        Ok(0) => {}
+        Ok(moved) => info!(
+            "Moved {} context values of skill {} to {}",
+            moved,
+            skill.key(),
+            ContextScope::current_user()
+        ),
+        Err(e) => warn!("Failed to move the context of skill {}: {}", skill.key(), e),
+    }
+}
+
+/// Replicates the keys the skill lists in its manifest `replicated`, whether shared
+/// between users or kept for one of them.
+pub(crate) fn replicate_manifest_keys(skill: &SkillContext) {
+    let Ok(c) = runtime() else {
+        return;
+    };
+    for keys in &skill.info.replicated {
+        c.context
+            .replication()
+            .mark(ContextScope::Skill(skill.key().to_string()), keys);
+    }
+}
+
+/// The scope a script names in the `*_in` functions.
+///
+/// `skill`, `user`, `device` and `session` are the skill's own values, shared by everyone
+/// or kept for the current user, device or request. Their `global_` counterparts are
+/// shared with every skill and need the `context.global` permission, like `global`.
+fn named_scope(ctx: &NativeCallContext, name: &str) -> Result<ContextScope, Box<EvalAltResult>> {
+    let skill = if name.starts_with("global") {
+        require_permission(ctx, permission::CONTEXT_GLOBAL)?
+    } else {
+        get_skill_context(ctx)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?
+    };
+    let own = ContextScope::Skill(skill.key().to_string());
+
+    Ok(match name {
+        "skill" => own,
+        "user" => own.within(ContextScope::current_user()),
+        "device" => own.within(ContextScope::current_device()),
+        "session" => own.within(ContextScope::current_session()),
+        "global" => ContextScope::Global,
+        "global_user" => ContextScope::current_user(),
+        "global_device" => ContextScope::current_device(),
+        "global_session" => ContextScope::current_session(),
+        _ => {
+            return Err(Box::new(EvalAltResult::ErrorRuntime(
+                format!(
+                    "Unknown context scope '{}', expected skill, user, device, session, \
+                     global, global_user, global_device or global_session",
+                    name
+                )
+                .into(),
+                ctx.call_position(),
+            )));
+        }
+    })
+}
+
+/// Calls `handler` in the running skill whenever a key matching `spec` changes.
+///
+/// `spec` is a key the skill keeps for the current user, a key it shares between users when
+/// prefixed with `skill:`, or a key of the global or device context when prefixed with
+/// `global:` or `device:`, which need the `context.global` permission.
+pub(crate) fn watch_context(
+    skill: &SkillContext,
+    spec: &str,
+    handler: FnPtr,
+) -> Result<u64, String> {
+    let Some(skill_ref) = SkillRef::current() else {
+        return Err("Context watchers can only be added while a skill runs".to_string());
+    };
+    let (source, pattern) = WatchSource::parse(spec, skill.key());
+    let own = ContextScope::Skill(skill.key().to_string());
+    let shared = match &source {
+        WatchSource::Scope(ContextScope::Within(outer, _)) => **outer != own,
+        WatchSource::Scope(scope) => *scope != own,
+        WatchSource::Device => true,
+    };
+    if shared && !skill.has_permission(permission::CONTEXT_GLOBAL) {
+        return Err(format!(
+            "Skill '{}' is missing permission '{}' required to watch {}",
+            skill.key(),
+            permission::CONTEXT_GLOBAL,
+            spec
+        ));
+    }
+
+    let c = runtime()?;
+    let owner = skill_ref.name().to_string();
+    Ok(c.context.watch(&owner, source, pattern, move |change| {
+        let Ok(c) = runtime() else {
+            return;
+        };
+        let skill = skill_ref.clone();
+        let handler = handler.clone();
+        let key = change.key.clone();
+        let old = change.old.clone().map(json_to_dynamic).unwrap_or_default();
+        let new = change.new.clone().map(json_to_dynamic).unwrap_or_default();
+
+        // The change may come from inside another skill, so never run the watcher in place
+        c.rt.spawn_blocking(move || {
+            if let Err(e) = skill.run(|s| s.run_context_watcher(handler, &key, old, new)) {
+                warn!("Error watching {} in skill {}: {}", key, skill.name(), e);
+            }
+        });
+    }))
+}
 
 #[export_module]
 pub mod context_module {
 
-    /// Gets a value from the skill's persistent context
+    /// Gets a value the skill stored for the current user
     ///
     /// # Arguments
     /// * `key` - The key of the value to retrieve
@@ -17,13 +153,13 @@ pub mod context_module {
     #[rhai_fn(volatile)]
     pub fn get(ctx: NativeCallContext, key: ImmutableString) -> Dynamic {
         skill_context_def(ctx, |v| {
-            get_ctx!(skill: v.info.name.clone(), &key)
+            get_ctx!(scope: user_scope(&v), &key)
                 .map(json_to_dynamic)
                 .unwrap_or(Dynamic::UNIT)
         })
     }
 
-    /// Checks if a key exists in the skill's persistent context
+    /// Checks if the skill stored a value for the current user
     ///
     /// # Arguments
     /// * `key` - The key to check
@@ -32,10 +168,10 @@ pub mod context_module {
     /// True if the key exists, false otherwise
     #[rhai_fn(volatile)]
     pub fn has(ctx: NativeCallContext, key: ImmutableString) -> bool {
-        skill_context_def(ctx, |v| has_ctx!(skill: v.info.name.clone(), &key))
+        skill_context_def(ctx, |v| has_ctx!(scope: user_scope(&v), &key))
     }
 
-    /// Removes a value from the skill's persistent context
+    /// Removes a value the skill stored for the current user
     ///
     /// # Arguments
     /// * `key` - The key of the value to remove
@@ -45,11 +181,13 @@ pub mod context_module {
     #[rhai_fn(volatile)]
     pub fn remove(ctx: NativeCallContext, key: ImmutableString) {
         skill_context_def(ctx, |v| {
-            let _ = remove_ctx!(skill: v.info.name.clone(), &key);
+            let _ = remove_ctx!(scope: user_scope(&v), &key);
         });
     }
 
-    /// Sets a value in the skill's persistent context
+    /// Sets a value of the skill for the current user
+    ///
+    /// Use `set_in` to store it for every user, device or only for this request.
     ///
     /// # Arguments
     /// * `key` - The key to set
@@ -69,7 +207,391 @@ pub mod context_module {
     ) {
         skill_context_def(
             ctx,
-            |v| set_ctx!(skill: v.info.name.clone(), key, value, ttl, persist),
+            |v| set_ctx!(scope: user_scope(&v), key, value, ttl, persist),
         );
     }
+
+    /// Gets a value from a scope of the context
+    ///
+    /// # Arguments
+    /// * `scope` - The scope: skill, user, device or session for the skill's own values,
+    ///   or global, global_user, global_device or global_session, which require the
+    ///   `context.global` permission
+    /// * `key` - The key of the value to retrieve
+    ///
+    /// # Returns
+    /// The value associated with the key, or UNIT if not found
+    #[rhai_fn(volatile, return_raw)]
+    pub fn get_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+        key: ImmutableString,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+
+        Ok(get_ctx!(scope: scope, &key)
+            .map(json_to_dynamic)
+            .unwrap_or(Dynamic::UNIT))
+    }
+
+    /// Checks if a key exists in a scope of the context
+    ///
+    /// # Arguments
+    /// * `scope` - The scope, as in `get_in`
+    /// * `key` - The key to check
+    ///
+    /// # Returns
+    /// True if the key exists, false otherwise
+    #[rhai_fn(volatile, return_raw)]
+    pub fn has_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+        key: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+
+        Ok(has_ctx!(scope: scope, &key))
+    }
+
+    /// Removes a value from a scope of the context
+    ///
+    /// # Arguments
+    /// * `scope` - The scope, as in `get_in`
+    /// * `key` - The key of the value to remove
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(volatile, return_raw)]
+    pub fn remove_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+        key: ImmutableString,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+
+        remove_ctx!(scope: scope, &key)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))
+    }
+
+    /// Sets a value in a scope of the context
+    ///
+    /// # Arguments
+    /// * `scope` - The scope, as in `get_in`
+    /// * `key` - The key to set
+    /// * `value` - The value to store
+    /// * `ttl` - Time to live in seconds (0 for no TTL)
+    /// * `persist` - Whether to persist the value across sessions
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(volatile, return_raw)]
+    pub fn set_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+        key: ImmutableString,
+        value: Dynamic,
+        ttl: u64,
+        persist: bool,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+
+        set_ctx!(scope: scope, key, value, ttl, persist);
+        Ok(())
+    }
+
+    /// Lists the keys of a scope of the context starting with a prefix
+    ///
+    /// # Arguments
+    /// * `scope` - The scope, as in `get_in`
+    /// * `prefix` - The start of the keys, "" for all of them
+    ///
+    /// # Returns
+    /// An array of the matching keys, sorted
+    #[rhai_fn(volatile, return_raw)]
+    pub fn keys_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+        prefix: ImmutableString,
+    ) -> Result<rhai::Array, Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+
+        Ok(c.context
+            .keys(&scope, &prefix)
+            .into_iter()
+            .map(Dynamic::from)
+            .collect())
+    }
+
+    /// Removes every value of a scope of the context
+    ///
+    /// # Arguments
+    /// * `scope` - The scope, as in `get_in`
+    ///
+    /// # Returns
+    /// The number of values removed
+    #[rhai_fn(volatile, return_raw)]
+    pub fn clear_in(
+        ctx: NativeCallContext,
+        scope: ImmutableString,
+    ) -> Result<i64, Box<EvalAltResult>> {
+        let scope = named_scope(&ctx, &scope)?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+
+        c.context
+            .clear(&scope)
+            .map(|n| n as i64)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))
+    }
+
+    /// Gets a value from the global context shared by every skill
+    ///
+    /// Requires the `context.global` permission.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the value to retrieve
+    ///
+    /// # Returns
+    /// The value associated with the key, or UNIT if not found
+    #[rhai_fn(volatile, return_raw)]
+    pub fn get_global(
+        ctx: NativeCallContext,
+        key: ImmutableString,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::CONTEXT_GLOBAL)?;
+
+        Ok(get_ctx!(&key).map(json_to_dynamic).unwrap_or(Dynamic::UNIT))
+    }
+
+    /// Checks if a key exists in the global context
+    ///
+    /// Requires the `context.global` permission.
+    ///
+    /// # Arguments
+    /// * `key` - The key to check
+    ///
+    /// # Returns
+    /// True if the key exists, false otherwise
+    #[rhai_fn(volatile, return_raw)]
+    pub fn has_global(
+        ctx: NativeCallContext,
+        key: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::CONTEXT_GLOBAL)?;
+
+        Ok(has_ctx!(&key))
+    }
+
+    /// Removes a value from the global context
+    ///
+    /// Requires the `context.global` permission.
+    ///
+    /// # Arguments
+    /// * `key` - The key of the value to remove
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(volatile, return_raw)]
+    pub fn remove_global(
+        ctx: NativeCallContext,
+        key: ImmutableString,
+    ) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::CONTEXT_GLOBAL)?;
+
+        remove_ctx!(&key)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))
+    }
+
+    /// Sets a value in the global context
+    ///
+    /// Requires the `context.global` permission.
+    ///
+    /// # Arguments
+    /// * `key` - The key to set
+    /// * `value` - The value to store
+    /// * `ttl` - Time to live in seconds (0 for no TTL)
+    /// * `persist` - Whether to persist the value across sessions
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(volatile, return_raw)]
+    pub fn set_global(
+        ctx: NativeCallContext,
+        key: ImmutableString,
+        value: Dynamic,
+        ttl: u64,
+        persist: bool,
+    ) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::CONTEXT_GLOBAL)?;
+
+        let value = dynamic_to_json(value)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+
+        c.context.set(
+            ContextScope::Global,
+            key.to_string(),
+            value,
+            (ttl > 0).then(|| Duration::from_secs(ttl)),
+            persist,
+        );
+        Ok(())
+    }
+
+    /// Lists the keys the skill stored for the current user
+    ///
+    /// # Returns
+    /// An array of every key, sorted
+    #[rhai_fn(volatile)]
+    pub fn keys(ctx: NativeCallContext) -> rhai::Array {
+        keys_with_prefix(ctx, ImmutableString::new())
+    }
+
+    /// Lists the keys the skill stored for the current user starting with a prefix
+    ///
+    /// # Arguments
+    /// * `prefix` - The start of the keys, e.g. "alarm."
+    ///
+    /// # Returns
+    /// An array of the matching keys, sorted
+    #[rhai_fn(name = "keys", volatile)]
+    pub fn keys_with_prefix(ctx: NativeCallContext, prefix: ImmutableString) -> rhai::Array {
+        skill_context_def(ctx, |v| match runtime() {
+            Ok(c) => c
+                .context
+                .keys(&user_scope(&v), &prefix)
+                .into_iter()
+                .map(Dynamic::from)
+                .collect(),
+            Err(_) => rhai::Array::new(),
+        })
+    }
+
+    /// Lists the keys of the global context starting with a prefix
+    ///
+    /// Requires the `context.global` permission.
+    ///
+    /// # Arguments
+    /// * `prefix` - The start of the keys, "" for all of them
+    ///
+    /// # Returns
+    /// An array of the matching keys, sorted
+    #[rhai_fn(volatile, return_raw)]
+    pub fn keys_global(
+        ctx: NativeCallContext,
+        prefix: ImmutableString,
+    ) -> Result<rhai::Array, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::CONTEXT_GLOBAL)?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), Position::NONE)))?;
+
+        Ok(c.context
+            .keys(&ContextScope::Global, &prefix)
+            .into_iter()
+            .map(Dynamic::from)
+            .collect())
+    }
+
+    /// Gets the time left before a value the skill stored for the current user expires
+    ///
+    /// # Arguments
+    /// * `key` - The key of the value
+    ///
+    /// # Returns
+    /// The seconds left, or UNIT if the key doesn't exist or never expires
+    #[rhai_fn(volatile)]
+    pub fn ttl(ctx: NativeCallContext, key: ImmutableString) -> Dynamic {
+        skill_context_def(ctx, |v| {
+            runtime()
+                .ok()
+                .and_then(|c| c.context.ttl(&user_scope(&v), &key))
+                .map(|ttl| Dynamic::from(ttl.as_secs() as i64))
+                .unwrap_or(Dynamic::UNIT)
+        })
+    }
+
+    /// Removes every value the skill stored for the current user
+    ///
+    /// # Returns
+    /// The number of values removed
+    #[rhai_fn(volatile, return_raw)]
+    pub fn clear(ctx: NativeCallContext) -> Result<i64, Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+
+        c.context
+            .clear(&user_scope(&skill))
+            .map(|n| n as i64)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))
+    }
+
+    /// Calls a function whenever a context value is set, removed or expires
+    ///
+    /// Watches the values the skill keeps for the current user, the ones it shares between
+    /// users when the key starts with `skill:`, or the global or device context when it starts
+    /// with `global:` or `device:`, which requires the `context.global` permission.
+    /// A key ending in `*` watches every key starting with it.
+    ///
+    /// # Arguments
+    /// * `key` - The key to watch, e.g. "timer", "alarm.*" or "global:user"
+    /// * `handler` - A function called with the old and the new value, () when there is none,
+    ///   and with the key too if it takes a third parameter
+    ///
+    /// # Returns
+    /// The id of the watcher, to stop it with `unwatch`
+    #[rhai_fn(volatile, return_raw)]
+    pub fn watch(
+        ctx: NativeCallContext,
+        key: ImmutableString,
+        handler: FnPtr,
+    ) -> Result<i64, Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+
+        watch_context(&skill, &key, handler)
+            .map(|id| id as i64)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))
+    }
+
+    /// Keeps the skill's values on every node of the mesh, so another core gets them back
+    ///
+    /// Covers the values shared between users and the ones kept for a user or device,
+    /// like the manifest `replicated` list. Session values are never replicated.
+    ///
+    /// # Arguments
+    /// * `keys` - The key to replicate, or a prefix ending in `*`
+    #[rhai_fn(volatile, return_raw)]
+    pub fn replicate(
+        ctx: NativeCallContext,
+        keys: ImmutableString,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+        let c = runtime()
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+        c.context
+            .replication()
+            .mark(ContextScope::Skill(skill.key().to_string()), &keys);
+        Ok(())
+    }
+
+    /// Stops a watcher added with `watch`
+    ///
+    /// # Arguments
+    /// * `id` - The id `watch` returned
+    ///
+    /// # Returns
+    /// True if the watcher existed, false otherwise
+    #[rhai_fn(volatile)]
+    pub fn unwatch(id: i64) -> bool {
+        match (runtime(), SkillRef::current()) {
+            (Ok(c), Some(skill)) => c.context.unwatch(skill.name(), id as u64),
+            _ => false,
+        }
+    }
 }
@@ -0,0 +1,158 @@
+use crate::commands::{ArgSchema, CommandSchema};
+use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::rt_spawn;
+use crate::skills::avi_script::helpers::{
+    block_on, dynamic_to_json, json_to_dynamic, require_permission,
+};
+use crate::skills::skill_context::permission;
+use log::warn;
+use rhai::plugin::*;
+use rhai::{Dynamic, EvalAltResult, Map, NativeCallContext};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[export_module]
+pub mod device_module {
+    use rhai::{Array, FnPtr};
+
+    /// Registers a command other devices can call on this device
+    ///
+    /// # Arguments
+    /// * `name` - The name of the command, e.g. `"lights.set"`
+    /// * `args` - A map of argument names to their type: `"string"`, `"number"`, `"bool"`,
+    ///   `"list"`, `"map"` or `"any"`, with a trailing `?` for optional arguments
+    /// * `handler` - A named function called with the arguments map, its return value is sent back
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(return_raw)]
+    pub fn register(
+        ctx: NativeCallContext,
+        name: ImmutableString,
+        args: Map,
+        handler: FnPtr,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let skill = require_permission(&ctx, permission::DEVICE_COMMANDS)?;
+
+        if handler.is_anonymous() {
+            return Err(runtime_error(
+                &ctx,
+                "Command handlers must be named functions".to_string(),
+            ));
+        }
+
+        let mut schema_args = BTreeMap::new();
+        for (arg, kind) in args {
+            let kind = kind.into_immutable_string().map_err(|t| {
+                runtime_error(
+                    &ctx,
+                    format!("Type of argument '{}' must be a string, got {}", arg, t),
+                )
+            })?;
+            let schema = kind
+                .parse::<ArgSchema>()
+                .map_err(|e| runtime_error(&ctx, e))?;
+            schema_args.insert(arg.to_string(), schema);
+        }
+
+        let schema = CommandSchema {
+            name: name.to_string(),
+            args: schema_args,
+        };
+
+        runtime()?
+            .commands
+            .register(skill.key(), schema, handler.fn_name())
+            .map_err(|e| runtime_error(&ctx, e))?;
+
+        rt_spawn! {
+            if let Ok(c) = runtime()
+                && let Err(e) = c.commands.announce().await
+            {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls a command on another device and waits for its result
+    ///
+    /// # Arguments
+    /// * `peer` - The id of the device
+    /// * `command` - The name of the command
+    /// * `args` - The arguments of the command
+    ///
+    /// # Returns
+    /// The value returned by the command
+    #[rhai_fn(return_raw)]
+    pub fn call(
+        ctx: NativeCallContext,
+        peer: ImmutableString,
+        command: ImmutableString,
+        args: Map,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::DEVICE_COMMANDS)?;
+
+        let args = dynamic_to_json(Dynamic::from_map(args)).map_err(|e| runtime_error(&ctx, e))?;
+        let timeout = Duration::from_secs(setting_or::<u64>("command_timeout", 5));
+
+        let result = block_on(async move {
+            runtime()?
+                .commands
+                .call(&peer, &command, args, timeout)
+                .await
+        })
+        .map_err(|e| runtime_error(&ctx, e))?;
+
+        Ok(json_to_dynamic(result))
+    }
+
+    /// Calls a command without arguments on another device and waits for its result
+    ///
+    /// # Arguments
+    /// * `peer` - The id of the device
+    /// * `command` - The name of the command
+    ///
+    /// # Returns
+    /// The value returned by the command
+    #[rhai_fn(name = "call", return_raw)]
+    pub fn call_no_args(
+        ctx: NativeCallContext,
+        peer: ImmutableString,
+        command: ImmutableString,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        call(ctx, peer, command, Map::new())
+    }
+
+    /// Lists the commands a device announced
+    ///
+    /// # Arguments
+    /// * `peer` - The id of the device
+    ///
+    /// # Returns
+    /// An array of maps with the `name` and `args` of each command
+    #[rhai_fn(return_raw)]
+    pub fn commands(
+        ctx: NativeCallContext,
+        peer: ImmutableString,
+    ) -> Result<Array, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::DEVICE_COMMANDS)?;
+
+        let schemas = block_on(async move { runtime()?.commands.peer_commands(&peer).await })
+            .map_err(|e| runtime_error(&ctx, e))?;
+
+        Ok(schemas
+            .into_iter()
+            .filter_map(|schema| serde_json::to_value(schema).ok())
+            .map(json_to_dynamic)
+            .collect())
+    }
+}
+
+fn runtime_error(ctx: &NativeCallContext, message: String) -> Box<EvalAltResult> {
+    Box::new(EvalAltResult::ErrorRuntime(
+        message.into(),
+        ctx.call_position(),
+    ))
+}
@@ -1,16 +1,22 @@
 use crate::ctx::runtime;
 use crate::data::user::user_name;
-use crate::dialogue::reply::{RequestReply, ValidatorErasure};
+use crate::dialogue::reply::{ReplyHandler, RequestReply, ValidatorErasure};
+use crate::dialogue::request::{current_device, current_origin, with_origin};
 use crate::dialogue::response::{
-    AnyValidator, BoolValidator, ListOrNoneValidator, MappedValidator, OptionalValidator,
+    AnyValidator, BoolValidator, ChoiceValidator, DatetimeValidator, DurationValidator,
+    ListOrNoneValidator, MappedValidator, NumberValidator, OptionalValidator, RegexValidator,
+    ScriptCall, ScriptValidator,
 };
 use crate::dialogue::utils::speak;
-use crate::skills::avi_script::helpers::get_skill_context;
+use crate::skills::avi_script::helpers::{block_on, get_skill_context, require_permission};
+use crate::skills::manager::SkillRef;
+use crate::skills::skill_context::permission;
 use crate::{get_ctx, rt_spawn, speak};
 use log::error;
 use rhai::plugin::*;
-use rhai::{Dynamic, FnPtr};
+use rhai::{Dynamic, FnPtr, Map};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[export_module]
 pub mod dialogue_module {
@@ -52,6 +58,74 @@ pub mod dialogue_module {
         MappedValidator::new(mappings)
     }
 
+    /// Creates a validator that accepts any number, written with digits or words
+    ///
+    /// # Returns
+    /// A NumberValidator object, replying with the number as a float
+    pub fn number_validator() -> NumberValidator {
+        NumberValidator::new(None, None)
+    }
+
+    /// Creates a validator that accepts a number within a range
+    ///
+    /// # Arguments
+    /// * `min` - The smallest number accepted, or () for no minimum
+    /// * `max` - The largest number accepted, or () for no maximum
+    ///
+    /// # Returns
+    /// A NumberValidator object, replying with the number as a float
+    #[rhai_fn(name = "number_validator", return_raw)]
+    pub fn number_validator_range(
+        min: Dynamic,
+        max: Dynamic,
+    ) -> Result<NumberValidator, Box<EvalAltResult>> {
+        Ok(NumberValidator::new(bound(min)?, bound(max)?))
+    }
+
+    /// Creates a validator that accepts a date and/or a time, e.g. "tomorrow at 3pm"
+    ///
+    /// # Returns
+    /// A DatetimeValidator object, replying with an RFC 3339 timestamp in the user's timezone
+    pub fn datetime_validator() -> DatetimeValidator {
+        DatetimeValidator::new()
+    }
+
+    /// Creates a validator that accepts a duration, e.g. "an hour and a half"
+    ///
+    /// # Returns
+    /// A DurationValidator object, replying with the duration in seconds
+    pub fn duration_validator() -> DurationValidator {
+        DurationValidator::new()
+    }
+
+    /// Creates a validator that accepts replies matching a regular expression
+    ///
+    /// # Arguments
+    /// * `pattern` - The regular expression
+    ///
+    /// # Returns
+    /// A RegexValidator object, replying with the matched text
+    #[rhai_fn(return_raw)]
+    pub fn regex_validator(pattern: ImmutableString) -> Result<RegexValidator, Box<EvalAltResult>> {
+        RegexValidator::new(&pattern).map_err(|e| {
+            Box::new(EvalAltResult::ErrorRuntime(
+                format!("Invalid pattern {}: {}", pattern, e).into(),
+                Position::NONE,
+            ))
+        })
+    }
+
+    /// Creates a validator that accepts one of a list of options, tolerating typos
+    ///
+    /// # Arguments
+    /// * `options` - The options
+    ///
+    /// # Returns
+    /// A ChoiceValidator object, replying with the option closest to what was said
+    pub fn choice_validator(options: rhai::Array) -> ChoiceValidator {
+        ChoiceValidator::new(options.into_iter().map(|o| o.to_string()).collect())
+    }
+
     /// Speaks a given text
     ///
     /// # Arguments
@@ -59,32 +133,70 @@ pub mod dialogue_module {
     ///
     /// # Returns
     /// Nothing
-    pub fn say(text: ImmutableString) {
+    #[rhai_fn(return_raw)]
+    pub fn say(ctx: NativeCallContext, text: ImmutableString) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::SPEAK)?;
+
         speak(&text, true);
+        Ok(())
     }
 
-    pub fn say_once(text: ImmutableString) {
+    /// Speaks a given text without storing it as the last utterance
+    ///
+    /// # Arguments
+    /// * `text` - The text to speak
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(return_raw)]
+    pub fn say_once(
+        ctx: NativeCallContext,
+        text: ImmutableString,
+    ) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::SPEAK)?;
+
         speak!(&text);
+        Ok(())
     }
 
-    pub fn listen() {
+    /// Asks the last active listener to start listening
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(return_raw)]
+    pub fn listen(ctx: NativeCallContext) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::LISTEN)?;
+
         crate::dialogue::utils::listen();
+        Ok(())
     }
 
-    pub fn repeat() {
+    /// Repeats the last stored utterance
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(return_raw)]
+    pub fn repeat(ctx: NativeCallContext) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::SPEAK)?;
+
         if let Some(v) = get_ctx!("utterance.last") {
             speak!(&v.to_string())
         };
+        Ok(())
     }
 
     /// Requests the user's attention and starts listening
     ///
     /// # Returns
     /// Nothing
-    #[rhai_fn(name = "request_attention")]
-    pub fn request_attention() {
+    #[rhai_fn(name = "request_attention", return_raw)]
+    pub fn request_attention(ctx: NativeCallContext) -> Result<(), Box<EvalAltResult>> {
+        require_permission(&ctx, permission::SPEAK)?;
+        require_permission(&ctx, permission::LISTEN)?;
+
         speak!(&format!("{}!", user_name()));
         crate::dialogue::utils::listen();
+        Ok(())
     }
 
     /// Asks the user a yes/no question and handles the response
@@ -101,12 +213,7 @@ pub mod dialogue_module {
         question_locale_id: ImmutableString,
         handler: FnPtr,
     ) -> Result<(), Box<EvalAltResult>> {
-        let skill_context = get_skill_context(&ctx).map_err(|_| {
-            Box::new(EvalAltResult::ErrorRuntime(
-                "Could not get the skill context".to_string().into(),
-                Position::NONE,
-            ))
-        })?;
+        let skill_context = require_permission(&ctx, permission::SPEAK)?;
 
         speak!(
             &skill_context
@@ -123,7 +230,7 @@ pub mod dialogue_module {
             handle_on_reply(
                 handler_cloned.clone(),
                 Box::new(BoolValidator::new(false)),
-                skill.info.id.clone(),
+                skill.key().to_string(),
             );
         });
 
@@ -134,7 +241,10 @@ pub mod dialogue_module {
     ///
     /// # Arguments
     /// * `handler` - The name of the function to call with the response
-    /// * `validator` - The validator to use for the response
+    /// * `validator` - The validator to use for the response: a built-in validator,
+    ///   a closure `|text| ...` returning `()` or `false` to reject the reply, `true` to accept
+    ///   it or the parsed value, or a map with such a `validate` closure and an `error`
+    ///   closure returning what to tell the user when the reply is rejected
     ///
     /// # Returns
     /// Nothing
@@ -155,24 +265,152 @@ pub mod dialogue_module {
                     Box::new(v)
                 } else if let Some(v) = validator_cloned.clone().try_cast::<MappedValidator>() {
                     Box::new(v)
+                } else if let Some(v) = validator_cloned.clone().try_cast::<NumberValidator>() {
+                    Box::new(v)
+                } else if let Some(v) = validator_cloned.clone().try_cast::<DatetimeValidator>() {
+                    Box::new(v)
+                } else if let Some(v) = validator_cloned.clone().try_cast::<DurationValidator>() {
+                    Box::new(v)
+                } else if let Some(v) = validator_cloned.clone().try_cast::<RegexValidator>() {
+                    Box::new(v)
+                } else if let Some(v) = validator_cloned.clone().try_cast::<ChoiceValidator>() {
+                    Box::new(v)
+                } else if let Some(v) = script_validator(&validator_cloned) {
+                    Box::new(v)
                 } else {
                     error!("Invalid validator type: {}", validator_type);
                     return;
                 };
 
-            handle_on_reply(handler_cloned.clone(), v, skill.info.id.clone());
+            handle_on_reply(handler_cloned.clone(), v, skill.key().to_string());
         });
     }
+
+    /// Lists the replies devices are expected to give to the skill, or to any skill with
+    /// the `dialogue.replies` permission
+    ///
+    /// # Returns
+    /// An array of maps with `origin`, `skill`, `handler`, `age` in seconds and `retries`
+    #[rhai_fn(return_raw)]
+    pub fn pending_replies(ctx: NativeCallContext) -> Result<rhai::Array, Box<EvalAltResult>> {
+        let owner = reply_owner(&ctx)?;
+        let pending = block_on(async { Ok(runtime()?.reply_manager.list().await) })?;
+
+        Ok(pending
+            .into_iter()
+            .filter(|info| {
+                owner
+                    .as_ref()
+                    .is_none_or(|owner| &info.skill_request == owner)
+            })
+            .map(|info| {
+                let mut map = Map::new();
+                map.insert("origin".into(), info.origin.into());
+                map.insert("skill".into(), info.skill_request.into());
+                map.insert("handler".into(), info.handler.into());
+                map.insert("age".into(), (info.age.as_secs() as rhai::INT).into());
+                map.insert("retries".into(), (info.retry_count as rhai::INT).into());
+                Dynamic::from_map(map)
+            })
+            .collect())
+    }
+
+    /// Cancels the reply a device or session is expected to give to the skill, or to any
+    /// skill with the `dialogue.replies` permission
+    ///
+    /// # Arguments
+    /// * `origin` - The device or session, as listed by `pending_replies`
+    ///
+    /// # Returns
+    /// Whether there was a pending reply
+    #[rhai_fn(return_raw)]
+    pub fn cancel_reply(
+        ctx: NativeCallContext,
+        origin: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let owner = reply_owner(&ctx)?;
+        Ok(block_on(async move {
+            let replies = &runtime()?.reply_manager;
+            Ok(match owner {
+                Some(owner) => replies.cancel_of(&origin, &owner).await,
+                None => replies.cancel(&origin).await,
+            })
+        })?)
+    }
+}
+
+/// The skill whose pending replies a script may see, `None` when it may see them all.
+fn reply_owner(ctx: &NativeCallContext) -> Result<Option<String>, Box<EvalAltResult>> {
+    let skill = get_skill_context(ctx)
+        .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+    if skill.has_permission(permission::DIALOGUE_REPLIES) {
+        Ok(None)
+    } else {
+        Ok(Some(skill.key().to_string()))
+    }
+}
+
+/// A validator written as a closure, or as a map with `validate` and `error` closures.
+fn script_validator(validator: &Dynamic) -> Option<ScriptValidator> {
+    let (validate, error) = if let Some(validate) = validator.clone().try_cast::<FnPtr>() {
+        (validate, None)
+    } else {
+        let map = validator.clone().try_cast::<Map>()?;
+        let validate = map.get("validate")?.clone().try_cast::<FnPtr>()?;
+        let error = map.get("error").and_then(|e| e.clone().try_cast::<FnPtr>());
+        (validate, error)
+    };
+
+    let Some(skill) = SkillRef::current() else {
+        error!("Script validators can only be created while a skill runs");
+        return None;
+    };
+    let name = skill.name().to_string();
+    // The reply is awaited from the device being answered now, so the validator speaks to it
+    let origin = current_origin();
+    // Lets the async worker run other tasks while the skill validates
+    let call: ScriptCall = Arc::new(move |function, text| {
+        let validate = || {
+            tokio::task::block_in_place(|| {
+                skill.run(move |skill| skill.run_function_ptr::<Dynamic>(function, (text,)))
+            })
+        };
+        match &origin {
+            Some(origin) => with_origin(origin, validate),
+            None => validate(),
+        }
+    });
+    Some(ScriptValidator::new(name, call, validate, error))
+}
+
+/// A bound of a number validator, an integer, a float or () for none.
+fn bound(value: Dynamic) -> Result<Option<f64>, Box<EvalAltResult>> {
+    if value.is_unit() {
+        Ok(None)
+    } else if let Ok(n) = value.as_int() {
+        Ok(Some(n as f64))
+    } else if let Ok(n) = value.as_float() {
+        Ok(Some(n))
+    } else {
+        Err(Box::new(EvalAltResult::ErrorMismatchDataType(
+            "number".to_string(),
+            value.type_name().to_string(),
+            Position::NONE,
+        )))
+    }
 }
 
 fn handle_on_reply(handler: FnPtr, validator: Box<dyn ValidatorErasure>, skill_name: String) {
+    let origin = current_device();
     rt_spawn! {
-        if let Ok(c) = runtime() { c.reply_manager
-        .set_reply(RequestReply {
-            skill_request: skill_name,
-            handler,
-            validator,
-        })
-        .await };
+        if let Ok(c) = runtime() {
+            c.reply_manager
+                .set_reply(&origin, RequestReply {
+                    skill_request: skill_name,
+                    handler: ReplyHandler::Function(handler),
+                    validator,
+                })
+                .await;
+        }
     }
 }
@@ -0,0 +1,76 @@
+use crate::ctx::runtime;
+use crate::events::EventBus;
+use crate::skills::avi_script::helpers::{dynamic_to_json, require_permission};
+use crate::skills::skill_context::permission;
+use rhai::plugin::*;
+use rhai::{Dynamic, EvalAltResult, NativeCallContext};
+
+#[export_module]
+pub mod events_module {
+    /// Emitted when a skill finishes loading
+    pub const SKILL_LOADED: &str = crate::events::SKILL_LOADED;
+    /// Emitted before a skill is unloaded or reloaded
+    pub const SKILL_UNLOADED: &str = crate::events::SKILL_UNLOADED;
+    /// Emitted after an intent is dispatched to a skill
+    pub const INTENT_EXECUTED: &str = crate::events::INTENT_EXECUTED;
+    /// Emitted when a pending reply expires
+    pub const REPLY_TIMED_OUT: &str = crate::events::REPLY_TIMED_OUT;
+    /// Emitted when the user profile changes
+    pub const USER_UPDATED: &str = crate::events::USER_UPDATED;
+    /// Emitted when the configuration is reloaded
+    pub const CONFIG_RELOADED: &str = crate::events::CONFIG_RELOADED;
+    /// Emitted when a peer joins the mesh
+    pub const PEER_CONNECTED: &str = crate::events::PEER_CONNECTED;
+    /// Emitted when a scheduled job of a skill is due
+    pub const SCHEDULE_FIRED: &str = crate::events::SCHEDULE_FIRED;
+    /// Emitted when another device calls a command of a skill
+    pub const COMMAND_RECEIVED: &str = crate::events::COMMAND_RECEIVED;
+
+    /// Emits an event on the internal event bus
+    ///
+    /// Skills receive it with `subscribe event "name" as <data> { }` when the
+    /// event is listed as `event:name` in their manifest subscriptions.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the event
+    /// * `data` - The payload sent to subscribers
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(return_raw)]
+    pub fn emit(
+        ctx: NativeCallContext,
+        name: ImmutableString,
+        data: Dynamic,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let skill = require_permission(&ctx, permission::EVENTS_EMIT)?;
+
+        if EventBus::is_core_event(&name) {
+            return Err(Box::new(EvalAltResult::ErrorRuntime(
+                format!("Event '{}' is reserved for the core", name).into(),
+                ctx.call_position(),
+            )));
+        }
+
+        let data = dynamic_to_json(data)
+            .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))?;
+
+        runtime()?.events.emit(&name, data, skill.key());
+        Ok(())
+    }
+
+    /// Emits an event without a payload on the internal event bus
+    ///
+    /// # Arguments
+    /// * `name` - The name of the event
+    ///
+    /// # Returns
+    /// Nothing
+    #[rhai_fn(name = "emit", return_raw)]
+    pub fn emit_empty(
+        ctx: NativeCallContext,
+        name: ImmutableString,
+    ) -> Result<(), Box<EvalAltResult>> {
+        emit(ctx, name, Dynamic::UNIT)
+    }
+}
@@ -1,22 +1,35 @@
+use crate::skills::avi_script::helpers::require_permission;
+use crate::skills::skill_context::{SkillContext, permission};
 use rhai::plugin::*;
-use rhai::{EvalAltResult, Position};
+use rhai::{EvalAltResult, NativeCallContext, Position};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+
+/// Prefix used by scripts to address the read-only view of their own skill directory.
+const SKILL_PREFIX: &str = "skill:";
 
 #[export_module]
 pub mod fs_module {
     /// Reads the entire contents of a file as a ImmutableString
     ///
+    /// Paths are relative to the skill's data directory. Use the `skill:` prefix
+    /// (e.g. `skill:responses/en.lang`) to read files shipped with the skill.
+    ///
     /// # Arguments
     /// * `path` - The path to the file to read
     ///
     /// # Returns
     /// The file contents as a ImmutableString, or UNIT if the file could not be read
     #[rhai_fn(return_raw)]
-    pub fn read(path: ImmutableString) -> Result<ImmutableString, Box<EvalAltResult>> {
-        match fs::read_to_string(path.as_str()) {
+    pub fn read(
+        ctx: NativeCallContext,
+        path: ImmutableString,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Read)?;
+
+        match fs::read_to_string(&path) {
             Ok(content) => Ok(ImmutableString::from(content)),
             Err(e) => Err(Box::new(EvalAltResult::ErrorRuntime(
                 format!("Could not read directory: {}", e).into(),
@@ -35,14 +48,17 @@ pub mod fs_module {
     /// Nothing
     #[rhai_fn(return_raw)]
     pub fn write(
+        ctx: NativeCallContext,
         path: ImmutableString,
         content: ImmutableString,
     ) -> Result<(), Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Write)?;
+
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(path.as_str())
+            .open(&path)
             .map_err(|e| {
                 Box::new(EvalAltResult::ErrorRuntime(
                     format!("Could not open file: {}", e).into(),
@@ -70,13 +86,16 @@ pub mod fs_module {
     /// Nothing
     #[rhai_fn(return_raw)]
     pub fn append(
+        ctx: NativeCallContext,
         path: ImmutableString,
         content: ImmutableString,
     ) -> Result<(), Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Write)?;
+
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(path.as_str())
+            .open(&path)
             .map_err(|e| {
                 Box::new(EvalAltResult::ErrorRuntime(
                     format!("Could not open file: {}", e).into(),
@@ -101,16 +120,34 @@ pub mod fs_module {
     ///
     /// # Returns
     /// True if the path exists, false otherwise
-    pub fn exists(path: ImmutableString) -> bool {
-        Path::new(path.as_str()).exists()
+    #[rhai_fn(return_raw)]
+    pub fn exists(
+        ctx: NativeCallContext,
+        path: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Read)?;
+
+        Ok(path.exists())
     }
 
-    pub fn delete(path: ImmutableString) -> bool {
-        let path = Path::new(path.as_str());
+    /// Deletes a file or an empty directory
+    ///
+    /// # Arguments
+    /// * `path` - The path to delete
+    ///
+    /// # Returns
+    /// True if the path was deleted, false otherwise
+    #[rhai_fn(return_raw)]
+    pub fn delete(
+        ctx: NativeCallContext,
+        path: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Write)?;
+
         if path.is_dir() {
-            fs::remove_dir(path).is_ok()
+            Ok(fs::remove_dir(&path).is_ok())
         } else {
-            fs::remove_file(path).is_ok()
+            Ok(fs::remove_file(&path).is_ok())
         }
     }
 
@@ -122,8 +159,16 @@ pub mod fs_module {
     ///
     /// # Returns
     /// True if the copy was successful, false otherwise
-    pub fn copy(src: ImmutableString, dest: ImmutableString) -> bool {
-        fs::copy(src.as_str(), dest.as_str()).is_ok()
+    #[rhai_fn(return_raw)]
+    pub fn copy(
+        ctx: NativeCallContext,
+        src: ImmutableString,
+        dest: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let src = sandboxed(&ctx, &src, FsAccess::Read)?;
+        let dest = sandboxed(&ctx, &dest, FsAccess::Write)?;
+
+        Ok(fs::copy(&src, &dest).is_ok())
     }
 
     /// Moves or renames a file or directory
@@ -134,9 +179,16 @@ pub mod fs_module {
     ///
     /// # Returns
     /// True if the move was successful, false otherwise
-    #[rhai_fn(name = "move")]
-    pub fn move_file(src: ImmutableString, dest: ImmutableString) -> bool {
-        fs::rename(src.as_str(), dest.as_str()).is_ok()
+    #[rhai_fn(name = "move", return_raw)]
+    pub fn move_file(
+        ctx: NativeCallContext,
+        src: ImmutableString,
+        dest: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let src = sandboxed(&ctx, &src, FsAccess::Write)?;
+        let dest = sandboxed(&ctx, &dest, FsAccess::Write)?;
+
+        Ok(fs::rename(&src, &dest).is_ok())
     }
 
     /// Lists the names of files and directories in a given path
@@ -147,10 +199,15 @@ pub mod fs_module {
     /// # Returns
     /// A list of file and directory names
     #[rhai_fn(return_raw)]
-    pub fn list_files(path: ImmutableString) -> Result<Vec<ImmutableString>, Box<EvalAltResult>> {
+    pub fn list_files(
+        ctx: NativeCallContext,
+        path: ImmutableString,
+    ) -> Result<Vec<ImmutableString>, Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Read)?;
+
         let mut files = Vec::new();
 
-        match fs::read_dir(path.as_str()) {
+        match fs::read_dir(&path) {
             Ok(entries) => {
                 for entry in entries.flatten() {
                     if let Ok(name) = entry.file_name().into_string() {
@@ -166,8 +223,21 @@ pub mod fs_module {
         }
     }
 
-    pub fn mkdir(path: ImmutableString) -> bool {
-        fs::create_dir_all(path.as_str()).is_ok()
+    /// Creates a directory and all of its missing parents
+    ///
+    /// # Arguments
+    /// * `path` - The directory to create
+    ///
+    /// # Returns
+    /// True if the directory exists after the call, false otherwise
+    #[rhai_fn(return_raw)]
+    pub fn mkdir(
+        ctx: NativeCallContext,
+        path: ImmutableString,
+    ) -> Result<bool, Box<EvalAltResult>> {
+        let path = sandboxed(&ctx, &path, FsAccess::Write)?;
+
+        Ok(fs::create_dir_all(&path).is_ok())
     }
 
     pub fn basename(path: ImmutableString) -> ImmutableString {
@@ -195,3 +265,196 @@ pub mod fs_module {
         )
     }
 }
+
+/// The kind of access a script asks for on a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsAccess {
+    Read,
+    Write,
+}
+
+impl FsAccess {
+    fn permission(&self) -> &'static str {
+        match self {
+            FsAccess::Read => permission::FS_READ,
+            FsAccess::Write => permission::FS_WRITE,
+        }
+    }
+}
+
+/// Maps the paths used by a skill script onto the real filesystem.
+///
+/// Plain paths live under the skill's `data` directory, paths prefixed with `skill:`
+/// are a read-only view of the skill directory itself. Skills holding the
+/// `fs.unrestricted` permission, which no wildcard grants, may also use absolute host paths.
+#[derive(Debug, Clone)]
+pub struct FsSandbox {
+    data_root: PathBuf,
+    skill_root: PathBuf,
+    unrestricted: bool,
+}
+
+impl FsSandbox {
+    pub fn new(skill_root: PathBuf, unrestricted: bool) -> Self {
+        Self {
+            data_root: skill_root.join("data"),
+            skill_root,
+            unrestricted,
+        }
+    }
+
+    pub fn for_skill(skill: &SkillContext) -> Self {
+        Self::new(
+            PathBuf::from(skill.path.as_ref()),
+            skill.has_permission(permission::FS_UNRESTRICTED),
+        )
+    }
+
+    /// Resolves a script path to a host path, rejecting anything outside the sandbox.
+    pub fn resolve(&self, path: &str, access: FsAccess) -> Result<PathBuf, String> {
+        if self.unrestricted && Path::new(path).is_absolute() {
+            return Ok(PathBuf::from(path));
+        }
+
+        let (root, relative) = match path.strip_prefix(SKILL_PREFIX) {
+            Some(_) if access == FsAccess::Write => {
+                return Err(format!("'{}' is read-only", path));
+            }
+            Some(rest) => (&self.skill_root, rest),
+            None => (&self.data_root, path),
+        };
+
+        fs::create_dir_all(&self.data_root)
+            .map_err(|e| format!("Could not create skill data directory: {}", e))?;
+
+        let resolved = root.join(Self::normalize(relative)?);
+        Self::ensure_inside(root, &resolved)?;
+
+        Ok(resolved)
+    }
+
+    /// Lexically normalizes a relative path, refusing `..` components that climb out of it.
+    fn normalize(relative: &str) -> Result<PathBuf, String> {
+        let mut normalized = PathBuf::new();
+
+        for component in Path::new(relative).components() {
+            match component {
+                Component::Normal(part) => normalized.push(part),
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return Err(format!("Path '{}' escapes the skill sandbox", relative));
+                    }
+                }
+                Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            }
+        }
+
+        Ok(normalized)
+    }
+
+    /// Follows symlinks on the deepest existing ancestor of `path` and checks it stays under `root`.
+    fn ensure_inside(root: &Path, path: &Path) -> Result<(), String> {
+        let canonical_root = root
+            .canonicalize()
+            .map_err(|e| format!("Could not resolve {}: {}", root.display(), e))?;
+
+        let mut existing = path.to_path_buf();
+        while fs::symlink_metadata(&existing).is_err() {
+            if !existing.pop() {
+                return Err(format!("Could not resolve {}", path.display()));
+            }
+        }
+
+        let canonical = existing
+            .canonicalize()
+            .map_err(|_| format!("Path '{}' points to a broken link", path.display()))?;
+
+        if canonical.starts_with(&canonical_root) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Path '{}' escapes the skill sandbox",
+                path.display()
+            ))
+        }
+    }
+}
+
+/// Checks the permission for `access` and resolves `path` inside the calling skill's sandbox.
+fn sandboxed(
+    ctx: &NativeCallContext,
+    path: &str,
+    access: FsAccess,
+) -> Result<PathBuf, Box<EvalAltResult>> {
+    let skill = require_permission(ctx, access.permission())?;
+
+    FsSandbox::for_skill(&skill)
+        .resolve(path, access)
+        .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), ctx.call_position())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_resolve_relative_paths_into_data_dir() {
+        let dir = tempdir().unwrap();
+        let sandbox = FsSandbox::new(dir.path().to_path_buf(), false);
+
+        let resolved = sandbox.resolve("notes/today.txt", FsAccess::Write).unwrap();
+        assert_eq!(resolved, dir.path().join("data/notes/today.txt"));
+
+        let rooted = sandbox.resolve("/etc/passwd", FsAccess::Read).unwrap();
+        assert_eq!(rooted, dir.path().join("data/etc/passwd"));
+    }
+
+    #[test]
+    fn test_resolve_rejects_parent_escape() {
+        let dir = tempdir().unwrap();
+        let sandbox = FsSandbox::new(dir.path().to_path_buf(), false);
+
+        assert!(sandbox.resolve("../main.avi", FsAccess::Read).is_err());
+        assert!(sandbox.resolve("a/../../x", FsAccess::Write).is_err());
+        assert!(sandbox.resolve("a/../b", FsAccess::Write).is_ok());
+        assert!(sandbox.resolve("skill:../other", FsAccess::Read).is_err());
+    }
+
+    #[test]
+    fn test_skill_view_is_read_only() {
+        let dir = tempdir().unwrap();
+        let sandbox = FsSandbox::new(dir.path().to_path_buf(), false);
+
+        assert_eq!(
+            sandbox.resolve("skill:main.avi", FsAccess::Read).unwrap(),
+            dir.path().join("main.avi")
+        );
+        assert!(sandbox.resolve("skill:main.avi", FsAccess::Write).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_rejects_symlink_breakout() {
+        let dir = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        let sandbox = FsSandbox::new(dir.path().to_path_buf(), false);
+        sandbox.resolve("", FsAccess::Read).unwrap();
+
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("data/link")).unwrap();
+
+        assert!(sandbox.resolve("link/secret", FsAccess::Read).is_err());
+        assert!(sandbox.resolve("link", FsAccess::Write).is_err());
+    }
+
+    #[test]
+    fn test_unrestricted_allows_absolute_paths() {
+        let dir = tempdir().unwrap();
+        let sandbox = FsSandbox::new(dir.path().to_path_buf(), true);
+
+        assert_eq!(
+            sandbox.resolve("/tmp/file", FsAccess::Write).unwrap(),
+            PathBuf::from("/tmp/file")
+        );
+    }
+}
@@ -1,7 +1,7 @@
-use crate::dialogue::languages::lang;
-use crate::skills::avi_script::helpers::{skill_context, skill_context_def};
+use crate::dialogue::languages::user_language;
+use crate::skills::avi_script::helpers::{dynamic_to_json, skill_context, skill_context_def};
 use rhai::plugin::*;
-use rhai::{Dynamic, EvalAltResult, Map, NativeCallContext};
+use rhai::{EvalAltResult, Map, NativeCallContext};
 
 #[export_module]
 pub mod locale_module {
@@ -29,9 +29,13 @@ pub mod locale_module {
 
     /// Gets a formatted translation for a given ID in the current locale
     ///
+    /// The translation is an ICU style message, e.g.
+    /// `"{count, plural, one {# minute} other {# minutes}}"` or
+    /// `"{gender, select, female {Ela} other {Ele}}"`, with `number`, `date` and `time` arguments.
+    ///
     /// # Arguments
     /// * `id` - The ID of the translation to retrieve
-    /// * `params` - A map of parameters to interpolate into the translation
+    /// * `params` - A map of the values of the arguments, numbers stay numbers
     ///
     /// # Returns
     /// The formatted translation ImmutableString if found, or UNIT if not found
@@ -41,9 +45,9 @@ pub mod locale_module {
         id: ImmutableString,
         params: Map,
     ) -> Result<ImmutableString, Box<EvalAltResult>> {
-        let string_params = map_to_string_hashmap(params)?;
+        let params = map_to_json(params)?;
         skill_context(ctx, None, |v| {
-            v.languages.locale_fmt(&lang(), &id, &string_params)
+            v.languages.locale_fmt(&user_language(), &id, &params)
         })
         .ok_or(Box::new(EvalAltResult::ErrorRuntime(
             "Could not get the skill context".to_string().into(),
@@ -58,7 +62,7 @@ pub mod locale_module {
     /// * `code` - The locale code (e.g., 'en-US')
     ///
     /// # Returns
-    /// A map of translations
+    /// A map of translations, with keys missing in `code` filled from its base language, the system language and 'en'
     pub fn list(
         ctx: NativeCallContext,
         code: ImmutableString,
@@ -80,38 +84,19 @@ pub mod locale_module {
         skill_context_def(ctx, |v| v.languages.has(&id))
     }
 
-    /// Gets the current language code
+    /// Gets the current language code, the user's language
     ///
     /// # Returns
     /// The current language code (e.g., 'en-US')
     pub fn current(_ctx: NativeCallContext) -> ImmutableString {
-        ImmutableString::from(lang())
+        ImmutableString::from(user_language())
     }
 }
 
-fn map_to_string_hashmap(
+fn map_to_json(
     map: Map,
-) -> Result<std::collections::HashMap<String, String>, Box<EvalAltResult>> {
-    let mut result = std::collections::HashMap::new();
-
-    for (key, value) in map {
-        let string_value = dynamic_to_string(value)?;
-        result.insert(key.to_string(), string_value);
-    }
-
-    Ok(result)
-}
-
-fn dynamic_to_string(value: Dynamic) -> Result<String, Box<EvalAltResult>> {
-    if let Some(s) = value.clone().try_cast::<String>() {
-        Ok(s)
-    } else if let Some(i) = value.clone().try_cast::<i64>() {
-        Ok(i.to_string())
-    } else if let Some(f) = value.clone().try_cast::<f64>() {
-        Ok(f.to_string())
-    } else if let Some(b) = value.clone().try_cast::<bool>() {
-        Ok(b.to_string())
-    } else {
-        Ok(format!("{:?}", value))
-    }
+) -> Result<std::collections::HashMap<String, serde_json::Value>, Box<EvalAltResult>> {
+    map.into_iter()
+        .map(|(key, value)| Ok((key.to_string(), dynamic_to_json(value)?)))
+        .collect()
 }
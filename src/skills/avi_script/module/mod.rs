@@ -3,13 +3,17 @@ use rhai::module_resolvers::StaticModuleResolver;
 
 pub(crate) mod constant;
 pub(crate) mod context;
+pub(crate) mod device;
 pub(crate) mod dialogue;
+pub(crate) mod events;
 pub(crate) mod fs;
 pub(crate) mod json;
 pub(crate) mod locale;
 pub(crate) mod log;
 mod ml;
+pub(crate) mod parse;
 mod rand;
+pub(crate) mod schedule;
 pub(crate) mod settings;
 pub(crate) mod skill;
 pub(crate) mod slots;
@@ -25,6 +29,7 @@ pub fn resolver() -> StaticModuleResolver {
         "dialogue",
         rhai::exported_module!(dialogue::dialogue_module),
     );
+    resolver.insert("device", rhai::exported_module!(device::device_module));
     resolver.insert("skill", rhai::exported_module!(skill::skill_module));
     resolver.insert("locale", rhai::exported_module!(locale::locale_module));
     resolver.insert("json", rhai::exported_module!(json::json_module));
@@ -37,6 +42,11 @@ pub fn resolver() -> StaticModuleResolver {
         rhai::exported_module!(settings::settings_module),
     );
     resolver.insert("context", rhai::exported_module!(context::context_module));
+    resolver.insert("events", rhai::exported_module!(events::events_module));
+    resolver.insert(
+        "schedule",
+        rhai::exported_module!(schedule::schedule_module),
+    );
     resolver.insert("fs", rhai::exported_module!(fs::fs_module));
     resolver.insert("slots", rhai::exported_module!(slots::slots_module));
     resolver.insert("user", rhai::exported_module!(user::user_module));
@@ -44,6 +54,7 @@ pub fn resolver() -> StaticModuleResolver {
     resolver.insert("url", rhai::exported_module!(urls::url_module));
     resolver.insert("ml", rhai::exported_module!(ml::ml));
     resolver.insert("rand", rhai::exported_module!(rand::rand_functions));
+    resolver.insert("parse", rhai::exported_module!(parse::parse_module));
 
     resolver
 }
@@ -54,6 +65,10 @@ pub(crate) fn add_static_modules(engine: &mut Engine) {
         "dialogue",
         rhai::exported_module!(dialogue::dialogue_module).into(),
     );
+    engine.register_static_module(
+        "device",
+        rhai::exported_module!(device::device_module).into(),
+    );
     engine.register_static_module("skill", rhai::exported_module!(skill::skill_module).into());
     engine.register_static_module(
         "locale",
@@ -72,6 +87,14 @@ pub(crate) fn add_static_modules(engine: &mut Engine) {
         "context",
         rhai::exported_module!(context::context_module).into(),
     );
+    engine.register_static_module(
+        "events",
+        rhai::exported_module!(events::events_module).into(),
+    );
+    engine.register_static_module(
+        "schedule",
+        rhai::exported_module!(schedule::schedule_module).into(),
+    );
     engine.register_static_module("fs", rhai::exported_module!(fs::fs_module).into());
     engine.register_static_module("slots", rhai::exported_module!(slots::slots_module).into());
     engine.register_static_module("user", rhai::exported_module!(user::user_module).into());
@@ -79,4 +102,5 @@ pub(crate) fn add_static_modules(engine: &mut Engine) {
     engine.register_static_module("url", rhai::exported_module!(urls::url_module).into());
     engine.register_static_module("ml", rhai::exported_module!(ml::ml).into());
     engine.register_static_module("rand", rhai::exported_module!(rand::rand_functions).into());
+    engine.register_static_module("parse", rhai::exported_module!(parse::parse_module).into());
 }
@@ -0,0 +1,138 @@
+use crate::dialogue::languages::user_language;
+use rhai::plugin::*;
+
+#[export_module]
+pub mod parse_module {
+    use crate::data::user::user_timezone;
+    use crate::dialogue::lang_parse::{
+        ExtractDatetime, ExtractDuration, ExtractNumber, ExtractNumbers, IsFractional,
+        extract_datetime, extract_duration, extract_number, extract_numbers, is_fractional,
+    };
+    use chrono::Utc;
+
+    /// Extracts every number in a text, in the user's language
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "twenty five or 3.5"
+    ///
+    /// # Returns
+    /// An ExtractNumbers whose `values` is an array of the numbers found
+    pub fn numbers(text: ImmutableString) -> ExtractNumbers {
+        extract_numbers(&text, &user_language())
+    }
+
+    /// Extracts every number in a text
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "vinte e cinco ou 3,5"
+    /// * `language` - The language of the text (e.g., 'pt')
+    ///
+    /// # Returns
+    /// An ExtractNumbers whose `values` is an array of the numbers found
+    #[rhai_fn(name = "numbers")]
+    pub fn numbers_in(text: ImmutableString, language: ImmutableString) -> ExtractNumbers {
+        extract_numbers(&text, &language)
+    }
+
+    /// Extracts the first number in a text, in the user's language
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "set the volume to seventy"
+    ///
+    /// # Returns
+    /// An ExtractNumber whose `value` is the number, or () if there is none
+    pub fn number(text: ImmutableString) -> ExtractNumber {
+        extract_number(&text, &user_language())
+    }
+
+    /// Extracts the first number in a text
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse
+    /// * `language` - The language of the text (e.g., 'pt')
+    ///
+    /// # Returns
+    /// An ExtractNumber whose `value` is the number, or () if there is none
+    #[rhai_fn(name = "number")]
+    pub fn number_in(text: ImmutableString, language: ImmutableString) -> ExtractNumber {
+        extract_number(&text, &language)
+    }
+
+    /// Extracts a duration from a text, in the user's language
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "set a timer for an hour and a half"
+    ///
+    /// # Returns
+    /// An ExtractDuration whose `duration` is in seconds, or () if there is none,
+    /// and whose `leftover` is the rest of the text
+    pub fn duration(text: ImmutableString) -> ExtractDuration {
+        extract_duration(&text, &user_language())
+    }
+
+    /// Extracts a duration from a text
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "uma hora e meia"
+    /// * `language` - The language of the text (e.g., 'pt')
+    ///
+    /// # Returns
+    /// An ExtractDuration whose `duration` is in seconds, or () if there is none,
+    /// and whose `leftover` is the rest of the text
+    #[rhai_fn(name = "duration")]
+    pub fn duration_in(text: ImmutableString, language: ImmutableString) -> ExtractDuration {
+        extract_duration(&text, &language)
+    }
+
+    /// Extracts a datetime from a text, in the user's language and timezone
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "remind me next friday at 3pm"
+    ///
+    /// # Returns
+    /// An ExtractDatetime whose `datetime` is an RFC 3339 timestamp, or () if there is none,
+    /// and whose `leftover` is the rest of the text
+    pub fn datetime(text: ImmutableString) -> ExtractDatetime {
+        let now = Utc::now().with_timezone(&user_timezone());
+        extract_datetime(&text, &user_language(), now)
+    }
+
+    /// Extracts a datetime from a text, in the user's timezone
+    ///
+    /// # Arguments
+    /// * `text` - The text to parse, e.g. "amanhã às 15h30"
+    /// * `language` - The language of the text (e.g., 'pt')
+    ///
+    /// # Returns
+    /// An ExtractDatetime whose `datetime` is an RFC 3339 timestamp, or () if there is none,
+    /// and whose `leftover` is the rest of the text
+    #[rhai_fn(name = "datetime")]
+    pub fn datetime_in(text: ImmutableString, language: ImmutableString) -> ExtractDatetime {
+        let now = Utc::now().with_timezone(&user_timezone());
+        extract_datetime(&text, &language, now)
+    }
+
+    /// Checks whether a word names a fraction, in the user's language
+    ///
+    /// # Arguments
+    /// * `text` - The word, e.g. "half" or "third"
+    ///
+    /// # Returns
+    /// An IsFractional whose `value` is the fraction (0.5 for "half"), or () if it isn't one
+    pub fn fractional(text: ImmutableString) -> IsFractional {
+        is_fractional(&text, &user_language())
+    }
+
+    /// Checks whether a word names a fraction
+    ///
+    /// # Arguments
+    /// * `text` - The word, e.g. "meio" or "terço"
+    /// * `language` - The language of the word (e.g., 'pt')
+    ///
+    /// # Returns
+    /// An IsFractional whose `value` is the fraction (0.5 for "meio"), or () if it isn't one
+    #[rhai_fn(name = "fractional")]
+    pub fn fractional_in(text: ImmutableString, language: ImmutableString) -> IsFractional {
+        is_fractional(&text, &language)
+    }
+}
@@ -0,0 +1,225 @@
+use crate::ctx::runtime;
+use crate::data::scheduler::{Job, JobTarget, Trigger};
+use crate::data::user::user_timezone;
+use crate::skills::avi_script::helpers::{dynamic_to_json, get_skill_context, require_permission};
+use crate::skills::skill_context::permission;
+use chrono::Utc;
+use rhai::plugin::*;
+use rhai::{Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext};
+
+#[export_module]
+pub mod schedule_module {
+    use rhai::{Array, FnPtr};
+
+    /// Calls a function of the skill repeatedly
+    ///
+    /// The job is stored and survives restarts. Scheduling the same function with the
+    /// same spec again returns the existing job.
+    ///
+    /// # Arguments
+    /// * `spec` - An interval such as `"30s"`, `"5m"` or `"2 hours"`, or a cron expression
+    /// * `handler` - A named function, e.g. `Fn("water_plants")`
+    ///
+    /// # Returns
+    /// The id of the job
+    #[rhai_fn(return_raw)]
+    pub fn every(
+        ctx: NativeCallContext,
+        spec: ImmutableString,
+        handler: FnPtr,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let trigger = Trigger::every(&spec).map_err(|e| runtime_error(&ctx, e))?;
+        schedule_function(&ctx, trigger, handler)
+    }
+
+    /// Calls a function of the skill once, at a given time in the user's timezone
+    ///
+    /// # Arguments
+    /// * `time` - `"HH:MM"` for its next occurrence, `"YYYY-MM-DD HH:MM"` or an RFC 3339 timestamp
+    /// * `handler` - A named function, e.g. `Fn("remind")`
+    ///
+    /// # Returns
+    /// The id of the job
+    #[rhai_fn(return_raw)]
+    pub fn at(
+        ctx: NativeCallContext,
+        time: ImmutableString,
+        handler: FnPtr,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let trigger =
+            Trigger::at(&time, Utc::now(), user_timezone()).map_err(|e| runtime_error(&ctx, e))?;
+        schedule_function(&ctx, trigger, handler)
+    }
+
+    /// Publishes a message to a mesh topic repeatedly
+    ///
+    /// # Arguments
+    /// * `spec` - An interval or a cron expression
+    /// * `topic` - The topic to publish to
+    /// * `data` - The payload, sent as-is if it is a string and as JSON otherwise
+    ///
+    /// # Returns
+    /// The id of the job
+    #[rhai_fn(return_raw)]
+    pub fn publish_every(
+        ctx: NativeCallContext,
+        spec: ImmutableString,
+        topic: ImmutableString,
+        data: Dynamic,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let trigger = Trigger::every(&spec).map_err(|e| runtime_error(&ctx, e))?;
+        schedule_topic(&ctx, trigger, topic, data)
+    }
+
+    /// Publishes a message to a mesh topic once, at a given time in the user's timezone
+    ///
+    /// # Arguments
+    /// * `time` - `"HH:MM"` for its next occurrence, `"YYYY-MM-DD HH:MM"` or an RFC 3339 timestamp
+    /// * `topic` - The topic to publish to
+    /// * `data` - The payload, sent as-is if it is a string and as JSON otherwise
+    ///
+    /// # Returns
+    /// The id of the job
+    #[rhai_fn(return_raw)]
+    pub fn publish_at(
+        ctx: NativeCallContext,
+        time: ImmutableString,
+        topic: ImmutableString,
+        data: Dynamic,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let trigger =
+            Trigger::at(&time, Utc::now(), user_timezone()).map_err(|e| runtime_error(&ctx, e))?;
+        schedule_topic(&ctx, trigger, topic, data)
+    }
+
+    /// Cancels a job of the skill
+    ///
+    /// # Arguments
+    /// * `id` - The id of the job
+    ///
+    /// # Returns
+    /// Whether the job existed
+    #[rhai_fn(return_raw)]
+    pub fn cancel(ctx: NativeCallContext, id: ImmutableString) -> Result<bool, Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx).map_err(|e| runtime_error(&ctx, e))?;
+        Ok(runtime()?.scheduler.cancel(skill.key(), &id))
+    }
+
+    /// Lists the jobs of the skill
+    ///
+    /// # Returns
+    /// An array of maps with `id`, `trigger`, `target` and `next_run`
+    #[rhai_fn(return_raw)]
+    pub fn list(ctx: NativeCallContext) -> Result<Array, Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx).map_err(|e| runtime_error(&ctx, e))?;
+        Ok(runtime()?
+            .scheduler
+            .jobs_of(skill.key())
+            .into_iter()
+            .map(|job| Dynamic::from_map(job_to_map(job)))
+            .collect())
+    }
+
+    /// Gets the next run of a job of the skill
+    ///
+    /// # Arguments
+    /// * `id` - The id of the job
+    ///
+    /// # Returns
+    /// The next run as an RFC 3339 timestamp in the user's timezone, or `()` if unknown
+    #[rhai_fn(return_raw)]
+    pub fn next(
+        ctx: NativeCallContext,
+        id: ImmutableString,
+    ) -> Result<Dynamic, Box<EvalAltResult>> {
+        let skill = get_skill_context(&ctx).map_err(|e| runtime_error(&ctx, e))?;
+        Ok(runtime()?
+            .scheduler
+            .jobs_of(skill.key())
+            .into_iter()
+            .find(|job| job.id == id.as_str())
+            .map(|job| job_to_map(job).remove("next_run").unwrap_or(Dynamic::UNIT))
+            .unwrap_or(Dynamic::UNIT))
+    }
+}
+
+fn runtime_error(ctx: &NativeCallContext, message: String) -> Box<EvalAltResult> {
+    Box::new(EvalAltResult::ErrorRuntime(
+        message.into(),
+        ctx.call_position(),
+    ))
+}
+
+fn schedule_function(
+    ctx: &NativeCallContext,
+    trigger: Trigger,
+    handler: FnPtr,
+) -> Result<ImmutableString, Box<EvalAltResult>> {
+    let skill = get_skill_context(ctx).map_err(|e| runtime_error(ctx, e))?;
+
+    // Only the name of the function is stored, closures cannot outlive the script
+    if handler.is_anonymous() {
+        return Err(runtime_error(
+            ctx,
+            "Scheduled functions must be named, use `every`/`at` blocks for closures".to_string(),
+        ));
+    }
+
+    let target = JobTarget::Function {
+        function: handler.fn_name().to_string(),
+    };
+
+    runtime()?
+        .scheduler
+        .add(skill.key(), trigger, target, true)
+        .map(Into::into)
+        .map_err(|e| runtime_error(ctx, e))
+}
+
+fn schedule_topic(
+    ctx: &NativeCallContext,
+    trigger: Trigger,
+    topic: ImmutableString,
+    data: Dynamic,
+) -> Result<ImmutableString, Box<EvalAltResult>> {
+    let skill = require_permission(ctx, permission::MESH_PUBLISH)?;
+    let data = dynamic_to_json(data).map_err(|e| runtime_error(ctx, e))?;
+
+    let target = JobTarget::Topic {
+        topic: topic.to_string(),
+        data,
+    };
+
+    runtime()?
+        .scheduler
+        .add(skill.key(), trigger, target, true)
+        .map(Into::into)
+        .map_err(|e| runtime_error(ctx, e))
+}
+
+fn job_to_map(job: Job) -> Map {
+    let mut map = Map::new();
+    map.insert("id".into(), job.id.into());
+
+    let trigger = match job.trigger {
+        Trigger::Cron { expression } => expression,
+        Trigger::Interval { seconds } => format!("{}s", seconds),
+        Trigger::At { time } => time.with_timezone(&user_timezone()).to_rfc3339(),
+    };
+    map.insert("trigger".into(), trigger.into());
+
+    let target = match job.target {
+        JobTarget::Function { function } => function,
+        JobTarget::Handler { index } => format!("block {}", index),
+        JobTarget::Topic { topic, .. } => format!("topic:{}", topic),
+    };
+    map.insert("target".into(), target.into());
+
+    let next_run = job
+        .next_run
+        .map(|time| Dynamic::from(time.with_timezone(&user_timezone()).to_rfc3339()))
+        .unwrap_or(Dynamic::UNIT);
+    map.insert("next_run".into(), next_run);
+
+    map
+}
@@ -1,5 +1,7 @@
+use crate::skills::avi_script::helpers::require_permission;
+use crate::skills::skill_context::permission;
 use rhai::plugin::*;
-use rhai::{EvalAltResult, Position};
+use rhai::{EvalAltResult, NativeCallContext, Position};
 use std::process::Command;
 use uuid::Uuid;
 
@@ -44,7 +46,12 @@ pub mod util_module {
     /// # Note
     /// Uses "cmd /C" on Windows, "sh -c" on Unix-like systems
     #[rhai_fn(return_raw)]
-    pub fn cmd(command: ImmutableString) -> Result<i64, Box<EvalAltResult>> {
+    pub fn cmd(
+        ctx: NativeCallContext,
+        command: ImmutableString,
+    ) -> Result<i64, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::PROCESS_EXEC)?;
+
         let output = if cfg!(target_os = "windows") {
             Command::new("cmd").args(["/C", command.as_str()]).output()
         } else {
@@ -79,8 +86,17 @@ pub mod util_module {
     ///
     /// # Returns
     /// The environment variable value, or the default if not found
-    pub fn env(name: ImmutableString, default: ImmutableString) -> ImmutableString {
-        ImmutableString::from(std::env::var(name.to_string()).unwrap_or(default.to_string()))
+    #[rhai_fn(return_raw)]
+    pub fn env(
+        ctx: NativeCallContext,
+        name: ImmutableString,
+        default: ImmutableString,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        require_permission(&ctx, permission::ENV_READ)?;
+
+        Ok(ImmutableString::from(
+            std::env::var(name.to_string()).unwrap_or(default.to_string()),
+        ))
     }
 
     pub fn get_string(data: Vec<u8>) -> ImmutableString {
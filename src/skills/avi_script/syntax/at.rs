@@ -0,0 +1,32 @@
+use crate::data::scheduler::Trigger;
+use crate::data::user::user_timezone;
+use chrono::Utc;
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, Position};
+
+pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
+    engine.register_custom_syntax(["at", "$string$", "$func$"], false, at_syntax_handler)?;
+    Ok(())
+}
+
+fn at_syntax_handler(
+    context: &mut EvalContext,
+    inputs: &[Expression],
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let time = inputs[0]
+        .get_string_value()
+        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from("Expected a time!"),
+            Position::NONE,
+        )))?;
+    let trigger = Trigger::at(time, Utc::now(), user_timezone()).map_err(|e| {
+        Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from(e),
+            inputs[0].position(),
+        ))
+    })?;
+    let handler = super::handler_fn(context, &inputs[1])?;
+
+    super::handlers(context)?.on_schedule(trigger, handler);
+
+    Ok(Dynamic::UNIT)
+}
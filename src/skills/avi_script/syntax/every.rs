@@ -0,0 +1,30 @@
+use crate::data::scheduler::Trigger;
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, Position};
+
+pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
+    engine.register_custom_syntax(["every", "$string$", "$func$"], false, every_syntax_handler)?;
+    Ok(())
+}
+
+fn every_syntax_handler(
+    context: &mut EvalContext,
+    inputs: &[Expression],
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let spec = inputs[0]
+        .get_string_value()
+        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from("Expected an interval or a cron expression!"),
+            Position::NONE,
+        )))?;
+    let trigger = Trigger::every(spec).map_err(|e| {
+        Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from(e),
+            inputs[0].position(),
+        ))
+    })?;
+    let handler = super::handler_fn(context, &inputs[1])?;
+
+    super::handlers(context)?.on_schedule(trigger, handler);
+
+    Ok(Dynamic::UNIT)
+}
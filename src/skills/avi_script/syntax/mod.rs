@@ -1,6 +1,11 @@
-use rhai::{Engine, EvalAltResult};
+use crate::skills::handlers::{HANDLERS_SCOPE_KEY, HandlerRegistry};
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, FnPtr, Position};
 
+mod at;
+mod every;
+mod on_context;
 mod on_end;
+mod on_fallback;
 mod on_intent;
 mod on_start;
 mod operators;
@@ -10,8 +15,37 @@ pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
     operators::add(engine)?;
     on_start::add(engine)?;
     on_end::add(engine)?;
+    on_fallback::add(engine)?;
+    on_context::add(engine)?;
     on_intent::add(engine)?;
     subscribe::add(engine)?;
+    every::add(engine)?;
+    at::add(engine)?;
 
     Ok(())
 }
+
+/// Gets the handler registry of the skill being loaded.
+fn handlers(context: &EvalContext) -> Result<HandlerRegistry, Box<EvalAltResult>> {
+    context
+        .scope()
+        .get_value::<HandlerRegistry>(HANDLERS_SCOPE_KEY)
+        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from("Handlers can only be registered while loading a skill"),
+            Position::NONE,
+        )))
+}
+
+/// Evaluates a `$func$` input into the closure that will be registered as a handler.
+fn handler_fn(context: &mut EvalContext, input: &Expression) -> Result<FnPtr, Box<EvalAltResult>> {
+    let handler = context.eval_expression_tree(input)?;
+    let type_name = handler.type_name();
+
+    handler
+        .try_cast::<FnPtr>()
+        .ok_or(Box::new(EvalAltResult::ErrorMismatchDataType(
+            "Fn".to_string(),
+            type_name.to_string(),
+            input.position(),
+        )))
+}
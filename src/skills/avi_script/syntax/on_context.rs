@@ -0,0 +1,41 @@
+use crate::skills::avi_script::module::context::watch_context;
+use crate::skills::skill_context::SkillContext;
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, Position};
+
+pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
+    engine.register_custom_syntax(
+        ["on_context", "$string$", "$func$"],
+        false,
+        on_context_syntax_handler,
+    )?;
+    Ok(())
+}
+
+fn on_context_syntax_handler(
+    context: &mut EvalContext,
+    inputs: &[Expression],
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let spec = inputs[0]
+        .get_string_value()
+        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from("Expected a context key!"),
+            Position::NONE,
+        )))?
+        .to_string();
+
+    let skill = context
+        .tag()
+        .clone()
+        .try_cast::<SkillContext>()
+        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from("on_context can only be used in a skill"),
+            inputs[0].position(),
+        )))?;
+
+    let handler = super::handler_fn(context, &inputs[1])?;
+
+    watch_context(&skill, &spec, handler)
+        .map_err(|e| Box::new(EvalAltResult::ErrorRuntime(e.into(), inputs[0].position())))?;
+
+    Ok(Dynamic::UNIT)
+}
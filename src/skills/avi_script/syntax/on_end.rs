@@ -1,7 +1,7 @@
 use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression};
 
 pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
-    engine.register_custom_syntax(["on_end", "$block$"], false, on_end_syntax_handler)?;
+    engine.register_custom_syntax(["on_end", "$func$"], false, on_end_syntax_handler)?;
     Ok(())
 }
 
@@ -9,11 +9,9 @@ fn on_end_syntax_handler(
     context: &mut EvalContext,
     inputs: &[Expression],
 ) -> Result<Dynamic, Box<EvalAltResult>> {
-    let block = &inputs[0];
+    let handler = super::handler_fn(context, &inputs[0])?;
 
-    if let Some(true) = context.scope().get_value::<bool>("END") {
-        let _ = context.eval_expression_tree(block);
-    }
+    super::handlers(context)?.on_end(handler);
 
     Ok(Dynamic::UNIT)
 }
@@ -0,0 +1,17 @@
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression};
+
+pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
+    engine.register_custom_syntax(["on_fallback", "$func$"], false, on_fallback_syntax_handler)?;
+    Ok(())
+}
+
+fn on_fallback_syntax_handler(
+    context: &mut EvalContext,
+    inputs: &[Expression],
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let handler = super::handler_fn(context, &inputs[0])?;
+
+    super::handlers(context)?.on_fallback(handler);
+
+    Ok(Dynamic::UNIT)
+}
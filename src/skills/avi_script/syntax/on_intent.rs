@@ -1,9 +1,8 @@
-use crate::dialogue::intent::Intent;
-use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, ImmutableString, Position};
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, Position};
 
 pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
     engine.register_custom_syntax(
-        ["on_intent", "$string$", "$block$"],
+        ["on_intent", "$string$", "$func$"],
         false,
         on_intent_syntax_handler,
     )?;
@@ -21,22 +20,9 @@ fn on_intent_syntax_handler(
             Position::NONE,
         )))?
         .to_string();
-    let block = &inputs[1];
+    let handler = super::handler_fn(context, &inputs[1])?;
 
-    if let Some(intent_name_sent) = context.scope().get_value::<ImmutableString>("INTENT_NAME")
-        && intent_name_sent.eq_ignore_ascii_case(&intent_name)
-    {
-        let scope = context.scope_mut();
-
-        scope.push_constant("name", intent_name.clone());
-        scope.push_constant(
-            "intent",
-            scope
-                .get_value::<Intent>("INTENT")
-                .ok_or("Expected Intent data")?,
-        );
-        let _ = context.eval_expression_tree(block);
-    }
+    super::handlers(context)?.on_intent(&intent_name, handler);
 
     Ok(Dynamic::UNIT)
 }
@@ -1,7 +1,8 @@
+use crate::skills::skill::STARTED_SCOPE_KEY;
 use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression};
 
 pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
-    engine.register_custom_syntax(["on_start", "$block$"], false, on_start_syntax_handler)?;
+    engine.register_custom_syntax(["on_start", "$block$"], true, on_start_syntax_handler)?;
     Ok(())
 }
 
@@ -11,9 +12,13 @@ fn on_start_syntax_handler(
 ) -> Result<Dynamic, Box<EvalAltResult>> {
     let block = &inputs[0];
 
-    if context.scope().get_value::<bool>("STARTED").is_none() {
+    if context
+        .scope()
+        .get_value::<bool>(STARTED_SCOPE_KEY)
+        .is_none()
+    {
         let scope = context.scope_mut();
-        scope.push_constant("STARTED", true);
+        scope.push_constant(STARTED_SCOPE_KEY, true);
 
         let _ = context.eval_expression_tree(block);
     }
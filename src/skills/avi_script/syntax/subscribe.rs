@@ -1,5 +1,5 @@
-use crate::utils::{Event, EventType};
-use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, ImmutableString, Position};
+use crate::utils::EventType;
+use rhai::{Dynamic, Engine, EvalAltResult, EvalContext, Expression, Position};
 
 pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
     engine.register_custom_syntax(
@@ -11,9 +11,9 @@ pub fn add(engine: &mut Engine) -> Result<(), Box<EvalAltResult>> {
             "<",
             "$ident$",
             ">",
-            "$block$",
+            "$func$",
         ],
-        true,
+        false,
         on_sub_syntax_handler,
     )?;
     Ok(())
@@ -34,18 +34,8 @@ fn on_sub_syntax_handler(
         .ok_or(Box::new(EvalAltResult::ErrorRuntime(
             Dynamic::from("Expected Event Name!"),
             Position::NONE,
-        )))?;
-    let ident = inputs[2]
-        .get_string_value()
-        .ok_or(Box::new(EvalAltResult::ErrorRuntime(
-            Dynamic::from("Expected Identifier Name!"),
-            Position::NONE,
-        )))?;
-    let func = &inputs[3];
-
-    let event_name = context.scope().get_value::<ImmutableString>("EVENT_NAME");
-    let event_data = context.scope().get_value::<Dynamic>("EVENT_DATA");
-    let event_from = context.scope().get_value::<Dynamic>("EVENT_FROM");
+        )))?
+        .to_string();
 
     let expected_type =
         EventType::from(e_type).ok_or(Box::new(EvalAltResult::ErrorCustomSyntax(
@@ -54,19 +44,9 @@ fn on_sub_syntax_handler(
             inputs[0].position(),
         )))?;
 
-    if let Some(event_name) = event_name {
-        let received_event = Event::get_event(event_name.to_string())?;
-        if received_event.event_name.eq_ignore_ascii_case(event)
-            && received_event.event_type != expected_type
-        {
-            return Ok(Dynamic::UNIT);
-        }
+    let handler = super::handler_fn(context, &inputs[3])?;
 
-        let scope = context.scope_mut();
-        scope.push_constant(ident, event_data.ok_or("Expected Data")?);
-        scope.push_constant("from", event_from.ok_or("Expected Data")?);
-        let _ = context.eval_expression_tree(func);
-    };
+    super::handlers(context)?.subscribe(expected_type, &event, handler);
 
     Ok(Dynamic::UNIT)
 }
@@ -0,0 +1,178 @@
+use crate::data::scheduler::Trigger;
+use crate::utils::{Event, EventType};
+use log::warn;
+use parking_lot::RwLock;
+use rhai::FnPtr;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Name of the scope constant the custom syntax uses to reach the registry of the running skill.
+pub const HANDLERS_SCOPE_KEY: &str = "HANDLERS";
+
+#[derive(Default)]
+struct Handlers {
+    intents: HashMap<String, FnPtr>,
+    topics: HashMap<String, Vec<FnPtr>>,
+    events: HashMap<String, Vec<FnPtr>>,
+    schedules: Vec<(Trigger, FnPtr)>,
+    fallback: Option<FnPtr>,
+    end: Vec<FnPtr>,
+}
+
+/// Per-skill dispatch table filled by the `on_intent`, `subscribe`, `every`, `at`, `on_fallback`
+/// and `on_end` blocks.
+///
+/// The table is populated once, when the skill's top-level statements run, so an intent or
+/// an event only invokes the block registered for it instead of re-running the whole script.
+#[derive(Clone, Default)]
+pub struct HandlerRegistry {
+    inner: Arc<RwLock<Handlers>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler of an intent, replacing any previous one.
+    pub fn on_intent(&self, intent_name: &str, handler: FnPtr) {
+        let key = intent_name.to_lowercase();
+        if self.inner.write().intents.insert(key, handler).is_some() {
+            warn!(
+                "Intent '{}' has more than one handler, using the last one",
+                intent_name
+            );
+        }
+    }
+
+    /// Registers a handler for a topic or an internal event.
+    pub fn subscribe(&self, event_type: EventType, event_name: &str, handler: FnPtr) {
+        let mut handlers = self.inner.write();
+        let table = match event_type {
+            EventType::Topic => &mut handlers.topics,
+            EventType::Event => &mut handlers.events,
+        };
+        table
+            .entry(event_name.to_lowercase())
+            .or_default()
+            .push(handler);
+    }
+
+    /// Registers a block that runs on a schedule.
+    pub fn on_schedule(&self, trigger: Trigger, handler: FnPtr) {
+        self.inner.write().schedules.push((trigger, handler));
+    }
+
+    /// Registers the handler of utterances no intent matched, replacing any previous one.
+    pub fn on_fallback(&self, handler: FnPtr) {
+        if self.inner.write().fallback.replace(handler).is_some() {
+            warn!("More than one on_fallback block, using the last one");
+        }
+    }
+
+    /// Registers a handler that runs when the skill is stopped.
+    pub fn on_end(&self, handler: FnPtr) {
+        self.inner.write().end.push(handler);
+    }
+
+    /// Returns the handler registered for an intent, if any.
+    pub fn intent(&self, intent_name: &str) -> Option<FnPtr> {
+        self.inner
+            .read()
+            .intents
+            .get(&intent_name.to_lowercase())
+            .cloned()
+    }
+
+    /// Returns the handlers subscribed to an event.
+    pub fn subscribers(&self, event: &Event) -> Vec<FnPtr> {
+        let handlers = self.inner.read();
+        let table = match event.event_type {
+            EventType::Topic => &handlers.topics,
+            EventType::Event => &handlers.events,
+        };
+        table
+            .get(&event.event_name.to_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the triggers of the scheduled blocks, in registration order.
+    pub fn schedules(&self) -> Vec<Trigger> {
+        self.inner
+            .read()
+            .schedules
+            .iter()
+            .map(|(trigger, _)| trigger.clone())
+            .collect()
+    }
+
+    /// Returns the scheduled block at `index`, as numbered by [`HandlerRegistry::schedules`].
+    pub fn scheduled(&self, index: usize) -> Option<FnPtr> {
+        self.inner
+            .read()
+            .schedules
+            .get(index)
+            .map(|(_, handler)| handler.clone())
+    }
+
+    /// Returns the handler registered with `on_fallback`, if any.
+    pub fn fallback(&self) -> Option<FnPtr> {
+        self.inner.read().fallback.clone()
+    }
+
+    /// Returns the handlers registered with `on_end`.
+    pub fn end(&self) -> Vec<FnPtr> {
+        self.inner.read().end.clone()
+    }
+
+    /// Removes every registered handler, used before the script is evaluated again.
+    pub fn clear(&self) {
+        *self.inner.write() = Handlers::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intent_lookup_is_case_insensitive() {
+        let registry = HandlerRegistry::new();
+        registry.on_intent("Ask_Time", FnPtr::new("ask_time").unwrap());
+
+        assert_eq!(
+            registry.intent("ask_time").map(|f| f.fn_name().to_string()),
+            Some("ask_time".to_string())
+        );
+        assert!(registry.intent("other").is_none());
+    }
+
+    #[test]
+    fn test_subscribers_are_split_by_event_type() {
+        let registry = HandlerRegistry::new();
+        registry.subscribe(EventType::Topic, "lights", FnPtr::new("on_topic").unwrap());
+        registry.subscribe(EventType::Event, "lights", FnPtr::new("on_event").unwrap());
+
+        let topic = Event::get_event("topic:lights".to_string()).unwrap();
+        let subscribers = registry.subscribers(&topic);
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].fn_name(), "on_topic");
+
+        registry.clear();
+        assert!(registry.subscribers(&topic).is_empty());
+    }
+
+    #[test]
+    fn test_last_fallback_wins() {
+        let registry = HandlerRegistry::new();
+        assert!(registry.fallback().is_none());
+
+        registry.on_fallback(FnPtr::new("first").unwrap());
+        registry.on_fallback(FnPtr::new("second").unwrap());
+        assert_eq!(
+            registry.fallback().map(|f| f.fn_name().to_string()),
+            Some("second".to_string())
+        );
+    }
+}
@@ -1,22 +1,114 @@
 use crate::ctx::runtime;
+use crate::data::config::setting_or;
+use crate::data::scheduler::Trigger;
+use crate::dialogue::follow_up::Turn;
 use crate::dialogue::intent::Intent;
-use crate::dialogue::languages::lang;
+use crate::dialogue::languages::{lang, user_language};
+use crate::dialogue::reply::{ReplyHandler, RequestReply};
+use crate::dialogue::request::{Origin, with_origin};
+use crate::dialogue::slot_filling::SlotFilling;
+use crate::events::{
+    BusEvent, COMMAND_RECEIVED, CORE_SOURCE, EventBus, INTENT_EXECUTED, SCHEDULE_FIRED,
+    SKILL_LOADED, SKILL_UNLOADED,
+};
 use crate::skills::avi_script::avi_librarymanager::initialize_avi_library;
-use crate::skills::skill::Skill;
-use crate::utils::{config_dir, get_all_docs_on_folder};
+use crate::skills::avi_script::helpers::{dynamic_to_json, json_to_dynamic};
+use crate::skills::skill::{Skill, SkillTimeout};
+use crate::utils::{Event, EventType, config_dir, get_all_docs_on_folder};
+use crate::{emit, rt_spawn, speak, subscribe};
 use avi_nlu_client::models::{self, Data, Data1Inner, Entity, InputIntent};
-use log::{info, warn};
-use rhai::{FnPtr, Variant};
-use std::collections::HashMap;
+use log::{error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use rhai::{Dynamic, FnPtr, Variant};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Extra time given to a blocked native call before the caller stops waiting for it.
+const TIMEOUT_GRACE: Duration = Duration::from_millis(500);
+
+/// A loaded skill, locked independently of every other skill.
+pub type SkillHandle = Arc<Mutex<Skill>>;
+
+/// The loaded skills keyed by their directory name, shared with the tasks dispatching to them.
+type Skills = Arc<RwLock<HashMap<String, SkillHandle>>>;
+
+thread_local! {
+    static CURRENT_SKILL: RefCell<Option<SkillRef>> = const { RefCell::new(None) };
+}
+
+/// A skill that native code holds on to in order to call back into it later, e.g. a
+/// validator written in the skill's script.
+#[derive(Clone)]
+pub struct SkillRef {
+    name: String,
+    handle: SkillHandle,
+    timeout: Duration,
+}
+
+impl SkillRef {
+    /// The skill whose code is running on this thread, if any.
+    pub fn current() -> Option<SkillRef> {
+        CURRENT_SKILL.with(|c| c.borrow().clone())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn enter<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _restore = RestoreSkill(CURRENT_SKILL.with(|c| c.replace(Some(self.clone()))));
+        f()
+    }
+
+    /// Runs `f` against the skill on the calling thread, under the skill's lock and
+    /// time limit like [`SkillManager::execute`].
+    pub fn run<T>(
+        &self,
+        f: impl FnOnce(&mut Skill) -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<T, String> {
+        let Some(mut skill) = self.handle.try_lock_for(self.timeout) else {
+            return Err(format!("Skill {} is busy", self.name));
+        };
+
+        self.enter(|| skill.guarded(self.timeout, f)).map_err(|e| {
+            if e.is::<SkillTimeout>() {
+                SkillManager::report_timeout(&self.name, self.timeout);
+            }
+            e.to_string()
+        })
+    }
+}
+
+/// Puts the previous current skill back when dropped, even if the skill panicked.
+struct RestoreSkill(Option<SkillRef>);
+
+impl Drop for RestoreSkill {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT_SKILL.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
 /// Manages the lifecycle and execution of skills.
 ///
 /// It is responsible for loading skills from the filesystem and dispatching
-/// intents to the appropriate skill.
+/// intents to the appropriate skill. Every invocation runs on the blocking
+/// worker pool under the skill's own lock, so a slow skill never holds up the others.
 pub struct SkillManager {
     /// A collection of loaded skills, keyed by their directory name.
-    skills: HashMap<String, Skill>,
+    skills: Skills,
+    /// The skills marked as `fallback` in their manifest, in the order they are asked.
+    fallbacks: RwLock<Vec<String>>,
+    /// The time a single invocation may run before it is aborted.
+    timeout: Duration,
 }
 
 impl SkillManager {
@@ -29,53 +121,236 @@ impl SkillManager {
             }
         }
         info!("Creating skills manager.");
+        let timeout = Duration::from_secs(setting_or::<u64>("skill_timeout", 10));
+        let loaded = Self::load_skills(timeout);
+        let fallbacks = loaded
+            .iter()
+            .filter_map(|(name, skill)| Some((name.clone(), skill.lock().fallback_priority()?)))
+            .collect();
+        let names: Vec<String> = loaded.keys().cloned().collect();
+        let skills = Arc::new(RwLock::new(loaded));
+
+        for name in &names {
+            if let Some(skill) = skills.read().get(name).cloned() {
+                Self::subscribe_skill(Arc::clone(&skills), name.clone(), skill, timeout);
+            }
+        }
+        // Announced once the skills are subscribed, so they can see each other load
+        for name in &names {
+            emit!(SKILL_LOADED, json!({ "skill": name }));
+        }
+        Self::hold_orphaned_jobs(&names);
+        Self::dispatch_core_events(Arc::clone(&skills), timeout);
+
         Self {
-            skills: Self::load_skills(),
+            skills,
+            fallbacks: RwLock::new(Self::fallback_order(fallbacks)),
+            timeout,
         }
     }
 
+    /// Orders fallback skills by descending priority, then by name.
+    fn fallback_order(mut fallbacks: Vec<(String, i64)>) -> Vec<String> {
+        fallbacks.sort_by(|(a, pa), (b, pb)| pb.cmp(pa).then_with(|| a.cmp(b)));
+        fallbacks.into_iter().map(|(name, _)| name).collect()
+    }
+
     /// Scans the skill directory and attempts to load all skills found within.
     ///
     /// # Returns
     ///
     /// A `HashMap` containing the successfully loaded skills.
     ///
-    pub fn load_skills() -> HashMap<String, Skill> {
+    pub fn load_skills(timeout: Duration) -> HashMap<String, SkillHandle> {
         let mut skills = HashMap::new();
 
+        for path in Self::skill_dirs() {
+            match Self::load(path, timeout) {
+                Ok((dir, skill)) => {
+                    skills.insert(dir, skill);
+                }
+                Err(e) => {
+                    warn!("{}", e)
+                }
+            }
+        }
+
+        skills
+    }
+
+    /// Handles the persisted jobs of skills that didn't load, dropping the ones of skills
+    /// that were uninstalled and holding the others until their skill loads.
+    fn hold_orphaned_jobs(loaded: &[String]) {
+        let Ok(c) = runtime() else {
+            return;
+        };
+        for owner in c.scheduler.owners() {
+            if loaded.contains(&owner) {
+                continue;
+            }
+            if Self::is_installed(&owner) {
+                warn!(
+                    "Holding the scheduled jobs of skill {} until it loads",
+                    owner
+                );
+                c.scheduler.suspend(&owner);
+            } else {
+                info!("Dropping the scheduled jobs of uninstalled skill {}", owner);
+                c.scheduler.remove_owner(&owner);
+            }
+        }
+    }
+
+    /// Whether the skill directory `name` still exists.
+    fn is_installed(name: &str) -> bool {
+        config_dir().join("skills").join(name).is_dir()
+    }
+
+    /// Lists the entries of the skill directory.
+    fn skill_dirs() -> Vec<PathBuf> {
         if let Ok(_c) = runtime()
             && let Ok(entries) = fs::read_dir(config_dir().join("skills"))
         {
             info!("Searching skills path {}/skills", config_dir().display());
-            for entry_dir in entries {
-                let entry = match entry_dir {
-                    Ok(v) => v,
-                    Err(_) => continue,
-                };
+            entries.flatten().map(|entry| entry.path()).collect()
+        } else {
+            Vec::new()
+        }
+    }
 
-                let path = entry.path();
-                match Self::load(path) {
-                    Ok((dir, skill)) => {
-                        skills.insert(dir, skill);
-                    }
-                    Err(e) => {
-                        warn!("{}", e)
+    /// Reloads every skill from disk, each one under its own lock, and loads the skills
+    /// added since the last load.
+    ///
+    /// A skill failing to reload doesn't stop the others, it is unloaded like the skills
+    /// whose directory was removed, and loaded again by a later reload once it is fixed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the skills that failed to reload, once every skill was tried.
+    pub async fn reload(&self) -> Result<(), String> {
+        info!("Reloading skills.");
+        let loaded: Vec<(String, SkillHandle)> = self
+            .skills
+            .read()
+            .iter()
+            .map(|(name, skill)| (name.clone(), Arc::clone(skill)))
+            .collect();
+
+        let mut fallbacks = Vec::new();
+        let mut failed = Vec::new();
+        for (name, skill) in loaded {
+            if !Self::is_installed(&name) {
+                info!("Skill {} was removed, unloading it", name);
+                self.unload(&name);
+                continue;
+            }
+
+            emit!(SKILL_UNLOADED, json!({ "skill": name }));
+            if let Ok(c) = runtime() {
+                c.commands.remove_skill(&name);
+                c.context.unwatch_owner(&name);
+            }
+            let result = Self::execute(name.clone(), skill, self.timeout, |skill| {
+                skill.reload()?;
+                Ok((skill.schedules(), skill.fallback_priority()))
+            })
+            .await;
+
+            match result {
+                Ok((schedules, fallback)) => {
+                    Self::register_schedules(&name, schedules);
+                    if let Some(priority) = fallback {
+                        fallbacks.push((name.clone(), priority));
                     }
+                    emit!(SKILL_LOADED, json!({ "skill": name }));
+                }
+                Err(e) => {
+                    error!("Error reloading skill {}, unloading it: {}", name, e);
+                    self.drop_registrations(&name);
+                    failed.push(name);
                 }
             }
+        }
+
+        for (name, skill) in self.load_new_skills(&failed).await {
+            if let Some(priority) = skill.lock().fallback_priority() {
+                fallbacks.push((name.clone(), priority));
+            }
+            self.skills.write().insert(name.clone(), Arc::clone(&skill));
+            Self::subscribe_skill(Arc::clone(&self.skills), name.clone(), skill, self.timeout);
+            emit!(SKILL_LOADED, json!({ "skill": name }));
+        }
+        *self.fallbacks.write() = Self::fallback_order(fallbacks);
 
-            skills
+        if let Ok(c) = runtime()
+            && let Err(e) = c.commands.announce().await
+        {
+            warn!("{}", e);
+        }
+
+        if failed.is_empty() {
+            Ok(())
         } else {
-            skills
+            Err(format!("Failed to reload skills {}", failed.join(", ")))
         }
     }
 
-    pub fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Reloading skills.");
-        for skill in &mut self.skills.values_mut() {
-            skill.reload()?;
+    /// Unloads a skill that was uninstalled, dropping its commands, watchers and scheduled jobs.
+    fn unload(&self, name: &str) {
+        emit!(SKILL_UNLOADED, json!({ "skill": name }));
+        self.drop_registrations(name);
+    }
+
+    /// Forgets a skill and everything it registered in the core. Its subscriptions stop
+    /// dispatching once it is no longer among the loaded skills.
+    ///
+    /// The jobs of a skill that is still installed are held until it loads again.
+    fn drop_registrations(&self, name: &str) {
+        self.skills.write().remove(name);
+        if let Ok(c) = runtime() {
+            c.commands.remove_skill(name);
+            c.context.unwatch_owner(name);
+            if Self::is_installed(name) {
+                c.scheduler.suspend(name);
+            } else {
+                c.scheduler.remove_owner(name);
+            }
+        }
+    }
+
+    /// Whether `skill` is still the loaded skill `name`, rather than unloaded or replaced.
+    fn is_loaded(skills: &Skills, name: &str, skill: &SkillHandle) -> bool {
+        skills
+            .read()
+            .get(name)
+            .is_some_and(|loaded| Arc::ptr_eq(loaded, skill))
+    }
+
+    /// Loads the skill directories that aren't loaded yet, except the ones in `skip`.
+    async fn load_new_skills(&self, skip: &[String]) -> Vec<(String, SkillHandle)> {
+        let mut added = Vec::new();
+        for path in Self::skill_dirs() {
+            let is_new = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    !self.skills.read().contains_key(name) && !skip.iter().any(|s| s == name)
+                });
+            if !is_new || !path.is_dir() {
+                continue;
+            }
+
+            let timeout = self.timeout;
+            match tokio::task::spawn_blocking(move || Self::load(path, timeout)).await {
+                Ok(Ok((name, skill))) => {
+                    info!("Loaded new skill {}", name);
+                    added.push((name, skill));
+                }
+                Ok(Err(e)) => warn!("{}", e),
+                Err(e) => error!("Loading a new skill panicked: {}", e),
+            }
         }
-        Ok(())
+        added
     }
 
     /// Loads an individual skill from a directory path.
@@ -105,24 +380,52 @@ impl SkillManager {
         Ok((dir_name_str.into(), Skill::new(dir_name_str.to_string())?))
     }
 
-    fn load(path: PathBuf) -> Result<(String, Skill), String> {
+    fn load(path: PathBuf, timeout: Duration) -> Result<(String, SkillHandle), String> {
         match Self::load_skill(path.clone()) {
-            Ok((dir, mut v)) => match v.start() {
-                Ok(_) => {
-                    info!("Loaded skill {} from {}", v.name(), path.display());
-                    Ok((dir.to_string(), v))
+            Ok((dir, v)) => {
+                let skill = SkillRef {
+                    name: dir.clone(),
+                    handle: Arc::new(Mutex::new(v)),
+                    timeout,
+                };
+                // Entered so the top-level statements can register callbacks, e.g. context watchers
+                let started = skill.enter(|| skill.handle.lock().start(timeout));
+                let v = skill.handle.lock();
+                match started {
+                    Ok(_) => {
+                        info!("Loaded skill {} from {}", v.name(), path.display());
+                        Self::register_schedules(&dir, v.schedules());
+                        drop(v);
+                        Ok((dir, skill.handle))
+                    }
+                    Err(e) => {
+                        if let Ok(c) = runtime() {
+                            c.context.unwatch_owner(&dir);
+                        }
+                        Err(format!(
+                            "Error loading skill from {} ({}): {}",
+                            path.display(),
+                            v.name(),
+                            e
+                        ))
+                    }
                 }
-                Err(e) => Err(format!(
-                    "Error loading skill from {} ({}): {}",
-                    path.display(),
-                    v.name(),
-                    e
-                )),
-            },
+            }
             Err(e) => Err(format!("Error loading skill (SkillManager): {}", e)),
         }
     }
 
+    /// Replaces the jobs of a skill's `every` and `at` blocks and lets its held jobs run again.
+    fn register_schedules(name: &str, schedules: Vec<Trigger>) {
+        match runtime() {
+            Ok(c) => {
+                c.scheduler.replace_handlers(name, schedules);
+                c.scheduler.resume(name);
+            }
+            Err(e) => warn!("Could not schedule the blocks of skill {}: {}", name, e),
+        }
+    }
+
     fn merge_entities_and_intents(
         entities: Vec<Entity>,
         intents: Vec<InputIntent>,
@@ -140,16 +443,20 @@ impl SkillManager {
         data
     }
 
+    /// Returns the directory of every loaded skill.
+    pub fn skill_paths(&self) -> Vec<PathBuf> {
+        self.skills
+            .read()
+            .values()
+            .map(|skill| skill.lock().pathname())
+            .collect()
+    }
+
     pub fn get_dataset(&self) -> Data {
         let mut docs_intent: Vec<InputIntent> = Default::default();
         let mut docs_entities: Vec<Entity> = Default::default();
 
-        for skill in self
-            .skills
-            .values()
-            .map(|skill| skill.pathname())
-            .collect::<Vec<PathBuf>>()
-        {
+        for skill in self.skill_paths() {
             docs_intent.append(&mut get_all_docs_on_folder(
                 skill.join("intent/intents").clone(),
                 None,
@@ -175,16 +482,19 @@ impl SkillManager {
     /// Dispatches an intent to the corresponding skill for execution.
     ///
     /// The skill name is extracted from the full intent name (format: `skill_name@intent_name`).
+    /// When the intent is missing a required slot, the user is asked for it instead and the
+    /// intent is dispatched again once the reply fills it.
     ///
     /// # Arguments
     ///
     /// * `intent` - The intent object to be executed.
+    /// * `origin` - The request the text came from, used to key replies and route speech.
     ///
     /// # Errors
     ///
     /// Returns an error if the intent is malformed or if the target skill is not found.
-    pub fn run_intent(&mut self, intent: Intent) -> Result<bool, Box<dyn std::error::Error>> {
-        info!("Running intent {:?}", intent);
+    pub async fn run_intent(&self, intent: Intent, origin: &Origin) -> Result<bool, String> {
+        info!("[{}] Running intent {:?}", origin.trace_id, intent);
         let intent_info = match intent.intent.clone() {
             Some(v) => v.0,
             None => return Err("Intent is not defined".into()),
@@ -199,34 +509,449 @@ impl SkillManager {
 
         let skill_name = parts[0];
 
-        match self.skills.get_mut(skill_name) {
-            Some(v) => Ok(v.run_intent(intent)?),
-            None => Err(format!("Skill {} not found", skill_name).into()),
+        let skill = self.skills.read().get(skill_name).cloned();
+        let result = match skill {
+            Some(v) => {
+                let missing =
+                    Self::execute(skill_name.to_string(), Arc::clone(&v), self.timeout, {
+                        let intent = intent.clone();
+                        move |skill| Ok(skill.missing_slot(&intent))
+                    })
+                    .await?;
+
+                if let Some((slot, prompt)) = missing {
+                    info!(
+                        "[{}] Intent {} is missing slot {}, asking for it",
+                        origin.trace_id, full_name, slot.name
+                    );
+                    speak!(&prompt, to: origin);
+                    return Ok(runtime()?
+                        .reply_manager
+                        .set_reply(
+                            &origin.device,
+                            RequestReply {
+                                skill_request: skill_name.to_string(),
+                                validator: slot.validator.build(),
+                                handler: ReplyHandler::Slot(Box::new(SlotFilling { intent, slot })),
+                            },
+                        )
+                        .await);
+                }
+
+                let turn = intent.clone();
+                let result = Self::execute(skill_name.to_string(), v, self.timeout, {
+                    let origin = origin.clone();
+                    move |skill| with_origin(&origin, || skill.run_intent(intent))
+                })
+                .await;
+                if let Ok(true) = result {
+                    Turn::remember(&origin.device, skill_name, &turn);
+                }
+                result
+            }
+            None => Err(format!("Skill {} not found", skill_name)),
+        };
+
+        emit!(
+            INTENT_EXECUTED,
+            json!({
+                "skill": skill_name,
+                "intent": full_name,
+                "handled": matches!(result, Ok(true)),
+                "trace_id": origin.trace_id,
+            })
+        );
+
+        result
+    }
+
+    /// The question asking the user whether they meant `intent`, in the current language.
+    ///
+    /// Skills name their intents with an `intent.{name}` key in their responses, e.g.
+    /// `intent.hi`, otherwise the user is asked whether they wanted the skill itself.
+    pub fn confirm_prompt(&self, intent: &str) -> Option<String> {
+        let skill_name = intent.split('@').next().unwrap_or_default();
+        let skill = self.skills.read().get(skill_name).cloned()?;
+        let (described, skill_name) = {
+            let skill = skill.lock();
+            (
+                skill.describe_intent(intent),
+                skill.display_name().to_string(),
+            )
+        };
+
+        let c = runtime().ok()?;
+        match described {
+            Some(description) => {
+                let fmt = HashMap::from([("intent".to_string(), description.into())]);
+                c.language_system
+                    .locale_fmt(&user_language(), "did_you_mean", &fmt)
+            }
+            None => {
+                let fmt = HashMap::from([("skill".to_string(), skill_name.into())]);
+                c.language_system
+                    .locale_fmt(&user_language(), "did_you_mean_skill", &fmt)
+            }
+        }
+    }
+
+    /// Offers an utterance no intent matched to the fallback skills, in order, until one handles it.
+    ///
+    /// # Returns
+    ///
+    /// Whether a fallback skill handled the utterance.
+    pub async fn run_fallback(&self, text: &str, origin: &Origin) -> bool {
+        let fallbacks = self.fallbacks.read().clone();
+        for name in fallbacks {
+            let Some(skill) = self.skills.read().get(&name).cloned() else {
+                continue;
+            };
+
+            let result = Self::execute(name.clone(), skill, self.timeout, {
+                let text = text.to_string();
+                let origin = origin.clone();
+                move |skill| with_origin(&origin, || skill.run_fallback(&text))
+            })
+            .await;
+
+            match result {
+                Ok(true) => {
+                    info!(
+                        "[{}] Fallback skill {} handled '{}'",
+                        origin.trace_id, name, text
+                    );
+                    return true;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "[{}] Fallback skill {} failed: {}",
+                    origin.trace_id, name, e
+                ),
+            }
         }
+        false
     }
 
     #[allow(dead_code)]
-    pub fn run_skill_function<T: Variant + Clone>(
-        &mut self,
+    pub async fn run_skill_function<T: Variant + Clone>(
+        &self,
         skill_name: &str,
         function_name: &str,
         args: Vec<T>,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        match self.skills.get_mut(skill_name) {
-            Some(v) => Ok(v.run_function(function_name, args)?),
-            None => Err(format!("Skill {} not found", skill_name).into()),
+    ) -> Result<bool, String> {
+        let function_name = function_name.to_string();
+        let skill = self.skills.read().get(skill_name).cloned();
+        match skill {
+            Some(v) => {
+                Self::execute(skill_name.to_string(), v, self.timeout, move |skill| {
+                    skill.run_function(&function_name, args)
+                })
+                .await
+            }
+            None => Err(format!("Skill {} not found", skill_name)),
         }
     }
 
-    pub fn run_skill_function_ptr<T: Variant + Clone>(
-        &mut self,
+    /// Calls a function pointer of a skill on behalf of the request `origin`.
+    pub async fn run_skill_function_ptr<T: Variant + Clone>(
+        &self,
         skill_name: &str,
         function: FnPtr,
         args: Vec<T>,
-    ) -> Result<bool, Box<dyn std::error::Error>> {
-        match self.skills.get_mut(skill_name) {
-            Some(v) => Ok(v.run_function_ptr(function, args)?),
-            None => Err(format!("Skill {} not found", skill_name).into()),
+        origin: &Origin,
+    ) -> Result<bool, String> {
+        let origin = origin.clone();
+        let skill = self.skills.read().get(skill_name).cloned();
+        match skill {
+            Some(v) => {
+                Self::execute(skill_name.to_string(), v, self.timeout, move |skill| {
+                    with_origin(&origin, || skill.run_function_ptr(function, args))
+                })
+                .await
+            }
+            None => Err(format!("Skill {} not found", skill_name)),
+        }
+    }
+
+    /// Runs `f` against a skill on the blocking worker pool.
+    ///
+    /// The skill's watchdog aborts the script once `timeout` is exceeded. If the
+    /// script is stuck in a native call instead, the caller stops waiting shortly after.
+    /// Either way the failure is reported to the log and to the user.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the skill stays busy, times out, panics or fails.
+    async fn execute<T, F>(
+        name: String,
+        skill: SkillHandle,
+        timeout: Duration,
+        f: F,
+    ) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Skill) -> Result<T, Box<dyn std::error::Error>> + Send + 'static,
+    {
+        let reported = Arc::new(AtomicBool::new(false));
+
+        let task = {
+            let name = name.clone();
+            let reported = Arc::clone(&reported);
+
+            tokio::task::spawn_blocking(move || {
+                let current = SkillRef {
+                    name: name.clone(),
+                    handle: Arc::clone(&skill),
+                    timeout,
+                };
+                let Some(mut skill) = skill.try_lock_for(timeout) else {
+                    return Err(format!("Skill {} is busy", name));
+                };
+
+                current.enter(|| skill.guarded(timeout, f)).map_err(|e| {
+                    if e.is::<SkillTimeout>() && !reported.swap(true, Ordering::SeqCst) {
+                        Self::report_timeout(&name, timeout);
+                    }
+                    e.to_string()
+                })
+            })
+        };
+
+        match tokio::time::timeout(timeout + TIMEOUT_GRACE, task).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(format!("Skill {} panicked: {}", name, e)),
+            Err(_) => {
+                if !reported.swap(true, Ordering::SeqCst) {
+                    Self::report_timeout(&name, timeout);
+                }
+                Err(format!(
+                    "Skill {} did not finish within {:?}",
+                    name, timeout
+                ))
+            }
+        }
+    }
+
+    fn report_timeout(name: &str, timeout: Duration) {
+        error!(
+            "Skill {} ran for more than {:?} and was aborted",
+            name, timeout
+        );
+        speak!(locale: "skill_timeout");
+    }
+
+    /// Subscribes to the topics and events listed in a skill's manifest, dispatching them to its handlers.
+    ///
+    /// The skill receives every bus event emitted once this returns, until it is unloaded.
+    fn subscribe_skill(skills: Skills, name: String, skill: SkillHandle, timeout: Duration) {
+        let subscriptions = skill.lock().subscriptions();
+        let receiver = match runtime() {
+            Ok(c) => c.events.subscribe(),
+            Err(e) => {
+                error!("Failed to subscribe skill {} to events: {}", name, e);
+                return;
+            }
+        };
+
+        rt_spawn! {
+            let mut events = HashSet::new();
+
+            for subscription in subscriptions {
+                let event = match Event::get_event(subscription) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Invalid subscription in skill {}: {}", name, e);
+                        continue;
+                    }
+                };
+
+                if matches!(event.event_type, EventType::Event) {
+                    events.insert(event.event_name.to_lowercase());
+                    continue;
+                }
+
+                let topic = event.event_name.clone();
+                subscribe!(&topic, captures: [skills, name, skill, event], async: |from, _topic, data| {
+                    if !Self::is_loaded(&skills, &name, &skill) {
+                        return;
+                    }
+                    let dispatched = event.clone();
+                    let result = Self::execute(name.clone(), skill, timeout, move |skill| {
+                        skill.run_event(&dispatched, Dynamic::from_blob(data), from.to_string())
+                    })
+                    .await;
+
+                    if let Err(e) = result {
+                        warn!("Error dispatching {} to skill {}: {}", event.string(), name, e);
+                    }
+                });
+            }
+
+            if !events.is_empty() {
+                Self::listen_events(skills, name, skill, timeout, events, receiver).await;
+            }
+        }
+    }
+
+    /// Dispatches the internal bus events a skill subscribed to, one at a time.
+    async fn listen_events(
+        skills: Skills,
+        name: String,
+        skill: SkillHandle,
+        timeout: Duration,
+        events: HashSet<String>,
+        mut receiver: Receiver<BusEvent>,
+    ) {
+        loop {
+            let bus_event = match receiver.recv().await {
+                Ok(v) => v,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Skill {} missed {} events", name, missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if !Self::is_loaded(&skills, &name, &skill) {
+                return;
+            }
+            if !events.contains(&bus_event.name.to_lowercase())
+                || !EventBus::is_visible_to(&bus_event, &name)
+            {
+                continue;
+            }
+
+            let event = Event {
+                event_type: EventType::Event,
+                event_name: bus_event.name,
+            };
+            let data = json_to_dynamic(bus_event.data);
+            let source = bus_event.source;
+
+            let result = Self::execute(name.clone(), Arc::clone(&skill), timeout, {
+                let event = event.clone();
+                move |skill| skill.run_event(&event, data, source)
+            })
+            .await;
+
+            if let Err(e) = result {
+                warn!(
+                    "Error dispatching {} to skill {}: {}",
+                    event.string(),
+                    name,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Runs the skill code of scheduled jobs and command calls when the core reports them.
+    fn dispatch_core_events(skills: Skills, timeout: Duration) {
+        let mut receiver = match runtime() {
+            Ok(c) => c.events.subscribe(),
+            Err(e) => {
+                error!("Failed to listen for scheduled jobs and commands: {}", e);
+                return;
+            }
+        };
+
+        rt_spawn! {
+            loop {
+                let bus_event = match receiver.recv().await {
+                    Ok(v) => v,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Missed {} events while dispatching to skills", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                if bus_event.source != CORE_SOURCE
+                    || (bus_event.name != SCHEDULE_FIRED && bus_event.name != COMMAND_RECEIVED)
+                {
+                    continue;
+                }
+
+                let Some(name) = bus_event.data["skill"].as_str().map(str::to_string) else {
+                    continue;
+                };
+                let Some(skill) = skills.read().get(&name).cloned() else {
+                    warn!("{} for unknown skill {}", bus_event.name, name);
+                    // The caller is waiting on the result, tell it the command is gone
+                    if bus_event.name == COMMAND_RECEIVED
+                        && let (Some(id), Some(from), Ok(c)) = (
+                            bus_event.data["id"].as_str(),
+                            bus_event.data["from"].as_str(),
+                            runtime(),
+                        )
+                    {
+                        let error = format!("Skill {} is not loaded", name);
+                        c.commands.respond(from, id, Err(error)).await;
+                    }
+                    continue;
+                };
+
+                let data = bus_event.data;
+                if bus_event.name == SCHEDULE_FIRED {
+                    tokio::spawn(Self::run_scheduled_job(name, skill, timeout, data));
+                } else {
+                    tokio::spawn(Self::run_command(name, skill, timeout, data));
+                }
+            }
+        }
+    }
+
+    async fn run_scheduled_job(name: String, skill: SkillHandle, timeout: Duration, data: Value) {
+        let result = if let Some(function) = data["function"].as_str() {
+            let function = function.to_string();
+            Self::execute(name.clone(), skill, timeout, move |skill| {
+                skill.run_function::<Dynamic>(&function, ()).map(|_| true)
+            })
+            .await
+        } else if let Some(index) = data["handler"].as_u64() {
+            Self::execute(name.clone(), skill, timeout, move |skill| {
+                skill.run_scheduled(index as usize)
+            })
+            .await
+        } else {
+            Ok(false)
+        };
+
+        if let Err(e) = result {
+            warn!(
+                "Error running scheduled job {} of skill {}: {}",
+                data["job"], name, e
+            );
+        }
+    }
+
+    /// Runs the function behind a command and sends its return value to the caller.
+    async fn run_command(name: String, skill: SkillHandle, timeout: Duration, data: Value) {
+        let (Some(id), Some(from), Some(function)) = (
+            data["id"].as_str(),
+            data["from"].as_str(),
+            data["function"].as_str().map(str::to_string),
+        ) else {
+            warn!("Malformed command call for skill {}", name);
+            return;
+        };
+
+        let args = json_to_dynamic(data["args"].clone());
+        let result = Self::execute(name.clone(), skill, timeout, move |skill| {
+            skill.run_function::<Dynamic>(&function, (args,))
+        })
+        .await
+        .and_then(dynamic_to_json);
+
+        if let Err(e) = &result {
+            warn!(
+                "Command {} of skill {} failed: {}",
+                data["command"], name, e
+            );
+        }
+
+        if let Ok(c) = runtime() {
+            c.commands.respond(from, id, result).await;
         }
     }
 }
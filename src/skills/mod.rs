@@ -1,3 +1,4 @@
+pub mod handlers;
 pub mod manager;
 pub mod skill;
 mod skill_context;
@@ -1,17 +1,90 @@
 use crate::ctx::runtime;
+use crate::data::scheduler::Trigger;
 use crate::dialogue::intent::Intent;
+use crate::dialogue::languages::user_language;
+use crate::dialogue::slot_filling::{RequiredSlot, SlotRequirements};
 use crate::skills::avi_script::engine::create_avi_script_engine;
-use crate::skills::avi_script::helpers::fix_module_imports;
+use crate::skills::avi_script::helpers::{expand_handler_blocks, fix_module_imports};
+use crate::skills::avi_script::module::context::{migrate_legacy_context, replicate_manifest_keys};
+use crate::skills::handlers::{HANDLERS_SCOPE_KEY, HandlerRegistry};
 use crate::skills::skill_context::SkillContext;
-use crate::utils::{Event, EventType, config_dir};
-use crate::{rt_spawn, subscribe};
-use log::error;
+use crate::utils::{Event, config_dir};
+use log::{error, warn};
 use memory_size_derive::{DeepSize, DeepSizeTree};
-use rhai::{AST, Dynamic, Engine, FnPtr, FuncArgs, ImmutableString, Scope, Variant};
+use parking_lot::Mutex;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, FnPtr, FuncArgs, ImmutableString, Scope, Variant};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Name of the scope constant set once the skill's `on_start` block ran.
+pub const STARTED_SCOPE_KEY: &str = "STARTED";
+
+/// Name of the scope constant telling the top-level statements they run for a hot reload.
+pub const RELOADED_SCOPE_KEY: &str = "RELOADED";
+
+/// Number of script operations between two deadline checks of the watchdog.
+const WATCHDOG_CHECK_INTERVAL: u64 = 256;
+
+/// Error returned when a skill invocation is aborted for running past its time limit.
+#[derive(Debug)]
+pub struct SkillTimeout(pub Duration);
+
+impl std::fmt::Display for SkillTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Script aborted after running for more than {:?}", self.0)
+    }
+}
+
+impl std::error::Error for SkillTimeout {}
+
+/// Deadline checked by the engine's progress callback to abort runaway scripts.
+#[derive(Clone, Default)]
+struct Watchdog {
+    deadline: Arc<Mutex<Option<Instant>>>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// Hooks the watchdog into the engine's progress callback.
+    fn install(&self, engine: &mut Engine) {
+        let watchdog = self.clone();
+        engine.on_progress(move |ops| {
+            if ops % WATCHDOG_CHECK_INTERVAL != 0 {
+                return None;
+            }
+            watchdog.check()
+        });
+    }
+
+    fn check(&self) -> Option<Dynamic> {
+        let expired = self
+            .deadline
+            .lock()
+            .is_some_and(|deadline| Instant::now() >= deadline);
+
+        if expired {
+            self.expired.store(true, Ordering::SeqCst);
+            return Some(Dynamic::from("timeout"));
+        }
+        None
+    }
+
+    fn arm(&self, timeout: Duration) {
+        self.expired.store(false, Ordering::SeqCst);
+        *self.deadline.lock() = Some(Instant::now() + timeout);
+    }
+
+    /// Clears the deadline, returning whether the script was aborted while it was armed.
+    fn disarm(&self) -> bool {
+        *self.deadline.lock() = None;
+        self.expired.swap(false, Ordering::SeqCst)
+    }
+}
 
 /// Represents a standalone skill that can be executed by the Avi system.
 ///
@@ -32,8 +105,17 @@ pub struct Skill {
     #[deep_size(opaque)]
     /// The Rhai scope used to execute the skill.
     scope: Arc<RwLock<Scope<'static>>>,
+    #[deep_size(opaque)]
+    /// Intent and event handlers registered by the skill's script.
+    handlers: HandlerRegistry,
+    #[deep_size(opaque)]
+    /// Aborts invocations that run past their time limit.
+    watchdog: Watchdog,
     /// The configuration and state of the skill.
     context: SkillContext,
+    #[deep_size(opaque)]
+    /// Slots the core asks for before running an intent.
+    slots: SlotRequirements,
 }
 
 impl Skill {
@@ -49,11 +131,15 @@ impl Skill {
     pub fn new(name: String) -> Result<Self, Box<dyn std::error::Error>> {
         let pathname = Self::skill_path(&name)?;
         let context = SkillContext::new(&pathname)?;
+        migrate_legacy_context(&context);
+        replicate_manifest_keys(&context);
 
+        let watchdog = Watchdog::default();
         let mut engine = create_avi_script_engine(false, Some(pathname.clone()))?;
-        Arc::<Engine>::get_mut(&mut engine)
-            .ok_or("Failed to get mutable engine")?
-            .set_default_tag(Dynamic::from(context.clone()));
+        let engine_mut =
+            Arc::<Engine>::get_mut(&mut engine).ok_or("Failed to get mutable engine")?;
+        engine_mut.set_default_tag(Dynamic::from(context.clone()));
+        watchdog.install(engine_mut);
 
         let ast = Arc::new(RwLock::new(Self::compile_ast(
             &engine,
@@ -61,13 +147,19 @@ impl Skill {
             &context.info.entry,
         )?));
 
+        let handlers = HandlerRegistry::new();
+        let slots = SlotRequirements::load(&Path::new(&pathname).join("intent/intents"));
+
         Ok(Self {
             pathname: Arc::from(pathname),
             name: Arc::from(name),
             engine,
             ast,
-            scope: Arc::new(RwLock::new(Self::create_scope())),
+            scope: Arc::new(RwLock::new(Self::create_scope(&handlers, false))),
+            handlers,
+            watchdog,
             context,
+            slots,
         })
     }
 
@@ -85,7 +177,7 @@ impl Skill {
     ) -> Result<AST, Box<dyn std::error::Error>> {
         let file_path = Path::new(path).join(entry);
         let raw_script = Self::read_file(&file_path)?;
-        let processed_script = fix_module_imports(raw_script)?;
+        let processed_script = expand_handler_blocks(fix_module_imports(raw_script)?)?;
         Ok(engine.compile(processed_script)?)
     }
 
@@ -107,83 +199,72 @@ impl Skill {
     }
 
     /// Initializes a new Rhai scope for the skill.
-    fn create_scope() -> Scope<'static> {
-        Scope::new()
+    ///
+    /// A reload scope counts as started, so `on_start` blocks don't run again.
+    fn create_scope(handlers: &HandlerRegistry, reloaded: bool) -> Scope<'static> {
+        let mut scope = Scope::new();
+        scope.push_constant(HANDLERS_SCOPE_KEY, handlers.clone());
+        scope.push_constant(RELOADED_SCOPE_KEY, reloaded);
+        if reloaded {
+            scope.push_constant(STARTED_SCOPE_KEY, true);
+        }
+        scope
     }
 
-    /// Starts the skill by running its main module and subscribing to events.
+    /// Starts the skill by running its main module, which registers its handlers.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time the main module may run before it is aborted.
     ///
     /// # Errors
     ///
     /// Returns an error if the skill is disabled or if the runtime fails.
-    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn start(&mut self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
         if self.is_disabled() {
             return Err("Skill is disabled".into());
         }
 
-        let subscriptions = self.context.info.subscription.clone();
-        let skill_path = Arc::clone(&self.pathname);
-        let ast = Arc::clone(&self.ast);
-        let scope = Arc::clone(&self.scope);
-        let engine = Arc::clone(&self.engine);
+        self.guarded(timeout, Self::run)
+    }
+
+    /// Runs `f` with the watchdog armed, failing with [`SkillTimeout`] if the script ran past `timeout`.
+    pub fn guarded<T>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Self) -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.watchdog.arm(timeout);
+        let result = f(self);
 
-        rt_spawn! {
-            let _ = Self::subscribe_internal(subscriptions, skill_path, ast, scope, engine).await;
+        if self.watchdog.disarm() {
+            return Err(Box::new(SkillTimeout(timeout)));
         }
+        result
+    }
 
-        self.run()
+    /// Returns the events the skill subscribes to in its manifest.
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.context.info.subscription.clone()
     }
 
-    #[allow(dead_code)]
-    /// Subscribes to all events defined in the skill's configuration
-    pub async fn subscribe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        Self::subscribe_internal(
-            self.context.info.subscription.clone(),
-            Arc::clone(&self.pathname),
-            Arc::clone(&self.ast),
-            Arc::clone(&self.scope),
-            Arc::clone(&self.engine),
-        )
-        .await
-    }
-
-    async fn subscribe_internal(
-        subscriptions: Vec<String>,
-        _skill_path: Arc<str>,
-        ast: Arc<RwLock<AST>>,
-        scope: Arc<RwLock<Scope<'static>>>,
-        engine: Arc<Engine>,
+    /// Runs the handlers the skill registered for a topic or an event.
+    pub fn run_event(
+        &mut self,
+        event: &Event,
+        data: Dynamic,
+        from: String,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        for subscription in subscriptions {
-            let event = Event::get_event(subscription.to_string())?;
-
-            if matches!(event.event_type, EventType::Topic) {
-                // Clone Arc and create new engine instance for closure
-                let ast_clone = Arc::clone(&ast);
-                let scope_clone = Arc::clone(&scope);
-                let engine_clone = Arc::clone(&engine);
-
-                subscribe!(event.event_name.clone(), move |from, _topic, data| {
-                    let mut scope_guard = match scope_clone.write() {
-                        Ok(v) => v,
-                        Err(_) => return,
-                    };
-
-                    // Prepare scope with event data
-                    scope_guard.push_constant("EVENT_NAME", event.string());
-                    scope_guard.push_constant("EVENT_DATA", data);
-                    scope_guard.push_constant("EVENT_FROM", from.to_string());
-
-                    // Execute the skill
-                    if let Ok(ast_guard) = ast_clone.read() {
-                        let _ = engine_clone.run_ast_with_scope(&mut scope_guard, &ast_guard);
-                    }
-
-                    // Clean up scope
-                    let _ = scope_guard.remove::<ImmutableString>("EVENT_NAME");
-                    let _ = scope_guard.remove::<Vec<u8>>("EVENT_DATA");
-                    let _ = scope_guard.remove::<String>("EVENT_FROM");
-                });
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
+
+        for handler in self.handlers.subscribers(event) {
+            if let Err(e) =
+                handler.call::<Dynamic>(&self.engine, &ast_guard, (data.clone(), from.clone()))
+            {
+                error!("Error handling {} in {}: {}", event.string(), self.name, e);
             }
         }
 
@@ -205,18 +286,16 @@ impl Skill {
     }
 
     #[allow(dead_code)]
-    /// Stops the skill execution
+    /// Stops the skill execution by running its `on_end` handlers
     pub fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.scope
-            .write()
-            .map_err(|e| e.to_string())?
-            .push_constant("END", true);
-        self.run()?;
-        let _ = self
-            .scope
-            .write()
-            .map_err(|e| e.to_string())?
-            .remove::<bool>("END");
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
+
+        for handler in self.handlers.end() {
+            let _ = handler.call::<Dynamic>(&self.engine, &ast_guard, ())?;
+        }
         Ok(())
     }
 
@@ -225,6 +304,47 @@ impl Skill {
         name.split('@').nth(1).unwrap_or(name).replace('.', "_")
     }
 
+    /// How the skill names `intent` to the user, from the `intent.{name}` key of its responses.
+    pub fn describe_intent(&self, intent: &str) -> Option<String> {
+        let name = intent.split('@').nth(1).unwrap_or(intent);
+        self.context
+            .languages
+            .get_translation(&format!("intent.{}", name))
+    }
+
+    /// The name of the skill in its manifest.
+    pub fn display_name(&self) -> &str {
+        &self.context.info.name
+    }
+
+    /// The first required slot `intent` is missing, with its prompt in the current language.
+    pub fn missing_slot(&self, intent: &Intent) -> Option<(RequiredSlot, String)> {
+        let slot = self.slots.missing(intent)?;
+        let prompt = match self.context.languages.get_translation(&slot.prompt) {
+            Some(prompt) => prompt,
+            None => {
+                warn!(
+                    "Skill {} has no translation for slot prompt {}",
+                    self.name, slot.prompt
+                );
+                Self::generic_slot_prompt(&slot.name)
+            }
+        };
+        Some((slot.clone(), prompt))
+    }
+
+    /// The core question asking for a slot whose prompt the skill doesn't translate.
+    fn generic_slot_prompt(slot: &str) -> String {
+        let fmt = HashMap::from([("slot".to_string(), slot.replace(['_', '-'], " ").into())]);
+        runtime()
+            .ok()
+            .and_then(|c| {
+                c.language_system
+                    .locale_fmt(&user_language(), "missing_slot", &fmt)
+            })
+            .unwrap_or_else(|| slot.replace(['_', '-'], " "))
+    }
+
     /// Executes a specific intent within the skill's Rhai module.
     ///
     /// # Arguments
@@ -244,21 +364,24 @@ impl Skill {
 
         let formatted_name = Self::format_intent_name(&intent_name);
 
-        {
-            let mut scope = self.scope.write().map_err(|e| e.to_string())?;
-            scope.push_constant("INTENT_NAME", ImmutableString::from(formatted_name));
-            scope.push_constant("INTENT", intent);
-        }
+        let Some(handler) = self.handlers.intent(&formatted_name) else {
+            warn!(
+                "Skill {} has no handler for intent {}",
+                self.name, intent_name
+            );
+            return Ok(false);
+        };
 
-        let result = self.run();
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
 
-        // Clean up scope
-        {
-            let mut scope = self.scope.write().map_err(|e| e.to_string())?;
-            let _ = scope.remove::<ImmutableString>("INTENT_NAME");
-            let _ = scope.remove::<Intent>("INTENT");
-        }
-        match result {
+        match handler.call::<Dynamic>(
+            &self.engine,
+            &ast_guard,
+            (ImmutableString::from(formatted_name), intent),
+        ) {
             Ok(_) => {}
             Err(e) => error!("Error running the intent: {}", e),
         };
@@ -276,7 +399,9 @@ impl Skill {
             .read()
             .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
 
-        Ok(self.engine.call_fn::<T>(
+        // The top-level statements already ran when the skill started, don't run them again
+        Ok(self.engine.call_fn_with_options::<T>(
+            CallFnOptions::new().eval_ast(false),
             &mut *self.scope.write().map_err(|e| e.to_string())?,
             &ast_guard,
             function_name,
@@ -298,6 +423,83 @@ impl Skill {
         Ok(function.call(&self.engine, &ast_guard, args)?)
     }
 
+    /// Runs a context watcher, passing it the key too when it takes a third parameter.
+    pub fn run_context_watcher(
+        &mut self,
+        handler: FnPtr,
+        key: &str,
+        old: Dynamic,
+        new: Dynamic,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
+
+        let arity = ast_guard
+            .iter_functions()
+            .find(|f| f.name == handler.fn_name())
+            .map(|f| f.params.len().saturating_sub(handler.curry().len()));
+
+        if arity == Some(3) {
+            let key = ImmutableString::from(key);
+            let _ = handler.call::<Dynamic>(&self.engine, &ast_guard, (old, new, key))?;
+        } else {
+            let _ = handler.call::<Dynamic>(&self.engine, &ast_guard, (old, new))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the triggers of the skill's `every` and `at` blocks.
+    pub fn schedules(&self) -> Vec<Trigger> {
+        self.handlers.schedules()
+    }
+
+    /// Runs the `every` or `at` block at `index`, returning whether it exists.
+    pub fn run_scheduled(&mut self, index: usize) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(handler) = self.handlers.scheduled(index) else {
+            warn!("Skill {} has no scheduled block {}", self.name, index);
+            return Ok(false);
+        };
+
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
+
+        if let Err(e) = handler.call::<Dynamic>(&self.engine, &ast_guard, ()) {
+            error!("Error running scheduled block in {}: {}", self.name, e);
+        }
+        Ok(true)
+    }
+
+    /// Returns the fallback priority of the skill, `None` unless its manifest marks it as a fallback.
+    pub fn fallback_priority(&self) -> Option<i64> {
+        self.context
+            .info
+            .fallback
+            .then_some(self.context.info.priority)
+    }
+
+    /// Runs the `on_fallback` block with an utterance no intent matched.
+    ///
+    /// Returns whether the skill handled it, the block passes it on by returning `false`.
+    pub fn run_fallback(&mut self, text: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let Some(handler) = self.handlers.fallback() else {
+            warn!("Fallback skill {} has no on_fallback block", self.name);
+            return Ok(false);
+        };
+
+        let ast_guard = self
+            .ast
+            .read()
+            .map_err(|e| format!("Failed to acquire AST lock: {}", e))?;
+
+        let handled =
+            handler.call::<Dynamic>(&self.engine, &ast_guard, (ImmutableString::from(text),))?;
+        Ok(handled.as_bool().unwrap_or(true))
+    }
+
     /// Checks if the skill is currently disabled.
     pub fn is_disabled(&self) -> bool {
         self.context.info.disabled
@@ -314,9 +516,14 @@ impl Skill {
         (self.pathname.to_string()).into()
     }
 
-    /// Reloads the skill from disk
+    /// Reloads the skill from disk.
+    ///
+    /// The top-level statements run again to register the handlers of the new script, but
+    /// `on_start` blocks don't. Scripts can check the `RELOADED` constant to skip other side
+    /// effects of their top-level statements.
     pub fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.context = SkillContext::new(&self.pathname)?;
+        self.slots = SlotRequirements::load(&Path::new(&*self.pathname).join("intent/intents"));
 
         let new_ast =
             Self::compile_ast(&self.engine, &self.context.path, &self.context.info.entry)?;
@@ -326,7 +533,16 @@ impl Skill {
             .write()
             .map_err(|e| format!("Failed to acquire AST write lock: {}", e))? = new_ast;
 
-        Ok(())
+        // Handlers point into the previous AST, so evaluate the new one to register them again.
+        // The top-level statements run in a fresh scope, skipping `on_start`, and see `RELOADED`
+        // set so they can skip any other side effect that should only happen once.
+        self.handlers.clear();
+        *self
+            .scope
+            .write()
+            .map_err(|e| format!("Failed to acquire scope write lock: {}", e))? =
+            Self::create_scope(&self.handlers, true);
+        self.run()
     }
 
     /// Returns the size of the Skill struct in bytes
@@ -360,4 +576,41 @@ mod tests {
         );
         assert_eq!(Skill::format_intent_name("skill@ask.time"), "ask_time");
     }
+
+    #[test]
+    fn test_watchdog_aborts_runaway_script() {
+        let watchdog = Watchdog::default();
+        let mut engine = Engine::new();
+        watchdog.install(&mut engine);
+
+        watchdog.arm(Duration::from_millis(50));
+        let result = engine.run("loop { }");
+
+        assert!(matches!(
+            result.map_err(|e| *e),
+            Err(rhai::EvalAltResult::ErrorTerminated(..))
+        ));
+        assert!(watchdog.disarm());
+        assert!(!watchdog.disarm());
+        assert!(engine.run("let x = 1;").is_ok());
+    }
+
+    #[test]
+    fn test_reload_scope_skips_on_start() {
+        let engine = create_avi_script_engine(false, Some(".".into())).unwrap();
+        let ast = engine
+            .compile("let started = false; on_start { started = true; } let reloaded = RELOADED;")
+            .unwrap();
+        let handlers = HandlerRegistry::new();
+
+        let mut scope = Skill::create_scope(&handlers, false);
+        engine.run_ast_with_scope(&mut scope, &ast).unwrap();
+        assert_eq!(scope.get_value::<bool>("started"), Some(true));
+        assert_eq!(scope.get_value::<bool>("reloaded"), Some(false));
+
+        let mut scope = Skill::create_scope(&handlers, true);
+        engine.run_ast_with_scope(&mut scope, &ast).unwrap();
+        assert_eq!(scope.get_value::<bool>("started"), Some(false));
+        assert_eq!(scope.get_value::<bool>("reloaded"), Some(true));
+    }
 }
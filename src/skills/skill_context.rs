@@ -5,6 +5,7 @@ use memory_size_derive::{DeepSize, DeepSizeTree};
 use rhai::CustomType;
 use rhai::TypeBuilder;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::Arc;
 
 /// Helper function to provide a default value of `true` for serde.
@@ -12,6 +13,40 @@ fn default_true() -> bool {
     true
 }
 
+/// Names of the permissions a skill can declare in its manifest.
+///
+/// A manifest entry may also end in `.*` to grant a whole family (e.g. `fs.*`),
+/// or be `*` to grant everything, except for the [`permission::EXPLICIT`] ones.
+pub mod permission {
+    /// Read files through the `fs` module.
+    pub const FS_READ: &str = "fs.read";
+    /// Create, modify or delete files through the `fs` module.
+    pub const FS_WRITE: &str = "fs.write";
+    /// Use absolute host paths in the `fs` module instead of the skill sandbox.
+    pub const FS_UNRESTRICTED: &str = "fs.unrestricted";
+    /// Run shell commands through `util::cmd`.
+    pub const PROCESS_EXEC: &str = "process.exec";
+    /// Read environment variables through `util::env`.
+    pub const ENV_READ: &str = "env.read";
+    /// Speak to the user through the `dialogue` module.
+    pub const SPEAK: &str = "speak";
+    /// Ask a listener device to start listening.
+    pub const LISTEN: &str = "listen";
+    /// Read and write the global (non skill scoped) context.
+    pub const CONTEXT_GLOBAL: &str = "context.global";
+    /// Emit events on the internal event bus through the `events` module.
+    pub const EVENTS_EMIT: &str = "events.emit";
+    /// Publish messages to mesh topics, e.g. from `schedule::publish_every`.
+    pub const MESH_PUBLISH: &str = "mesh.publish";
+    /// Register device commands and call the commands of other devices through the `device` module.
+    pub const DEVICE_COMMANDS: &str = "device.commands";
+    /// List and cancel the pending replies other skills are waiting for.
+    pub const DIALOGUE_REPLIES: &str = "dialogue.replies";
+
+    /// Permissions no wildcard grants, a manifest has to name them exactly.
+    pub const EXPLICIT: &[&str] = &[FS_UNRESTRICTED];
+}
+
 /// The manifest file containing metadata and configuration for a skill.
 #[derive(Debug, Serialize, Default, Deserialize, Clone, CustomType, DeepSize, DeepSizeTree)]
 pub struct Manifest {
@@ -38,6 +73,15 @@ pub struct Manifest {
     /// Whether the skill supports immediate re-execution.
     #[serde(default = "default_true")]
     pub can_go_again: bool,
+    /// Whether the skill gets the utterances no intent matched, through its `on_fallback` block.
+    #[serde(default)]
+    pub fallback: bool,
+    /// The order of fallback skills, higher priorities are asked first.
+    #[serde(default)]
+    pub priority: i64,
+    /// Context keys the skill keeps on every node of the mesh, a key or a `prefix*`.
+    #[serde(default)]
+    pub replicated: Vec<String>,
     /// The author of the skill.
     pub author: String,
     /// The version of the skill.
@@ -81,4 +125,80 @@ impl SkillContext {
             languages: Arc::new(LanguageSystem::new(&format!("{}/responses", path))),
         })
     }
+
+    /// The key the core knows the skill by, the name of its directory.
+    ///
+    /// Everything a skill owns in the core (jobs, commands, context, replies) is keyed by it,
+    /// since the manifest `id` and `name` may differ from the directory.
+    pub fn key(&self) -> &str {
+        Path::new(self.path.as_ref())
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+
+    /// Checks if the skill declared the given permission in its manifest.
+    pub fn has_permission(&self, permission: &str) -> bool {
+        let explicit = permission::EXPLICIT.contains(&permission);
+        self.info.permissions.iter().any(|granted| {
+            granted == permission
+                || !explicit
+                    && (granted == "*"
+                        || granted
+                            .strip_suffix(".*")
+                            .is_some_and(|family| permission.starts_with(&format!("{}.", family))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context_with(permissions: &[&str]) -> SkillContext {
+        SkillContext {
+            path: Arc::from("/tmp/skill"),
+            info: Arc::new(Manifest {
+                id: "test".to_string(),
+                permissions: permissions.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            }),
+            config: Arc::new(ConfigSystem::default()),
+            languages: Arc::new(LanguageSystem { languages: vec![] }),
+        }
+    }
+
+    #[test]
+    fn test_key_is_the_directory_name() {
+        assert_eq!(context_with(&[]).key(), "skill");
+    }
+
+    #[test]
+    fn test_has_permission_exact() {
+        let context = context_with(&["speak", "fs.read"]);
+
+        assert!(context.has_permission(permission::SPEAK));
+        assert!(context.has_permission(permission::FS_READ));
+        assert!(!context.has_permission(permission::FS_WRITE));
+        assert!(!context.has_permission(permission::PROCESS_EXEC));
+    }
+
+    #[test]
+    fn test_has_permission_wildcards() {
+        let family = context_with(&["fs.*"]);
+        assert!(family.has_permission(permission::FS_READ));
+        assert!(family.has_permission(permission::FS_WRITE));
+        assert!(!family.has_permission("fsx.read"));
+        assert!(!family.has_permission(permission::SPEAK));
+
+        let all = context_with(&["*"]);
+        assert!(all.has_permission(permission::PROCESS_EXEC));
+    }
+
+    #[test]
+    fn test_wildcards_do_not_grant_unrestricted_fs() {
+        assert!(!context_with(&["fs.*"]).has_permission(permission::FS_UNRESTRICTED));
+        assert!(!context_with(&["*"]).has_permission(permission::FS_UNRESTRICTED));
+        assert!(context_with(&["fs.unrestricted"]).has_permission(permission::FS_UNRESTRICTED));
+    }
 }
@@ -4,11 +4,16 @@ use crate::actions::intent::IntentAction;
 use crate::actions::mesh::MeshAction;
 use crate::cli::setup::Setup;
 use crate::cli::ui;
+use crate::commands::with_commands;
 use crate::content::getters::get_from_settings;
 use crate::ctx::{create_runtime, runtime};
 use crate::data::config::setting_or;
-use crate::data::context::context_cleanup_task;
-use crate::{register_action, watch_dir};
+use crate::data::context::{context_cleanup_task, context_watch_task};
+use crate::data::context_replication::context_replication_task;
+use crate::data::scheduler::scheduler_task;
+use crate::dialogue::matcher::IntentEngine;
+use crate::events::CONFIG_RELOADED;
+use crate::{emit, register_action, watch_dir};
 use avi_device::DeviceCapabilities;
 use avi_device::device::{AviDevice, AviDeviceConfig, AviDeviceType};
 use log::{error, info};
@@ -35,7 +40,7 @@ pub async fn start_avi(config_path: PathBuf) -> Result<(), Box<dyn std::error::E
             AviDeviceType::NODE
         },
         can_gateway_embedded: setting_or("can_gateway", false),
-        capabilities: DeviceCapabilities::default(),
+        capabilities: with_commands(DeviceCapabilities::default()),
     };
 
     let device = Arc::new(AviDevice::new(config).await?);
@@ -43,7 +48,7 @@ pub async fn start_avi(config_path: PathBuf) -> Result<(), Box<dyn std::error::E
     device.start_event_loop();
 
     ui::step(4, 8, "Initializing Runtime");
-    create_runtime(&config_path.display().to_string(), device);
+    create_runtime(&config_path.display().to_string(), device)?;
 
     setup
         .online_setup(
@@ -62,6 +67,7 @@ pub async fn start_avi(config_path: PathBuf) -> Result<(), Box<dyn std::error::E
     register_action!(IntentAction, pb, if: is_core, {
         watch_skill_dir: setting_or::<bool>("watch_skill_dir", false),
         watch_dir_debounce_time: setting_or::<u64>("watch_dir_debounce_time", 1),
+        engine: IntentEngine::new(setting_or::<String>("intent_engine", "hybrid".to_string())),
     });
 
     register_action!(DialogueAction, pb, {
@@ -77,14 +83,18 @@ pub async fn start_avi(config_path: PathBuf) -> Result<(), Box<dyn std::error::E
         match runtime() {
             Ok(v) => {
                 info!("Change in config directory. Reloading Configuration.");
-                v.configuration.reload()
+                v.configuration.reload();
+                emit!(CONFIG_RELOADED);
             },
             Err(e) => error!("Error reloading configuration: {}", e),
         }
     });
 
-    ui::step(7, 8, "Creating context clenup task");
+    ui::step(7, 8, "Creating context clenup and scheduler tasks");
     context_cleanup_task();
+    context_watch_task();
+    context_replication_task();
+    scheduler_task();
 
     ui::step(8, 8, "Started AVI");
     tokio::signal::ctrl_c().await?;
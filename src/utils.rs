@@ -8,7 +8,7 @@ use log::warn;
 use serde::Deserialize;
 use std::fs;
 use std::path::PathBuf;
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub enum EventType {
     Topic,
     Event,
@@ -31,6 +31,7 @@ impl EventType {
     }
 }
 
+#[derive(Clone)]
 pub struct Event {
     pub event_type: EventType,
     pub event_name: String,